@@ -0,0 +1,84 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Accept throughput over loopback, run with `cargo bench`.
+//!
+//! Compares a loop built on accept_nonblocking() with handle_incoming().
+//! Each round a client thread makes CONNECTIONS connections as fast as
+//! it can, and the time until the server has accepted them all is
+//! reported as connections per second.
+
+use nblistener::Listener;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECTIONS: usize = 500;
+const ROUNDS: usize = 3;
+
+fn connect(listener: &TcpListener) -> thread::JoinHandle<()> {
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for _ in 0..CONNECTIONS {
+            drop(TcpStream::connect(addr).unwrap());
+        }
+    })
+}
+
+fn bench_accept_nonblocking() -> Duration {
+    let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+    let started = Instant::now();
+    let client = connect(&listener);
+    let mut accepted = 0;
+    while accepted < CONNECTIONS {
+        match listener.accept_nonblocking().unwrap() {
+            Some(_) => accepted += 1,
+            None => thread::yield_now(),
+        }
+    }
+    let elapsed = started.elapsed();
+    client.join().unwrap();
+    elapsed
+}
+
+fn bench_handle_incoming() -> Duration {
+    let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+    let started = Instant::now();
+    let client = connect(&listener);
+    let mut accepted = 0;
+    listener
+        .handle_incoming(
+            |_| {
+                accepted += 1;
+                if accepted == CONNECTIONS {
+                    listener.close();
+                }
+            },
+            Duration::from_micros(10),
+        )
+        .unwrap();
+    let elapsed = started.elapsed();
+    client.join().unwrap();
+    elapsed
+}
+
+fn report(name: &str, bench: fn() -> Duration) {
+    let mut best = Duration::from_secs(u64::MAX);
+    for _ in 0..ROUNDS {
+        best = std::cmp::min(best, bench());
+    }
+    let rate = CONNECTIONS as f64 / best.as_secs_f64();
+    println!("{:<20} {:>10.0} conn/s (best of {})", name, rate, ROUNDS);
+}
+
+fn main() {
+    report("accept_nonblocking", bench_accept_nonblocking);
+    report("handle_incoming", bench_handle_incoming);
+}
@@ -0,0 +1,215 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The stoppable accept loop for any kind of listener.
+//!
+//! An [Acceptor](trait.Acceptor.html) only has to accept a connection
+//! without blocking and to close itself; the trait provides serve() on
+//! top, which terminates normally once close_listener() is called from
+//! any thread. It is implemented for TcpListener and, on Unix,
+//! UnixListener, and can be implemented for other transports, such as
+//! an SCTP socket wrapper.
+//!
+//! This is the loop behind
+//! [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming)
+//! and its variants, so every transport gets the same handling of
+//! drains, recoverable errors and stats. A transport with an OS handle
+//! returns it from raw_handle(), so that
+//! [close_after_drain()](../trait.Listener.html#tymethod.close_after_drain)
+//! can find its loop and, on Windows, the loop waits for a connection
+//! rather than sleeping.
+//!
+//! The module loops, such as
+//! [ThreadPoolListener](../threaded/struct.ThreadPoolListener.html)'s
+//! and [StoppableListener](../stop/struct.StoppableListener.html)'s,
+//! run on it too. A few entry points which don't serve one listener
+//! until it stops have loops of their own:
+//! [ListenerSet](../set/struct.ListenerSet.html), the
+//! [Incoming](../future/struct.Incoming.html) future, the
+//! [StoppableIncoming](../incoming/struct.StoppableIncoming.html)
+//! iterator, [ListenerSource](../source/struct.ListenerSource.html) and
+//! [Listener::handle_incoming_for()](../trait.Listener.html#tymethod.handle_incoming_for).
+//! They don't assign connection ids, record stats, trace or call hooks.
+//! ListenerSet and StoppableIncoming carry on after recoverable accept
+//! errors; the others return them.
+
+use crate::{accept_loop, close_raw, Callbacks, Error, StopReason};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{self, UnixListener, UnixStream};
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+use std::time::Duration;
+
+/// A connection accepted by A, with its peer address, if one was waiting.
+pub type Accepted<A> = Option<(<A as Acceptor>::Conn, <A as Acceptor>::Addr)>;
+
+/// A listener which accepts connections without blocking.
+pub trait Acceptor {
+    /// The accepted connections.
+    type Conn;
+
+    /// The peer addresses of accepted connections.
+    type Addr;
+
+    /// Accept a connection if one is waiting, or return None. Once
+    /// closed, this must fail with an error for which is_closed_error()
+    /// is true.
+    fn accept_nb(&self) -> Result<Accepted<Self>, io::Error>;
+
+    /// Close the listener. No more connections will be accepted and
+    /// if serve() is active, it will terminate normally.
+    fn close_listener(&self);
+
+    /// Did accept_nb() fail because the listener was closed? By default
+    /// this checks for EBADF or EINVAL, as returned by a socket closed
+    /// with the crate's close().
+    fn is_closed_error(&self, err: &io::Error) -> bool {
+        crate::is_closed(err)
+    }
+
+    /// The listener's raw descriptor or socket, if it has one. None by
+    /// default, in which case the listener can't be drained and the
+    /// loop always sleeps between accepts.
+    fn raw_handle(&self) -> Option<usize> {
+        None
+    }
+
+    /// Start handling incoming connections, sleeping for timeout while
+    /// none is waiting, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming).
+    fn serve<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(Self::Conn),
+        Self: Sized,
+    {
+        let mut handler = handler;
+        accept_loop(
+            self,
+            &mut |conn, _, _| handler(conn),
+            timeout,
+            Callbacks::default(),
+        )
+        .map_err(Error::from)
+    }
+}
+
+impl Acceptor for TcpListener {
+    type Conn = TcpStream;
+    type Addr = SocketAddr;
+
+    fn accept_nb(&self) -> Result<Option<(TcpStream, SocketAddr)>, io::Error> {
+        match self.accept() {
+            Ok(accepted) => Ok(Some(accepted)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn close_listener(&self) {
+        if let Some(raw) = self.raw_handle() {
+            close_raw(raw);
+        }
+    }
+
+    fn raw_handle(&self) -> Option<usize> {
+        #[cfg(windows)]
+        return Some(self.as_raw_socket() as usize);
+        #[cfg(not(windows))]
+        return Some(self.as_raw_fd() as usize);
+    }
+}
+
+#[cfg(unix)]
+impl Acceptor for UnixListener {
+    type Conn = UnixStream;
+    type Addr = net::SocketAddr;
+
+    fn accept_nb(&self) -> Result<Option<(UnixStream, net::SocketAddr)>, io::Error> {
+        match self.accept() {
+            Ok(accepted) => Ok(Some(accepted)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn close_listener(&self) {
+        close_raw(self.as_raw_fd() as usize);
+    }
+
+    fn raw_handle(&self) -> Option<usize> {
+        Some(self.as_raw_fd() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    // A transport whose connections are queued numbers
+    #[derive(Default)]
+    struct Queue {
+        waiting: Mutex<VecDeque<u32>>,
+        closed: AtomicBool,
+    }
+
+    impl Acceptor for Queue {
+        type Conn = u32;
+        type Addr = ();
+
+        fn accept_nb(&self) -> Result<Option<(u32, ())>, io::Error> {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(ErrorKind::NotConnected.into());
+            }
+            Ok(self.waiting.lock().unwrap().pop_front().map(|n| (n, ())))
+        }
+
+        fn close_listener(&self) {
+            self.closed.store(true, Ordering::SeqCst);
+        }
+
+        fn is_closed_error(&self, err: &io::Error) -> bool {
+            err.kind() == ErrorKind::NotConnected
+        }
+    }
+
+    #[test]
+    fn test_acceptor() {
+        let queue = Queue::default();
+        queue.waiting.lock().unwrap().extend(&[1, 2, 3]);
+        let mut accepted = vec![];
+        let reason = queue
+            .serve(
+                |n| {
+                    accepted.push(n);
+                    if n == 3 {
+                        queue.close_listener();
+                    }
+                },
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        assert_eq!(reason, StopReason::Closed);
+        assert_eq!(accepted, vec![1, 2, 3]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let reason = listener
+            .serve(|_| listener.close_listener(), Duration::from_millis(1))
+            .unwrap();
+        assert_eq!(reason, StopReason::Closed);
+    }
+}
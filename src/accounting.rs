@@ -0,0 +1,278 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Aggregate byte accounting and bandwidth caps.
+//!
+//! A [ByteMeter](struct.ByteMeter.html) counts bytes read and written by
+//! every stream attached to it and can optionally cap their combined
+//! rate. [ByteAccounting](struct.ByteAccounting.html) keeps a global
+//! meter plus one per [QosClass](../qos/enum.QosClass.html), so that
+//! traffic is both counted and capped at each level. Unlike a
+//! [Throttle](../throttle/struct.Throttle.html), which limits each
+//! connection separately, caps here are shared by all connections.
+
+use crate::qos::QosClass;
+use crate::throttle::TokenBucket;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Snapshot of a [ByteMeter](struct.ByteMeter.html).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ByteStats {
+    /// Bytes read from attached streams.
+    pub bytes_in: u64,
+    /// Bytes written to attached streams.
+    pub bytes_out: u64,
+    /// Streams currently attached.
+    pub active: usize,
+}
+
+#[derive(Default)]
+struct MeterInner {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    active: AtomicUsize,
+    read_cap: Option<Mutex<TokenBucket>>,
+    write_cap: Option<Mutex<TokenBucket>>,
+}
+
+/// Counts, and optionally caps, the bytes transferred by a group of
+/// streams. Clones share the same counters.
+#[derive(Clone, Default)]
+pub struct ByteMeter {
+    inner: Arc<MeterInner>,
+}
+
+impl ByteMeter {
+    /// Create a meter without any caps.
+    pub fn new() -> ByteMeter {
+        ByteMeter::default()
+    }
+
+    /// Create a meter which caps the combined read and write rates of
+    /// its streams, in bytes per second. Bursts of up to a second's
+    /// worth of traffic are allowed.
+    pub fn with_caps(read: Option<u64>, write: Option<u64>) -> ByteMeter {
+        let cap = |rate| Mutex::new(TokenBucket::new(rate, rate));
+        ByteMeter {
+            inner: Arc::new(MeterInner {
+                read_cap: read.map(cap),
+                write_cap: write.map(cap),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Current counters.
+    pub fn stats(&self) -> ByteStats {
+        ByteStats {
+            bytes_in: self.inner.bytes_in.load(Ordering::SeqCst),
+            bytes_out: self.inner.bytes_out.load(Ordering::SeqCst),
+            active: self.inner.active.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Attach stream to this meter only.
+    pub fn attach<S>(&self, stream: S) -> Metered<S> {
+        Metered::new(stream, vec![self.clone()])
+    }
+}
+
+// Wait until cap has room for some of wanted bytes, without holding the
+// lock while sleeping. Returns how many bytes may be transferred, which
+// is zero if the cap's rate is zero and it is used up.
+fn wait_for(cap: &Mutex<TokenBucket>, wanted: usize) -> usize {
+    loop {
+        let wait = {
+            let mut bucket = cap.lock().unwrap();
+            let wait = bucket.time_until(wanted as u64);
+            if wait == Duration::from_secs(0) || wait == Duration::MAX {
+                return std::cmp::min(bucket.available() as usize, wanted);
+            }
+            wait
+        };
+        thread::sleep(wait);
+    }
+}
+
+/// Global and per [QosClass](../qos/enum.QosClass.html) byte meters.
+#[derive(Clone, Default)]
+pub struct ByteAccounting {
+    global: ByteMeter,
+    classes: HashMap<QosClass, ByteMeter>,
+}
+
+impl ByteAccounting {
+    /// Create accounting with an uncapped global meter.
+    pub fn new() -> ByteAccounting {
+        ByteAccounting::default()
+    }
+
+    /// Use meter as the global meter.
+    pub fn global(mut self, meter: ByteMeter) -> Self {
+        self.global = meter;
+        self
+    }
+
+    /// Use meter for connections in class. Classes without a meter are
+    /// only counted globally.
+    pub fn class(mut self, class: QosClass, meter: ByteMeter) -> Self {
+        self.classes.insert(class, meter);
+        self
+    }
+
+    /// Counters across all connections.
+    pub fn global_stats(&self) -> ByteStats {
+        self.global.stats()
+    }
+
+    /// Counters for connections in class, if it has a meter.
+    pub fn class_stats(&self, class: QosClass) -> Option<ByteStats> {
+        self.classes.get(&class).map(|m| m.stats())
+    }
+
+    /// Attach stream to the global meter and to the meter for class.
+    pub fn attach<S>(&self, stream: S, class: QosClass) -> Metered<S> {
+        let mut meters = vec![self.global.clone()];
+        if let Some(meter) = self.classes.get(&class) {
+            meters.push(meter.clone());
+        }
+        Metered::new(stream, meters)
+    }
+}
+
+/// A stream whose traffic is counted, and possibly capped, by one or
+/// more [ByteMeter](struct.ByteMeter.html)s.
+pub struct Metered<S> {
+    stream: S,
+    meters: Vec<ByteMeter>,
+}
+
+impl<S> Metered<S> {
+    fn new(stream: S, meters: Vec<ByteMeter>) -> Metered<S> {
+        for meter in meters.iter() {
+            meter.inner.active.fetch_add(1, Ordering::SeqCst);
+        }
+        Metered { stream, meters }
+    }
+
+    /// The wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The wrapped stream. Traffic on it isn't counted.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S> Drop for Metered<S> {
+    fn drop(&mut self) {
+        for meter in self.meters.iter() {
+            meter.inner.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<S: Read> Read for Metered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut allowed = buf.len();
+        for meter in self.meters.iter() {
+            if let Some(cap) = meter.inner.read_cap.as_ref() {
+                allowed = wait_for(cap, allowed);
+            }
+        }
+        if allowed == 0 && !buf.is_empty() {
+            return Err(Error::new(ErrorKind::WouldBlock, "byte cap exhausted"));
+        }
+        let n = self.stream.read(&mut buf[..allowed])?;
+        for meter in self.meters.iter() {
+            meter.inner.bytes_in.fetch_add(n as u64, Ordering::SeqCst);
+            if let Some(cap) = meter.inner.read_cap.as_ref() {
+                cap.lock().unwrap().consume(n as u64);
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Metered<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut allowed = buf.len();
+        for meter in self.meters.iter() {
+            if let Some(cap) = meter.inner.write_cap.as_ref() {
+                allowed = wait_for(cap, allowed);
+            }
+        }
+        if allowed == 0 && !buf.is_empty() {
+            return Err(Error::new(ErrorKind::WouldBlock, "byte cap exhausted"));
+        }
+        let n = self.stream.write(&buf[..allowed])?;
+        for meter in self.meters.iter() {
+            meter.inner.bytes_out.fetch_add(n as u64, Ordering::SeqCst);
+            if let Some(cap) = meter.inner.write_cap.as_ref() {
+                cap.lock().unwrap().consume(n as u64);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_accounting() {
+        let accounting = ByteAccounting::new()
+            .class(QosClass::Background, ByteMeter::with_caps(None, Some(1000)));
+
+        let mut a = accounting.attach(Vec::new(), QosClass::Premium);
+        let mut b = accounting.attach(Vec::new(), QosClass::Background);
+        let mut c = accounting.attach(&b"hello"[..], QosClass::Background);
+        assert_eq!(accounting.global_stats().active, 3);
+
+        a.write_all(&[0; 2000]).unwrap();
+        let started = Instant::now();
+        b.write_all(&[0; 1500]).unwrap();
+        // A second's burst, then the remaining 500 bytes at 1000/s
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        let mut buf = String::new();
+        c.read_to_string(&mut buf).unwrap();
+        drop(c);
+
+        assert_eq!(
+            accounting.global_stats(),
+            ByteStats {
+                bytes_in: 5,
+                bytes_out: 3500,
+                active: 2
+            }
+        );
+        assert_eq!(
+            accounting.class_stats(QosClass::Background),
+            Some(ByteStats {
+                bytes_in: 5,
+                bytes_out: 1500,
+                active: 1
+            })
+        );
+        assert_eq!(accounting.class_stats(QosClass::Premium), None);
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Varying the sleep when no connection is waiting.
+//!
+//! A fixed sleep is either short, waking often for nothing, or long,
+//! delaying connections which arrive while the loop sleeps. A
+//! [BackoffStrategy](enum.BackoffStrategy.html) passed to
+//! [handle_incoming_with_backoff()](../trait.Listener.html#tymethod.handle_incoming_with_backoff)
+//! lets the sleep start short after activity and grow while the
+//! listener is idle.
+
+use std::time::{Duration, Instant};
+
+/// How long to sleep each time the listener would block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackoffStrategy {
+    /// Always sleep for this long.
+    Fixed(Duration),
+    /// Sleep for min after a connection is accepted, doubling with each
+    /// idle sleep up to max.
+    Exponential {
+        /// First sleep after a connection.
+        min: Duration,
+        /// Longest sleep.
+        max: Duration,
+    },
+    /// Sleep for a quarter of the average time between recent
+    /// connections, kept between min and max, so a busy listener is
+    /// checked often and a quiet one rarely.
+    Adaptive {
+        /// Shortest sleep.
+        min: Duration,
+        /// Longest sleep.
+        max: Duration,
+    },
+}
+
+// Sleep state of one accept loop.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    strategy: BackoffStrategy,
+    current: Duration,
+    last_accept: Option<Instant>,
+    // Moving average of the time between connections
+    mean_gap: Option<Duration>,
+}
+
+impl Backoff {
+    pub(crate) fn new(strategy: BackoffStrategy) -> Backoff {
+        let current = match strategy {
+            BackoffStrategy::Fixed(sleep) => sleep,
+            BackoffStrategy::Exponential { min, .. } => min,
+            BackoffStrategy::Adaptive { max, .. } => max,
+        };
+        Backoff {
+            strategy,
+            current,
+            last_accept: None,
+            mean_gap: None,
+        }
+    }
+
+    pub(crate) fn on_accept(&mut self, now: Instant) {
+        match self.strategy {
+            BackoffStrategy::Fixed(_) => (),
+            BackoffStrategy::Exponential { min, .. } => self.current = min,
+            BackoffStrategy::Adaptive { min, max } => {
+                if let Some(last) = self.last_accept {
+                    let gap = now.saturating_duration_since(last);
+                    let mean = match self.mean_gap {
+                        Some(mean) => mean.saturating_mul(7).saturating_add(gap) / 8,
+                        None => gap,
+                    };
+                    self.mean_gap = Some(mean);
+                    self.current = std::cmp::min(std::cmp::max(mean / 4, min), max);
+                }
+                self.last_accept = Some(now);
+            }
+        }
+    }
+
+    // The next sleep, advancing the state.
+    pub(crate) fn next_sleep(&mut self) -> Duration {
+        let sleep = self.current;
+        if let BackoffStrategy::Exponential { max, .. } = self.strategy {
+            // Saturate, so that a max near Duration::MAX can't overflow
+            self.current = std::cmp::min(self.current.saturating_mul(2), max);
+        }
+        sleep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let ms = Duration::from_millis;
+        let mut exp = Backoff::new(BackoffStrategy::Exponential {
+            min: ms(1),
+            max: ms(5),
+        });
+        let sleeps: Vec<Duration> = (0..4).map(|_| exp.next_sleep()).collect();
+        assert_eq!(sleeps, vec![ms(1), ms(2), ms(4), ms(5)]);
+        exp.on_accept(Instant::now());
+        assert_eq!(exp.next_sleep(), ms(1));
+
+        let mut adaptive = Backoff::new(BackoffStrategy::Adaptive {
+            min: ms(1),
+            max: ms(100),
+        });
+        assert_eq!(adaptive.next_sleep(), ms(100));
+        let start = Instant::now();
+        adaptive.on_accept(start);
+        adaptive.on_accept(start + ms(40));
+        assert_eq!(adaptive.next_sleep(), ms(10));
+
+        // Doubling past Duration::MAX stops at max
+        let mut huge = Backoff::new(BackoffStrategy::Exponential {
+            min: Duration::MAX / 2 + ms(1),
+            max: Duration::MAX,
+        });
+        huge.next_sleep();
+        assert_eq!(huge.next_sleep(), Duration::MAX);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! In-kernel packet filters on a listening socket (Linux only).
+//!
+//! A classic BPF program attached with [attach_filter()](fn.attach_filter.html),
+//! or an eBPF program attached with [attach_bpf()](fn.attach_bpf.html),
+//! runs on every packet that arrives for the listener. Packets which
+//! the program rejects are dropped by the kernel, so unwanted peers
+//! never complete a handshake and never reach accept().
+//! [ListenerBuilder::filter()](../builder/struct.ListenerBuilder.html#method.filter)
+//! attaches a classic program before the socket starts listening, so
+//! that no connection slips through before the filter is in place.
+//!
+//! For classic filters the program sees the packet from its IP header
+//! onwards. [stmt()](fn.stmt.html) and [jump()](fn.jump.html) build
+//! instructions in the same way as the BPF_STMT and BPF_JUMP macros.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// A classic BPF instruction.
+pub type Instruction = libc::sock_filter;
+
+// Not defined by every libc version
+const SO_ATTACH_BPF: libc::c_int = 50;
+
+/// Build a non-jump instruction, like BPF_STMT.
+pub fn stmt(code: u16, k: u32) -> Instruction {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// Build a jump instruction, like BPF_JUMP.
+pub fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Instruction {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+// A classic program held until a socket exists to attach it to.
+#[derive(Clone)]
+pub(crate) struct Program(pub(crate) Vec<Instruction>);
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Program({} instructions)", self.0.len())
+    }
+}
+
+/// Attach a classic BPF program to listener, replacing any filter
+/// already attached. Fails with InvalidInput if the program is longer
+/// than 65535 instructions, the most a socket filter can hold.
+pub fn attach_filter(listener: &TcpListener, program: &[Instruction]) -> Result<(), Error> {
+    attach_filter_fd(listener.as_raw_fd(), program)
+}
+
+pub(crate) fn attach_filter_fd(fd: RawFd, program: &[Instruction]) -> Result<(), Error> {
+    if program.len() > libc::c_ushort::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("filter of {} instructions is too long", program.len()),
+        ));
+    }
+    let prog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    setsockopt(
+        fd,
+        libc::SO_ATTACH_FILTER,
+        &prog as *const _ as *const libc::c_void,
+        std::mem::size_of::<libc::sock_fprog>(),
+    )
+}
+
+/// Attach a loaded eBPF socket filter program, given its descriptor,
+/// to listener.
+pub fn attach_bpf(listener: &TcpListener, prog_fd: RawFd) -> Result<(), Error> {
+    setsockopt(
+        listener.as_raw_fd(),
+        SO_ATTACH_BPF,
+        &prog_fd as *const RawFd as *const libc::c_void,
+        std::mem::size_of::<RawFd>(),
+    )
+}
+
+/// Remove the filter attached to listener.
+pub fn detach_filter(listener: &TcpListener) -> Result<(), Error> {
+    let dummy: libc::c_int = 0;
+    setsockopt(
+        listener.as_raw_fd(),
+        libc::SO_DETACH_FILTER,
+        &dummy as *const libc::c_int as *const libc::c_void,
+        std::mem::size_of::<libc::c_int>(),
+    )
+}
+
+fn setsockopt(
+    fd: RawFd,
+    name: libc::c_int,
+    val: *const libc::c_void,
+    len: usize,
+) -> Result<(), Error> {
+    let rc = unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, name, val, len as libc::socklen_t) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    const BPF_RET_K: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+
+    #[test]
+    fn test_filter() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Drop everything
+        attach_filter(&listener, &[stmt(BPF_RET_K, 0)]).unwrap();
+        assert!(TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_err());
+
+        // Accept everything
+        attach_filter(&listener, &[stmt(BPF_RET_K, u32::MAX)]).unwrap();
+        assert!(TcpStream::connect_timeout(&addr, Duration::from_secs(5)).is_ok());
+
+        detach_filter(&listener).unwrap();
+        assert!(detach_filter(&listener).is_err());
+
+        let long = vec![stmt(BPF_RET_K, 0); 65536];
+        let err = attach_filter(&listener, &long).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,558 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Configure socket options before binding (Unix only).
+//!
+//! Some options, such as SO_REUSEPORT, IPV6_V6ONLY and the listen
+//! backlog, only take effect if they are set before the socket is bound
+//! or starts listening, which TcpListener::bind() doesn't allow. A
+//! [ListenerBuilder](struct.ListenerBuilder.html) creates the socket
+//! itself, applies the options, then binds and listens. Options for
+//! accepted streams, such as TCP_NODELAY, are applied by the returned
+//! [ConfiguredListener](struct.ConfiguredListener.html) as each
+//! connection is accepted, as is a cap on simultaneous connections set
+//! with [max_connections()](struct.ListenerBuilder.html#method.max_connections).
+//! Its accept loops keep [stats()](struct.ConfiguredListener.html#method.stats)
+//! as they run.
+//!
+//! On Linux, TCP Fast Open and TCP_DEFER_ACCEPT can also be enabled, so
+//! that the accept loop only sees connections whose first data has
+//! arrived. Elsewhere these options are ignored;
+//! [supports_tcp_fastopen()](struct.ListenerBuilder.html#method.supports_tcp_fastopen)
+//! and [supports_defer_accept()](struct.ListenerBuilder.html#method.supports_defer_accept)
+//! say whether they take effect.
+//!
+//! On Linux a classic BPF program can be attached with
+//! [filter()](struct.ListenerBuilder.html#method.filter) before the
+//! socket starts listening; see the [bpf](../bpf/index.html) module.
+//!
+//! For options the builder doesn't cover, configure a socket2::Socket
+//! and pass it to [Listener::from_std()](../trait.Listener.html#tymethod.from_std).
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::bpf;
+use crate::labels::Labels;
+use crate::limit::{ConnectionLimit, Permit};
+use crate::stats::ListenerStats;
+use crate::{accept_loop, Callbacks};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Deref;
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+
+/// Creates listeners with socket options applied before binding.
+#[derive(Clone, Debug)]
+pub struct ListenerBuilder {
+    reuse_addr: bool,
+    reuse_port: bool,
+    only_v6: Option<bool>,
+    backlog: i32,
+    nodelay: bool,
+    nonblocking: bool,
+    tcp_fastopen: Option<i32>,
+    defer_accept: Option<u32>,
+    max_connections: Option<usize>,
+    labels: Labels,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    filter: Option<bpf::Program>,
+}
+
+impl Default for ListenerBuilder {
+    /// SO_REUSEADDR and non-blocking, as with
+    /// [Listener::bind()](../trait.Listener.html#tymethod.bind), and a
+    /// backlog of 128.
+    fn default() -> ListenerBuilder {
+        ListenerBuilder {
+            reuse_addr: true,
+            reuse_port: false,
+            only_v6: None,
+            backlog: 128,
+            nodelay: false,
+            nonblocking: true,
+            tcp_fastopen: None,
+            defer_accept: None,
+            max_connections: None,
+            labels: Labels::new(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            filter: None,
+        }
+    }
+}
+
+impl ListenerBuilder {
+    /// Create a builder with the default options.
+    pub fn new() -> ListenerBuilder {
+        ListenerBuilder::default()
+    }
+
+    /// Set SO_REUSEADDR.
+    pub fn reuse_addr(mut self, reuse: bool) -> Self {
+        self.reuse_addr = reuse;
+        self
+    }
+
+    /// Set SO_REUSEPORT, so several sockets can bind the same address.
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// Set IPV6_V6ONLY on IPv6 sockets. If not set, the system default
+    /// applies.
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Length of the queue of connections waiting to be accepted.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set TCP_NODELAY on accepted streams.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Whether the listener is non-blocking. Accept loops need it to be.
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Enable TCP Fast Open, allowing up to qlen connections whose
+    /// handshake is still pending to carry data (Linux only).
+    pub fn tcp_fastopen(mut self, qlen: i32) -> Self {
+        self.tcp_fastopen = Some(qlen);
+        self
+    }
+
+    /// Handle at most max connections at once, with a
+    /// [ConnectionLimit](../limit/struct.ConnectionLimit.html): while
+    /// every slot is in use, the accept loop stops accepting.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Attach labels to the listener, which its accept loops carry to
+    /// every connection, trace event and stats, as inside
+    /// [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope).
+    pub fn labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Only accept a connection once data has arrived on it, or after
+    /// secs seconds, when the kernel gives up waiting (Linux only).
+    pub fn defer_accept(mut self, secs: u32) -> Self {
+        self.defer_accept = Some(secs);
+        self
+    }
+
+    /// Attach a classic BPF program to the socket before it starts
+    /// listening (Linux only). See [bpf::attach_filter()](../bpf/fn.attach_filter.html).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn filter(mut self, program: &[bpf::Instruction]) -> Self {
+        self.filter = Some(bpf::Program(program.to_vec()));
+        self
+    }
+
+    /// Does tcp_fastopen() take effect on this platform?
+    pub fn supports_tcp_fastopen() -> bool {
+        cfg!(any(target_os = "linux", target_os = "android"))
+    }
+
+    /// Does defer_accept() take effect on this platform?
+    pub fn supports_defer_accept() -> bool {
+        cfg!(any(target_os = "linux", target_os = "android"))
+    }
+
+    /// Create a socket, apply the options, and bind it to the first of
+    /// addr's addresses which succeeds.
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> Result<ConfiguredListener, Error> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_one(&addr) {
+                Ok(listener) => {
+                    return Ok(ConfiguredListener {
+                        listener,
+                        nodelay: self.nodelay,
+                        limit: self.max_connections.map(ConnectionLimit::new),
+                        labels: self.labels.clone(),
+                        stats: ListenerStats::with_labels(self.labels.clone()),
+                    })
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "could not resolve to any address")
+        }))
+    }
+
+    fn bind_one(&self, addr: &SocketAddr) -> Result<TcpListener, Error> {
+        let family = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = socket(family)?;
+        // Owning the fd from here on closes it on error
+        let listener = unsafe { <TcpListener as FromRawFd>::from_raw_fd(fd) };
+        set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, self.reuse_addr)?;
+        if self.reuse_port {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, true)?;
+        }
+        if let (Some(only_v6), SocketAddr::V6(_)) = (self.only_v6, addr) {
+            set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, only_v6)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if let Some(qlen) = self.tcp_fastopen {
+                set_option_int(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, qlen)?;
+            }
+            if let Some(secs) = self.defer_accept {
+                let secs = std::cmp::min(secs, libc::c_int::MAX as u32) as libc::c_int;
+                set_option_int(fd, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, secs)?;
+            }
+            if let Some(program) = self.filter.as_ref() {
+                bpf::attach_filter_fd(fd, &program.0)?;
+            }
+        }
+        let (storage, len) = sockaddr(addr);
+        let rc = unsafe {
+            libc::bind(
+                fd,
+                &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+                len,
+            )
+        };
+        if rc != 0 || unsafe { libc::listen(fd, self.backlog) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        listener.set_nonblocking(self.nonblocking)?;
+        Ok(listener)
+    }
+}
+
+/// A listener created by a [ListenerBuilder](struct.ListenerBuilder.html).
+/// Derefs to the TcpListener, so close() and the
+/// [Listener](../trait.Listener.html) accept loops can be used directly,
+/// but only the methods here apply the accepted stream options.
+#[derive(Debug)]
+pub struct ConfiguredListener {
+    listener: TcpListener,
+    nodelay: bool,
+    limit: Option<ConnectionLimit>,
+    labels: Labels,
+    stats: ListenerStats,
+}
+
+impl ConfiguredListener {
+    /// Unwrap the listener.
+    pub fn into_inner(self) -> TcpListener {
+        self.listener
+    }
+
+    /// Apply the accepted stream options to stream.
+    pub fn configure(&self, stream: &TcpStream) -> Result<(), Error> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        Ok(())
+    }
+
+    /// The labels set by labels(). Run other accept loops inside
+    /// labels().in_scope() to carry them.
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    /// The limit set by max_connections(), if any.
+    pub fn connection_limit(&self) -> Option<&ConnectionLimit> {
+        self.limit.as_ref()
+    }
+
+    /// Counters kept by handle_incoming() and
+    /// handle_incoming_with_permit(), which can be read from another
+    /// thread while they run.
+    pub fn stats(&self) -> &ListenerStats {
+        &self.stats
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// applying the accepted stream options to each one. With
+    /// max_connections(), each connection holds a slot while handler
+    /// runs.
+    pub fn handle_incoming<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+    ) -> Result<crate::StopReason, crate::Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        self.handle_incoming_with_permit(|stream, _| handler(stream), timeout)
+    }
+
+    /// Works like handle_incoming(), but with max_connections() handler
+    /// is also given the connection's
+    /// [Permit](../limit/struct.Permit.html), which it keeps until it is
+    /// finished with the connection, possibly on another thread.
+    pub fn handle_incoming_with_permit<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+    ) -> Result<crate::StopReason, crate::Error>
+    where
+        H: FnMut(TcpStream, Option<Permit>),
+    {
+        let mut handler = handler;
+        let mut handle = |stream, permit| {
+            let _running = self.stats.handler_started();
+            let _ = self.configure(&stream);
+            handler(stream, permit)
+        };
+        self.labels.in_scope(|| match self.limit.as_ref() {
+            Some(limit) => limit.serve(
+                &self.listener,
+                |stream, permit| handle(stream, Some(permit)),
+                timeout,
+                Some(&self.stats),
+            ),
+            None => {
+                let callbacks = Callbacks {
+                    stats: Some(&self.stats),
+                    ..Default::default()
+                };
+                accept_loop(
+                    &self.listener,
+                    &mut |stream, _, _| handle(stream, None),
+                    timeout,
+                    callbacks,
+                )
+                .map_err(crate::Error::from)
+            }
+        })
+    }
+}
+
+impl Deref for ConfiguredListener {
+    type Target = TcpListener;
+
+    fn deref(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+// Create a close-on-exec TCP socket. Where the platform allows, the
+// flag is set atomically, so a fork and exec on another thread can't
+// inherit the socket.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn socket(family: libc::c_int) -> Result<libc::c_int, Error> {
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn socket(family: libc::c_int) -> Result<libc::c_int, Error> {
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        let err = Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+fn set_option(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    on: bool,
+) -> Result<(), Error> {
+    set_option_int(fd, level, name, on as libc::c_int)
+}
+
+fn set_option_int(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    val: libc::c_int,
+) -> Result<(), Error> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &val as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Convert addr to a sockaddr for bind().
+pub(crate) fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(v4) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(v4.ip().octets()),
+            };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: v6.ip().octets(),
+            };
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_scope_id = v6.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sockopt::EffectiveOptions;
+    use crate::Listener;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_builder() {
+        let listener = ListenerBuilder::new()
+            .reuse_port(true)
+            .backlog(16)
+            .nodelay(true)
+            .tcp_fastopen(16)
+            .defer_accept(5)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let report = listener.effective_options().unwrap();
+        assert_eq!(report.reuse_port, Some(true));
+        assert_eq!(report.nonblocking, Some(true));
+        let flags = unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+
+        // Another socket can share the port. Drop it, so that the kernel
+        // doesn't hand it the connection below.
+        drop(ListenerBuilder::new().reuse_port(true).bind(addr).unwrap());
+
+        // With a deferred accept, only a connection with data is seen
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET").unwrap();
+        let (tx, rx) = mpsc::channel();
+        listener
+            .handle_incoming(
+                |stream| {
+                    tx.send(stream.nodelay().unwrap()).unwrap();
+                    listener.close();
+                },
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        assert!(rx.recv().unwrap());
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let listener = ListenerBuilder::new()
+            .max_connections(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                listener
+                    .handle_incoming_with_permit(
+                        |stream, permit| tx.send((stream, permit.unwrap())).unwrap(),
+                        Duration::from_millis(5),
+                    )
+                    .unwrap()
+            });
+            let _first = TcpStream::connect(addr).unwrap();
+            let held = rx.recv().unwrap();
+            assert_eq!(listener.connection_limit().unwrap().active(), 1);
+
+            // The second connection waits until the permit is released
+            let _second = TcpStream::connect(addr).unwrap();
+            assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+            drop(held);
+            let held = rx.recv().unwrap();
+
+            // A close is seen while waiting at the limit
+            listener.close();
+            assert_eq!(server.join().unwrap(), crate::StopReason::Closed);
+            drop(held);
+            let stats = listener.stats().snapshot();
+            assert_eq!(stats.accepted, 2);
+            assert_eq!(stats.active, 0);
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_filter() {
+        let drop_all = crate::bpf::stmt((libc::BPF_RET | libc::BPF_K) as u16, 0);
+        let listener = ListenerBuilder::new()
+            .filter(&[drop_all])
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_err());
+
+        let long = vec![drop_all; 65536];
+        let err = ListenerBuilder::new()
+            .filter(&long)
+            .bind("127.0.0.1:0")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Record connections to files and replay them into handlers.
+//!
+//! A [Capture](struct.Capture.html) wraps selected connections in a
+//! [Captured](struct.Captured.html) stream, which writes the bytes read
+//! from the peer, and optionally those written to it, to a file per
+//! connection. [replay()](fn.replay.html) later feeds the recorded
+//! inbound bytes to a handler, so a problem seen in production can be
+//! reproduced offline.
+//!
+//! A capture file starts with `key: value` metadata lines, ending with
+//! a blank line. Each record then follows as a direction byte, `<` for
+//! inbound or `>` for outbound, a 4 byte big-endian length and the
+//! data.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Error, ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type Selector = Box<dyn Fn(&SocketAddr) -> bool + Send + Sync>;
+
+/// Where and what to capture.
+pub struct Capture {
+    dir: PathBuf,
+    outbound: bool,
+    select: Option<Selector>,
+    count: AtomicU64,
+}
+
+impl Capture {
+    /// Capture inbound bytes of every connection to files in dir.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Capture {
+        Capture {
+            dir: dir.as_ref().to_path_buf(),
+            outbound: false,
+            select: None,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Also capture the bytes written to the peer.
+    pub fn outbound(mut self, outbound: bool) -> Self {
+        self.outbound = outbound;
+        self
+    }
+
+    /// Only capture connections from peers for which select returns
+    /// true.
+    pub fn select<F>(mut self, select: F) -> Self
+    where
+        F: Fn(&SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.select = Some(Box::new(select));
+        self
+    }
+
+    /// Wrap stream, which is connected to peer. If the peer is selected
+    /// a capture file is created for it.
+    pub fn wrap<S>(&self, stream: S, peer: SocketAddr) -> Result<Captured<S>> {
+        if !self.select.as_ref().map_or(true, |select| select(&peer)) {
+            return Ok(Captured {
+                stream,
+                file: None,
+                outbound: false,
+                path: None,
+            });
+        }
+        let seq = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        fs::create_dir_all(&self.dir)?;
+        let name = format!("{}-{}-{}.cap", started, seq, peer).replace([':', '[', ']'], "_");
+        let path = self.dir.join(name);
+        let mut file = BufWriter::new(File::create(&path)?);
+        write!(
+            file,
+            "peer: {}\nstarted: {}\nsequence: {}\noutbound: {}\n\n",
+            peer, started, seq, self.outbound
+        )?;
+        Ok(Captured {
+            stream,
+            file: Some(file),
+            outbound: self.outbound,
+            path: Some(path),
+        })
+    }
+}
+
+/// A stream whose traffic may be recorded to a capture file.
+pub struct Captured<S> {
+    stream: S,
+    file: Option<BufWriter<File>>,
+    outbound: bool,
+    path: Option<PathBuf>,
+}
+
+impl<S> Captured<S> {
+    /// The capture file, if this connection is being captured.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Unwrap the stream, flushing the capture file.
+    pub fn into_inner(mut self) -> S {
+        if let Some(mut file) = self.file.take() {
+            let _ = file.flush();
+        }
+        self.stream
+    }
+
+    // Capture failures must not break the connection, so stop
+    // capturing instead.
+    fn record(&mut self, direction: u8, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if let Some(file) = self.file.as_mut() {
+            let ok = file.write_all(&[direction]).is_ok()
+                && file.write_all(&(data.len() as u32).to_be_bytes()).is_ok()
+                && file.write_all(data).is_ok();
+            if !ok {
+                self.file = None;
+            }
+        }
+    }
+}
+
+impl<S> Deref for Captured<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> DerefMut for Captured<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S: Read> Read for Captured<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.stream.read(buf)?;
+        self.record(b'<', &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Captured<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.stream.write(buf)?;
+        if self.outbound {
+            self.record(b'>', &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.flush();
+        }
+        self.stream.flush()
+    }
+}
+
+/// A capture file read back into memory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recording {
+    /// Metadata from the file header, in order.
+    pub metadata: Vec<(String, String)>,
+    /// All bytes read from the peer.
+    pub inbound: Vec<u8>,
+    /// All bytes written to the peer, if they were captured.
+    pub outbound: Vec<u8>,
+}
+
+impl Recording {
+    /// Load a capture file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Recording> {
+        let data = fs::read(path)?;
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid capture file");
+        let end = data
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or_else(invalid)?;
+        let header = std::str::from_utf8(&data[..end]).map_err(|_| invalid())?;
+        let mut recording = Recording::default();
+        for line in header.lines() {
+            let (key, value) = line.split_once(": ").ok_or_else(invalid)?;
+            recording
+                .metadata
+                .push((key.to_string(), value.to_string()));
+        }
+        let mut rest = &data[end + 2..];
+        while !rest.is_empty() {
+            if rest.len() < 5 {
+                return Err(invalid());
+            }
+            let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            let body = rest.get(5..5 + len).ok_or_else(invalid)?;
+            match rest[0] {
+                b'<' => recording.inbound.extend_from_slice(body),
+                b'>' => recording.outbound.extend_from_slice(body),
+                _ => return Err(invalid()),
+            }
+            rest = &rest[5 + len..];
+        }
+        Ok(recording)
+    }
+
+    /// The value of a metadata key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A stream which reads recorded inbound bytes and collects whatever
+/// is written to it.
+pub struct ReplayStream {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl ReplayStream {
+    /// What has been written to the stream.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Feed the inbound bytes recorded in path to handler and return what
+/// it wrote in response.
+pub fn replay<P, F>(path: P, handler: F) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut ReplayStream),
+{
+    let recording = Recording::load(path)?;
+    let mut stream = ReplayStream {
+        input: Cursor::new(recording.inbound),
+        output: Vec::new(),
+    };
+    handler(&mut stream);
+    Ok(stream.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(stream: &mut ReplayStream) {
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+        stream.write_all(buf.to_uppercase().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let dir = std::env::temp_dir().join(format!("nblistener-capture-{}", std::process::id()));
+        let capture = Capture::new(&dir)
+            .outbound(true)
+            .select(|peer| peer.port() != 1);
+        let peer: SocketAddr = "[::1]:4000".parse().unwrap();
+
+        let skipped = capture.wrap(Cursor::new(Vec::<u8>::new()), "[::1]:1".parse().unwrap());
+        assert!(skipped.unwrap().path().is_none());
+
+        let mut stream = capture.wrap(Cursor::new(b"hello".to_vec()), peer).unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(b"HELLO").unwrap();
+        let path = stream.path().unwrap().to_path_buf();
+        stream.into_inner();
+
+        let recording = Recording::load(&path).unwrap();
+        assert_eq!(recording.get("peer"), Some("[::1]:4000"));
+        assert_eq!(recording.inbound, b"hello");
+        assert_eq!(recording.outbound, b"HELLO");
+        assert_eq!(replay(&path, echo).unwrap(), recording.outbound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Receive accepted connections over a channel.
+//!
+//! [spawn_incoming()](trait.SpawnIncoming.html#tymethod.spawn_incoming)
+//! runs the accept loop on a new thread and sends each connection down
+//! an `mpsc` channel, rather than calling a handler, which suits code
+//! that waits on several sources at once. When the listener is closed
+//! the thread exits and the channel is closed, so the receiver sees a
+//! disconnect once it has taken every connection. If the
+//! [IncomingReceiver](struct.IncomingReceiver.html) is dropped, the
+//! loop stops the next time it wakes, within timeout, with
+//! [StopReason::Condition](../enum.StopReason.html#variant.Condition).
+//! The listener itself is left open for its owner.
+
+use crate::{Error, Listener, StopReason};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Deref;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The receiving end of [spawn_incoming()](trait.SpawnIncoming.html#tymethod.spawn_incoming).
+/// Derefs to the channel's Receiver.
+#[derive(Debug)]
+pub struct IncomingReceiver {
+    rx: Receiver<TcpStream>,
+    // The loop stops once this has no strong references
+    _live: Arc<()>,
+}
+
+impl Deref for IncomingReceiver {
+    type Target = Receiver<TcpStream>;
+
+    fn deref(&self) -> &Receiver<TcpStream> {
+        &self.rx
+    }
+}
+
+/// Run an accept loop which feeds a channel.
+pub trait SpawnIncoming {
+    /// Spawn a thread handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// which sends each one to the returned receiver. The thread's
+    /// result is that of the loop.
+    fn spawn_incoming(
+        &self,
+        timeout: Duration,
+    ) -> (JoinHandle<Result<StopReason, Error>>, IncomingReceiver);
+}
+
+impl SpawnIncoming for Arc<TcpListener> {
+    fn spawn_incoming(
+        &self,
+        timeout: Duration,
+    ) -> (JoinHandle<Result<StopReason, Error>>, IncomingReceiver) {
+        let (tx, rx) = mpsc::channel();
+        let live = Arc::new(());
+        let receiver = Arc::downgrade(&live);
+        let listener = self.clone();
+        let thread = thread::spawn(move || {
+            let reason = listener.handle_incoming_until(
+                |stream| {
+                    // If nobody is receiving, the stream is just dropped
+                    let _ = tx.send(stream);
+                },
+                timeout,
+                || receiver.upgrade().is_none(),
+            )?;
+            Ok(reason)
+        });
+        (thread, IncomingReceiver { rx, _live: live })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let (thread, incoming) = listener.spawn_incoming(Duration::from_millis(5));
+        let client = TcpStream::connect(addr).unwrap();
+        let stream = incoming.recv().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), client.local_addr().unwrap());
+
+        listener.close();
+        assert_eq!(thread.join().unwrap().unwrap(), StopReason::Closed);
+        assert!(incoming.recv().is_err());
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let (thread, incoming) = listener.spawn_incoming(Duration::from_millis(5));
+        drop(incoming);
+        assert_eq!(thread.join().unwrap().unwrap(), StopReason::Condition);
+        // The listener is still usable by its owner
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pluggable time, for deterministic simulation tests.
+//!
+//! The accept loop, [TokenBucket](../throttle/struct.TokenBucket.html)
+//! and [Deadline](../until/struct.Deadline.html) read the time through
+//! a [Clock](trait.Clock.html) and wait through a
+//! [Sleeper](trait.Sleeper.html). Normally these are the real clock,
+//! [RealTime](struct.RealTime.html), but a test can substitute a
+//! [SimClock](struct.SimClock.html), whose time only moves when it is
+//! advanced or slept on, so that timeouts and rate limits measured in
+//! hours run instantly and repeatably.
+//!
+//! A Sleeper can also be given on its own, with
+//! [Listener::handle_incoming_with_sleeper()](../trait.Listener.html#tymethod.handle_incoming_with_sleeper),
+//! to change how the loop waits while keeping the real clock, e.g.
+//! [YieldNow](struct.YieldNow.html) to poll for connections with the
+//! lowest latency at the cost of a busy CPU.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+/// A way to wait.
+pub trait Sleeper: Send + Sync {
+    /// Wait for duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// A clock which can also sleep.
+pub trait Time: Clock + Sleeper + Debug {}
+
+impl<T: Clock + Sleeper + Debug> Time for T {}
+
+/// The real clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealTime;
+
+impl Clock for RealTime {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl Sleeper for RealTime {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Yields to other threads instead of sleeping.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct YieldNow;
+
+impl Sleeper for YieldNow {
+    fn sleep(&self, _duration: Duration) {
+        thread::yield_now()
+    }
+}
+
+// The real clock, waiting with another sleeper.
+pub(crate) struct RealClockWith<'a, S>(pub(crate) &'a S);
+
+impl<S> Debug for RealClockWith<'_, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("RealClockWith")
+    }
+}
+
+impl<S: Sleeper> Clock for RealClockWith<'_, S> {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<S: Sleeper> Sleeper for RealClockWith<'_, S> {
+    fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration)
+    }
+}
+
+/// A simulated clock. Sleeping advances the clock instead of waiting.
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct SimClock {
+    base: Instant,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl SimClock {
+    /// Create a clock which starts at the current real time.
+    pub fn new() -> SimClock {
+        SimClock {
+            base: Instant::now(),
+            offset: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+
+    /// Simulated time since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.offset.lock().unwrap()
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> SimClock {
+        SimClock::new()
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+}
+
+impl Sleeper for SimClock {
+    /// Advance the clock and yield, so other threads can run.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+        thread::yield_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::throttle::TokenBucket;
+
+    #[test]
+    fn test_sim_clock() {
+        let clock = SimClock::new();
+        let mut bucket = TokenBucket::with_time(1, 1, Arc::new(clock.clone()));
+        bucket.consume(1);
+        assert_eq!(bucket.time_until(1), Duration::from_secs(1));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(bucket.available(), 0);
+        // Waiting sleeps on the simulated clock
+        assert_eq!(bucket.wait_for(1), 1);
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An accepted stream together with its metadata.
+//!
+//! [Listener::handle_connections()](../trait.Listener.html#tymethod.handle_connections)
+//! passes each handler a [Connection](struct.Connection.html), which
+//! carries what a handler usually wants to log or trace alongside the
+//! stream, as a [ConnInfo](../struct.ConnInfo.html): who connected, to
+//! which address, when, and which accept loop took it.
+//!
+//! Each connection also gets an id which is unique within the process
+//! and never reused, unlike the peer's port, so that it can correlate
+//! server logs with the client's. The accept loop assigns it, so the
+//! ids in [trace](../trace/index.html) events and
+//! [stats](../stats/index.html) are the same ones.
+
+use crate::ConnInfo;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_LISTENER_ID: AtomicU64 = AtomicU64::new(1);
+static LAST_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// An accepted connection.
+#[derive(Debug)]
+pub struct Connection {
+    /// The stream.
+    pub stream: TcpStream,
+    /// Who connected, to which address, when, and which accept loop
+    /// took it.
+    pub info: ConnInfo,
+}
+
+impl Connection {
+    /// Unwrap the stream.
+    pub fn into_stream(self) -> TcpStream {
+        self.stream
+    }
+}
+
+/// The id most recently given to a connection, or zero if none has
+/// been accepted.
+pub fn last_connection_id() -> u64 {
+    LAST_CONNECTION_ID.load(Ordering::SeqCst)
+}
+
+// Allocate an id for a new connection.
+pub(crate) fn next_connection_id() -> u64 {
+    LAST_CONNECTION_ID.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+// Allocate an id for a new accept loop.
+pub(crate) fn next_listener_id() -> u64 {
+    NEXT_LISTENER_ID.fetch_add(1, Ordering::Relaxed)
+}
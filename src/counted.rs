@@ -0,0 +1,154 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Per connection byte counters and last activity time.
+//!
+//! A [CountedStream](struct.CountedStream.html) wraps a stream and
+//! records the bytes read and written through it and when it was last
+//! used. The [StreamCounters](struct.StreamCounters.html) are shared,
+//! so another thread, e.g. one reaping idle connections or writing an
+//! audit log, can watch a connection while its handler uses it. The
+//! wrapper derefs to the inner stream, so handlers can still call
+//! methods such as peer_addr() or set_nodelay() on it.
+
+use std::io::{Read, Result, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Counters for one [CountedStream](struct.CountedStream.html).
+#[derive(Debug)]
+pub struct StreamCounters {
+    created: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    // Microseconds from created to the last read or write
+    last_activity: AtomicU64,
+}
+
+impl StreamCounters {
+    /// Bytes read from the stream.
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::SeqCst)
+    }
+
+    /// Bytes written to the stream.
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::SeqCst)
+    }
+
+    /// When the stream was last read or written, or when it was wrapped
+    /// if it hasn't been used.
+    pub fn last_activity(&self) -> Instant {
+        self.created + Duration::from_micros(self.last_activity.load(Ordering::SeqCst))
+    }
+
+    /// Time since the stream was last read or written.
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity().elapsed()
+    }
+
+    fn touch(&self) {
+        let micros = self.created.elapsed().as_micros() as u64;
+        self.last_activity.fetch_max(micros, Ordering::SeqCst);
+    }
+}
+
+/// A stream which counts the bytes transferred through it.
+pub struct CountedStream<S> {
+    stream: S,
+    counters: Arc<StreamCounters>,
+}
+
+impl<S> CountedStream<S> {
+    /// Wrap stream with fresh counters.
+    pub fn new(stream: S) -> CountedStream<S> {
+        CountedStream {
+            stream,
+            counters: Arc::new(StreamCounters {
+                created: Instant::now(),
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+                last_activity: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// The counters, which may be kept after the stream is dropped.
+    pub fn counters(&self) -> Arc<StreamCounters> {
+        self.counters.clone()
+    }
+
+    /// Unwrap the stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> Deref for CountedStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> DerefMut for CountedStream<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S: Read> Read for CountedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.stream.read(buf)?;
+        self.counters.bytes_in.fetch_add(n as u64, Ordering::SeqCst);
+        self.counters.touch();
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for CountedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.stream.write(buf)?;
+        self.counters
+            .bytes_out
+            .fetch_add(n as u64, Ordering::SeqCst);
+        self.counters.touch();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_counters() {
+        let mut stream = CountedStream::new(std::io::Cursor::new(b"hello".to_vec()));
+        let counters = stream.counters();
+        thread::sleep(Duration::from_millis(20));
+        assert!(counters.idle_for() >= Duration::from_millis(20));
+
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(b"abcd").unwrap();
+        assert_eq!(counters.bytes_in(), 3);
+        assert_eq!(counters.bytes_out(), 4);
+        assert!(counters.idle_for() < Duration::from_millis(20));
+        // Deref reaches the inner stream
+        assert_eq!(stream.position(), 7);
+    }
+}
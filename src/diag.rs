@@ -0,0 +1,138 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Kernel accept queue diagnostics (Linux only).
+//!
+//! When the accept queue is full, the kernel silently drops incoming
+//! SYNs, so peers see slow or failed connects while the listener sees
+//! nothing at all. [diagnose()](fn.diagnose.html) reads the number of
+//! connections waiting on a listener from `/proc/net/tcp` (or `tcp6`),
+//! matching the socket by inode, together with the system wide
+//! ListenOverflows and ListenDrops counters from `/proc/net/netstat`.
+//! The kernel only counts overflows and drops system wide, so a rise in
+//! them can only be attributed to a listener whose queue is also full.
+
+use std::fmt;
+use std::fs;
+use std::io::Error;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+
+/// System wide listen counters from `/proc/net/netstat`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KernelCounters {
+    /// Times a connection arrived to a full accept queue.
+    pub listen_overflows: u64,
+    /// Connections dropped during listen for any reason, including
+    /// overflows.
+    pub listen_drops: u64,
+}
+
+/// Accept queue state of one listener, plus the system wide counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenDiagnostics {
+    /// Connections waiting to be accepted, None if the socket wasn't
+    /// found in `/proc/net`.
+    pub queued: Option<u64>,
+    /// System wide counters.
+    pub counters: KernelCounters,
+}
+
+impl fmt::Display for ListenDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.queued {
+            Some(queued) => write!(f, "queued={}", queued)?,
+            None => write!(f, "queued=-")?,
+        }
+        write!(
+            f,
+            " listen_overflows={} listen_drops={}",
+            self.counters.listen_overflows, self.counters.listen_drops
+        )
+    }
+}
+
+/// Read the system wide listen counters.
+pub fn kernel_counters() -> Result<KernelCounters, Error> {
+    Ok(parse_netstat(&fs::read_to_string("/proc/net/netstat")?))
+}
+
+/// Read the accept queue state of listener and the system wide
+/// counters.
+pub fn diagnose(listener: &TcpListener) -> Result<ListenDiagnostics, Error> {
+    let inode = inode(listener)?;
+    let table = if listener.local_addr()?.is_ipv6() {
+        "/proc/net/tcp6"
+    } else {
+        "/proc/net/tcp"
+    };
+    Ok(ListenDiagnostics {
+        queued: parse_queued(&fs::read_to_string(table)?, inode),
+        counters: kernel_counters()?,
+    })
+}
+
+fn inode(listener: &TcpListener) -> Result<u64, Error> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(listener.as_raw_fd(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat.st_ino as u64)
+}
+
+// TcpExt appears twice, a line of names followed by a line of values.
+fn parse_netstat(text: &str) -> KernelCounters {
+    let mut lines = text.lines().filter(|l| l.starts_with("TcpExt:"));
+    let mut counters = KernelCounters::default();
+    if let (Some(names), Some(values)) = (lines.next(), lines.next()) {
+        for (name, value) in names.split_whitespace().zip(values.split_whitespace()) {
+            let value = value.parse().unwrap_or(0);
+            match name {
+                "ListenOverflows" => counters.listen_overflows = value,
+                "ListenDrops" => counters.listen_drops = value,
+                _ => (),
+            }
+        }
+    }
+    counters
+}
+
+// For a listening socket, rx_queue is the accept queue length.
+fn parse_queued(table: &str, inode: u64) -> Option<u64> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(9)?.parse::<u64>().ok()? != inode {
+            return None;
+        }
+        let rx_queue = fields.get(4)?.split(':').nth(1)?;
+        u64::from_str_radix(rx_queue, 16).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_diagnose() {
+        let counters =
+            parse_netstat("TcpExt: SyncookiesSent ListenOverflows ListenDrops\nTcpExt: 1 2 3\n");
+        assert_eq!(counters.listen_overflows, 2);
+        assert_eq!(counters.listen_drops, 3);
+
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let _a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _b = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let diag = diagnose(&listener).unwrap();
+        assert_eq!(diag.queued, Some(2));
+        assert!(diag.to_string().starts_with("queued=2 "));
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Graceful shutdown of active connections.
+//!
+//! Handlers register their stream with a [DrainRegistry](struct.DrainRegistry.html)
+//! and keep the returned registration while they run. When the
+//! application shuts down, [drain()](struct.DrainRegistry.html#method.drain)
+//! half-closes every registered connection, waits for each peer to
+//! finish and close its side, and only then closes the connection fully.
+//! An optional [Goodbye](enum.Goodbye.html) is written to each connection
+//! as the drain begins.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Outcome of a [drain()](struct.DrainRegistry.html#method.drain).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DrainSummary {
+    /// Connections closed by the peer within the grace period.
+    pub drained: usize,
+    /// Connections still open when the grace period expired.
+    pub aborted: usize,
+}
+
+/// Message written to every registered connection when a drain begins.
+pub enum Goodbye {
+    /// Write these bytes, e.g. an HTTP 503 with Connection: close.
+    Payload(Vec<u8>),
+    /// Call this to write a protocol specific message.
+    Callback(GoodbyeWriter),
+}
+
+/// Writes a goodbye message to a stream.
+pub type GoodbyeWriter = Box<dyn Fn(&mut TcpStream) -> Result<(), Error> + Send + Sync>;
+
+type Streams = Arc<Mutex<HashMap<u64, TcpStream>>>;
+
+/// Tracks active connections so that they can be drained.
+#[derive(Default)]
+pub struct DrainRegistry {
+    streams: Streams,
+    next_id: AtomicU64,
+    goodbye: Mutex<Option<Goodbye>>,
+}
+
+/// Keeps a stream registered with a [DrainRegistry](struct.DrainRegistry.html).
+/// Dropping it removes the stream from the registry.
+pub struct Registration {
+    streams: Streams,
+    id: u64,
+}
+
+impl DrainRegistry {
+    /// Create an empty registry.
+    pub fn new() -> DrainRegistry {
+        DrainRegistry::default()
+    }
+
+    /// Register a stream until the returned registration is dropped.
+    pub fn register(&self, stream: &TcpStream) -> Result<Registration, Error> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.streams.lock().unwrap().insert(id, stream.try_clone()?);
+        Ok(Registration {
+            streams: self.streams.clone(),
+            id,
+        })
+    }
+
+    /// Set the message written to each connection when a drain begins.
+    pub fn set_goodbye(&self, goodbye: Goodbye) {
+        *self.goodbye.lock().unwrap() = Some(goodbye);
+    }
+
+    /// Number of streams currently registered.
+    pub fn active(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// Write the goodbye message, if any, and shut down the write side
+    /// of every registered stream, then wait for the peers to close
+    /// theirs. Goodbyes and waiting share one deadline, grace from now,
+    /// however many streams there are. Once a stream's write side is
+    /// shut down, any data its peer sends in the meantime is discarded;
+    /// a stream which can't be shut down is left alone. All streams are
+    /// fully shut down before returning, and their read and write
+    /// timeouts are put back as they were.
+    pub fn drain(&self, grace: Duration) -> DrainSummary {
+        let deadline = Instant::now() + grace;
+        let mut closing = vec![];
+        let goodbye = self.goodbye.lock().unwrap();
+        for mut stream in self.snapshot() {
+            let timeouts = (stream.read_timeout(), stream.write_timeout());
+            // Accepted sockets inherit the listener's non-blocking mode
+            // on Windows and macOS, which would make the timeouts below
+            // return WouldBlock at once
+            let blocking = stream.set_nonblocking(false).is_ok();
+            if let Some(goodbye) = goodbye.as_ref() {
+                let _ = stream.set_write_timeout(Some(remaining(deadline)));
+                let _ = match goodbye {
+                    Goodbye::Payload(bytes) => stream.write_all(bytes),
+                    Goodbye::Callback(write) => write(&mut stream),
+                };
+            }
+            let shut = stream.shutdown(Shutdown::Write);
+            closing.push((stream, timeouts, blocking, shut));
+        }
+        drop(goodbye);
+        let mut summary = DrainSummary::default();
+        for (stream, timeouts, blocking, shut) in closing {
+            let closed = match shut {
+                Ok(()) => wait_for_eof(&stream, blocking, deadline),
+                // Already disconnected
+                Err(err) => err.kind() == ErrorKind::NotConnected,
+            };
+            if closed {
+                summary.drained += 1;
+            } else {
+                summary.aborted += 1;
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+            if let (Ok(read), Ok(write)) = timeouts {
+                let _ = stream.set_read_timeout(read);
+                let _ = stream.set_write_timeout(write);
+            }
+        }
+        summary
+    }
+
+    fn snapshot(&self) -> Vec<TcpStream> {
+        self.streams
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|s| s.try_clone().ok())
+            .collect()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Read and discard until EOF or the deadline passes. Returns true if
+// the peer closed (or the connection failed) before the deadline. A
+// peer which closed while we were waiting on another is still found,
+// since there's always at least one short read. If stream couldn't be
+// made blocking, WouldBlock only means nothing has arrived yet.
+fn wait_for_eof(mut stream: &TcpStream, blocking: bool, deadline: Instant) -> bool {
+    let mut buf = [0; 512];
+    loop {
+        if stream.set_read_timeout(Some(remaining(deadline))).is_err() {
+            return true;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(_) => {
+                if Instant::now() >= deadline {
+                    return false;
+                }
+            }
+            Err(err) => match err.kind() {
+                ErrorKind::WouldBlock if !blocking => {
+                    if Instant::now() >= deadline {
+                        return false;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => return false,
+                ErrorKind::Interrupted => (),
+                _ => return true,
+            },
+        }
+    }
+}
+
+// Time left until deadline, at least 1ms since a zero timeout means
+// none.
+fn remaining(deadline: Instant) -> Duration {
+    std::cmp::max(
+        deadline.saturating_duration_since(Instant::now()),
+        Duration::from_millis(1),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_drain() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let registry = DrainRegistry::new();
+
+        // This peer closes once it sees our FIN
+        let (mut polite, server_a) = pair(&listener);
+        let _reg_a = registry.register(&server_a).unwrap();
+        let client = thread::spawn(move || {
+            let mut buf = vec![];
+            polite.read_to_end(&mut buf).unwrap();
+        });
+
+        // This peer never closes
+        let (_rude, server_b) = pair(&listener);
+        let _reg_b = registry.register(&server_b).unwrap();
+        server_b
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+
+        assert_eq!(registry.active(), 2);
+        let summary = registry.drain(Duration::from_millis(200));
+        assert_eq!(
+            server_b.read_timeout().unwrap(),
+            Some(Duration::from_secs(30))
+        );
+        client.join().unwrap();
+        assert_eq!(
+            summary,
+            DrainSummary {
+                drained: 1,
+                aborted: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_nonblocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let registry = DrainRegistry::new();
+
+        // As accepted from a non-blocking listener on Windows and macOS
+        let (mut polite, server) = pair(&listener);
+        server.set_nonblocking(true).unwrap();
+        let _reg = registry.register(&server).unwrap();
+        let client = thread::spawn(move || {
+            let mut buf = vec![];
+            polite.read_to_end(&mut buf).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        });
+        let summary = registry.drain(Duration::from_secs(5));
+        client.join().unwrap();
+        assert_eq!(summary.drained, 1);
+    }
+
+    #[test]
+    fn test_goodbye() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let registry = DrainRegistry::new();
+        registry.set_goodbye(Goodbye::Payload(b"BYE\r\n".to_vec()));
+
+        let (mut client, server) = pair(&listener);
+        let _reg = registry.register(&server).unwrap();
+        let reader = thread::spawn(move || {
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).unwrap();
+            buf
+        });
+        let summary = registry.drain(Duration::from_secs(5));
+        assert_eq!(reader.join().unwrap(), b"BYE\r\n");
+        assert_eq!(summary.drained, 1);
+    }
+}
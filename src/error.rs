@@ -0,0 +1,196 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Structured accept loop errors.
+//!
+//! [Error](enum.Error.html), re-exported as `nblistener::Error`, says
+//! why an accept loop stopped in terms an application can match on,
+//! rather than leaving it to interpret OS error numbers. It converts to
+//! and from `std::io::Error`, so `?` works in either direction.
+//!
+//! An [ErrorPolicy](enum.ErrorPolicy.html) says what a loop does when a
+//! handler returns an error.
+//!
+//! A loop which terminates normally says why with a
+//! [StopReason](enum.StopReason.html), also re-exported at the crate
+//! root, so that, for instance, an operator's close() can be told apart
+//! from an idle timeout.
+
+use crate::is_closed;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Why an accept loop failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The listener was closed. Loops treat this as normal termination
+    /// and return [StopReason::Closed](enum.StopReason.html#variant.Closed),
+    /// so it is only returned by the single accepts,
+    /// [accept_timeout()](../trait.Listener.html#tymethod.accept_timeout)
+    /// and [accept_nonblocking()](../trait.Listener.html#tymethod.accept_nonblocking),
+    /// and seen when converting an io::Error.
+    Closed,
+    /// accept() failed.
+    AcceptFailed(io::Error),
+    /// A handler panicked.
+    HandlerPanicked,
+    /// A handler returned an error and the
+    /// [ErrorPolicy](enum.ErrorPolicy.html) stopped the loop.
+    HandlerFailed(Box<dyn std::error::Error + Send + Sync>),
+    /// No connection arrived within
+    /// [accept_timeout()](../trait.Listener.html#tymethod.accept_timeout)'s
+    /// timeout. A loop which reaches its
+    /// [Deadline](../until/struct.Deadline.html) has done what it was
+    /// asked, so terminates normally with
+    /// [StopReason::Deadline](enum.StopReason.html#variant.Deadline)
+    /// instead.
+    Timeout,
+}
+
+impl Error {
+    /// Is this the listener having been closed?
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Error::Closed)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Closed => write!(f, "listener closed"),
+            Error::AcceptFailed(err) => write!(f, "accept failed: {}", err),
+            Error::HandlerPanicked => write!(f, "handler panicked"),
+            Error::HandlerFailed(err) => write!(f, "handler failed: {}", err),
+            Error::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::AcceptFailed(err) => Some(err),
+            Error::HandlerFailed(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Why an accept loop terminated normally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The listener was closed.
+    Closed,
+    /// The listener was closed by
+    /// [close_after_drain()](../trait.Listener.html#tymethod.close_after_drain)
+    /// once the queued connections had been handled.
+    Drained,
+    /// No connection arrived within the idle timeout.
+    Idle,
+    /// A [Deadline](../until/struct.Deadline.html) passed.
+    Deadline,
+    /// Some other [StopCondition](../until/trait.StopCondition.html)
+    /// was met, or the loop was asked to stop, by a
+    /// [ListenerHandle](../stop/struct.ListenerHandle.html) or a
+    /// shutdown future.
+    Condition,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            StopReason::Closed => "listener closed",
+            StopReason::Drained => "listener drained and closed",
+            StopReason::Idle => "idle timeout",
+            StopReason::Deadline => "deadline passed",
+            StopReason::Condition => "stop condition met",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// What an accept loop does when a handler returns an error.
+#[derive(Clone, Default)]
+pub enum ErrorPolicy {
+    /// Carry on accepting.
+    #[default]
+    Ignore,
+    /// Write the error to stderr and carry on accepting.
+    LogAndContinue,
+    /// Pass the error to the closure, e.g. to send it to a logger or
+    /// count it in metrics, and carry on accepting.
+    Report(Arc<dyn Fn(Box<dyn std::error::Error + Send + Sync>) + Send + Sync>),
+    /// Stop accepting and return the error as
+    /// [HandlerFailed](enum.Error.html#variant.HandlerFailed).
+    StopLoop,
+}
+
+impl fmt::Debug for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorPolicy::Ignore => f.write_str("Ignore"),
+            ErrorPolicy::LogAndContinue => f.write_str("LogAndContinue"),
+            ErrorPolicy::Report(_) => f.write_str("Report(..)"),
+            ErrorPolicy::StopLoop => f.write_str("StopLoop"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Errors from a closed listener become Closed, timeouts Timeout,
+    /// and anything else AcceptFailed.
+    fn from(err: io::Error) -> Error {
+        if is_closed(&err) {
+            Error::Closed
+        } else if err.kind() == io::ErrorKind::TimedOut {
+            Error::Timeout
+        } else {
+            Error::AcceptFailed(err)
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::AcceptFailed(err) => err,
+            Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, err.to_string()),
+            _ => io::Error::new(io::ErrorKind::Other, err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_classify() {
+        let closed = io::Error::from_raw_os_error(crate::plat_specifics::EBADF);
+        assert!(Error::from(closed).is_closed());
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        match Error::from(refused) {
+            Error::AcceptFailed(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+            other => panic!("unexpected {}", other),
+        }
+        let timeout = Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(matches!(timeout, Error::Timeout));
+        let timeout: io::Error = timeout.into();
+        assert_eq!(timeout.kind(), io::ErrorKind::TimedOut);
+
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        listener.close();
+        let result = listener.handle_incoming(|_| (), Duration::from_millis(1));
+        assert_eq!(result.unwrap(), StopReason::Closed);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Periodic export of statistics to a file.
+//!
+//! For deployments without a metrics stack, a [StatsWriter](struct.StatsWriter.html)
+//! appends a snapshot of named counters to a CSV or JSON lines file on
+//! a fixed interval. Each row is timestamped with seconds since the
+//! Unix epoch. When the file grows past a size limit it is rotated, so
+//! `stats.csv` becomes `stats.csv.1`, `stats.csv.1` becomes
+//! `stats.csv.2` and so on, up to a configured number of old files.
+//! [StatsSnapshot::metrics()](../stats/struct.StatsSnapshot.html#method.metrics)
+//! gives a loop's counters named with its labels.
+
+use std::fs::{self, OpenOptions};
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Output file format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Comma separated values, with a header row at the start of each
+    /// file.
+    Csv,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// When to rotate the output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate once the file is at least this many bytes.
+    pub max_bytes: u64,
+    /// Number of rotated files to keep.
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    /// Rotate at 10MiB and keep 5 old files.
+    fn default() -> Rotation {
+        Rotation {
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+/// Writes snapshots of statistics to a file on an interval.
+pub struct StatsWriter {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StatsWriter {
+    /// Start a thread which calls snapshot every interval and appends
+    /// the result to path. Write errors are ignored, so that a full disk
+    /// doesn't take down the service, and the next interval tries again.
+    pub fn start<P, F>(
+        path: P,
+        format: Format,
+        interval: Duration,
+        rotation: Rotation,
+        snapshot: F,
+    ) -> StatsWriter
+    where
+        P: AsRef<Path>,
+        F: Fn() -> Vec<(String, u64)> + Send + 'static,
+    {
+        let path = path.as_ref().to_path_buf();
+        let (stop, rx) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                let _ = write_snapshot(&path, format, rotation, &snapshot());
+            }
+        });
+        StatsWriter {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for StatsWriter {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Append one snapshot to path, rotating it first if needed.
+pub fn write_snapshot(
+    path: &Path,
+    format: Format,
+    rotation: Rotation,
+    values: &[(String, u64)],
+) -> Result<(), Error> {
+    let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if size > 0 && size >= rotation.max_bytes {
+        rotate(path, rotation.keep)?;
+    }
+    let is_new = fs::metadata(path).map(|m| m.len() == 0).unwrap_or(true);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut out = String::new();
+    match format {
+        Format::Csv => {
+            if is_new {
+                out.push_str("timestamp");
+                for (name, _) in values {
+                    out.push(',');
+                    out.push_str(&csv_field(name));
+                }
+                out.push('\n');
+            }
+            out.push_str(&now.to_string());
+            for (_, value) in values {
+                out.push(',');
+                out.push_str(&value.to_string());
+            }
+        }
+        Format::JsonLines => {
+            out.push_str(&format!("{{\"timestamp\":{}", now));
+            for (name, value) in values {
+                out.push_str(&format!(",{}:{}", json_string(name), value));
+            }
+            out.push('}');
+        }
+    }
+    out.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(out.as_bytes())
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn rotate(path: &Path, keep: usize) -> Result<(), Error> {
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    let _ = fs::remove_file(rotated(path, keep));
+    for n in (1..keep).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            fs::rename(&from, rotated(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(path, 1))
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("nblistener-export-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("stats.csv");
+        let rotation = Rotation {
+            max_bytes: 1,
+            keep: 2,
+        };
+        let values = vec![("accepted".to_string(), 3), ("a,b".to_string(), 4)];
+        for _ in 0..4 {
+            write_snapshot(&csv, Format::Csv, rotation, &values).unwrap();
+        }
+        // Every write rotated, and only two old files were kept
+        let contents = fs::read_to_string(&csv).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "timestamp,accepted,\"a,b\"");
+        assert!(lines[1].ends_with(",3,4"));
+        assert!(rotated(&csv, 2).exists());
+        assert!(!rotated(&csv, 3).exists());
+
+        let json = dir.join("stats.json");
+        write_snapshot(&json, Format::JsonLines, Rotation::default(), &values).unwrap();
+        let contents = fs::read_to_string(&json).unwrap();
+        assert!(contents.ends_with(",\"accepted\":3,\"a,b\":4}\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
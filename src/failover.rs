@@ -0,0 +1,230 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Listener with a warm-standby fallback address.
+//!
+//! Both the primary and the fallback address are bound up front, so
+//! the standby is already listening, and connections to it wait in its
+//! accept queue. If the primary can't be bound, the standby is used
+//! from the start. If accepting on the primary fails in a way which
+//! suggests the address has gone away (e.g. a VIP moved to another
+//! host), or the primary socket is closed from elsewhere, the loop
+//! switches over to the standby and carries on.
+
+use crate::{accept_loop, Callbacks, Listener, StopReason};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Transitions reported by a [FailoverListener](struct.FailoverListener.html).
+#[derive(Debug)]
+pub enum FailoverEvent {
+    /// The primary address couldn't be bound.
+    PrimaryBindFailed(Error),
+    /// Accepting on the primary address failed.
+    PrimaryFailed(Error),
+    /// The primary socket was closed other than by
+    /// [close()](struct.FailoverListener.html#method.close).
+    PrimaryClosed,
+    /// Now accepting on this fallback address.
+    UsingFallback(SocketAddr),
+}
+
+type EventCallback = Box<dyn Fn(&FailoverEvent) + Send + Sync>;
+
+/// A listener which fails over from a primary to a standby listener on
+/// the fallback address.
+pub struct FailoverListener {
+    primary: Option<TcpListener>,
+    standby: TcpListener,
+    on_fallback: AtomicBool,
+    closed: AtomicBool,
+    on_event: EventCallback,
+}
+
+impl FailoverListener {
+    /// Bind to both primary and fallback, using fallback from the start
+    /// if primary can't be bound. Fails if fallback can't be bound.
+    /// Transitions are reported to on_event.
+    pub fn bind<A, B, F>(primary: A, fallback: B, on_event: F) -> Result<FailoverListener, Error>
+    where
+        A: ToSocketAddrs,
+        B: ToSocketAddrs,
+        F: Fn(&FailoverEvent) + Send + Sync + 'static,
+    {
+        let standby = <TcpListener as Listener>::bind(fallback)?;
+        let on_event: EventCallback = Box::new(on_event);
+        let primary = match <TcpListener as Listener>::bind(primary) {
+            Ok(listener) => Some(listener),
+            Err(err) => {
+                on_event(&FailoverEvent::PrimaryBindFailed(err));
+                on_event(&FailoverEvent::UsingFallback(standby.local_addr()?));
+                None
+            }
+        };
+        Ok(FailoverListener {
+            on_fallback: AtomicBool::new(primary.is_none()),
+            primary,
+            standby,
+            closed: AtomicBool::new(false),
+            on_event,
+        })
+    }
+
+    /// Address of the listener currently in use.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.current().local_addr()
+    }
+
+    /// Address of the standby listener.
+    pub fn standby_addr(&self) -> Result<SocketAddr, Error> {
+        self.standby.local_addr()
+    }
+
+    /// Is the fallback address in use?
+    pub fn is_on_fallback(&self) -> bool {
+        self.on_fallback.load(Ordering::SeqCst)
+    }
+
+    /// Close both listeners. An active handle_incoming() will terminate
+    /// normally.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(primary) = self.primary.as_ref() {
+            primary.close();
+        }
+        self.standby.close();
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// switching over to the standby if the primary goes away.
+    pub fn handle_incoming<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        loop {
+            let result = accept_loop(
+                self.current(),
+                &mut |stream, _, _| handler(stream),
+                timeout,
+                Callbacks::default(),
+            );
+            if self.closed.load(Ordering::SeqCst) || self.is_on_fallback() {
+                return result.map_err(crate::Error::from);
+            }
+            match result {
+                Ok(StopReason::Closed) => (self.on_event)(&FailoverEvent::PrimaryClosed),
+                Err(err) if address_gone(&err) => {
+                    (self.on_event)(&FailoverEvent::PrimaryFailed(err))
+                }
+                result => return result.map_err(crate::Error::from),
+            }
+            self.on_fallback.store(true, Ordering::SeqCst);
+            (self.on_event)(&FailoverEvent::UsingFallback(self.standby.local_addr()?));
+        }
+    }
+
+    fn current(&self) -> &TcpListener {
+        match self.primary.as_ref() {
+            Some(primary) if !self.is_on_fallback() => primary,
+            _ => &self.standby,
+        }
+    }
+}
+
+// Errors which indicate the bound address is no longer usable.
+fn address_gone(err: &Error) -> bool {
+    if err.kind() == ErrorKind::AddrNotAvailable {
+        return true;
+    }
+    #[cfg(not(windows))]
+    {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::ENETDOWN) | Some(libc::ENODEV) | Some(libc::EINVAL)
+        )
+    }
+    #[cfg(windows)]
+    {
+        // WSAENETDOWN and WSAEINVAL
+        matches!(err.raw_os_error(), Some(10050) | Some(10022))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn recorder() -> (
+        Arc<Mutex<Vec<String>>>,
+        impl Fn(&FailoverEvent) + Send + Sync,
+    ) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let e_clone = events.clone();
+        (events, move |event: &FailoverEvent| {
+            e_clone.lock().unwrap().push(format!("{:?}", event))
+        })
+    }
+
+    #[test]
+    fn test_bind_fallback() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (events, on_event) = recorder();
+        let listener =
+            FailoverListener::bind(taken.local_addr().unwrap(), "127.0.0.1:0", on_event).unwrap();
+        assert!(listener.is_on_fallback());
+        let events = events.lock().unwrap();
+        assert!(events[0].starts_with("PrimaryBindFailed"));
+        assert!(events[1].starts_with("UsingFallback"));
+        listener.close();
+        listener
+            .handle_incoming(|_| (), Duration::from_millis(10))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_switch_over() {
+        let (events, on_event) = recorder();
+        let listener =
+            Arc::new(FailoverListener::bind("127.0.0.1:0", "127.0.0.1:0", on_event).unwrap());
+        assert!(!listener.is_on_fallback());
+        // The standby is already listening, so this waits in its queue
+        let _early = TcpStream::connect(listener.standby_addr().unwrap()).unwrap();
+        let l_clone = listener.clone();
+        let server = thread::spawn(move || {
+            let mut handled = 0;
+            let reason = l_clone
+                .handle_incoming(
+                    |_| {
+                        handled += 1;
+                        l_clone.close();
+                    },
+                    Duration::from_millis(5),
+                )
+                .unwrap();
+            (reason, handled)
+        });
+        thread::sleep(Duration::from_millis(20));
+        listener.primary.as_ref().unwrap().close();
+        assert_eq!(server.join().unwrap(), (StopReason::Closed, 1));
+        assert!(listener.is_on_fallback());
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], "PrimaryClosed");
+        assert!(events[1].starts_with("UsingFallback"));
+    }
+}
@@ -0,0 +1,599 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! TLS client fingerprinting.
+//!
+//! [Ja3](struct.Ja3.html) and [Ja4](struct.Ja4.html) fingerprints are
+//! computed from the ClientHello which starts a TLS connection. The
+//! ClientHello is only peeked at, so the stream can still be handed to
+//! a TLS library afterwards. Fingerprints identify client stacks rather
+//! than clients, which makes them useful for spotting (and rate
+//! limiting) known bad tools.
+//!
+//! [handle_incoming_with_fingerprint()](../trait.Listener.html#tymethod.handle_incoming_with_fingerprint)
+//! fingerprints each connection as it is accepted and records the
+//! [Fingerprint](struct.Fingerprint.html) on its
+//! [ConnInfo](../struct.ConnInfo.html), whose Display form suits an
+//! audit log.
+
+use crate::tls::{peek_client_hello, ClientHello, Reader};
+use std::fmt;
+use std::io::Error;
+use std::net::TcpStream;
+use std::time::Duration;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// The JA3 and JA4 fingerprints of a TLS ClientHello.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    /// The JA3 fingerprint.
+    pub ja3: Ja3,
+    /// The JA4 fingerprint.
+    pub ja4: Ja4,
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.ja3, self.ja4)
+    }
+}
+
+impl Fingerprint {
+    /// Compute both fingerprints of the ClientHello at the start of
+    /// data. Returns None if data doesn't hold a complete ClientHello.
+    pub fn from_client_hello(data: &[u8]) -> Option<Fingerprint> {
+        Some(Fingerprint {
+            ja3: Ja3::from_client_hello(data)?,
+            ja4: Ja4::from_client_hello(data)?,
+        })
+    }
+
+    /// Peek at the ClientHello on stream and fingerprint it, as
+    /// [Ja3::peek()](struct.Ja3.html#method.peek).
+    pub fn peek(stream: &TcpStream, timeout: Duration) -> Result<Option<Fingerprint>, Error> {
+        let record = peek_client_hello(stream, timeout)?;
+        Ok(record.and_then(|record| Fingerprint::from_client_hello(&record)))
+    }
+}
+
+/// A JA3 fingerprint of a TLS ClientHello.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ja3 {
+    /// The JA3 string: version, ciphers, extensions, curves and point
+    /// formats, with GREASE values removed.
+    pub text: String,
+    /// MD5 of text, as lower case hex. This is the usual form for
+    /// matching against published fingerprints.
+    pub hash: String,
+}
+
+impl fmt::Display for Ja3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ja3={} ja3_full={}", self.hash, self.text)
+    }
+}
+
+impl Ja3 {
+    /// Compute the fingerprint of the ClientHello at the start of data,
+    /// which should be the first TLS record sent by the client. Returns
+    /// None if data doesn't hold a complete ClientHello.
+    pub fn from_client_hello(data: &[u8]) -> Option<Ja3> {
+        let hello = ClientHello::parse(data)?;
+        let ciphers = u16_list(hello.ciphers)?;
+        let mut extensions = vec![];
+        let mut curves = vec![];
+        let mut formats = vec![];
+        for (kind, body) in hello.extensions {
+            if is_grease(kind) {
+                continue;
+            }
+            extensions.push(kind);
+            let mut body = Reader::new(body);
+            match kind {
+                EXT_SUPPORTED_GROUPS => curves = u16_list(body.vec16()?)?,
+                EXT_EC_POINT_FORMATS => formats = body.vec8()?.iter().map(|f| *f as u16).collect(),
+                _ => (),
+            }
+        }
+        let text = format!(
+            "{},{},{},{},{}",
+            hello.version,
+            join(&ciphers),
+            join(&extensions),
+            join(&curves),
+            join(&formats)
+        );
+        let hash = md5(text.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Some(Ja3 { text, hash })
+    }
+
+    /// Peek at the ClientHello on stream and fingerprint it, waiting up
+    /// to timeout for it to arrive. Returns None if the client doesn't
+    /// appear to be speaking TLS. No data is consumed from the stream.
+    pub fn peek(stream: &TcpStream, timeout: Duration) -> Result<Option<Ja3>, Error> {
+        let record = peek_client_hello(stream, timeout)?;
+        Ok(record.and_then(|record| Ja3::from_client_hello(&record)))
+    }
+}
+
+/// A JA4 fingerprint of a TLS ClientHello, for a connection over TCP.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ja4 {
+    /// The raw JA4 string (ja4_r): the summary of version, SNI, counts
+    /// and ALPN, then the sorted ciphers, and the sorted extensions
+    /// followed by the signature algorithms, all in hex with GREASE
+    /// values removed.
+    pub text: String,
+    /// The JA4 fingerprint, in which the cipher and extension parts of
+    /// text are replaced by truncated SHA-256 hashes. This is the usual
+    /// form for matching against published fingerprints.
+    pub hash: String,
+}
+
+impl fmt::Display for Ja4 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ja4={} ja4_r={}", self.hash, self.text)
+    }
+}
+
+impl Ja4 {
+    /// Compute the fingerprint of the ClientHello at the start of data,
+    /// which should be the first TLS record sent by the client. Returns
+    /// None if data doesn't hold a complete ClientHello.
+    pub fn from_client_hello(data: &[u8]) -> Option<Ja4> {
+        let hello = ClientHello::parse(data)?;
+        let mut ciphers = u16_list(hello.ciphers)?;
+        let mut version = hello.version;
+        let mut sni = 'i';
+        let mut alpn = "00".to_string();
+        let mut count = 0;
+        let mut extensions = vec![];
+        let mut algorithms = vec![];
+        for (kind, body) in hello.extensions {
+            if is_grease(kind) {
+                continue;
+            }
+            count += 1;
+            let mut body = Reader::new(body);
+            match kind {
+                EXT_SERVER_NAME => sni = 'd',
+                EXT_ALPN => alpn = alpn_code(Reader::new(body.vec16()?).vec8()?),
+                EXT_SIGNATURE_ALGORITHMS => algorithms = u16_list(body.vec16()?)?,
+                EXT_SUPPORTED_VERSIONS => {
+                    if let Some(max) = u16_list(body.vec8()?)?.into_iter().max() {
+                        version = max;
+                    }
+                }
+                _ => (),
+            }
+            if kind != EXT_SERVER_NAME && kind != EXT_ALPN {
+                extensions.push(kind);
+            }
+        }
+        ciphers.sort_unstable();
+        extensions.sort_unstable();
+
+        let summary = format!(
+            "t{}{}{:02}{:02}{}",
+            version_code(version),
+            sni,
+            ciphers.len().min(99),
+            count.min(99),
+            alpn
+        );
+        let ciphers = hex_list(&ciphers);
+        let mut extensions = hex_list(&extensions);
+        if !algorithms.is_empty() {
+            extensions = format!("{}_{}", extensions, hex_list(&algorithms));
+        }
+        Some(Ja4 {
+            hash: format!(
+                "{}_{}_{}",
+                summary,
+                truncated_sha256(&ciphers),
+                truncated_sha256(&extensions)
+            ),
+            text: format!("{}_{}_{}", summary, ciphers, extensions),
+        })
+    }
+
+    /// Peek at the ClientHello on stream and fingerprint it, as
+    /// [Ja3::peek()](struct.Ja3.html#method.peek).
+    pub fn peek(stream: &TcpStream, timeout: Duration) -> Result<Option<Ja4>, Error> {
+        let record = peek_client_hello(stream, timeout)?;
+        Ok(record.and_then(|record| Ja4::from_client_hello(&record)))
+    }
+}
+
+fn version_code(version: u16) -> &'static str {
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00",
+    }
+}
+
+// The first and last characters of the first ALPN protocol, or the
+// outer hex digits of its bytes if either isn't alphanumeric.
+fn alpn_code(protocol: &[u8]) -> String {
+    match (protocol.first(), protocol.last()) {
+        (Some(first), Some(last))
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() =>
+        {
+            format!("{}{}", *first as char, *last as char)
+        }
+        (Some(first), Some(last)) => {
+            let first = format!("{:02x}", first);
+            let last = format!("{:02x}", last);
+            format!("{}{}", &first[..1], &last[1..])
+        }
+        _ => "00".to_string(),
+    }
+}
+
+fn hex_list(vals: &[u16]) -> String {
+    vals.iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn truncated_sha256(text: &str) -> String {
+    if text.is_empty() {
+        return "000000000000".to_string();
+    }
+    sha256(text.as_bytes())[..6]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// GREASE values (RFC 8701) are random and must be ignored.
+fn is_grease(val: u16) -> bool {
+    val & 0x0f0f == 0x0a0a && val >> 8 == val & 0xff
+}
+
+fn u16_list(data: &[u8]) -> Option<Vec<u16>> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+    Some(
+        data.chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .filter(|v| !is_grease(*v))
+            .collect(),
+    )
+}
+
+fn join(vals: &[u16]) -> String {
+    vals.iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// MD5 (RFC 1321). Only used to format JA3 hashes, not for security.
+fn md5(input: &[u8]) -> [u8; 16] {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32)
+        .collect();
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+    let mut msg = input.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((input.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    for block in msg.chunks(64) {
+        let m: Vec<u32> = block
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(S[i]));
+        }
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+    let mut out = [0; 16];
+    for (i, word) in state.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+// SHA-256 (FIPS 180-4). Only used to format JA4 hashes.
+fn sha256(input: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a_2f98,
+        0x7137_4491,
+        0xb5c0_fbcf,
+        0xe9b5_dba5,
+        0x3956_c25b,
+        0x59f1_11f1,
+        0x923f_82a4,
+        0xab1c_5ed5,
+        0xd807_aa98,
+        0x1283_5b01,
+        0x2431_85be,
+        0x550c_7dc3,
+        0x72be_5d74,
+        0x80de_b1fe,
+        0x9bdc_06a7,
+        0xc19b_f174,
+        0xe49b_69c1,
+        0xefbe_4786,
+        0x0fc1_9dc6,
+        0x240c_a1cc,
+        0x2de9_2c6f,
+        0x4a74_84aa,
+        0x5cb0_a9dc,
+        0x76f9_88da,
+        0x983e_5152,
+        0xa831_c66d,
+        0xb003_27c8,
+        0xbf59_7fc7,
+        0xc6e0_0bf3,
+        0xd5a7_9147,
+        0x06ca_6351,
+        0x1429_2967,
+        0x27b7_0a85,
+        0x2e1b_2138,
+        0x4d2c_6dfc,
+        0x5338_0d13,
+        0x650a_7354,
+        0x766a_0abb,
+        0x81c2_c92e,
+        0x9272_2c85,
+        0xa2bf_e8a1,
+        0xa81a_664b,
+        0xc24b_8b70,
+        0xc76c_51a3,
+        0xd192_e819,
+        0xd699_0624,
+        0xf40e_3585,
+        0x106a_a070,
+        0x19a4_c116,
+        0x1e37_6c08,
+        0x2748_774c,
+        0x34b0_bcb5,
+        0x391c_0cb3,
+        0x4ed8_aa4a,
+        0x5b9c_ca4f,
+        0x682e_6ff3,
+        0x748f_82ee,
+        0x78a5_636f,
+        0x84c8_7814,
+        0x8cc7_0208,
+        0x90be_fffa,
+        0xa450_6ceb,
+        0xbef9_a3f7,
+        0xc671_78f2,
+    ];
+    let mut state: [u32; 8] = [
+        0x6a09_e667,
+        0xbb67_ae85,
+        0x3c6e_f372,
+        0xa54f_f53a,
+        0x510e_527f,
+        0x9b05_688c,
+        0x1f83_d9ab,
+        0x5be0_cd19,
+    ];
+
+    let mut msg = input.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((input.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+    let mut out = [0; 32];
+    for (i, word) in state.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // A minimal ClientHello: TLS 1.2, a GREASE cipher plus two real
+    // ones, and GREASE, supported groups and point format extensions,
+    // followed by extra.
+    fn client_hello_with(extra: &[u8]) -> Vec<u8> {
+        let mut ext = vec![];
+        ext.extend_from_slice(&[0x1a, 0x1a, 0, 0]);
+        ext.extend_from_slice(&[0, 10, 0, 6, 0, 4, 0, 29, 0, 23]);
+        ext.extend_from_slice(&[0, 11, 0, 2, 1, 0]);
+        ext.extend_from_slice(extra);
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0, 6, 0x0a, 0x0a, 0xc0, 0x2b, 0xc0, 0x2f]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+        let mut hs = vec![1, 0];
+        hs.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hs.extend_from_slice(&body);
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&(hs.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hs);
+        record
+    }
+
+    fn client_hello() -> Vec<u8> {
+        client_hello_with(&[])
+    }
+
+    #[test]
+    fn test_ja3() {
+        assert_eq!(
+            md5(b"abc")
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let hello = client_hello();
+        client.write_all(&hello).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let ja3 = Ja3::peek(&stream, Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(ja3.text, "771,49195-49199,10-11,29-23,0");
+        assert_eq!(ja3.hash.len(), 32);
+
+        // Nothing was consumed
+        let mut buf = vec![0; hello.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, hello);
+    }
+
+    #[test]
+    fn test_ja4() {
+        assert_eq!(
+            sha256(b"abc")
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let ja4 = Ja4::from_client_hello(&client_hello()).unwrap();
+        assert_eq!(ja4.text, "t12i020200_c02b,c02f_000a,000b");
+        assert_eq!(ja4.hash, "t12i020200_b6f57f3be927_33a13ba74d1c");
+
+        // Add SNI, signature algorithms, ALPN "h2", and supported
+        // versions with GREASE, TLS 1.3 and TLS 1.2
+        let mut extra = vec![];
+        extra.extend_from_slice(&[0, 0, 0, 9, 0, 7, 0, 0, 4, b'h', b'o', b's', b't']);
+        extra.extend_from_slice(&[0, 13, 0, 6, 0, 4, 4, 3, 8, 4]);
+        extra.extend_from_slice(&[0, 16, 0, 5, 0, 3, 2, b'h', b'2']);
+        extra.extend_from_slice(&[0, 43, 0, 7, 6, 0x2a, 0x2a, 3, 4, 3, 3]);
+        let ja4 = Ja4::from_client_hello(&client_hello_with(&extra)).unwrap();
+        assert_eq!(
+            ja4.text,
+            "t13d0206h2_c02b,c02f_000a,000b,000d,002b_0403,0804"
+        );
+        assert_eq!(ja4.hash, "t13d0206h2_b6f57f3be927_fb71836bce29");
+
+        assert_eq!(alpn_code(&[0xab, b'x', 0xcd]), "ad");
+        assert!(Fingerprint::from_client_hello(b"GET / HTTP/1.1\r\n").is_none());
+    }
+
+    #[test]
+    fn test_conn_info() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut tls = TcpStream::connect(addr).unwrap();
+        tls.write_all(&client_hello()).unwrap();
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut fingerprints = vec![];
+        listener
+            .handle_incoming_with_fingerprint(
+                |_, info| {
+                    fingerprints.push(info.fingerprint.clone());
+                    if fingerprints.len() == 2 {
+                        listener.close();
+                    }
+                },
+                Duration::from_millis(1),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        let fingerprint = fingerprints[0].as_ref().unwrap();
+        assert_eq!(fingerprint.ja3.text, "771,49195-49199,10-11,29-23,0");
+        assert_eq!(fingerprint.ja4.hash, "t12i020200_b6f57f3be927_33a13ba74d1c");
+        assert!(fingerprint.to_string().starts_with("ja3="));
+        assert!(fingerprints[1].is_none());
+    }
+}
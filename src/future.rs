@@ -0,0 +1,306 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The accept loop as a future.
+//!
+//! [AsyncListener::handle_incoming_async()](trait.AsyncListener.html#tymethod.handle_incoming_async)
+//! returns an [Incoming](struct.Incoming.html) future which handles
+//! connections until the listener is closed or, if one was given with
+//! [with_shutdown()](struct.Incoming.html#method.with_shutdown), until a
+//! shutdown signal future completes. It doesn't depend on any runtime:
+//! while no connection is waiting, a helper thread waits for the
+//! listener to become ready, or to be closed, and wakes the task, so it
+//! can be awaited from any executor. At most
+//! [MAX_ACCEPTS_PER_POLL](constant.MAX_ACCEPTS_PER_POLL.html)
+//! connections are handled in one poll, after which the task yields to
+//! the executor, so a busy listener can't starve other tasks. Unlike
+//! the blocking loops, it doesn't assign connection ids, record stats
+//! or call hooks, and any accept error other than a close ends it.
+
+use crate::poll::wake;
+use crate::{drained, is_closed, StopReason};
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Accept loop which can be awaited.
+pub trait AsyncListener {
+    /// Handle incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// when the returned future is awaited. timeout bounds how long the
+    /// task goes without being woken, so that a close() or a shutdown
+    /// signal which doesn't wake the task is still noticed.
+    fn handle_incoming_async<H>(&self, handler: H, timeout: Duration) -> Incoming<'_, H>
+    where
+        H: FnMut(TcpStream);
+}
+
+impl AsyncListener for TcpListener {
+    fn handle_incoming_async<H>(&self, handler: H, timeout: Duration) -> Incoming<'_, H>
+    where
+        H: FnMut(TcpStream),
+    {
+        Incoming {
+            listener: self,
+            handler,
+            timeout,
+            shutdown: None,
+            helper: None,
+        }
+    }
+}
+
+/// Most connections handled in a single poll of an
+/// [Incoming](struct.Incoming.html).
+pub const MAX_ACCEPTS_PER_POLL: usize = 64;
+
+type Signal<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Future returned by [handle_incoming_async()](trait.AsyncListener.html#tymethod.handle_incoming_async).
+pub struct Incoming<'a, H> {
+    listener: &'a TcpListener,
+    handler: H,
+    timeout: Duration,
+    shutdown: Option<Signal<'a>>,
+    helper: Option<Helper>,
+}
+
+impl<'a, H> Incoming<'a, H> {
+    /// Stop handling connections, and complete normally, when signal
+    /// completes. The listener stays open.
+    pub fn with_shutdown<S>(mut self, signal: S) -> Self
+    where
+        S: Future<Output = ()> + Send + 'a,
+    {
+        self.shutdown = Some(Box::pin(signal));
+        self
+    }
+}
+
+impl<H> Future for Incoming<'_, H>
+where
+    H: FnMut(TcpStream) + Unpin,
+{
+    type Output = Result<StopReason, crate::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let poll = this.accept(cx);
+        if poll.is_ready() {
+            // Let go of the helper's copy of the socket now, rather than
+            // when the future is dropped
+            this.helper = None;
+        }
+        poll
+    }
+}
+
+impl<H> Incoming<'_, H>
+where
+    H: FnMut(TcpStream),
+{
+    fn accept(&mut self, cx: &mut Context) -> Poll<Result<StopReason, crate::Error>> {
+        if let Some(signal) = self.shutdown.as_mut() {
+            if signal.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Ok(StopReason::Condition));
+            }
+        }
+        for _ in 0..MAX_ACCEPTS_PER_POLL {
+            match self.listener.accept() {
+                Ok((stream, _)) => (self.handler)(stream),
+                Err(err) => {
+                    if err.kind() == ErrorKind::WouldBlock {
+                        if drained(self.listener) {
+                            return Poll::Ready(Ok(StopReason::Drained));
+                        }
+                        if self.helper.is_none() {
+                            self.helper = Some(Helper::start(self.listener, self.timeout)?);
+                        }
+                        self.helper.as_ref().unwrap().arm(cx.waker().clone());
+                        return Poll::Pending;
+                    } else if is_closed(&err) {
+                        return Poll::Ready(Ok(StopReason::Closed));
+                    } else {
+                        return Poll::Ready(Err(err.into()));
+                    }
+                }
+            }
+        }
+        // Let other tasks run before accepting more
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+struct HelperState {
+    waker: Option<Waker>,
+    done: bool,
+}
+
+// Thread which wakes the task when the listener may be ready. It is
+// stopped and joined when dropped.
+struct Helper {
+    state: Arc<(Mutex<HelperState>, Condvar)>,
+    waker: Arc<wake::Waker>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Helper {
+    // The helper polls its own duplicate of the listener, so the socket
+    // it waits on stays open however the borrowed listener is closed or
+    // dropped. A close() replaces the listener's socket rather than the
+    // duplicate's, so the wait is registered on the listener itself to
+    // be woken by it.
+    fn start(listener: &TcpListener, timeout: Duration) -> Result<Helper, Error> {
+        let waker = Arc::new(wake::Waker::register(listener)?);
+        let listener = listener.try_clone()?;
+        let state = Arc::new((Mutex::new(HelperState::default()), Condvar::new()));
+        let (s_clone, w_clone) = (state.clone(), waker.clone());
+        let thread = thread::spawn(move || {
+            let (lock, armed) = &*s_clone;
+            loop {
+                let mut guard = lock.lock().unwrap();
+                while guard.waker.is_none() && !guard.done {
+                    guard = armed.wait(guard).unwrap();
+                }
+                if guard.done {
+                    return;
+                }
+                drop(guard);
+                let _ = w_clone.wait(&listener, timeout);
+                if let Some(waker) = lock.lock().unwrap().waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+        Ok(Helper {
+            state,
+            waker,
+            thread: Some(thread),
+        })
+    }
+
+    fn arm(&self, waker: Waker) {
+        let (lock, armed) = &*self.state;
+        lock.lock().unwrap().waker = Some(waker);
+        armed.notify_one();
+    }
+}
+
+impl Drop for Helper {
+    // Wake the helper out of its wait, so joining it doesn't block.
+    fn drop(&mut self) {
+        let (lock, armed) = &*self.state;
+        lock.lock().unwrap().done = true;
+        armed.notify_one();
+        self.waker.wake();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    // Minimal executor: park the thread until woken
+    struct Unparker(Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(Unparker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    // Completes once the flag is set. It doesn't wake the task, so
+    // relies on the timeout.
+    struct Flag(Arc<AtomicBool>);
+
+    impl Future for Flag {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+            if self.0.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_async() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let s_clone = stop.clone();
+        let client = thread::spawn(move || {
+            let _a = TcpStream::connect(addr).unwrap();
+            let _b = TcpStream::connect(addr).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            s_clone.store(true, Ordering::SeqCst);
+        });
+        let mut handled = 0;
+        let incoming = listener
+            .handle_incoming_async(|_| handled += 1, Duration::from_millis(10))
+            .with_shutdown(Flag(stop));
+        block_on(incoming).unwrap();
+        client.join().unwrap();
+        assert_eq!(handled, 2);
+    }
+
+    #[test]
+    fn test_drop_and_close() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let waker = Waker::from(Arc::new(Unparker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        // Dropping a pending future doesn't wait out the timeout
+        let mut incoming =
+            Box::pin(listener.handle_incoming_async(|_| (), Duration::from_secs(30)));
+        assert!(incoming.as_mut().poll(&mut cx).is_pending());
+        let started = std::time::Instant::now();
+        drop(incoming);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Closing wakes the task, rather than leaving it to the timeout
+        let l_clone = listener.clone();
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            l_clone.close();
+        });
+        let started = std::time::Instant::now();
+        block_on(listener.handle_incoming_async(|_| (), Duration::from_secs(30))).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        closer.join().unwrap();
+    }
+}
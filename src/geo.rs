@@ -0,0 +1,233 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Geographic accept filtering.
+//!
+//! A [GeoResolver](trait.GeoResolver.html) maps peer addresses to a
+//! country and/or ASN. A [GeoPolicy](struct.GeoPolicy.html) uses a
+//! resolver to allow, deny or tag connections, and can be used directly
+//! from any accept filter closure. [CidrResolver](struct.CidrResolver.html)
+//! is a simple table based resolver; anything else (e.g. a MaxMind
+//! database reader) can be plugged in by implementing the trait.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Location information for an address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 country code, e.g. "GB".
+    pub country: Option<String>,
+    /// Autonomous system number.
+    pub asn: Option<u32>,
+}
+
+/// Resolves addresses to location information.
+pub trait GeoResolver {
+    /// Look up addr, returning None if nothing is known about it.
+    fn lookup(&self, addr: &IpAddr) -> Option<GeoInfo>;
+}
+
+/// Resolver backed by a table of CIDR ranges. The longest matching
+/// prefix wins.
+#[derive(Clone, Debug, Default)]
+pub struct CidrResolver {
+    ranges: Vec<(IpAddr, u8, GeoInfo)>,
+}
+
+impl CidrResolver {
+    /// Create an empty resolver.
+    pub fn new() -> CidrResolver {
+        CidrResolver::default()
+    }
+
+    /// Map every address in network/prefix to info.
+    pub fn insert(&mut self, network: IpAddr, prefix: u8, info: GeoInfo) {
+        self.ranges.push((network, prefix, info));
+    }
+}
+
+impl GeoResolver for CidrResolver {
+    fn lookup(&self, addr: &IpAddr) -> Option<GeoInfo> {
+        self.ranges
+            .iter()
+            .filter(|(network, prefix, _)| in_network(addr, network, *prefix))
+            .max_by_key(|(_, prefix, _)| *prefix)
+            .map(|(_, _, info)| info.clone())
+    }
+}
+
+/// Dual stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses
+/// (::ffff:a.b.c.d). Treat those as the IPv4 address they carry.
+fn canonical(addr: &IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*addr, IpAddr::V4),
+        IpAddr::V4(_) => *addr,
+    }
+}
+
+fn in_network(addr: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    // A mapped network's prefix counts the 96 bit ::ffff: prefix too.
+    let (network, prefix) = match canonical(network) {
+        IpAddr::V4(n) if network.is_ipv6() => (IpAddr::V4(n), prefix.saturating_sub(96)),
+        n => (n, prefix),
+    };
+    match (&canonical(addr), &network) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix.min(32) as u32)
+                .unwrap_or(0);
+            u32::from(*a) & mask == u32::from(*n) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix.min(128) as u32)
+                .unwrap_or(0);
+            u128::from(*a) & mask == u128::from(*n) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Outcome of checking an address against a [GeoPolicy](struct.GeoPolicy.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GeoVerdict {
+    /// Accept the connection. The location, if known, can be used to
+    /// tag it.
+    Allow(Option<GeoInfo>),
+    /// Reject the connection.
+    Deny(Option<GeoInfo>),
+}
+
+/// Allow or deny connections based on their location.
+pub struct GeoPolicy<R> {
+    resolver: R,
+    allow_countries: Option<HashSet<String>>,
+    deny_countries: HashSet<String>,
+    deny_asns: HashSet<u32>,
+    allow_unknown: bool,
+}
+
+impl<R: GeoResolver> GeoPolicy<R> {
+    /// Create a policy which allows everything.
+    pub fn new(resolver: R) -> GeoPolicy<R> {
+        GeoPolicy {
+            resolver,
+            allow_countries: None,
+            deny_countries: HashSet::new(),
+            deny_asns: HashSet::new(),
+            allow_unknown: true,
+        }
+    }
+
+    /// Only allow connections from these countries.
+    pub fn allow_only_countries(mut self, countries: &[&str]) -> Self {
+        self.allow_countries = Some(countries.iter().map(|c| c.to_string()).collect());
+        self
+    }
+
+    /// Deny connections from this country.
+    pub fn deny_country(mut self, country: &str) -> Self {
+        self.deny_countries.insert(country.to_string());
+        self
+    }
+
+    /// Deny connections from this ASN.
+    pub fn deny_asn(mut self, asn: u32) -> Self {
+        self.deny_asns.insert(asn);
+        self
+    }
+
+    /// Decide what to do with addresses the resolver knows nothing
+    /// about, or whose country is unknown when an allow list is set.
+    /// The default is to allow them.
+    pub fn allow_unknown(mut self, allow: bool) -> Self {
+        self.allow_unknown = allow;
+        self
+    }
+
+    /// Check addr against the policy.
+    pub fn check(&self, addr: &SocketAddr) -> GeoVerdict {
+        let info = match self.resolver.lookup(&canonical(&addr.ip())) {
+            Some(info) => info,
+            None if self.allow_unknown => return GeoVerdict::Allow(None),
+            None => return GeoVerdict::Deny(None),
+        };
+        let denied = match info.country.as_ref() {
+            Some(country) => {
+                self.deny_countries.contains(country)
+                    || self
+                        .allow_countries
+                        .as_ref()
+                        .map_or(false, |allowed| !allowed.contains(country))
+            }
+            None => self.allow_countries.is_some() && !self.allow_unknown,
+        } || info.asn.map_or(false, |asn| self.deny_asns.contains(&asn));
+        if denied {
+            GeoVerdict::Deny(Some(info))
+        } else {
+            GeoVerdict::Allow(Some(info))
+        }
+    }
+
+    /// Is addr allowed? Suitable for use in an accept filter.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        matches!(self.check(addr), GeoVerdict::Allow(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let mut resolver = CidrResolver::new();
+        let gb = GeoInfo {
+            country: Some("GB".to_string()),
+            asn: Some(64500),
+        };
+        let xx = GeoInfo {
+            country: Some("XX".to_string()),
+            asn: Some(64511),
+        };
+        resolver.insert("10.0.0.0".parse().unwrap(), 8, gb.clone());
+        resolver.insert("10.1.0.0".parse().unwrap(), 16, xx.clone());
+        let policy = GeoPolicy::new(resolver).deny_country("XX");
+
+        assert_eq!(
+            policy.check(&"10.2.3.4:80".parse().unwrap()),
+            GeoVerdict::Allow(Some(gb))
+        );
+        assert_eq!(
+            policy.check(&"10.1.3.4:80".parse().unwrap()),
+            GeoVerdict::Deny(Some(xx))
+        );
+        assert!(policy.allows(&"192.168.0.1:80".parse().unwrap()));
+        let policy = policy.allow_unknown(false);
+        assert!(!policy.allows(&"192.168.0.1:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_mapped() {
+        let mut resolver = CidrResolver::new();
+        let xx = GeoInfo {
+            country: Some("XX".to_string()),
+            asn: None,
+        };
+        resolver.insert("10.1.0.0".parse().unwrap(), 16, xx.clone());
+        let policy = GeoPolicy::new(resolver).deny_country("XX");
+
+        assert_eq!(
+            policy.check(&"[::ffff:10.1.2.3]:80".parse().unwrap()),
+            GeoVerdict::Deny(Some(xx))
+        );
+        assert!(policy.allows(&"[::ffff:10.2.2.3]:80".parse().unwrap()));
+    }
+}
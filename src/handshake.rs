@@ -0,0 +1,267 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Offload connection handshakes to a dedicated thread pool.
+//!
+//! Handshakes, such as a TLS handshake, can be expensive and a flood of
+//! them shouldn't starve connections which are already established. A
+//! [HandshakePool](struct.HandshakePool.html) runs a user supplied
+//! handshake function on its own threads, fed from its own bounded
+//! queue, and passes each completed connection on to the application,
+//! e.g. by dispatching it to a [QosDispatcher](../qos/struct.QosDispatcher.html).
+//!
+//! The crate doesn't depend on a TLS library, so the handshake function
+//! is whatever the application's TLS library provides.
+
+use crate::{accept_loop, Callbacks, StopReason};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Counters kept by a [HandshakePool](struct.HandshakePool.html).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeStats {
+    /// Handshakes which succeeded.
+    pub completed: u64,
+    /// Handshakes which failed.
+    pub failed: u64,
+    /// Handshakes which timed out, either waiting in the queue or
+    /// while running.
+    pub timed_out: u64,
+    /// Connections dropped because the queue was full.
+    pub rejected: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    completed: AtomicU64,
+    failed: AtomicU64,
+    timed_out: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Default)]
+struct Queue {
+    streams: VecDeque<(TcpStream, Instant)>,
+    shutdown: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    capacity: usize,
+    timeout: Duration,
+    counters: Counters,
+}
+
+/// Runs handshakes on a fixed set of threads.
+pub struct HandshakePool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl HandshakePool {
+    /// Start worker threads which call handshake for each submitted
+    /// connection and pass successful results to on_ready. At most
+    /// capacity connections wait in the queue. A handshake must finish
+    /// within timeout of the connection being submitted: connections
+    /// which wait longer are dropped, and handshake I/O is given read
+    /// and write timeouts for whatever time remains. Those timeouts are
+    /// still set when on_ready is called.
+    pub fn new<T, H, F>(
+        workers: usize,
+        capacity: usize,
+        timeout: Duration,
+        handshake: H,
+        on_ready: F,
+    ) -> HandshakePool
+    where
+        T: 'static,
+        H: Fn(TcpStream) -> Result<T, Error> + Send + Sync + 'static,
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            capacity,
+            timeout,
+            counters: Counters::default(),
+        });
+        let handshake = Arc::new(handshake);
+        let on_ready = Arc::new(on_ready);
+        let workers = (0..workers)
+            .map(|_| {
+                let shared = shared.clone();
+                let handshake = handshake.clone();
+                let on_ready = on_ready.clone();
+                thread::spawn(move || {
+                    while let Some((stream, submitted)) = shared.next() {
+                        if let Some(ready) = shared.run(&*handshake, stream, submitted) {
+                            on_ready(ready);
+                        }
+                    }
+                })
+            })
+            .collect();
+        HandshakePool { shared, workers }
+    }
+
+    /// Queue a connection for its handshake. If the queue is full, the
+    /// connection is handed back.
+    pub fn submit(&self, stream: TcpStream) -> Result<(), TcpStream> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.streams.len() >= self.shared.capacity {
+            self.shared.counters.rejected.fetch_add(1, Ordering::SeqCst);
+            return Err(stream);
+        }
+        queue.streams.push_back((stream, Instant::now()));
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Number of connections waiting for a handshake thread.
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().streams.len()
+    }
+
+    /// Current counters.
+    pub fn stats(&self) -> HandshakeStats {
+        let counters = &self.shared.counters;
+        HandshakeStats {
+            completed: counters.completed.load(Ordering::SeqCst),
+            failed: counters.failed.load(Ordering::SeqCst),
+            timed_out: counters.timed_out.load(Ordering::SeqCst),
+            rejected: counters.rejected.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Accept connections from listener until it is closed, submitting
+    /// each one to the pool. Connections which don't fit in the queue
+    /// are dropped.
+    pub fn handle_incoming(
+        &self,
+        listener: &TcpListener,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error> {
+        let mut submit = |stream, _, _| {
+            let _ = self.submit(stream);
+        };
+        accept_loop(listener, &mut submit, timeout, Callbacks::default())
+            .map_err(crate::Error::from)
+    }
+}
+
+impl Shared {
+    // Wait for the next connection. Returns None once the pool is shut
+    // down and the queue is empty.
+    fn next(&self) -> Option<(TcpStream, Instant)> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(next) = queue.streams.pop_front() {
+                return Some(next);
+            }
+            if queue.shutdown {
+                return None;
+            }
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+
+    fn run<T, H>(&self, handshake: &H, stream: TcpStream, submitted: Instant) -> Option<T>
+    where
+        H: Fn(TcpStream) -> Result<T, Error>,
+    {
+        let remaining = self.timeout.saturating_sub(submitted.elapsed());
+        if remaining == Duration::from_secs(0) {
+            self.counters.timed_out.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        let prepared = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(remaining)))
+            .and_then(|_| stream.set_write_timeout(Some(remaining)));
+        let result = prepared.and_then(|_| handshake(stream));
+        let counter = match result {
+            Ok(_) => &self.counters.completed,
+            Err(ref err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                &self.counters.timed_out
+            }
+            Err(_) => &self.counters.failed,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        result.ok()
+    }
+}
+
+impl Drop for HandshakePool {
+    /// Queued connections are handshaken before the workers exit.
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.ready.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::mpsc;
+
+    // A toy handshake: the client must send "HELLO"
+    fn hello(mut stream: TcpStream) -> Result<TcpStream, Error> {
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf)?;
+        if &buf != b"HELLO" {
+            return Err(Error::new(ErrorKind::InvalidData, "bad hello"));
+        }
+        Ok(stream)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let pool = HandshakePool::new(2, 8, Duration::from_millis(100), hello, move |stream| {
+            tx.lock().unwrap().send(stream).unwrap();
+        });
+
+        let mut good = TcpStream::connect(addr).unwrap();
+        good.write_all(b"HELLO").unwrap();
+        let mut bad = TcpStream::connect(addr).unwrap();
+        bad.write_all(b"HOWDY").unwrap();
+        let _silent = TcpStream::connect(addr).unwrap();
+        for _ in 0..3 {
+            pool.submit(listener.accept().unwrap().0).unwrap();
+        }
+
+        let ready = rx.recv().unwrap();
+        assert_eq!(ready.peer_addr().unwrap(), good.local_addr().unwrap());
+        while pool.stats().timed_out == 0 || pool.stats().failed == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            pool.stats(),
+            HandshakeStats {
+                completed: 1,
+                failed: 1,
+                timed_out: 1,
+                rejected: 0
+            }
+        );
+    }
+}
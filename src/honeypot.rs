@@ -0,0 +1,242 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Capture details of rejected connections.
+//!
+//! Rather than closing connections which fail an accept filter straight
+//! away, a [Honeypot](struct.Honeypot.html) keeps them open long enough
+//! to record the first bytes the peer sends, along with timing and
+//! address information, and passes a [Capture](struct.Capture.html) to
+//! an audit callback. This gives some visibility into scanning traffic.
+
+use crate::{accept_loop, Callbacks, Error, StopReason};
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// What a [Honeypot](struct.Honeypot.html) recorded about a connection.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    /// Address of the peer.
+    pub peer_addr: SocketAddr,
+    /// Address the peer connected to.
+    pub local_addr: Option<SocketAddr>,
+    /// When the connection was captured.
+    pub accepted_at: SystemTime,
+    /// Delay before the peer sent its first byte, if it sent any.
+    pub first_byte_after: Option<Duration>,
+    /// The first bytes sent by the peer.
+    pub bytes: Vec<u8>,
+    /// How long the capture lasted.
+    pub duration: Duration,
+}
+
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "honeypot peer={} bytes={} first_byte_ms={} duration_ms={} data={:?}",
+            self.peer_addr,
+            self.bytes.len(),
+            self.first_byte_after
+                .map(|d| d.as_millis().to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.duration.as_millis(),
+            String::from_utf8_lossy(&self.bytes)
+        )
+    }
+}
+
+type AuditCallback = Box<dyn Fn(&Capture) + Send + Sync>;
+
+struct Inner {
+    max_bytes: usize,
+    linger: Duration,
+    max_active: usize,
+    active: AtomicUsize,
+    on_capture: AuditCallback,
+}
+
+// Counts a capture out when its thread exits.
+struct Active<'a>(&'a Inner);
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Records the first bytes sent on rejected connections.
+#[derive(Clone)]
+pub struct Honeypot {
+    inner: Arc<Inner>,
+}
+
+impl Honeypot {
+    /// Create a honeypot which records up to max_bytes from each peer,
+    /// waiting at most linger for them to arrive, and passes the result
+    /// to on_capture. Each capture runs on its own thread and at most
+    /// max_active captures run at once; connections beyond that are
+    /// simply closed.
+    pub fn new<F>(max_bytes: usize, linger: Duration, max_active: usize, on_capture: F) -> Honeypot
+    where
+        F: Fn(&Capture) + Send + Sync + 'static,
+    {
+        Honeypot {
+            inner: Arc::new(Inner {
+                max_bytes,
+                linger,
+                max_active,
+                active: AtomicUsize::new(0),
+                on_capture: Box::new(on_capture),
+            }),
+        }
+    }
+
+    /// Capture a rejected connection in the background. Returns false if
+    /// too many captures are already active, in which case the stream is
+    /// dropped.
+    pub fn capture(&self, stream: TcpStream, peer_addr: SocketAddr) -> bool {
+        let inner = self.inner.clone();
+        if inner.active.fetch_add(1, Ordering::SeqCst) >= inner.max_active {
+            inner.active.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        thread::spawn(move || {
+            // Released even if on_capture panics
+            let _active = Active(&inner);
+            let capture = inner.record(stream, peer_addr);
+            (inner.on_capture)(&capture);
+        });
+        true
+    }
+
+    /// Accept connections from listener until it is closed. Connections
+    /// which filter accepts are passed to handler and the rest are
+    /// captured by the honeypot.
+    pub fn handle_incoming<F, H>(
+        &self,
+        listener: &TcpListener,
+        mut filter: F,
+        mut handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(&SocketAddr) -> bool,
+        H: FnMut(TcpStream),
+    {
+        let mut handle = |stream, addr, _| {
+            if filter(&addr) {
+                handler(stream);
+            } else {
+                self.capture(stream, addr);
+            }
+        };
+        accept_loop(listener, &mut handle, timeout, Callbacks::default()).map_err(Error::from)
+    }
+}
+
+impl Inner {
+    fn record(&self, mut stream: TcpStream, peer_addr: SocketAddr) -> Capture {
+        let started = Instant::now();
+        let deadline = started + self.linger;
+        let mut capture = Capture {
+            peer_addr,
+            local_addr: stream.local_addr().ok(),
+            accepted_at: SystemTime::now(),
+            first_byte_after: None,
+            bytes: Vec::with_capacity(self.max_bytes),
+            duration: Duration::from_secs(0),
+        };
+        let mut buf = vec![0; self.max_bytes];
+        let _ = stream.set_nonblocking(false);
+        while capture.bytes.len() < self.max_bytes {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0)
+                || stream.set_read_timeout(Some(remaining)).is_err()
+            {
+                break;
+            }
+            let wanted = self.max_bytes - capture.bytes.len();
+            match stream.read(&mut buf[..wanted]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if capture.first_byte_after.is_none() {
+                        capture.first_byte_after = Some(started.elapsed());
+                    }
+                    capture.bytes.extend_from_slice(&buf[..n]);
+                }
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+        capture.duration = started.elapsed();
+        capture
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    fn handle_client(_stream: TcpStream) {
+        panic!("should have been rejected");
+    }
+
+    #[test]
+    fn test_capture() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let (tx, rx) = mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let honeypot = Honeypot::new(8, Duration::from_secs(5), 4, move |capture| {
+            tx.lock().unwrap().send(capture.clone()).unwrap();
+        });
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+        let l_clone = listener.clone();
+        let h_clone = honeypot.clone();
+        let server = thread::spawn(move || {
+            h_clone
+                .handle_incoming(&l_clone, |_| false, handle_client, Duration::from_millis(1))
+                .unwrap()
+        });
+        let capture = rx.recv().unwrap();
+        listener.close();
+        server.join().unwrap();
+        assert_eq!(capture.bytes, b"GET / HT");
+        assert_eq!(capture.peer_addr, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_panic() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let honeypot = Honeypot::new(8, Duration::from_millis(10), 1, |_| panic!("audit failed"));
+
+        let _client = TcpStream::connect(addr).unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        assert!(honeypot.capture(stream, peer_addr));
+        // The panicking capture still frees its slot
+        while honeypot.inner.active.load(Ordering::SeqCst) != 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let _client = TcpStream::connect(addr).unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        assert!(honeypot.capture(stream, peer_addr));
+    }
+}
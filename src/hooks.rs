@@ -0,0 +1,145 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Callbacks for an accept loop starting and stopping.
+//!
+//! [Hooks](struct.Hooks.html), passed to
+//! [Listener::handle_incoming_with_hooks()](../trait.Listener.html#tymethod.handle_incoming_with_hooks),
+//! are called as the loop starts accepting, when it terminates, with
+//! the result it is about to return, and for accept errors it recovers
+//! from. An application can use them to flip its health-check state at
+//! exactly the moments the listener starts and stops serving.
+//!
+//! Accept errors which only affect one connection, or which clear up
+//! when other connections close, such as running out of file
+//! descriptors, are recoverable: the loop carries on, sleeping first
+//! while resources are exhausted, and reports them to an on_error hook.
+//! Other errors stop the loop as usual.
+//!
+//! The hooks run on the loop's thread, so inside
+//! [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope)
+//! they can read the loop's labels with
+//! [Labels::current()](../labels/struct.Labels.html#method.current).
+//!
+//! on_stop is called however the loop ends. If a handler panics, it is
+//! called with [Error::HandlerPanicked](../enum.Error.html#variant.HandlerPanicked)
+//! as the panic unwinds, so it mustn't panic itself.
+
+use crate::{Error, StopReason};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+
+type StartHook<'a> = Box<dyn FnMut(SocketAddr) + 'a>;
+type StopHook<'a> = Box<dyn FnMut(&Result<StopReason, Error>) + 'a>;
+type ErrorHook<'a> = Box<dyn FnMut(&io::Error) + 'a>;
+
+/// Callbacks for the lifecycle of an accept loop.
+#[derive(Default)]
+pub struct Hooks<'a> {
+    pub(crate) on_start: Option<StartHook<'a>>,
+    pub(crate) on_stop: Option<StopHook<'a>>,
+    pub(crate) on_error: Option<ErrorHook<'a>>,
+}
+
+impl<'a> Hooks<'a> {
+    /// No callbacks.
+    pub fn new() -> Hooks<'a> {
+        Hooks::default()
+    }
+
+    /// Call on_start with the listener's address just before the first
+    /// accept.
+    pub fn on_start<F>(mut self, on_start: F) -> Self
+    where
+        F: FnMut(SocketAddr) + 'a,
+    {
+        self.on_start = Some(Box::new(on_start));
+        self
+    }
+
+    /// Call on_stop with the loop's result once it has terminated,
+    /// before it is returned, or with Error::HandlerPanicked if a
+    /// handler panics.
+    pub fn on_stop<F>(mut self, on_stop: F) -> Self
+    where
+        F: FnMut(&Result<StopReason, Error>) + 'a,
+    {
+        self.on_stop = Some(Box::new(on_stop));
+        self
+    }
+
+    /// Call on_error with each recoverable accept error, and carry on
+    /// accepting.
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: FnMut(&io::Error) + 'a,
+    {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+}
+
+// Calls an on_stop hook exactly once, including while a panic unwinds.
+pub(crate) struct StopGuard<'a>(Option<StopHook<'a>>);
+
+impl<'a> StopGuard<'a> {
+    pub(crate) fn new(on_stop: Option<StopHook<'a>>) -> StopGuard<'a> {
+        StopGuard(on_stop)
+    }
+
+    pub(crate) fn stop(mut self, result: &Result<StopReason, Error>) {
+        if let Some(mut on_stop) = self.0.take() {
+            on_stop(result);
+        }
+    }
+}
+
+impl Drop for StopGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(mut on_stop) = self.0.take() {
+            on_stop(&Err(Error::HandlerPanicked));
+        }
+    }
+}
+
+/// Can the loop carry on after err? Returns whether it should sleep
+/// before trying again as well.
+pub(crate) fn recoverable(err: &io::Error) -> Option<bool> {
+    match err.kind() {
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted => {
+            return Some(false)
+        }
+        _ => (),
+    }
+    #[cfg(unix)]
+    {
+        if let Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) = err.raw_os_error()
+        {
+            return Some(true);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recoverable() {
+        let aborted = io::Error::from(ErrorKind::ConnectionAborted);
+        assert_eq!(recoverable(&aborted), Some(false));
+        assert_eq!(recoverable(&io::Error::from(ErrorKind::InvalidInput)), None);
+        #[cfg(unix)]
+        assert_eq!(
+            recoverable(&io::Error::from_raw_os_error(libc::EMFILE)),
+            Some(true)
+        );
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An iterator over connections which ends when the listener closes.
+//!
+//! [StoppableIncoming](struct.StoppableIncoming.html), returned by
+//! [Listener::stoppable_incoming()](../trait.Listener.html#tymethod.stoppable_incoming),
+//! works like TcpListener::incoming(), but on a non-blocking listener:
+//! it sleeps while no connection is waiting instead of yielding
+//! WouldBlock errors, and ends, returning None, once the listener has
+//! been closed. Recoverable accept errors, such as running out of file
+//! descriptors, are skipped rather than yielded.
+
+use crate::{drained, hooks, is_closed};
+use std::io::{Error, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Iterator over incoming connections. Errors other than the listener
+/// being closed are yielded, as by TcpListener::incoming().
+#[derive(Debug)]
+pub struct StoppableIncoming<'a> {
+    listener: &'a TcpListener,
+    timeout: Duration,
+    closed: bool,
+}
+
+impl<'a> StoppableIncoming<'a> {
+    pub(crate) fn new(listener: &'a TcpListener, timeout: Duration) -> StoppableIncoming<'a> {
+        StoppableIncoming {
+            listener,
+            timeout,
+            closed: false,
+        }
+    }
+}
+
+impl Iterator for StoppableIncoming<'_> {
+    type Item = Result<TcpStream, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.closed {
+            match self.listener.accept() {
+                Ok((stream, _)) => return Some(Ok(stream)),
+                Err(err) => {
+                    if err.kind() == ErrorKind::WouldBlock {
+                        if drained(self.listener) {
+                            self.closed = true;
+                        } else {
+                            thread::sleep(self.timeout);
+                        }
+                    } else if is_closed(&err) {
+                        self.closed = true;
+                    } else if let Some(sleep) = hooks::recoverable(&err) {
+                        if sleep {
+                            thread::sleep(self.timeout);
+                        }
+                    } else {
+                        return Some(Err(err));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Listener;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    #[test]
+    fn test_incoming() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        let mut handled = 0;
+        for stream in listener.stoppable_incoming(Duration::from_millis(1)) {
+            stream.unwrap();
+            handled += 1;
+            if handled == 2 {
+                listener.close();
+            }
+        }
+        assert_eq!(handled, 2);
+    }
+}
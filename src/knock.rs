@@ -0,0 +1,245 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Port knocking.
+//!
+//! A [PortKnock](struct.PortKnock.html) gate only lets a peer reach the
+//! real handler after it has connected to a sequence of knock ports, in
+//! order, with no more than a configured gap between knocks. The
+//! knock ports and the service port are all members of one
+//! [ListenerSet](../set/struct.ListenerSet.html). Connections to knock
+//! ports are closed straight away, as are connections to the service
+//! port from peers which haven't knocked. Authorizations expire after a
+//! configured time. Expired state is purged as knocks arrive, and at
+//! most [DEFAULT_MAX_PEERS](constant.DEFAULT_MAX_PEERS.html) peers can
+//! be part way through the sequence at once; knocks which would start
+//! a sequence beyond that are ignored.
+
+use crate::set::ListenerSet;
+use crate::{Error, StopReason};
+use std::collections::HashMap;
+use std::net::{IpAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default limit on peers part way through the knock sequence.
+pub const DEFAULT_MAX_PEERS: usize = 65_536;
+
+struct Progress {
+    next: usize,
+    last: Instant,
+}
+
+struct State {
+    progress: HashMap<IpAddr, Progress>,
+    authorized: HashMap<IpAddr, Instant>,
+    purged: Instant,
+}
+
+/// Gates a service behind a port knocking sequence.
+pub struct PortKnock {
+    sequence: Vec<u16>,
+    gap: Duration,
+    ttl: Duration,
+    max_peers: usize,
+    state: Mutex<State>,
+}
+
+impl PortKnock {
+    /// Create a gate which requires knocks on the ports in sequence, at
+    /// most gap apart. A successful sequence authorizes the peer's
+    /// address for ttl.
+    pub fn new(sequence: &[u16], gap: Duration, ttl: Duration) -> PortKnock {
+        PortKnock {
+            sequence: sequence.to_vec(),
+            gap,
+            ttl,
+            max_peers: DEFAULT_MAX_PEERS,
+            state: Mutex::new(State {
+                progress: HashMap::new(),
+                authorized: HashMap::new(),
+                purged: Instant::now(),
+            }),
+        }
+    }
+
+    /// Track at most max_peers peers part way through the sequence.
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+
+    /// Is port one of the knock ports?
+    pub fn is_knock_port(&self, port: u16) -> bool {
+        self.sequence.contains(&port)
+    }
+
+    /// Record a knock on port from addr. Returns true if this completed
+    /// the sequence.
+    pub fn knock(&self, addr: IpAddr, port: u16) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if now.duration_since(state.purged) >= self.gap.min(self.ttl) {
+            self.purge_state(&mut state, now);
+        }
+        let next = match state.progress.get(&addr) {
+            Some(p) if now.duration_since(p.last) <= self.gap && self.sequence[p.next] == port => {
+                p.next + 1
+            }
+            // A wrong or late knock may still start a new sequence
+            Some(_) if self.sequence.first() == Some(&port) => 1,
+            None if self.sequence.first() == Some(&port)
+                && state.progress.len() < self.max_peers =>
+            {
+                1
+            }
+            _ => {
+                state.progress.remove(&addr);
+                return false;
+            }
+        };
+        if next == self.sequence.len() {
+            state.progress.remove(&addr);
+            state.authorized.insert(addr, now + self.ttl);
+            return true;
+        }
+        state.progress.insert(addr, Progress { next, last: now });
+        false
+    }
+
+    /// Has addr completed the sequence recently enough?
+    pub fn is_authorized(&self, addr: &IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.authorized.get(addr) {
+            Some(expires) if *expires > Instant::now() => true,
+            Some(_) => {
+                state.authorized.remove(addr);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Forget authorizations and partial sequences which have expired.
+    pub fn purge(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.purge_state(&mut state, Instant::now());
+    }
+
+    fn purge_state(&self, state: &mut State, now: Instant) {
+        let gap = self.gap;
+        state
+            .progress
+            .retain(|_, p| now.duration_since(p.last) <= gap);
+        state.authorized.retain(|_, expires| *expires > now);
+        state.purged = now;
+    }
+
+    /// Accept connections from every listener in set until they are all
+    /// closed. Connections to knock ports are recorded, and connections
+    /// to any other port are passed to handler if the peer is
+    /// authorized. Everything else is dropped.
+    pub fn handle_incoming<H>(
+        &self,
+        set: &ListenerSet,
+        mut handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        set.handle_incoming_with(
+            |stream, addr| {
+                let port = match stream.local_addr() {
+                    Ok(local) => local.port(),
+                    Err(_) => return,
+                };
+                if self.is_knock_port(port) {
+                    self.knock(addr.ip(), port);
+                } else if self.is_authorized(&addr.ip()) {
+                    handler(stream);
+                }
+            },
+            timeout,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn handle_client(mut stream: TcpStream) {
+        stream.write_all(b"ok").unwrap();
+    }
+
+    fn request(port: u16) -> Vec<u8> {
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut buf = vec![];
+        let _ = client.read_to_end(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_knock() {
+        let mut set = ListenerSet::new();
+        let mut ports = vec![];
+        for _ in 0..3 {
+            let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+            ports.push(listener.local_addr().unwrap().port());
+            set.add(listener);
+        }
+        let gate = Arc::new(PortKnock::new(
+            &ports[..2],
+            Duration::from_secs(5),
+            Duration::from_secs(60),
+        ));
+        let set = Arc::new(set);
+        let (g_clone, s_clone) = (gate.clone(), set.clone());
+        let server = thread::spawn(move || {
+            g_clone
+                .handle_incoming(&s_clone, handle_client, Duration::from_millis(1))
+                .unwrap()
+        });
+
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(request(ports[2]), b"");
+        request(ports[0]);
+        request(ports[1]);
+        while !gate.is_authorized(&local) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(request(ports[2]), b"ok");
+
+        set.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_expiry() {
+        let gate = PortKnock::new(&[1, 2], Duration::from_millis(20), Duration::from_secs(60))
+            .max_peers(1);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(!gate.knock(a, 1));
+        // b can't start a sequence while a is being tracked
+        assert!(!gate.knock(b, 1));
+        assert!(!gate.knock(b, 2));
+        thread::sleep(Duration::from_millis(30));
+        // a's progress is purged on the next knock, making room for b
+        assert!(!gate.knock(b, 1));
+        assert!(gate.knock(b, 2));
+        assert!(gate.state.lock().unwrap().progress.is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Key/value labels identifying a listener.
+//!
+//! In a process with several listeners, [Labels](struct.Labels.html)
+//! such as `service=api` or `listener=admin` say which one a metric,
+//! log line or audit record came from. Labels are attached to listeners
+//! in a [ListenerSet](../set/struct.ListenerSet.html) and passed to the
+//! handler with each connection. Display gives the `key=value` form
+//! used in logs and [prometheus()](struct.Labels.html#method.prometheus)
+//! the form used in metrics.
+//!
+//! Any other accept loop run inside [in_scope()](struct.Labels.html#method.in_scope)
+//! carries the labels to every sink: they are put in each
+//! [ConnInfo](../struct.ConnInfo.html), on each trace
+//! [LoopEvent](../trace/enum.LoopEvent.html) and on the
+//! [ListenerStats](../stats/struct.ListenerStats.html) the loop
+//! updates, and hooks, which run on the loop's thread, can read them
+//! with [current()](struct.Labels.html#method.current).
+//! [ListenerBuilder::labels()](../builder/struct.ListenerBuilder.html#method.labels)
+//! attaches them to a listener when it is built.
+
+use std::cell::RefCell;
+use std::fmt;
+
+/// An ordered set of key/value labels.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Labels {
+    pairs: Vec<(String, String)>,
+}
+
+impl Labels {
+    /// Create an empty set of labels.
+    pub fn new() -> Labels {
+        Labels::default()
+    }
+
+    /// Add a label, replacing any existing label with the same key.
+    pub fn with(mut self, key: &str, value: &str) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    /// Add a label, replacing any existing label with the same key.
+    pub fn insert(&mut self, key: &str, value: &str) {
+        match self.pairs.iter_mut().find(|(k, _)| k == key) {
+            Some(pair) => pair.1 = value.to_string(),
+            None => self.pairs.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// The value of the label with key, if any.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The labels, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Are there no labels?
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Run f with these labels attached to any accept loop it runs on
+    /// this thread. Scopes nest, and the outer labels are restored when
+    /// f returns or panics.
+    pub fn in_scope<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let outer = CURRENT.with(|current| current.replace(self.clone()));
+        // Restore the outer labels even if f panics
+        let _restore = Restore(Some(outer));
+        f()
+    }
+
+    /// The labels of the innermost in_scope() on this thread, empty
+    /// outside any.
+    pub fn current() -> Labels {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Format as a Prometheus label set, e.g. `{service="api"}`. Empty
+    /// labels format as an empty string.
+    pub fn prometheus(&self) -> String {
+        if self.pairs.is_empty() {
+            return String::new();
+        }
+        let pairs: Vec<String> = self
+            .pairs
+            .iter()
+            .map(|(k, v)| {
+                let v = v
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                format!("{}=\"{}\"", k, v)
+            })
+            .collect();
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Labels> = RefCell::new(Labels::new());
+}
+
+// Puts back the labels in_scope() replaced.
+struct Restore(Option<Labels>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let outer = self.0.take().unwrap_or_default();
+        CURRENT.with(|current| *current.borrow_mut() = outer);
+    }
+}
+
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, (k, v)) in self.pairs.iter().enumerate() {
+            if idx > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let labels = Labels::new()
+            .with("service", "api")
+            .with("env", "dev")
+            .with("env", "prod");
+        assert_eq!(labels.get("env"), Some("prod"));
+        assert_eq!(labels.to_string(), "service=api env=prod");
+        assert_eq!(labels.prometheus(), "{service=\"api\",env=\"prod\"}");
+        assert_eq!(Labels::new().prometheus(), "");
+    }
+
+    #[test]
+    fn test_scope() {
+        let api = Labels::new().with("service", "api");
+        let admin = Labels::new().with("listener", "admin");
+        api.in_scope(|| {
+            assert_eq!(Labels::current(), api);
+            admin.in_scope(|| assert_eq!(Labels::current(), admin));
+            assert_eq!(Labels::current(), api);
+            let panicked = std::panic::catch_unwind(|| admin.in_scope(|| panic!("handler")));
+            assert!(panicked.is_err());
+            assert_eq!(Labels::current(), api);
+        });
+        assert!(Labels::current().is_empty());
+    }
+}
@@ -30,23 +30,120 @@
 //! to support testing or low throughput usage.
 //!
 
-use std::io::{Error, ErrorKind};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::cell::{Cell, RefCell};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
 #[cfg(windows)]
 mod plat_specifics {
-    pub use std::os::windows::io::AsRawSocket;
-    pub use winapi::um::winsock2;
+    pub use std::os::windows::io::{
+        AsRawSocket, AsSocket, BorrowedSocket, FromRawSocket, RawSocket,
+    };
+    pub use winapi::shared::minwindef;
+    pub use winapi::um::{consoleapi, wincon, winsock2};
     pub const EBADF: i32 = 10038;
+    pub const EINVAL: i32 = 10022;
 }
 #[cfg(not(windows))]
 mod plat_specifics {
     pub use libc;
-    pub use std::os::unix::io::AsRawFd;
+    pub use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
     pub const EBADF: i32 = 9;
+    pub const EINVAL: i32 = 22;
 }
+use acceptor::Acceptor;
+use backoff::{Backoff, BackoffStrategy};
+use clock::{RealClockWith, RealTime, Sleeper, Time};
+use connection::Connection;
+pub use error::{Error, ErrorPolicy, StopReason};
+use fingerprint::Fingerprint;
+use hooks::Hooks;
+use incoming::StoppableIncoming;
+use labels::Labels;
+use limit::ConnectionLimit;
 use plat_specifics::*;
-use std::thread;
-use std::time::Duration;
+use set::{BindPolicy, ListenerSet};
+use stats::ListenerStats;
+use std::time::{Duration, Instant, SystemTime};
+use stop::{ListenerHandle, StoppableListener};
+use streamopt::StreamConfig;
+use trace::LoopEvent;
+use until::StopCondition;
+use watchdog::Watchdog;
+
+pub mod acceptor;
+pub mod accounting;
+pub mod backoff;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod bpf;
+#[cfg(unix)]
+pub mod builder;
+pub mod capture;
+pub mod channel;
+pub mod clock;
+pub mod connection;
+pub mod counted;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod diag;
+pub mod drain;
+pub mod error;
+pub mod export;
+pub mod failover;
+pub mod fingerprint;
+pub mod future;
+pub mod geo;
+pub mod handshake;
+pub mod honeypot;
+pub mod hooks;
+pub mod incoming;
+pub mod knock;
+pub mod labels;
+pub mod limit;
+pub mod maintenance;
+pub mod peek;
+pub mod phase;
+pub mod pipeline;
+pub mod poll;
+#[cfg(unix)]
+pub mod prefork;
+pub mod proxy;
+pub mod qos;
+pub mod queue;
+pub mod quota;
+pub mod ratelimit;
+pub mod rdns;
+pub mod registry;
+pub mod reject;
+#[cfg(unix)]
+pub mod restart;
+pub mod secure;
+pub mod server;
+pub mod set;
+#[cfg(unix)]
+pub mod shard;
+pub mod signal;
+pub mod sockopt;
+pub mod source;
+pub mod starvation;
+pub mod stats;
+pub mod stop;
+pub mod streamopt;
+pub mod supervisor;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
+pub mod testing;
+pub mod threaded;
+pub mod throttle;
+mod tls;
+pub mod trace;
+pub mod udp;
+#[cfg(unix)]
+pub mod unix;
+pub mod until;
+pub mod vhost;
+pub mod watchdog;
 
 /// Listener which simplifies using TcpListener
 ///
@@ -91,12 +188,86 @@ use std::time::Duration;
 ///     }
 /// }
 /// ```
-
 pub trait Listener {
     /// Creates a new TcpListener which will be bound to the specified
     /// address. Works exactly the same as TcpListener::bind(), but
     /// always forces the bound socket to be non-blocking.
-    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error>
+    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Bind to a port chosen by the system on the IPv4 loopback
+    /// address, as tests usually want, and return the listener with
+    /// the address it is bound to.
+    fn bind_ephemeral() -> Result<(Self, SocketAddr), io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Bind the addresses addr resolves to according to policy, rather
+    /// than failing if the first can't be bound. See
+    /// [ListenerSet::bind_with_policy()](set/struct.ListenerSet.html#method.bind_with_policy).
+    fn bind_with_policy<A: ToSocketAddrs>(
+        addr: A,
+        policy: BindPolicy,
+    ) -> Result<ListenerSet, io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Listen on port on every IPv4 and IPv6 address, with as few
+    /// sockets as the platform allows. See
+    /// [ListenerSet::bind_dual_stack()](set/struct.ListenerSet.html#method.bind_dual_stack).
+    fn bind_dual_stack(port: u16) -> Result<ListenerSet, io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Takes ownership of an existing listener, e.g. one created by
+    /// another library, and makes it non-blocking. A listener built and
+    /// configured with socket2 converts with `socket.into()`, keeping
+    /// options such as IP_TTL, IP_FREEBIND or IP_TRANSPARENT.
+    fn from_std(listener: TcpListener) -> Result<Self, io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Takes ownership of a listening socket descriptor, e.g. one
+    /// inherited from systemd socket activation, and makes it
+    /// non-blocking.
+    ///
+    /// # Safety
+    /// fd must be an open, listening TCP socket which nothing else owns.
+    #[cfg(not(windows))]
+    unsafe fn from_raw_fd(fd: RawFd) -> Result<Self, io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Takes ownership of the listening socket called name passed by
+    /// systemd socket activation, checking that it is a listening TCP
+    /// socket, and makes it non-blocking. The name is set with
+    /// FileDescriptorName= in the socket unit, and defaults to the
+    /// unit's name.
+    #[cfg(all(unix, feature = "systemd"))]
+    fn from_systemd(name: &str) -> Result<Self, io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Gives up ownership of the listening socket without closing it,
+    /// so that it stays bound and keeps queueing connections, and
+    /// returns its descriptor. The descriptor is left open across
+    /// exec(), so it can be handed to a new process, which resumes
+    /// accepting with from_raw_fd(). See the
+    /// [restart](restart/index.html) module.
+    #[cfg(not(windows))]
+    fn into_raw_parts(self) -> Result<RawFd, io::Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Takes ownership of a listening socket handle, e.g. one inherited
+    /// from a parent process, and makes it non-blocking.
+    ///
+    /// # Safety
+    /// socket must be an open, listening TCP socket which nothing else
+    /// owns.
+    #[cfg(windows)]
+    unsafe fn from_raw_socket(socket: RawSocket) -> Result<Self, io::Error>
     where
         Self: std::marker::Sized;
 
@@ -104,56 +275,1349 @@ pub trait Listener {
     /// if handle_incoming() is active, it will terminate normally.
     fn close(&self);
 
+    /// Close the listener once the connections already waiting in the
+    /// kernel's accept queue have been handled, rather than dropping
+    /// them. The running loop accepts without sleeping until nothing is
+    /// waiting, then closes the listener and terminates normally. This
+    /// blocks until then, or for at most max, after which the listener
+    /// is closed anyway. Every accept loop in this crate drains, when it
+    /// next finds nothing waiting, and returns
+    /// [StopReason::Drained](enum.StopReason.html#variant.Drained). A
+    /// loop which is waiting for a free slot or token rather than
+    /// accepting only drains once it accepts again, and with no loop
+    /// running, this waits for max.
+    fn close_after_drain(&self, max: Duration);
+
+    /// Wait up to timeout for a connection and accept it. Fails with
+    /// [Error::Timeout](enum.Error.html#variant.Timeout) if none
+    /// arrived in time, and
+    /// [Error::Closed](enum.Error.html#variant.Closed) if the listener
+    /// is closed, including by a close() during the wait.
+    fn accept_timeout(&self, timeout: Duration) -> Result<(TcpStream, SocketAddr), Error>;
+
+    /// Accept a connection if one is waiting, without waiting, sleeping
+    /// or calling anything else: the primitive to build a custom loop
+    /// on. Returns None if no connection is waiting, and
+    /// [Error::Closed](enum.Error.html#variant.Closed) once the
+    /// listener is closed.
+    fn accept_nonblocking(&self) -> Result<Option<(TcpStream, SocketAddr)>, Error>;
+
     /// Start handling incoming connections. On error this will
-    /// terminate with an error code, unless the error is EBADF, this
-    /// is interpreted as normal termination triggered by invocation
-    /// of the close() method.
-    fn handle_incoming(&self, handler: fn(TcpStream), timeout: Duration) -> Result<(), Error>;
+    /// terminate with an [Error](enum.Error.html), unless the error is
+    /// EBADF, this is interpreted as normal termination triggered by
+    /// invocation of the close() method, or only affects one
+    /// connection or clears up by itself, such as ECONNABORTED or
+    /// EMFILE, in which case the loop carries on. handler may be a plain
+    /// function or a closure which captures application state. On
+    /// normal termination the [StopReason](enum.StopReason.html) says
+    /// whether the listener was closed or drained. The variants below
+    /// return in the same way.
+    fn handle_incoming<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// Works like handle_incoming(), but catches handler panics and
+    /// passes their message to on_panic. If it returns true, the loop
+    /// keeps accepting; if false, the loop fails fast with
+    /// [Error::HandlerPanicked](enum.Error.html#variant.HandlerPanicked).
+    fn handle_incoming_catching<H, P>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        on_panic: P,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        P: FnMut(&str) -> bool;
+
+    /// Works like handle_incoming(), but handler returns a Result and
+    /// policy decides what happens when it is an error: ignore it,
+    /// report it, or stop the loop and return it as
+    /// [Error::HandlerFailed](enum.Error.html#variant.HandlerFailed).
+    fn handle_incoming_fallible<H, E>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        policy: ErrorPolicy,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream) -> Result<(), E>,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Works like handle_incoming(), but runs handler on a new thread
+    /// for each connection, so a handler blocking on I/O doesn't stall
+    /// accepts. With max_threads, at most that many handlers run at
+    /// once and accepting pauses until one finishes. The loop returns
+    /// without waiting for running handlers.
+    fn handle_incoming_spawn<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        max_threads: Option<usize>,
+    ) -> Result<StopReason, Error>
+    where
+        H: Fn(TcpStream) + Send + Sync + 'static;
+
+    /// Works like handle_incoming_spawn(), but registers each stream
+    /// with watchdog for as long as its handler runs. Streams which
+    /// can't be registered are dropped.
+    fn handle_incoming_spawn_with_watchdog<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        max_threads: Option<usize>,
+        watchdog: &Watchdog,
+    ) -> Result<StopReason, Error>
+    where
+        H: Fn(TcpStream) + Send + Sync + 'static;
+
+    /// Works like handle_incoming(), but calls on_idle each time the
+    /// listener would block, just before sleeping. This allows periodic
+    /// maintenance to be performed on the accepting thread.
+    fn handle_incoming_with_idle<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        on_idle: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&IdleInfo);
+
+    /// Works like handle_incoming(), but calls on_tick every interval,
+    /// whether or not connections are arriving. The tick is checked
+    /// between connections, so a slow handler will delay it.
+    fn handle_incoming_with_tick<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        interval: Duration,
+        on_tick: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&TickInfo);
+
+    /// Works like handle_incoming(), but reads the time from and sleeps
+    /// on time, e.g. a [SimClock](clock/struct.SimClock.html) in a
+    /// simulation test.
+    fn handle_incoming_with_time<H, T>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        time: &T,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        T: Time;
+
+    /// Works like handle_incoming(), but waits for connections by
+    /// calling sleeper with the timeout instead of sleeping, e.g. a
+    /// [YieldNow](clock/struct.YieldNow.html). Time is read from the
+    /// real clock.
+    fn handle_incoming_with_sleeper<H, S>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        sleeper: &S,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        S: Sleeper;
+
+    /// Works like handle_incoming(), but records what the loop does in
+    /// stats, which can be read from another thread while it runs.
+    /// handler returns a Result, so that failures can be counted.
+    fn handle_incoming_with_stats<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        stats: &ListenerStats,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream) -> Result<(), io::Error>;
+
+    /// Works like handle_incoming(), but the time slept when no
+    /// connection is waiting follows strategy instead of being fixed.
+    fn handle_incoming_with_backoff<H>(
+        &self,
+        handler: H,
+        strategy: BackoffStrategy,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// Works like handle_incoming(), but also returns normally as soon
+    /// as until is met, e.g. a [Deadline](until/struct.Deadline.html)
+    /// passes or a predicate returns true. It is checked before each
+    /// accept, so a slow handler delays it and the loop may sleep for
+    /// up to timeout past it. Returns the condition's
+    /// [reason()](until/trait.StopCondition.html#method.reason) when it
+    /// is met.
+    fn handle_incoming_until<H, U>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        until: U,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        U: StopCondition;
+
+    /// Works like handle_incoming(), but also returns normally once no
+    /// connection has been accepted for idle, counted from the start
+    /// if none has been, with [StopReason::Idle](enum.StopReason.html#variant.Idle).
+    /// The listener itself stays open.
+    fn handle_incoming_with_idle_timeout<H>(
+        &self,
+        handler: H,
+        poll_timeout: Duration,
+        idle: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// Works like handle_incoming(), calling hooks as the loop starts
+    /// and stops, and for accept errors it recovers from. See the
+    /// [hooks](hooks/index.html) module.
+    fn handle_incoming_with_hooks<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        hooks: Hooks,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// Works like handle_incoming(), but reports what the loop does to
+    /// on_event: starting, each connection accepted and handled, idle
+    /// sleeps (sampled), and stopping or failing. See the
+    /// [trace](trace/index.html) module.
+    fn handle_incoming_traced<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        on_event: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&LoopEvent);
+
+    /// Works like handle_incoming(), but shuts down (both directions)
+    /// the stream of a handler still running after max_lifetime, so a
+    /// handler blocked on I/O with a stuck peer fails and returns. A
+    /// handler which is busy rather than blocked isn't interrupted.
+    /// Uses a [Watchdog](watchdog/struct.Watchdog.html) thread shared
+    /// by every such loop in the process.
+    fn handle_incoming_with_deadline<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        max_lifetime: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// Works like handle_incoming(), but applies config to each stream
+    /// before passing it to handler. Fails with InvalidInput, without
+    /// accepting anything, if config doesn't
+    /// [validate](streamopt/struct.StreamConfig.html#method.validate).
+    /// Streams on which an option can't otherwise be set are dropped.
+    fn handle_incoming_configured<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        config: &StreamConfig,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// Works like handle_incoming(), but handler is also given the peer
+    /// address returned by accept().
+    fn handle_incoming_addr<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream, SocketAddr);
+
+    /// Works like handle_incoming(), but calls filter with the peer
+    /// address of each connection before the handler. If it returns
+    /// false, the connection is dropped without calling the handler.
+    fn handle_incoming_filtered<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        filter: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&SocketAddr) -> bool;
+
+    /// Works like handle_incoming(), but handler is a closure which is
+    /// also given a [ConnInfo](struct.ConnInfo.html) recording its
+    /// addresses, when it was accepted, its sequence number and its ids.
+    /// Connections whose local address can't be read, because the peer
+    /// has already gone, are dropped.
+    fn handle_incoming_with_info<F>(
+        &self,
+        handler: F,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, &ConnInfo);
+
+    /// Works like handle_incoming_with_info(), but also fingerprints
+    /// each connection's TLS ClientHello, waiting up to peek_timeout for
+    /// it to arrive, and records the result in
+    /// [ConnInfo::fingerprint](struct.ConnInfo.html#structfield.fingerprint).
+    /// Nothing is read from the stream. The wait happens on the accept
+    /// loop, so keep peek_timeout short.
+    fn handle_incoming_with_fingerprint<F>(
+        &self,
+        handler: F,
+        timeout: Duration,
+        peek_timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, &ConnInfo);
+
+    /// Works like handle_incoming_with_info(), but handler is given a
+    /// [Connection](connection/struct.Connection.html), which carries
+    /// the stream together with its [ConnInfo](struct.ConnInfo.html).
+    fn handle_connections<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(Connection);
+
+    /// Accept connections for at most slice and then return, so that
+    /// the accept loop can share a thread with other work, e.g. a game
+    /// or GUI loop. Returns early, without sleeping, as soon as no
+    /// connection is waiting. The slice is checked between connections,
+    /// so a slow handler will overrun it. state carries counters from
+    /// one slice to the next. Unlike handle_incoming(), it doesn't
+    /// assign connection ids, and returns any accept error other than a
+    /// close.
+    fn handle_incoming_for<H>(
+        &self,
+        handler: H,
+        slice: Duration,
+        state: &mut SliceState,
+    ) -> Result<SliceStatus, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// Works like handle_incoming(), but instead of sleeping when no
+    /// connection is waiting, blocks until one arrives or close() is
+    /// called, so connections are accepted without delay. timeout
+    /// bounds each wait, in case a close can't be signalled.
+    fn handle_incoming_poll<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream);
+
+    /// An iterator over incoming connections, like
+    /// TcpListener::incoming(), which sleeps for timeout while no
+    /// connection is waiting and ends when the listener is closed.
+    fn stoppable_incoming(&self, timeout: Duration) -> StoppableIncoming<'_>;
+
+    /// Bind a listener, as bind(), which is stopped through the
+    /// returned [ListenerHandle](stop/struct.ListenerHandle.html)
+    /// rather than by closing it.
+    fn with_shutdown<A: ToSocketAddrs>(
+        addr: A,
+    ) -> Result<(StoppableListener, ListenerHandle), io::Error>
+    where
+        Self: std::marker::Sized;
+}
+
+/// Information passed to the on_idle callback of
+/// [handle_incoming_with_idle()](trait.Listener.html#tymethod.handle_incoming_with_idle).
+#[derive(Clone, Debug)]
+pub struct IdleInfo {
+    /// Time since the last connection was accepted, or since the loop
+    /// started if no connection has been accepted yet.
+    pub idle_for: Duration,
+    /// Number of consecutive sleeps since the last accepted connection.
+    pub idle_sleeps: u64,
+    /// Total number of connections accepted by this loop.
+    pub accepted: u64,
+}
+
+/// Information passed to the on_tick callback of
+/// [handle_incoming_with_tick()](trait.Listener.html#tymethod.handle_incoming_with_tick).
+#[derive(Clone, Debug)]
+pub struct TickInfo {
+    /// Number of ticks so far, starting at 1.
+    pub ticks: u64,
+    /// Time since the loop started.
+    pub elapsed: Duration,
+    /// Total number of connections accepted by this loop.
+    pub accepted: u64,
+}
+
+/// Information about an accepted connection, passed to the handler of
+/// [handle_incoming_with_info()](trait.Listener.html#tymethod.handle_incoming_with_info)
+/// and carried by a [Connection](connection/struct.Connection.html).
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+    /// Identifies the connection, unique within the process. Ids start
+    /// at 1 and increase with each connection accepted by any loop.
+    pub id: u64,
+    /// Sequence number of this connection, starting at 1 and increasing
+    /// by one for each connection accepted by the loop.
+    pub seq: u64,
+    /// Identifies the accept loop, unique within the process.
+    pub listener_id: u64,
+    /// Address of the peer.
+    pub peer: SocketAddr,
+    /// Local address of the stream.
+    pub local: SocketAddr,
+    /// Wall clock time at which the connection was accepted.
+    pub accepted_at: SystemTime,
+    /// Monotonic time at which the connection was accepted, for
+    /// measuring inter-arrival times.
+    pub accepted_instant: Instant,
+    /// Labels of the loop which accepted it, see
+    /// [Labels::in_scope()](labels/struct.Labels.html#method.in_scope).
+    pub labels: Labels,
+    /// Fingerprint of the client's TLS ClientHello, if the loop was
+    /// [handle_incoming_with_fingerprint()](trait.Listener.html#tymethod.handle_incoming_with_fingerprint)
+    /// and the client spoke TLS.
+    pub fingerprint: Option<Fingerprint>,
+}
+
+/// Resumable state for
+/// [handle_incoming_for()](trait.Listener.html#tymethod.handle_incoming_for).
+#[derive(Clone, Debug, Default)]
+pub struct SliceState {
+    /// Number of slices run so far.
+    pub slices: u64,
+    /// Total number of connections accepted across all slices.
+    pub accepted: u64,
+    /// Set once the listener has been closed.
+    pub closed: bool,
+}
+
+/// Why a call to
+/// [handle_incoming_for()](trait.Listener.html#tymethod.handle_incoming_for)
+/// returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SliceStatus {
+    /// No connection was waiting.
+    Idle,
+    /// The slice ran out. More connections may be waiting.
+    Expired,
+    /// The listener was closed. Further calls return Closed at once.
+    Closed,
 }
 
 impl Listener for TcpListener {
-    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, io::Error> {
         let listener = TcpListener::bind(addr)?;
         listener.set_nonblocking(true)?;
 
         Ok(listener)
     }
 
+    fn bind_ephemeral() -> Result<(Self, SocketAddr), io::Error> {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        Ok((listener, addr))
+    }
+
+    fn bind_with_policy<A: ToSocketAddrs>(
+        addr: A,
+        policy: BindPolicy,
+    ) -> Result<ListenerSet, io::Error> {
+        ListenerSet::bind_with_policy(addr, policy)
+    }
+
+    fn bind_dual_stack(port: u16) -> Result<ListenerSet, io::Error> {
+        ListenerSet::bind_dual_stack(port)
+    }
+
+    fn from_std(listener: TcpListener) -> Result<Self, io::Error> {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    #[cfg(not(windows))]
+    unsafe fn from_raw_fd(fd: RawFd) -> Result<Self, io::Error> {
+        <TcpListener as Listener>::from_std(FromRawFd::from_raw_fd(fd))
+    }
+
+    #[cfg(all(unix, feature = "systemd"))]
+    fn from_systemd(name: &str) -> Result<Self, io::Error> {
+        let fd = systemd::listen_fd(name)?;
+        // listen_fd() checked that fd is a listening socket
+        unsafe { <TcpListener as Listener>::from_raw_fd(fd) }
+    }
+
+    #[cfg(not(windows))]
+    fn into_raw_parts(self) -> Result<RawFd, io::Error> {
+        restart::set_cloexec(self.as_raw_fd(), false)?;
+        Ok(self.into_raw_fd())
+    }
+
+    #[cfg(windows)]
+    unsafe fn from_raw_socket(socket: RawSocket) -> Result<Self, io::Error> {
+        <TcpListener as Listener>::from_std(FromRawSocket::from_raw_socket(socket))
+    }
+
     fn close(&self) {
-        unsafe {
-            #[cfg(windows)]
-            winsock2::closesocket(self.as_raw_socket() as usize);
-            #[cfg(not(windows))]
-            libc::close(self.as_raw_fd());
+        self.close_listener();
+    }
+
+    fn close_after_drain(&self, max: Duration) {
+        let raw = match self.raw_handle() {
+            Some(raw) => raw,
+            None => return self.close(),
+        };
+        DRAINING.lock().unwrap().push(raw);
+        let deadline = Instant::now() + max;
+        while !is_listener_closed(self) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        DRAINING.lock().unwrap().retain(|r| *r != raw);
+        if !is_listener_closed(self) {
+            self.close();
+        }
+    }
+
+    fn accept_timeout(&self, timeout: Duration) -> Result<(TcpStream, SocketAddr), Error> {
+        poll::accept_timeout(self, timeout)?.ok_or(Error::Timeout)
+    }
+
+    #[inline]
+    fn accept_nonblocking(&self) -> Result<Option<(TcpStream, SocketAddr)>, Error> {
+        match self.accept() {
+            Ok(accepted) => Ok(Some(accepted)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    fn handle_incoming<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        accept_loop(
+            self,
+            &mut |s, _, _| handler(s),
+            timeout,
+            Callbacks::default(),
+        )
+        .map_err(Error::from)
+    }
+
+    fn handle_incoming_catching<H, P>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        on_panic: P,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        P: FnMut(&str) -> bool,
+    {
+        let mut handler = handler;
+        let mut on_panic = on_panic;
+        let failed = Cell::new(false);
+        let mut catching = |stream, _, _| {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(stream))) {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+                    .unwrap_or("handler panicked");
+                if !on_panic(message) {
+                    failed.set(true);
+                }
+            }
+        };
+        let mut until = || failed.get();
+        let callbacks = Callbacks {
+            until: Some(&mut until),
+            ..Default::default()
+        };
+        let reason = accept_loop(self, &mut catching, timeout, callbacks)?;
+        if failed.get() {
+            return Err(Error::HandlerPanicked);
+        }
+        Ok(reason)
+    }
+
+    fn handle_incoming_fallible<H, E>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        policy: ErrorPolicy,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream) -> Result<(), E>,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut handler = handler;
+        let failure = RefCell::new(None);
+        let mut checked = |stream, _, _| {
+            if let Err(err) = handler(stream) {
+                let err = err.into();
+                match &policy {
+                    ErrorPolicy::Ignore => (),
+                    ErrorPolicy::LogAndContinue => eprintln!("handler failed: {}", err),
+                    ErrorPolicy::Report(report) => report(err),
+                    ErrorPolicy::StopLoop => *failure.borrow_mut() = Some(err),
+                }
+            }
+        };
+        let mut until = || failure.borrow().is_some();
+        let callbacks = Callbacks {
+            until: Some(&mut until),
+            ..Default::default()
+        };
+        let reason = accept_loop(self, &mut checked, timeout, callbacks)?;
+        match failure.into_inner() {
+            Some(err) => Err(Error::HandlerFailed(err)),
+            None => Ok(reason),
+        }
+    }
+
+    fn handle_incoming_spawn<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        max_threads: Option<usize>,
+    ) -> Result<StopReason, Error>
+    where
+        H: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        spawn_loop(self, handler, timeout, max_threads, None)
+    }
+
+    fn handle_incoming_spawn_with_watchdog<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        max_threads: Option<usize>,
+        watchdog: &Watchdog,
+    ) -> Result<StopReason, Error>
+    where
+        H: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        spawn_loop(self, handler, timeout, max_threads, Some(watchdog))
+    }
+
+    fn handle_incoming_with_idle<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        on_idle: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&IdleInfo),
+    {
+        let mut handler = handler;
+        let mut on_idle = on_idle;
+        let callbacks = Callbacks {
+            on_idle: Some(&mut on_idle),
+            ..Default::default()
+        };
+        accept_loop(self, &mut |s, _, _| handler(s), timeout, callbacks).map_err(Error::from)
+    }
+
+    fn handle_incoming_with_tick<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        interval: Duration,
+        on_tick: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&TickInfo),
+    {
+        let mut handler = handler;
+        let mut on_tick = on_tick;
+        let callbacks = Callbacks {
+            on_tick: Some((interval, &mut on_tick)),
+            ..Default::default()
+        };
+        accept_loop(self, &mut |s, _, _| handler(s), timeout, callbacks).map_err(Error::from)
+    }
+
+    fn handle_incoming_with_time<H, T>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        time: &T,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        T: Time,
+    {
+        let mut handler = handler;
+        let callbacks = Callbacks {
+            time: Some(time),
+            ..Default::default()
+        };
+        accept_loop(self, &mut |s, _, _| handler(s), timeout, callbacks).map_err(Error::from)
+    }
+
+    fn handle_incoming_with_sleeper<H, S>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        sleeper: &S,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        S: Sleeper,
+    {
+        self.handle_incoming_with_time(handler, timeout, &RealClockWith(sleeper))
+    }
+
+    fn handle_incoming_with_stats<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        stats: &ListenerStats,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream) -> Result<(), io::Error>,
+    {
+        let mut handler = handler;
+        let mut counted = |stream, _, _| {
+            let _running = stats.handler_started();
+            if handler(stream).is_err() {
+                stats.handler_failed();
+            }
+        };
+        let callbacks = Callbacks {
+            stats: Some(stats),
+            ..Default::default()
+        };
+        accept_loop(self, &mut counted, timeout, callbacks).map_err(Error::from)
+    }
+
+    fn handle_incoming_with_backoff<H>(
+        &self,
+        handler: H,
+        strategy: BackoffStrategy,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        let mut backoff = Backoff::new(strategy);
+        let callbacks = Callbacks {
+            backoff: Some(&mut backoff),
+            ..Default::default()
+        };
+        // The timeout is unused, each sleep comes from the backoff
+        accept_loop(
+            self,
+            &mut |s, _, _| handler(s),
+            Duration::from_secs(0),
+            callbacks,
+        )
+        .map_err(Error::from)
+    }
+
+    fn handle_incoming_until<H, U>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        until: U,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        U: StopCondition,
+    {
+        let mut handler = handler;
+        let mut until = until;
+        let callbacks = Callbacks {
+            until: Some(&mut until),
+            ..Default::default()
+        };
+        accept_loop(self, &mut |s, _, _| handler(s), timeout, callbacks).map_err(Error::from)
+    }
+
+    fn handle_incoming_with_idle_timeout<H>(
+        &self,
+        handler: H,
+        poll_timeout: Duration,
+        idle: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        let callbacks = Callbacks {
+            idle_timeout: Some(idle),
+            ..Default::default()
+        };
+        accept_loop(self, &mut |s, _, _| handler(s), poll_timeout, callbacks).map_err(Error::from)
+    }
+
+    fn handle_incoming_with_hooks<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        hooks: Hooks,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        let mut hooks = hooks;
+        if let Some(on_start) = hooks.on_start.as_mut() {
+            on_start(self.local_addr()?);
+        }
+        let stop = hooks::StopGuard::new(hooks.on_stop.take());
+        let callbacks = Callbacks {
+            on_error: hooks
+                .on_error
+                .as_mut()
+                .map(|f| f as &mut dyn FnMut(&io::Error)),
+            ..Default::default()
+        };
+        let result =
+            accept_loop(self, &mut |s, _, _| handler(s), timeout, callbacks).map_err(Error::from);
+        stop.stop(&result);
+        result
+    }
+
+    fn handle_incoming_traced<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        on_event: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&LoopEvent),
+    {
+        let mut handler = handler;
+        let labels = &Labels::current();
+        let on_event = RefCell::new(on_event);
+        let emit = |event: LoopEvent| (on_event.borrow_mut())(&event);
+        if let Ok(addr) = self.local_addr() {
+            emit(LoopEvent::Started { addr, labels });
         }
+        let mut accepted = 0;
+        let mut traced = |stream, peer, id| {
+            accepted += 1;
+            emit(LoopEvent::Accepted { id, peer, labels });
+            let started = Instant::now();
+            handler(stream);
+            emit(LoopEvent::HandlerFinished {
+                id,
+                peer,
+                elapsed: started.elapsed(),
+                labels,
+            });
+        };
+        let mut on_idle = |info: &IdleInfo| {
+            let sleeps = info.idle_sleeps + 1;
+            if trace::sample_idle(sleeps) {
+                emit(LoopEvent::Idle { sleeps, labels });
+            }
+        };
+        let callbacks = Callbacks {
+            on_idle: Some(&mut on_idle),
+            ..Default::default()
+        };
+        let result = accept_loop(self, &mut traced, timeout, callbacks);
+        match &result {
+            Ok(_) => emit(LoopEvent::Stopped { accepted, labels }),
+            Err(err) => emit(LoopEvent::Failed { err, labels }),
+        }
+        result.map_err(Error::from)
+    }
+
+    fn handle_incoming_with_deadline<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        max_lifetime: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        let watchdog = watchdog::lifetimes();
+        let mut guarded = |stream: TcpStream, _, _| {
+            // Without a guard there is no deadline, so drop the stream
+            if let Ok(_guard) = watchdog.watch_for(&stream, max_lifetime) {
+                handler(stream)
+            }
+        };
+        accept_loop(self, &mut guarded, timeout, Callbacks::default()).map_err(Error::from)
+    }
+
+    fn handle_incoming_configured<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        config: &StreamConfig,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        config.validate()?;
+        let mut handler = handler;
+        let mut configured = |stream: TcpStream, _, _| {
+            if config.apply(&stream).is_ok() {
+                handler(stream)
+            }
+        };
+        accept_loop(self, &mut configured, timeout, Callbacks::default()).map_err(Error::from)
+    }
+
+    fn handle_incoming_addr<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream, SocketAddr),
+    {
+        let mut handler = handler;
+        accept_loop(
+            self,
+            &mut |s, addr, _| handler(s, addr),
+            timeout,
+            Callbacks::default(),
+        )
+        .map_err(Error::from)
+    }
+
+    fn handle_incoming_filtered<H, F>(
+        &self,
+        handler: H,
+        timeout: Duration,
+        filter: F,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+        F: FnMut(&SocketAddr) -> bool,
+    {
+        let mut handler = handler;
+        let mut filter = filter;
+        let mut filtered = |stream, addr, _| {
+            if filter(&addr) {
+                handler(stream)
+            }
+        };
+        accept_loop(self, &mut filtered, timeout, Callbacks::default()).map_err(Error::from)
+    }
+
+    fn handle_incoming_with_info<F>(
+        &self,
+        handler: F,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, &ConnInfo),
+    {
+        let mut handler = handler;
+        info_loop(self, &mut handler, timeout, None)
+    }
+
+    fn handle_incoming_with_fingerprint<F>(
+        &self,
+        handler: F,
+        timeout: Duration,
+        peek_timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, &ConnInfo),
+    {
+        let mut handler = handler;
+        info_loop(self, &mut handler, timeout, Some(peek_timeout))
     }
 
-    fn handle_incoming(&self, handler: fn(TcpStream), timeout: Duration) -> Result<(), Error> {
-        for stream in self.incoming() {
-            match stream {
-                Ok(stream) => handler(stream),
+    fn handle_connections<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(Connection),
+    {
+        let mut handler = handler;
+        self.handle_incoming_with_info(
+            |stream, info| {
+                handler(Connection {
+                    stream,
+                    info: info.clone(),
+                })
+            },
+            timeout,
+        )
+    }
+
+    fn handle_incoming_for<H>(
+        &self,
+        handler: H,
+        slice: Duration,
+        state: &mut SliceState,
+    ) -> Result<SliceStatus, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        if state.closed {
+            return Ok(SliceStatus::Closed);
+        }
+        state.slices += 1;
+        let deadline = Instant::now() + slice;
+        loop {
+            match self.accept() {
+                Ok((stream, _)) => {
+                    state.accepted += 1;
+                    handler(stream);
+                }
                 Err(err) => {
                     if err.kind() == ErrorKind::WouldBlock {
-                        thread::sleep(timeout);
-                    } else {
-                        if let Some(val) = err.raw_os_error() {
-                            if val == plat_specifics::EBADF {
-                                return Ok(());
-                            }
+                        if !drained(self) {
+                            return Ok(SliceStatus::Idle);
                         }
-                        return Err(err);
+                        state.closed = true;
+                        return Ok(SliceStatus::Closed);
+                    } else if is_closed(&err) {
+                        state.closed = true;
+                        return Ok(SliceStatus::Closed);
                     }
+                    return Err(Error::from(err));
+                }
+            }
+            if Instant::now() >= deadline {
+                return Ok(SliceStatus::Expired);
+            }
+        }
+    }
+
+    fn handle_incoming_poll<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        let callbacks = Callbacks {
+            poll: true,
+            ..Callbacks::default()
+        };
+        accept_loop(self, &mut |s, _, _| handler(s), timeout, callbacks).map_err(Error::from)
+    }
+
+    fn stoppable_incoming(&self, timeout: Duration) -> StoppableIncoming<'_> {
+        StoppableIncoming::new(self, timeout)
+    }
+
+    fn with_shutdown<A: ToSocketAddrs>(
+        addr: A,
+    ) -> Result<(StoppableListener, ListenerHandle), io::Error> {
+        let listener: TcpListener = Listener::bind(addr)?;
+        StoppableListener::new(listener)
+    }
+}
+
+// Optional callbacks invoked by accept_loop(), and the time source it
+// uses, which defaults to the real clock, where it records stats, and
+// the backoff which replaces the fixed timeout. until ends the loop
+// early, as does going idle_timeout without a connection. Recoverable
+// accept errors don't end the loop; they are reported to on_error if
+// given. With poll, the loop waits for readiness rather than sleeping.
+#[derive(Default)]
+pub(crate) struct Callbacks<'a> {
+    pub(crate) on_idle: Option<&'a mut dyn FnMut(&IdleInfo)>,
+    pub(crate) on_tick: Option<(Duration, TickCallback<'a>)>,
+    pub(crate) time: Option<&'a dyn Time>,
+    pub(crate) stats: Option<&'a ListenerStats>,
+    pub(crate) backoff: Option<&'a mut Backoff>,
+    pub(crate) until: Option<&'a mut dyn StopCondition>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) on_error: Option<&'a mut dyn FnMut(&io::Error)>,
+    pub(crate) poll: bool,
+}
+
+type TickCallback<'a> = &'a mut dyn FnMut(&TickInfo);
+
+pub(crate) fn accept_loop<A: Acceptor>(
+    listener: &A,
+    handler: &mut dyn FnMut(A::Conn, A::Addr, u64),
+    timeout: Duration,
+    mut callbacks: Callbacks,
+) -> Result<StopReason, io::Error> {
+    // On Windows, or if asked to poll, wait for a connection or close()
+    // rather than sleep, unless a simulated clock is in charge of time
+    let poll = cfg!(windows) || callbacks.poll;
+    let waker = match (poll, callbacks.time, listener.raw_handle()) {
+        (true, None, Some(raw)) => Some((raw, poll::wake::Waker::register_raw(raw)?)),
+        _ => None,
+    };
+    if let Some(stats) = callbacks.stats {
+        let labels = Labels::current();
+        if !labels.is_empty() {
+            stats.labeled(labels);
+        }
+    }
+    let time = callbacks.time.unwrap_or(&RealTime);
+    let started = time.now();
+    let mut last_accept = started;
+    let mut next_tick = callbacks
+        .on_tick
+        .as_ref()
+        .map(|(every, _)| started + *every);
+    let mut ticks = 0;
+    let mut idle_sleeps = 0;
+    let mut accepted = 0;
+    loop {
+        if let Some(until) = callbacks.until.as_mut() {
+            if until.should_stop() {
+                return Ok(until.reason());
+            }
+        }
+        match listener.accept_nb() {
+            Ok(Some((stream, addr))) => {
+                last_accept = time.now();
+                if let Some(backoff) = callbacks.backoff.as_mut() {
+                    backoff.on_accept(last_accept);
+                }
+                idle_sleeps = 0;
+                accepted += 1;
+                let id = connection::next_connection_id();
+                if let Some(stats) = callbacks.stats {
+                    stats.assigned(id);
+                }
+                handler(stream, addr, id)
+            }
+            Ok(None) => {
+                if drained(listener) {
+                    return Ok(StopReason::Drained);
+                }
+                let idle_left = callbacks
+                    .idle_timeout
+                    .map(|idle| idle.saturating_sub(time.now().duration_since(last_accept)));
+                if idle_left == Some(Duration::from_secs(0)) {
+                    return Ok(StopReason::Idle);
                 }
+                if let Some(on_idle) = callbacks.on_idle.as_mut() {
+                    on_idle(&IdleInfo {
+                        idle_for: time.now().duration_since(last_accept),
+                        idle_sleeps,
+                        accepted,
+                    });
+                }
+                idle_sleeps += 1;
+                let timeout = match callbacks.backoff.as_mut() {
+                    Some(backoff) => backoff.next_sleep(),
+                    None => timeout,
+                };
+                // Don't oversleep a pending tick
+                let sleep = match next_tick {
+                    Some(due) => std::cmp::min(timeout, due.saturating_duration_since(time.now())),
+                    None => timeout,
+                };
+                let sleep = std::cmp::min(sleep, idle_left.unwrap_or(sleep));
+                let before = time.now();
+                match waker.as_ref() {
+                    Some((raw, waker)) => waker.wait_raw(*raw, sleep)?,
+                    None => time.sleep(sleep),
+                }
+                if let Some(stats) = callbacks.stats {
+                    stats.slept(time.now().duration_since(before));
+                }
+            }
+            Err(err) => {
+                if listener.is_closed_error(&err) {
+                    return Ok(StopReason::Closed);
+                }
+                let sleep = match hooks::recoverable(&err) {
+                    Some(sleep) => sleep,
+                    None => return Err(err),
+                };
+                if let Some(on_error) = callbacks.on_error.as_mut() {
+                    on_error(&err);
+                }
+                if sleep {
+                    time.sleep(timeout);
+                }
+            }
+        }
+        if let (Some(due), Some((every, on_tick))) = (next_tick, callbacks.on_tick.as_mut()) {
+            let now = time.now();
+            if now >= due {
+                ticks += 1;
+                on_tick(&TickInfo {
+                    ticks,
+                    elapsed: now.duration_since(started),
+                    accepted,
+                });
+                next_tick = Some(std::cmp::max(due + *every, now));
+            }
+        }
+    }
+}
+
+// Listeners whose loops should close them once nothing is waiting, by
+// raw descriptor or socket.
+static DRAINING: std::sync::Mutex<Vec<usize>> = std::sync::Mutex::new(Vec::new());
+
+// Called by an accept loop once nothing is waiting. If
+// close_after_drain() is waiting on listener, its queue is now empty,
+// so close it and return true.
+pub(crate) fn drained<A: Acceptor>(listener: &A) -> bool {
+    let draining = match listener.raw_handle() {
+        Some(raw) => DRAINING.lock().unwrap().contains(&raw),
+        None => false,
+    };
+    if draining {
+        listener.close_listener();
+    }
+    draining
+}
+
+// Accept loop which gives handler each connection's ConnInfo,
+// fingerprinting it if fingerprint holds a peek timeout.
+fn info_loop(
+    listener: &TcpListener,
+    handler: &mut dyn FnMut(TcpStream, &ConnInfo),
+    timeout: Duration,
+    fingerprint: Option<Duration>,
+) -> Result<StopReason, Error> {
+    let listener_id = connection::next_listener_id();
+    let labels = Labels::current();
+    let mut seq = 0;
+    let mut with_info = |stream: TcpStream, peer, id| {
+        let local = match stream.local_addr() {
+            Ok(local) => local,
+            Err(_) => return,
+        };
+        seq += 1;
+        let accepted_at = SystemTime::now();
+        let accepted_instant = Instant::now();
+        let fingerprint = fingerprint
+            .and_then(|timeout| Fingerprint::peek(&stream, timeout).ok())
+            .flatten();
+        let info = ConnInfo {
+            id,
+            seq,
+            listener_id,
+            peer,
+            local,
+            accepted_at,
+            accepted_instant,
+            labels: labels.clone(),
+            fingerprint,
+        };
+        handler(stream, &info)
+    };
+    accept_loop(listener, &mut with_info, timeout, Callbacks::default()).map_err(Error::from)
+}
+
+// Accept loop which runs each handler on its own thread, watched by
+// watchdog if given.
+fn spawn_loop<H>(
+    listener: &TcpListener,
+    handler: H,
+    timeout: Duration,
+    max_threads: Option<usize>,
+    watchdog: Option<&Watchdog>,
+) -> Result<StopReason, Error>
+where
+    H: Fn(TcpStream) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let limit = ConnectionLimit::new(max_threads.unwrap_or(usize::MAX));
+    limit.handle_incoming(
+        listener,
+        |stream, permit| {
+            let guard = match watchdog.map(|w| w.watch(&stream)) {
+                Some(Ok(guard)) => Some(guard),
+                Some(Err(_)) => return,
+                None => None,
+            };
+            let handler = handler.clone();
+            thread::spawn(move || {
+                // Hold the slot and the guard until the handler is done
+                let _permit = permit;
+                let _guard = guard;
+                handler(stream)
+            });
+        },
+        timeout,
+    )
+}
+
+/// Close a raw listening socket. On Unix the descriptor is replaced
+/// with an unbound socket rather than closed, so that it stays valid
+/// for its owner and can't be reused by an unrelated open(). This only
+/// uses async-signal-safe calls.
+pub(crate) fn close_raw(raw: usize) {
+    unsafe {
+        #[cfg(windows)]
+        {
+            winsock2::closesocket(raw);
+            poll::wake::wake(raw as RawSocket);
+        }
+        #[cfg(not(windows))]
+        {
+            let fd = raw as libc::c_int;
+            let dummy = cloexec_socket();
+            if dummy < 0 {
+                libc::close(fd);
+            } else {
+                replace_fd(dummy, fd);
+                libc::close(dummy);
             }
+            poll::wake::wake(fd);
         }
-        unreachable!()
+    }
+}
+
+// An unbound socket to replace a closed listener with, created
+// close-on-exec so that a concurrent fork and exec can't inherit it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn cloexec_socket() -> libc::c_int {
+    libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0)
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+unsafe fn cloexec_socket() -> libc::c_int {
+    let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+    if fd >= 0 {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    fd
+}
+
+// Make fd refer to dummy's socket, keeping fd close-on-exec.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn replace_fd(dummy: libc::c_int, fd: libc::c_int) {
+    libc::dup3(dummy, fd, libc::O_CLOEXEC);
+}
+
+// dup2() clears close-on-exec on fd, so set it again.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+unsafe fn replace_fd(dummy: libc::c_int, fd: libc::c_int) {
+    libc::dup2(dummy, fd);
+    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+}
+
+/// Is this the error returned by accept() on a closed listener?
+pub(crate) fn is_closed(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(val) => val == plat_specifics::EBADF || val == plat_specifics::EINVAL,
+        None => false,
+    }
+}
+
+/// Has this listener been closed by close_raw()? A bound listener never
+/// has port zero, but the unbound socket which replaces it does.
+pub(crate) fn is_listener_closed(listener: &TcpListener) -> bool {
+    match listener.local_addr() {
+        Ok(addr) => addr.port() == 0,
+        Err(_) => true,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
+    use std::thread;
 
     // Handle our client request
     fn handle_client(_stream: TcpStream) {
@@ -179,6 +1643,675 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_closure() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        let mut handled = 0;
+        listener
+            .handle_incoming(
+                |_| {
+                    handled += 1;
+                    if handled == 2 {
+                        listener.close();
+                    }
+                },
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        assert_eq!(handled, 2);
+    }
+
+    #[test]
+    fn test_accept_timeout() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        assert!(matches!(
+            listener.accept_timeout(Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+        let client = TcpStream::connect(addr).unwrap();
+        let (_, peer) = listener.accept_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        // A close wakes the wait
+        let l_clone = listener.clone();
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            l_clone.close();
+        });
+        let result = listener.accept_timeout(Duration::from_secs(60));
+        assert!(result.unwrap_err().is_closed());
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn test_accept_nonblocking() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        assert!(listener.accept_nonblocking().unwrap().is_none());
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let accepted = loop {
+            if let Some((_, peer)) = listener.accept_nonblocking().unwrap() {
+                break peer;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(accepted, client.local_addr().unwrap());
+        listener.close();
+        assert!(listener.accept_nonblocking().unwrap_err().is_closed());
+    }
+
+    #[test]
+    fn test_catching() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        let _c = TcpStream::connect(addr).unwrap();
+        let mut handled = 0;
+        let mut panics = vec![];
+        let result = listener.handle_incoming_catching(
+            |_| {
+                handled += 1;
+                panic!("bad request {}", handled);
+            },
+            Duration::from_millis(1),
+            |message| {
+                panics.push(message.to_string());
+                // Recover once, then fail fast
+                panics.len() < 2
+            },
+        );
+        assert!(matches!(result, Err(Error::HandlerPanicked)));
+        assert_eq!(handled, 2);
+        assert_eq!(panics, vec!["bad request 1", "bad request 2"]);
+    }
+
+    #[test]
+    fn test_fallible() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut handled = 0;
+        let result = listener.handle_incoming_fallible(
+            |_| {
+                handled += 1;
+                if handled == 2 {
+                    return Err(io::Error::new(ErrorKind::Other, "bad request"));
+                }
+                Ok(())
+            },
+            Duration::from_millis(1),
+            ErrorPolicy::StopLoop,
+        );
+        assert_eq!(handled, 2);
+        match result {
+            Err(Error::HandlerFailed(err)) => assert_eq!(err.to_string(), "bad request"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    #[test]
+    fn test_fallible_log() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _clients: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut handled = 0;
+        let result = listener.handle_incoming_fallible(
+            |_| {
+                handled += 1;
+                if handled == 2 {
+                    listener.close();
+                }
+                Err(io::Error::new(ErrorKind::Other, "bad request"))
+            },
+            Duration::from_millis(1),
+            ErrorPolicy::LogAndContinue,
+        );
+        assert_eq!(result.unwrap(), StopReason::Closed);
+        assert_eq!(handled, 2);
+    }
+
+    #[test]
+    fn test_fallible_report() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let reported = Arc::new(AtomicUsize::new(0));
+        let counter = reported.clone();
+        let report = move |err: Box<dyn std::error::Error + Send + Sync>| {
+            assert_eq!(err.to_string(), "bad request");
+            counter.fetch_add(1, Ordering::SeqCst);
+        };
+        let mut handled = 0;
+        let result = listener.handle_incoming_fallible(
+            |_| {
+                handled += 1;
+                if handled == 3 {
+                    listener.close();
+                }
+                Err(io::Error::new(ErrorKind::Other, "bad request"))
+            },
+            Duration::from_millis(1),
+            ErrorPolicy::Report(Arc::new(report)),
+        );
+        assert_eq!(result.unwrap(), StopReason::Closed);
+        assert_eq!(handled, 3);
+        assert_eq!(reported.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_spawn() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        // Both handlers must run at once to get past the barrier
+        let barrier = Arc::new(std::sync::Barrier::new(3));
+        let b_clone = barrier.clone();
+        let l_clone = listener.clone();
+        let server = thread::spawn(move || {
+            l_clone
+                .handle_incoming_spawn(
+                    move |_| {
+                        b_clone.wait();
+                    },
+                    Duration::from_millis(1),
+                    Some(2),
+                )
+                .unwrap()
+        });
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        barrier.wait();
+        listener.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_spawn_with_watchdog() {
+        use std::io::Read;
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let watchdog = Arc::new(Watchdog::new(Duration::from_millis(20), true, |_| ()));
+        let (l_clone, w_clone) = (listener.clone(), watchdog.clone());
+        let server = thread::spawn(move || {
+            l_clone
+                .handle_incoming_spawn_with_watchdog(
+                    |mut stream| {
+                        // Blocks until the watchdog shuts the stream down
+                        stream.set_nonblocking(false).unwrap();
+                        let _ = stream.read(&mut [0; 1]);
+                    },
+                    Duration::from_millis(1),
+                    None,
+                    &w_clone,
+                )
+                .unwrap()
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        // The watchdog shuts the stream down, so the client sees EOF
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        listener.close();
+        server.join().unwrap();
+        while watchdog.active() != 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_idle() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let mut calls = 0;
+        listener
+            .handle_incoming_with_idle(handle_client, Duration::from_millis(1), |info| {
+                assert_eq!(info.idle_sleeps, calls);
+                calls += 1;
+                if calls == 3 {
+                    listener.close();
+                }
+            })
+            .unwrap();
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_close_after_drain() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let _clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let l_clone = listener.clone();
+        // The loop sleeps a long time before its first accept, so the
+        // connections are still queued when the drain starts
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut handled = 0;
+            let reason = l_clone
+                .handle_incoming(|_| handled += 1, Duration::from_secs(60))
+                .unwrap();
+            (handled, reason)
+        });
+        listener.close_after_drain(Duration::from_secs(30));
+        assert_eq!(server.join().unwrap(), (3, StopReason::Drained));
+    }
+
+    #[test]
+    fn test_close_after_drain_poll() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let _clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let l_clone = listener.clone();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut handled = 0;
+            let reason = l_clone
+                .handle_incoming_poll(|_| handled += 1, Duration::from_secs(60))
+                .unwrap();
+            (handled, reason)
+        });
+        let started = Instant::now();
+        listener.close_after_drain(Duration::from_secs(30));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(server.join().unwrap(), (3, StopReason::Drained));
+    }
+
+    #[test]
+    fn test_ephemeral() {
+        let (listener, addr): (TcpListener, _) = Listener::bind_ephemeral().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert!(addr.ip().is_loopback());
+        let client = TcpStream::connect(addr).unwrap();
+        let (_, peer) = listener.accept_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_from_std() {
+        let std_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = std_listener.local_addr().unwrap();
+        #[cfg(not(windows))]
+        let listener: TcpListener =
+            unsafe { Listener::from_raw_fd(std_listener.into_raw_fd()) }.unwrap();
+        #[cfg(windows)]
+        let listener: TcpListener = Listener::from_std(std_listener).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        // Non-blocking, so nothing waiting doesn't block
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut handled = 0;
+        let started = std::time::Instant::now();
+        let reason = listener
+            .handle_incoming_with_idle_timeout(
+                |_| handled += 1,
+                Duration::from_secs(60),
+                Duration::from_millis(50),
+            )
+            .unwrap();
+        assert_eq!(reason, StopReason::Idle);
+        assert_eq!(handled, 1);
+        // The idle timeout cuts the poll timeout short
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_tick() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let mut ticks = 0;
+        listener
+            .handle_incoming_with_tick(
+                handle_client,
+                Duration::from_millis(50),
+                Duration::from_millis(5),
+                |info| {
+                    ticks = info.ticks;
+                    if info.ticks == 3 {
+                        listener.close();
+                    }
+                },
+            )
+            .unwrap();
+        assert_eq!(ticks, 3);
+    }
+
+    #[test]
+    fn test_time() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let clock = clock::SimClock::new();
+        let l_clone = listener.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            l_clone.close();
+        });
+        listener
+            .handle_incoming_with_time(handle_client, Duration::from_secs(3600), &clock)
+            .unwrap();
+        // Hour long sleeps passed instantly
+        assert!(clock.elapsed() >= Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_sleeper() {
+        // Counts the sleeps, closing the listener on the third
+        struct Closer(Arc<TcpListener>, std::sync::atomic::AtomicUsize);
+
+        impl Sleeper for Closer {
+            fn sleep(&self, _duration: Duration) {
+                if self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 2 {
+                    self.0.close();
+                }
+            }
+        }
+
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let sleeper = Closer(listener.clone(), Default::default());
+        let started = Instant::now();
+        listener
+            .handle_incoming_with_sleeper(handle_client, Duration::from_secs(3600), &sleeper)
+            .unwrap();
+        assert_eq!(sleeper.1.into_inner(), 3);
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_hooks() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+        let started = Cell::new(None);
+        let stopped = Cell::new(false);
+        let hooks = Hooks::new()
+            .on_start(|addr| started.set(Some(addr)))
+            .on_stop(|result| stopped.set(result.is_ok()))
+            .on_error(|err| panic!("unexpected {}", err));
+        listener
+            .handle_incoming_with_hooks(
+                |_| {
+                    // The loop has started, but not stopped
+                    assert_eq!(started.get(), Some(addr));
+                    assert!(!stopped.get());
+                    listener.close();
+                },
+                Duration::from_millis(1),
+                hooks,
+            )
+            .unwrap();
+        assert!(stopped.get());
+
+        // A panicking handler still stops the loop
+        let panicked = Cell::new(false);
+        let hooks = Hooks::new()
+            .on_stop(|result| panicked.set(matches!(result, Err(Error::HandlerPanicked))));
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            listener.handle_incoming_with_hooks(
+                |_| panic!("handler failed"),
+                Duration::from_millis(1),
+                hooks,
+            )
+        }));
+        assert!(result.is_err());
+        assert!(panicked.get());
+    }
+
+    #[test]
+    fn test_labels() {
+        let labels = Labels::new().with("service", "api");
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = ListenerStats::new();
+        let mut seen = vec![];
+        labels.in_scope(|| {
+            let _client = TcpStream::connect(addr).unwrap();
+            listener
+                .handle_incoming_with_info(
+                    |_, info| {
+                        seen.push(info.labels.clone());
+                        listener.close();
+                    },
+                    Duration::from_millis(1),
+                )
+                .unwrap();
+
+            let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+            let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let mut lines = vec![];
+            listener
+                .handle_incoming_traced(
+                    |_| listener.close(),
+                    Duration::from_millis(1),
+                    |event| lines.push(event.to_string()),
+                )
+                .unwrap();
+            assert!(lines.iter().all(|line| line.starts_with("service=api ")));
+
+            let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+            let hooks = Hooks::new().on_start(|_| seen.push(Labels::current()));
+            listener.close();
+            listener
+                .handle_incoming_with_hooks(|_| (), Duration::from_millis(1), hooks)
+                .unwrap();
+
+            let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+            listener.close();
+            listener
+                .handle_incoming_with_stats(|_| Ok(()), Duration::from_millis(1), &stats)
+                .unwrap();
+        });
+        assert_eq!(seen, vec![labels.clone(), labels.clone()]);
+        let metrics = stats.snapshot().metrics();
+        assert_eq!(metrics[0], ("accepted{service=\"api\"}".to_string(), 0));
+    }
+
+    #[test]
+    fn test_traced() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut events = vec![];
+        let mut ids = vec![];
+        listener
+            .handle_incoming_traced(
+                |_| listener.close(),
+                Duration::from_millis(1),
+                |event| {
+                    let line = event.to_string();
+                    events.push(line.split(' ').next().unwrap().to_string());
+                    match event {
+                        LoopEvent::Accepted { id, .. } | LoopEvent::HandlerFinished { id, .. } => {
+                            ids.push(*id)
+                        }
+                        _ => (),
+                    }
+                },
+            )
+            .unwrap();
+        // Ids are the process wide connection ids
+        let conn = format!("conn={}", ids[0]);
+        assert_eq!(events, vec!["listening", &conn, &conn, "stopped"]);
+        assert!(ids[0] > 0 && ids[0] <= connection::last_connection_id());
+    }
+
+    #[test]
+    fn test_deadline() {
+        use std::io::Read;
+
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut reads = vec![];
+        listener
+            .handle_incoming_with_deadline(
+                |mut stream| {
+                    // The client never writes, so this blocks until the
+                    // deadline shuts the stream down
+                    let _ = stream.set_nonblocking(false);
+                    let mut buf = [0; 1];
+                    reads.push(stream.read(&mut buf).map_err(|e| e.kind()));
+                    listener.close();
+                },
+                Duration::from_millis(1),
+                Duration::from_millis(20),
+            )
+            .unwrap();
+        assert_eq!(reads, vec![Ok(0)]);
+        // Every loop uses the same watchdog thread
+        assert!(Arc::ptr_eq(&watchdog::lifetimes(), &watchdog::lifetimes()));
+    }
+
+    #[test]
+    fn test_configured() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let config = streamopt::StreamConfig::new()
+            .nodelay(true)
+            .read_timeout(Duration::from_secs(7))
+            .keepalive(Duration::from_secs(30))
+            .linger(Duration::from_secs(1))
+            .send_buffer(64 * 1024);
+        let mut handled = 0;
+        listener
+            .handle_incoming_configured(
+                |stream| {
+                    assert!(stream.nodelay().unwrap());
+                    assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_secs(7)));
+                    handled += 1;
+                    listener.close();
+                },
+                Duration::from_millis(1),
+                &config,
+            )
+            .unwrap();
+        assert_eq!(handled, 1);
+    }
+
+    #[test]
+    fn test_addr() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut peers = vec![];
+        listener
+            .handle_incoming_addr(
+                |_, peer| {
+                    peers.push(peer);
+                    listener.close();
+                },
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        assert_eq!(peers, vec![client.local_addr().unwrap()]);
+    }
+
+    #[test]
+    fn test_filtered() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        let mut seen = 0;
+        let mut handled = 0;
+        listener
+            .handle_incoming_filtered(
+                |_| handled += 1,
+                Duration::from_millis(1),
+                |_| {
+                    seen += 1;
+                    if seen == 2 {
+                        listener.close();
+                    }
+                    // Deny the first connection
+                    seen > 1
+                },
+            )
+            .unwrap();
+        assert_eq!(handled, 1);
+    }
+
+    #[test]
+    fn test_info() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let a = TcpStream::connect(addr).unwrap();
+        let b = TcpStream::connect(addr).unwrap();
+        let mut infos = vec![];
+        listener
+            .handle_incoming_with_info(
+                |_, info| {
+                    infos.push(info.clone());
+                    if infos.len() == 2 {
+                        listener.close();
+                    }
+                },
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        assert_eq!(infos[0].seq, 1);
+        assert_eq!(infos[1].seq, 2);
+        assert_eq!(infos[0].peer, a.local_addr().unwrap());
+        assert_eq!(infos[1].peer, b.local_addr().unwrap());
+        assert!(infos[0].accepted_instant <= infos[1].accepted_instant);
+    }
+
+    #[test]
+    fn test_connections() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clients: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut seen = vec![];
+        listener
+            .handle_connections(
+                |conn| {
+                    assert_eq!(conn.info.local, addr);
+                    seen.push((
+                        conn.info.seq,
+                        conn.info.peer,
+                        conn.info.listener_id,
+                        conn.info.id,
+                    ));
+                    if seen.len() == 2 {
+                        listener.close();
+                    }
+                },
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        assert_eq!(seen[0].0, 1);
+        assert_eq!(seen[1].0, 2);
+        assert_eq!(seen[0].1, clients[0].local_addr().unwrap());
+        assert_eq!(seen[0].2, seen[1].2);
+        // Other tests may take ids in between
+        assert!(seen[1].3 > seen[0].3);
+    }
+
+    #[test]
+    fn test_slice() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let mut state = SliceState::default();
+        // A zero slice expires after the first connection
+        let status = listener
+            .handle_incoming_for(handle_client, Duration::from_secs(0), &mut state)
+            .unwrap();
+        assert_eq!(status, SliceStatus::Expired);
+        assert_eq!(state.accepted, 1);
+        let status = listener
+            .handle_incoming_for(handle_client, Duration::from_secs(1), &mut state)
+            .unwrap();
+        assert_eq!(status, SliceStatus::Idle);
+        assert_eq!(state.accepted, 2);
+        listener.close();
+        let status = listener
+            .handle_incoming_for(handle_client, Duration::from_secs(1), &mut state)
+            .unwrap();
+        assert_eq!(status, SliceStatus::Closed);
+        assert_eq!(state.slices, 3);
+    }
+
     #[test]
     fn test_pre_close() {
         let listener: Arc<TcpListener> = match Listener::bind("127.0.0.1:0") {
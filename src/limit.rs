@@ -0,0 +1,392 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A cap on simultaneous connections.
+//!
+//! A [ConnectionLimit](struct.ConnectionLimit.html) hands each accepted
+//! connection to the handler together with a [Permit](struct.Permit.html),
+//! which the handler keeps until it is finished with the connection,
+//! possibly on another thread. While every permit is in use, a
+//! [LimitPolicy](enum.LimitPolicy.html) decides whether the accept loop
+//! stops accepting, leaving new connections in the kernel's accept
+//! queue, or accepts them and answers them with a
+//! [RejectAction](../reject/enum.RejectAction.html). On Unix,
+//! [ListenerBuilder::max_connections()](../builder/struct.ListenerBuilder.html#method.max_connections)
+//! gives a listener its own limit.
+//!
+//! A [PerIpLimit](struct.PerIpLimit.html) caps connections from each
+//! peer address instead, so that one client can't take every slot, and
+//! can also apply a per address rate. Connections over either limit are
+//! accepted and closed straight away.
+
+use crate::ratelimit::KeyedRateLimiter;
+use crate::reject::RejectAction;
+use crate::stats::ListenerStats;
+use crate::until::StopCondition;
+use crate::{accept_loop, is_listener_closed, Callbacks, Error, StopReason};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// What to do with new connections while at the limit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Stop accepting until a permit is released.
+    Wait,
+    /// Accept and answer new connections with this action.
+    Reject(RejectAction),
+}
+
+#[derive(Debug, Default)]
+struct Slots {
+    active: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Limits the number of connections being handled at once.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    policy: LimitPolicy,
+    slots: Arc<Slots>,
+    rejected: AtomicU64,
+}
+
+/// A slot held by a connection. Dropping it frees the slot.
+#[derive(Debug)]
+pub struct Permit {
+    slots: Arc<Slots>,
+}
+
+impl ConnectionLimit {
+    /// Allow at most max connections at once, waiting for a free slot
+    /// when at the limit.
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            max: std::cmp::max(max, 1),
+            policy: LimitPolicy::Wait,
+            slots: Arc::default(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Deal with connections at the limit according to policy.
+    pub fn policy(mut self, policy: LimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Number of permits in use.
+    pub fn active(&self) -> usize {
+        *self.slots.active.lock().unwrap()
+    }
+
+    /// Number of connections rejected at the limit.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Take a permit, if one is free.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut active = self.slots.active.lock().unwrap();
+        if *active >= self.max {
+            return None;
+        }
+        *active += 1;
+        Some(Permit {
+            slots: self.slots.clone(),
+        })
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// passing each one to handler with its permit.
+    pub fn handle_incoming<F>(
+        &self,
+        listener: &TcpListener,
+        handler: F,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, Permit),
+    {
+        self.serve(listener, handler, timeout, None)
+    }
+
+    // handle_incoming(), recording the loop in stats.
+    pub(crate) fn serve<F>(
+        &self,
+        listener: &TcpListener,
+        mut handler: F,
+        timeout: Duration,
+        stats: Option<&ListenerStats>,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, Permit),
+    {
+        let mut handle = |stream, _, _| match (self.try_acquire(), &self.policy) {
+            (Some(permit), _) => handler(stream, permit),
+            (None, LimitPolicy::Reject(action)) => {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
+                action.apply(stream);
+            }
+            // Only reached if a permit was taken outside the loop
+            (None, LimitPolicy::Wait) => handler(stream, self.acquire(timeout)),
+        };
+        let mut at_limit = AtLimit {
+            limit: self,
+            listener,
+            timeout,
+        };
+        let callbacks = Callbacks {
+            until: match self.policy {
+                LimitPolicy::Wait => Some(&mut at_limit),
+                LimitPolicy::Reject(_) => None,
+            },
+            stats,
+            ..Callbacks::default()
+        };
+        accept_loop(listener, &mut handle, timeout, callbacks).map_err(Error::from)
+    }
+
+    // Wait as long as it takes for a permit.
+    fn acquire(&self, timeout: Duration) -> Permit {
+        loop {
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            self.wait_for_release(timeout);
+        }
+    }
+
+    fn wait_for_release(&self, timeout: Duration) {
+        let active = self.slots.active.lock().unwrap();
+        if *active >= self.max {
+            let _ = self.slots.released.wait_timeout(active, timeout).unwrap();
+        }
+    }
+}
+
+// Holds the accept loop while every permit is in use, so that new
+// connections wait in the kernel's accept queue. accept() isn't reached
+// meanwhile, so it checks for a close itself.
+struct AtLimit<'a> {
+    limit: &'a ConnectionLimit,
+    listener: &'a TcpListener,
+    timeout: Duration,
+}
+
+impl StopCondition for AtLimit<'_> {
+    fn should_stop(&mut self) -> bool {
+        while *self.limit.slots.active.lock().unwrap() >= self.limit.max {
+            if is_listener_closed(self.listener) {
+                return true;
+            }
+            self.limit.wait_for_release(self.timeout);
+        }
+        false
+    }
+
+    fn reason(&self) -> StopReason {
+        StopReason::Closed
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.slots.active.lock().unwrap() -= 1;
+        self.slots.released.notify_all();
+    }
+}
+
+/// Limits the number of connections from each peer address being
+/// handled at once, and optionally their rate.
+pub struct PerIpLimit {
+    max_per_ip: usize,
+    rate: Option<Arc<dyn KeyedRateLimiter>>,
+    reject: RejectAction,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    rejected: AtomicU64,
+}
+
+/// A slot held by a connection from an address. Dropping it frees the
+/// slot.
+pub struct IpPermit {
+    ip: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PerIpLimit {
+    /// Allow at most max_per_ip connections from each address at once.
+    /// Connections over the limit are closed gracefully.
+    pub fn new(max_per_ip: usize) -> PerIpLimit {
+        PerIpLimit {
+            max_per_ip: std::cmp::max(max_per_ip, 1),
+            rate: None,
+            reject: RejectAction::default(),
+            active: Arc::default(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Also limit the rate of connections from each address, e.g. with
+    /// a [PerIp](../ratelimit/struct.PerIp.html) token bucket.
+    pub fn rate(mut self, limiter: Arc<dyn KeyedRateLimiter>) -> Self {
+        self.rate = Some(limiter);
+        self
+    }
+
+    /// Answer connections over a limit with action.
+    pub fn reject_with(mut self, action: RejectAction) -> Self {
+        self.reject = action;
+        self
+    }
+
+    /// Number of permits in use for ip.
+    pub fn active(&self, ip: &IpAddr) -> usize {
+        self.active.lock().unwrap().get(ip).copied().unwrap_or(0)
+    }
+
+    /// Number of connections rejected.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Take a permit for ip, if it is within both limits.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        let mut active = self.active.lock().unwrap();
+        // Only add an entry once a permit is taken, so a rejected
+        // address doesn't leave one behind
+        if active.get(&ip).copied().unwrap_or(0) >= self.max_per_ip {
+            return None;
+        }
+        if let Some(rate) = &self.rate {
+            if rate.check_key(&ip).is_err() {
+                return None;
+            }
+        }
+        *active.entry(ip).or_insert(0) += 1;
+        Some(IpPermit {
+            ip,
+            active: self.active.clone(),
+        })
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// passing each one within the limits to handler with its permit.
+    pub fn handle_incoming<F>(
+        &self,
+        listener: &TcpListener,
+        mut handler: F,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, IpPermit),
+    {
+        let mut handle = |stream, addr: SocketAddr, _| match self.try_acquire(addr.ip()) {
+            Some(permit) => handler(stream, permit),
+            None => {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
+                self.reject.apply(stream);
+            }
+        };
+        accept_loop(listener, &mut handle, timeout, Callbacks::default()).map_err(Error::from)
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            // Forget idle addresses, to bound memory
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::Read;
+    use std::thread;
+
+    #[test]
+    fn test_limit() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let limit = Arc::new(
+            ConnectionLimit::new(1)
+                .policy(LimitPolicy::Reject(RejectAction::Respond(b"full".to_vec()))),
+        );
+        let (tx, rx) = std::sync::mpsc::channel();
+        let l_clone = listener.clone();
+        let m_clone = limit.clone();
+        let server = thread::spawn(move || {
+            m_clone
+                .handle_incoming(
+                    &l_clone,
+                    |stream, permit| tx.send((stream, permit)).unwrap(),
+                    Duration::from_millis(5),
+                )
+                .unwrap()
+        });
+
+        // The first connection holds the only permit
+        let _first = TcpStream::connect(addr).unwrap();
+        let held = rx.recv().unwrap();
+        assert_eq!(limit.active(), 1);
+        let mut reply = String::new();
+        TcpStream::connect(addr)
+            .unwrap()
+            .read_to_string(&mut reply)
+            .unwrap();
+        assert_eq!(reply, "full");
+        assert_eq!(limit.rejected(), 1);
+
+        drop(held);
+        assert_eq!(limit.active(), 0);
+        let _third = TcpStream::connect(addr).unwrap();
+        let _held = rx.recv().unwrap();
+        listener.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_per_ip() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let limit = PerIpLimit::new(2);
+        let first = limit.try_acquire(ip).unwrap();
+        let _second = limit.try_acquire(ip).unwrap();
+        assert!(limit.try_acquire(ip).is_none());
+        // Other addresses aren't affected
+        assert!(limit.try_acquire(other).is_some());
+        drop(first);
+        assert_eq!(limit.active(&ip), 1);
+        assert!(limit.try_acquire(ip).is_some());
+
+        let limit = PerIpLimit::new(10).rate(Arc::new(crate::ratelimit::PerIp::new(0, 1)));
+        let held = limit.try_acquire(ip).unwrap();
+        // Over the rate while the address holds a permit
+        assert!(limit.try_acquire(ip).is_none());
+        assert_eq!(limit.active(&ip), 1);
+        drop(held);
+        assert!(limit.active.lock().unwrap().is_empty());
+        // Over the rate with no permit held
+        assert!(limit.try_acquire(ip).is_none());
+        assert!(limit.active.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Maintenance mode, answering connections with a banner.
+//!
+//! While a [MaintenanceMode](struct.MaintenanceMode.html) is entered,
+//! its accept loop keeps the port bound but answers every new
+//! connection with a [RejectAction](../reject/enum.RejectAction.html),
+//! e.g. an HTTP 503 page, instead of passing it to the handler. Once it
+//! is exited, connections are handled normally again. Entering and
+//! exiting can be done from any thread, without swapping handlers or
+//! restarting the loop.
+
+use crate::reject::RejectAction;
+use crate::{accept_loop, Callbacks, Error, StopReason};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Switches an accept loop in and out of maintenance.
+#[derive(Default)]
+pub struct MaintenanceMode {
+    banner: RwLock<Option<RejectAction>>,
+    answered: AtomicU64,
+}
+
+impl MaintenanceMode {
+    /// Create a switch which is not in maintenance.
+    pub fn new() -> MaintenanceMode {
+        MaintenanceMode::default()
+    }
+
+    /// Answer new connections with banner until exit_maintenance() is
+    /// called.
+    pub fn enter_maintenance(&self, banner: RejectAction) {
+        *self.banner.write().unwrap() = Some(banner);
+    }
+
+    /// Handle new connections normally again.
+    pub fn exit_maintenance(&self) {
+        *self.banner.write().unwrap() = None;
+    }
+
+    /// Is maintenance mode entered?
+    pub fn in_maintenance(&self) -> bool {
+        self.banner.read().unwrap().is_some()
+    }
+
+    /// Number of connections answered with a banner.
+    pub fn answered(&self) -> u64 {
+        self.answered.load(Ordering::SeqCst)
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// except while in maintenance. The banner is written without
+    /// blocking, as by [RejectAction::apply()](../reject/enum.RejectAction.html#method.apply).
+    pub fn handle_incoming<H>(
+        &self,
+        listener: &TcpListener,
+        mut handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut answering = |stream: TcpStream, _, _| {
+            let banner = self.banner.read().unwrap().clone();
+            match banner {
+                Some(banner) => {
+                    self.answered.fetch_add(1, Ordering::SeqCst);
+                    banner.apply(stream);
+                }
+                None => handler(stream),
+            }
+        };
+        accept_loop(listener, &mut answering, timeout, Callbacks::default()).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn handle_client(mut stream: TcpStream) {
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.write_all(b"ok");
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let mode = Arc::new(MaintenanceMode::new());
+        mode.enter_maintenance(RejectAction::http_503("down"));
+
+        let l_clone = listener.clone();
+        let m_clone = mode.clone();
+        let server = thread::spawn(move || {
+            m_clone
+                .handle_incoming(&l_clone, handle_client, Duration::from_millis(5))
+                .unwrap()
+        });
+
+        let mut reply = String::new();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 503 "));
+        assert!(reply.ends_with("\r\n\r\ndown"));
+
+        mode.exit_maintenance();
+        reply.clear();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ok");
+        assert_eq!(mode.answered(), 1);
+
+        listener.close();
+        server.join().unwrap();
+    }
+}
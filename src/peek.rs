@@ -0,0 +1,252 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Look at the start of a stream without consuming it.
+//!
+//! A single peek returns whatever happens to have arrived, which may be
+//! less than is needed to identify a protocol. [peek_n()](fn.peek_n.html)
+//! waits until enough data has arrived or a timeout expires. Nothing is
+//! read from the stream, so it can still be handed to a handler intact.
+//! This uses MSG_PEEK on both Unix and Windows.
+//!
+//! The wait doesn't poll. On Unix the socket's receive low water mark
+//! is raised to n for the duration, so that poll() only wakes once n
+//! bytes have arrived or the peer has closed. Windows has no low water
+//! mark, so once some bytes have arrived it waits with a short, growing
+//! sleep for the rest.
+
+use crate::plat_specifics::*;
+use crate::poll::ceil_millis;
+use std::io::{Error, ErrorKind};
+use std::net::TcpStream;
+#[cfg(windows)]
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Peek at the first n bytes of stream, waiting up to timeout for them
+/// to arrive. Fails with TimedOut if they don't, or UnexpectedEof if
+/// the peer closes after sending fewer, and returns no bytes if the
+/// peer closes without sending anything. Works with blocking and
+/// non-blocking streams, and leaves the stream's options as they were.
+pub fn peek_n(stream: &TcpStream, n: usize, timeout: Duration) -> Result<Vec<u8>, Error> {
+    let deadline = Instant::now() + timeout;
+    #[cfg(not(windows))]
+    {
+        let previous = rcvlowat(stream)?;
+        set_rcvlowat(stream, n.min(i32::MAX as usize) as i32)?;
+        let result = peek_until(stream, n, deadline);
+        set_rcvlowat(stream, previous)?;
+        result
+    }
+    #[cfg(windows)]
+    {
+        let previous = stream.read_timeout()?;
+        let result = peek_until(stream, n, deadline);
+        stream.set_read_timeout(previous)?;
+        result
+    }
+}
+
+fn timed_out(got: usize, n: usize) -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        format!("only {} of {} bytes arrived", got, n),
+    )
+}
+
+#[cfg(not(windows))]
+fn peek_until(stream: &TcpStream, n: usize, deadline: Instant) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; n];
+    let mut last = 0;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(timed_out(last, n));
+        }
+        let ready = wait_stream(stream, remaining)?;
+        let rc = unsafe {
+            libc::recv(
+                stream.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                n,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        if rc < 0 {
+            let err = Error::last_os_error();
+            match err.kind() {
+                ErrorKind::WouldBlock | ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+        match rc as usize {
+            got if got >= n => return Ok(buf),
+            0 if ready => return Ok(vec![]),
+            // The low water mark wasn't reached, so the peer has closed
+            got if ready => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("peer closed after {} of {} bytes", got, n),
+                ))
+            }
+            got => last = got,
+        }
+    }
+}
+
+#[cfg(windows)]
+fn peek_until(stream: &TcpStream, n: usize, deadline: Instant) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; n];
+    let mut last = 0;
+    let mut pause = Duration::from_millis(1);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(timed_out(last, n));
+        }
+        if last > 0 {
+            // Readiness doesn't say whether more has arrived
+            thread::sleep(std::cmp::min(remaining, pause));
+            pause = std::cmp::min(pause * 2, Duration::from_millis(16));
+        } else {
+            wait_stream(stream, remaining)?;
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.peek(&mut buf) {
+            Ok(got) if got >= n => return Ok(buf),
+            Ok(0) => return Ok(vec![]),
+            Ok(got) => last = got,
+            Err(err) => match err.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => (),
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
+// Wait up to timeout for stream to become readable.
+#[cfg(not(windows))]
+fn wait_stream(stream: &TcpStream, timeout: Duration) -> Result<bool, Error> {
+    let mut fd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = ceil_millis(timeout).min(i32::MAX as u128) as i32;
+    let rc = unsafe { libc::poll(&mut fd, 1, millis) };
+    if rc < 0 {
+        let err = Error::last_os_error();
+        return match err.kind() {
+            ErrorKind::Interrupted => Ok(false),
+            _ => Err(err),
+        };
+    }
+    Ok(fd.revents != 0)
+}
+
+#[cfg(windows)]
+fn wait_stream(stream: &TcpStream, timeout: Duration) -> Result<bool, Error> {
+    let mut fd = winsock2::WSAPOLLFD {
+        fd: stream.as_raw_socket() as winsock2::SOCKET,
+        events: winsock2::POLLRDNORM,
+        revents: 0,
+    };
+    let millis = ceil_millis(timeout).min(i32::MAX as u128) as i32;
+    let rc = unsafe { winsock2::WSAPoll(&mut fd, 1, millis) };
+    if rc < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(fd.revents != 0)
+}
+
+#[cfg(not(windows))]
+fn rcvlowat(stream: &TcpStream) -> Result<i32, Error> {
+    let mut val: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVLOWAT,
+            &mut val as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(val)
+}
+
+#[cfg(not(windows))]
+fn set_rcvlowat(stream: &TcpStream, val: i32) -> Result<(), Error> {
+    let rc = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVLOWAT,
+            &val as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn test_peek_n() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        client.write_all(b"GE").unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            client.write_all(b"T / HTTP/1.1").unwrap();
+            client
+        });
+        assert_eq!(peek_n(&stream, 4, Duration::from_secs(5)).unwrap(), b"GET ");
+        let err = peek_n(&stream, 100, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let client = writer.join().unwrap();
+        assert_eq!(
+            peek_n(&stream, 14, Duration::from_secs(5)).unwrap(),
+            b"GET / HTTP/1.1"
+        );
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "GET / HTTP/1.1");
+    }
+
+    #[test]
+    fn test_peek_eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        client.write_all(b"GE").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let started = Instant::now();
+        let err = peek_n(&stream, 4, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
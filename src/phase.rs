@@ -0,0 +1,244 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Timeouts for the phases before a connection reaches its handler.
+//!
+//! Reading a PROXY header, performing a handshake or sniffing the
+//! protocol all wait on the client. [PhaseTimeouts](struct.PhaseTimeouts.html)
+//! runs each phase against an overall deadline, rather than a per-read
+//! timeout, so a client which trickles bytes can't hold a connection
+//! open indefinitely. Connections which time out are shut down and
+//! counted.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A phase of connection setup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Reading a PROXY protocol header.
+    ProxyHeader,
+    /// Performing a handshake, e.g. TLS.
+    Handshake,
+    /// Peeking at the first bytes to identify the protocol.
+    Sniff,
+}
+
+impl Phase {
+    fn index(self) -> usize {
+        match self {
+            Phase::ProxyHeader => 0,
+            Phase::Handshake => 1,
+            Phase::Sniff => 2,
+        }
+    }
+}
+
+/// Per [Phase](enum.Phase.html) timeouts and timeout counters.
+pub struct PhaseTimeouts {
+    timeouts: [Duration; 3],
+    timed_out: [AtomicU64; 3],
+}
+
+impl Default for PhaseTimeouts {
+    fn default() -> PhaseTimeouts {
+        PhaseTimeouts {
+            timeouts: [
+                Duration::from_secs(5),
+                Duration::from_secs(10),
+                Duration::from_secs(2),
+            ],
+            timed_out: Default::default(),
+        }
+    }
+}
+
+impl PhaseTimeouts {
+    /// Create timeouts of 5s for PROXY headers, 10s for handshakes and
+    /// 2s for sniffing.
+    pub fn new() -> PhaseTimeouts {
+        PhaseTimeouts::default()
+    }
+
+    /// Set the timeout for phase.
+    pub fn timeout(mut self, phase: Phase, timeout: Duration) -> Self {
+        self.timeouts[phase.index()] = timeout;
+        self
+    }
+
+    /// The timeout for phase.
+    pub fn get(&self, phase: Phase) -> Duration {
+        self.timeouts[phase.index()]
+    }
+
+    /// Number of connections which have timed out in phase.
+    pub fn timed_out(&self, phase: Phase) -> u64 {
+        self.timed_out[phase.index()].load(Ordering::SeqCst)
+    }
+
+    /// Run f against stream, with a deadline of the phase's timeout. If
+    /// f fails because the deadline passed, the stream is shut down, the
+    /// timeout is counted and an error of kind TimedOut is returned. The
+    /// stream's own read and write timeouts are restored afterwards,
+    /// however f finishes.
+    pub fn run<T, F>(&self, phase: Phase, stream: &TcpStream, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut DeadlineStream) -> Result<T, Error>,
+    {
+        let read_timeout = stream.read_timeout()?;
+        let write_timeout = stream.write_timeout()?;
+        let mut deadline = DeadlineStream {
+            stream,
+            deadline: Instant::now() + self.get(phase),
+        };
+        let restore = Restore {
+            stream,
+            read_timeout,
+            write_timeout,
+        };
+        let result = f(&mut deadline);
+        let expired = deadline.remaining().is_none();
+        let restored = restore.restore();
+        match result {
+            Err(ref err) if expired || is_timeout(err) => {
+                self.timed_out[phase.index()].fetch_add(1, Ordering::SeqCst);
+                let _ = stream.shutdown(Shutdown::Both);
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{:?} phase timed out", phase),
+                ))
+            }
+            _ => restored.and(result),
+        }
+    }
+}
+
+// Puts a stream's timeouts back, including when a phase panics.
+struct Restore<'a> {
+    stream: &'a TcpStream,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl Restore<'_> {
+    fn restore(self) -> Result<(), Error> {
+        let result = self.set();
+        std::mem::forget(self);
+        result
+    }
+
+    fn set(&self) -> Result<(), Error> {
+        self.stream.set_read_timeout(self.read_timeout)?;
+        self.stream.set_write_timeout(self.write_timeout)
+    }
+}
+
+impl Drop for Restore<'_> {
+    fn drop(&mut self) {
+        let _ = self.set();
+    }
+}
+
+fn is_timeout(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// A stream whose reads, writes and peeks fail once a deadline passes.
+pub struct DeadlineStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> DeadlineStream<'a> {
+    pub(crate) fn new(stream: &'a TcpStream, deadline: Instant) -> DeadlineStream<'a> {
+        DeadlineStream { stream, deadline }
+    }
+
+    /// The underlying stream. Using it directly bypasses the deadline.
+    pub fn get_ref(&self) -> &TcpStream {
+        self.stream
+    }
+
+    /// Time left before the deadline, or None if it has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            None
+        } else {
+            Some(remaining)
+        }
+    }
+
+    /// Peek at incoming data without consuming it.
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.stream.set_read_timeout(Some(self.left()?))?;
+        self.stream.peek(buf)
+    }
+
+    fn left(&self) -> Result<Duration, Error> {
+        self.remaining()
+            .ok_or_else(|| Error::new(ErrorKind::TimedOut, "deadline passed"))
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.stream.set_read_timeout(Some(self.left()?))?;
+        (&mut &*self.stream).read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.stream.set_write_timeout(Some(self.left()?))?;
+        (&mut &*self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        (&mut &*self.stream).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_trickle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let trickle = thread::spawn(move || {
+            // Each byte arrives well within a per-read timeout
+            while client.write_all(b"x").is_ok() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let timeouts = PhaseTimeouts::new().timeout(Phase::ProxyHeader, Duration::from_millis(100));
+        let result = timeouts.run(Phase::ProxyHeader, &stream, |stream| {
+            let mut line = vec![];
+            let mut byte = [0];
+            while byte[0] != b'\n' {
+                stream.read_exact(&mut byte)?;
+                line.push(byte[0]);
+            }
+            Ok(line)
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(timeouts.timed_out(Phase::ProxyHeader), 1);
+        assert_eq!(timeouts.timed_out(Phase::Handshake), 0);
+        assert_eq!(stream.read_timeout().unwrap(), None);
+        trickle.join().unwrap();
+    }
+}
@@ -0,0 +1,460 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Staged (SEDA style) connection processing.
+//!
+//! A [Pipeline](struct.Pipeline.html) is a chain of named stages, e.g.
+//! handshake, parse and application, built with a
+//! [PipelineBuilder](struct.PipelineBuilder.html). Each stage has its
+//! own bounded queue and thread pool, and passes its output to the next
+//! stage. The accept loop forms the first stage: it turns each accepted
+//! stream into the item type which flows through the pipeline.
+//!
+//! Stages return a [Disposition](enum.Disposition.html) for each item,
+//! which can pass it on, finish with it, send it back to an earlier
+//! stage by name, or hand it off to a named handler. Handlers are
+//! stages which aren't part of the chain and are only reached by a
+//! handoff, e.g. a different handler for authenticated connections.
+//!
+//! New items are rejected when the first stage's queue is full, while
+//! a stage whose successor is full waits for room, so overload pushes
+//! back towards the accept loop rather than piling up in the middle.
+//! Items which are requeued or handed off never wait: if the target
+//! queue is full they are dropped, since waiting on an earlier stage
+//! could deadlock.
+
+use crate::{accept_loop, Callbacks, Error, StopReason};
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Counters for one stage of a [Pipeline](struct.Pipeline.html).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StageStats {
+    /// Name given to the stage.
+    pub name: String,
+    /// Items waiting in the stage's queue.
+    pub queued: usize,
+    /// Items currently being processed.
+    pub busy: usize,
+    /// Items the stage has finished processing.
+    pub processed: u64,
+    /// Items rejected because the queue was full.
+    pub rejected: u64,
+    /// Items this stage routed to a stage or handler which doesn't
+    /// exist.
+    pub misrouted: u64,
+}
+
+/// What a stage wants done with an item once it has processed it.
+#[derive(Debug)]
+pub enum Disposition<T> {
+    /// Pass the item to the next stage in the chain. From the last
+    /// stage, or from a handler, this is the same as Done.
+    Next(T),
+    /// Finished with the item.
+    Done,
+    /// Queue the item for the named stage.
+    Requeue(String, T),
+    /// Queue the item for the named handler.
+    Handoff(String, T),
+}
+
+impl<T> From<Option<T>> for Disposition<T> {
+    /// Some passes the item on and None finishes with it.
+    fn from(item: Option<T>) -> Disposition<T> {
+        match item {
+            Some(item) => Disposition::Next(item),
+            None => Disposition::Done,
+        }
+    }
+}
+
+type StageFn<T> = Box<dyn Fn(T) -> Disposition<T> + Send + Sync>;
+
+struct StageQueue<T> {
+    items: VecDeque<T>,
+    shutdown: bool,
+}
+
+struct Stage<T> {
+    name: String,
+    workers: usize,
+    capacity: usize,
+    run: StageFn<T>,
+    queue: Mutex<StageQueue<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    chained: bool,
+    busy: AtomicUsize,
+    processed: AtomicU64,
+    rejected: AtomicU64,
+    misrouted: AtomicU64,
+}
+
+struct Stages<T> {
+    all: Vec<Stage<T>>,
+    // Index of the next stage in the chain for each stage
+    next: Vec<Option<usize>>,
+    stage_names: HashMap<String, usize>,
+    handler_names: HashMap<String, usize>,
+}
+
+/// Builds a [Pipeline](struct.Pipeline.html).
+pub struct PipelineBuilder<T> {
+    stages: Vec<Stage<T>>,
+}
+
+impl<T: Send + 'static> Default for PipelineBuilder<T> {
+    fn default() -> Self {
+        PipelineBuilder { stages: vec![] }
+    }
+}
+
+impl<T: Send + 'static> PipelineBuilder<T> {
+    /// Create a builder with no stages.
+    pub fn new() -> PipelineBuilder<T> {
+        PipelineBuilder::default()
+    }
+
+    /// Append a stage with workers threads and room for capacity queued
+    /// items. run is called for each item and returns a
+    /// [Disposition](enum.Disposition.html), or an Option: Some passes
+    /// the item to the next stage and None finishes with it.
+    pub fn stage<F, R>(self, name: &str, workers: usize, capacity: usize, run: F) -> Self
+    where
+        F: Fn(T) -> R + Send + Sync + 'static,
+        R: Into<Disposition<T>>,
+    {
+        self.add(name, workers, capacity, true, run)
+    }
+
+    /// Add a named handler, which works like a stage but is outside the
+    /// chain. Items only reach it through
+    /// [Disposition::Handoff](enum.Disposition.html#variant.Handoff).
+    pub fn handler<F, R>(self, key: &str, workers: usize, capacity: usize, run: F) -> Self
+    where
+        F: Fn(T) -> R + Send + Sync + 'static,
+        R: Into<Disposition<T>>,
+    {
+        self.add(key, workers, capacity, false, run)
+    }
+
+    fn add<F, R>(
+        mut self,
+        name: &str,
+        workers: usize,
+        capacity: usize,
+        chained: bool,
+        run: F,
+    ) -> Self
+    where
+        F: Fn(T) -> R + Send + Sync + 'static,
+        R: Into<Disposition<T>>,
+    {
+        self.stages.push(Stage {
+            name: name.to_string(),
+            workers: std::cmp::max(workers, 1),
+            capacity: std::cmp::max(capacity, 1),
+            run: Box::new(move |item| run(item).into()),
+            queue: Mutex::new(StageQueue {
+                items: VecDeque::new(),
+                shutdown: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            chained,
+            busy: AtomicUsize::new(0),
+            processed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            misrouted: AtomicU64::new(0),
+        });
+        self
+    }
+
+    /// Start the worker threads for every stage and handler.
+    pub fn build(self) -> Pipeline<T> {
+        let mut next = vec![None; self.stages.len()];
+        let mut stage_names = HashMap::new();
+        let mut handler_names = HashMap::new();
+        let mut last = None;
+        for (idx, stage) in self.stages.iter().enumerate() {
+            if stage.chained {
+                if let Some(prev) = last {
+                    next[prev] = Some(idx);
+                }
+                last = Some(idx);
+                stage_names.insert(stage.name.clone(), idx);
+            } else {
+                handler_names.insert(stage.name.clone(), idx);
+            }
+        }
+        let stages = Arc::new(Stages {
+            all: self.stages,
+            next,
+            stage_names,
+            handler_names,
+        });
+        let workers = (0..stages.all.len())
+            .map(|idx| {
+                (0..stages.all[idx].workers)
+                    .map(|_| {
+                        let stages = stages.clone();
+                        thread::spawn(move || work(&stages, idx))
+                    })
+                    .collect()
+            })
+            .collect();
+        Pipeline { stages, workers }
+    }
+}
+
+/// A chain of stages, each with its own queue and thread pool.
+pub struct Pipeline<T: Send + 'static> {
+    stages: Arc<Stages<T>>,
+    workers: Vec<Vec<JoinHandle<()>>>,
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+    /// Start building a pipeline.
+    pub fn builder() -> PipelineBuilder<T> {
+        PipelineBuilder::new()
+    }
+
+    /// Queue an item for the first stage. If the queue is full, the
+    /// item is handed back.
+    pub fn submit(&self, item: T) -> Result<(), T> {
+        let stage = match self.stages.all.iter().find(|s| s.chained) {
+            Some(stage) => stage,
+            None => return Err(item),
+        };
+        let mut queue = stage.queue.lock().unwrap();
+        if queue.items.len() >= stage.capacity {
+            stage.rejected.fetch_add(1, Ordering::SeqCst);
+            return Err(item);
+        }
+        queue.items.push_back(item);
+        stage.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Counters for every stage and handler, in the order they were
+    /// added.
+    pub fn stats(&self) -> Vec<StageStats> {
+        self.stages
+            .all
+            .iter()
+            .map(|stage| StageStats {
+                name: stage.name.clone(),
+                queued: stage.queue.lock().unwrap().items.len(),
+                busy: stage.busy.load(Ordering::SeqCst),
+                processed: stage.processed.load(Ordering::SeqCst),
+                rejected: stage.rejected.load(Ordering::SeqCst),
+                misrouted: stage.misrouted.load(Ordering::SeqCst),
+            })
+            .collect()
+    }
+
+    /// Accept connections from listener until it is closed. Each
+    /// connection is turned into an item by accept, which may drop it
+    /// by returning None, and the item is submitted to the first stage.
+    /// Items which don't fit are dropped.
+    pub fn handle_incoming<F>(
+        &self,
+        listener: &TcpListener,
+        mut accept: F,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(TcpStream, SocketAddr) -> Option<T>,
+    {
+        let mut handle = |stream, addr, _| {
+            if let Some(item) = accept(stream, addr) {
+                let _ = self.submit(item);
+            }
+        };
+        accept_loop(listener, &mut handle, timeout, Callbacks::default()).map_err(Error::from)
+    }
+}
+
+impl<T: Send + 'static> Drop for Pipeline<T> {
+    /// Stages, then handlers, are shut down in order, so every queued
+    /// item passes through the rest of the pipeline before the workers
+    /// exit. Items routed back to a stage which has already shut down
+    /// are dropped.
+    fn drop(&mut self) {
+        for (stage, workers) in self.stages.all.iter().zip(self.workers.iter_mut()) {
+            stage.queue.lock().unwrap().shutdown = true;
+            stage.not_empty.notify_all();
+            for worker in workers.drain(..) {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+fn work<T>(stages: &Stages<T>, idx: usize) {
+    let stage = &stages.all[idx];
+    while let Some(item) = stage.next() {
+        stage.busy.fetch_add(1, Ordering::SeqCst);
+        let disposition = (stage.run)(item);
+        stage.busy.fetch_sub(1, Ordering::SeqCst);
+        stage.processed.fetch_add(1, Ordering::SeqCst);
+        let (target, item) = match disposition {
+            Disposition::Next(item) => {
+                if let Some(next) = stages.next[idx] {
+                    stages.all[next].push(item);
+                }
+                continue;
+            }
+            Disposition::Done => continue,
+            Disposition::Requeue(name, item) => (stages.stage_names.get(&name), item),
+            Disposition::Handoff(key, item) => (stages.handler_names.get(&key), item),
+        };
+        match target {
+            Some(target) => {
+                let _ = stages.all[*target].try_push(item);
+            }
+            None => {
+                stage.misrouted.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl<T> Stage<T> {
+    // Wait for the next item. Returns None once the stage is shut down
+    // and its queue is empty.
+    fn next(&self) -> Option<T> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(item) = queue.items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            if queue.shutdown {
+                return None;
+            }
+            queue = self.not_empty.wait(queue).unwrap();
+        }
+    }
+
+    // Queue an item from the previous stage, waiting for room.
+    fn push(&self, item: T) {
+        let mut queue = self
+            .not_full
+            .wait_while(self.queue.lock().unwrap(), |q| {
+                q.items.len() >= self.capacity
+            })
+            .unwrap();
+        queue.items.push_back(item);
+        self.not_empty.notify_one();
+    }
+
+    // Queue a routed item, if there's room and the stage is running.
+    fn try_push(&self, item: T) -> Result<(), T> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.shutdown || queue.items.len() >= self.capacity {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+            return Err(item);
+        }
+        queue.items.push_back(item);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_stages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let results = Arc::new(Mutex::new(vec![]));
+        let r_clone = results.clone();
+        let pipeline = Pipeline::builder()
+            .stage("parse", 2, 4, |(mut stream, _): (TcpStream, String)| {
+                let mut line = String::new();
+                stream.read_to_string(&mut line).unwrap();
+                Some((stream, line.trim().to_uppercase()))
+            })
+            .stage("app", 1, 4, move |(_, line)| {
+                r_clone.lock().unwrap().push(line);
+                None
+            })
+            .build();
+
+        for word in ["one", "two", "three"].iter() {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(word.as_bytes()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            pipeline.submit((stream, String::new())).unwrap();
+        }
+        let stats = pipeline.stats();
+        assert_eq!(stats[0].name, "parse");
+        assert_eq!(stats[1].name, "app");
+        drop(pipeline);
+
+        let mut results = results.lock().unwrap();
+        results.sort();
+        assert_eq!(*results, vec!["ONE", "THREE", "TWO"]);
+    }
+
+    #[test]
+    fn test_routing() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let (l1, l2, l3) = (log.clone(), log.clone(), log.clone());
+        let pipeline = Pipeline::builder()
+            .stage("auth", 1, 4, move |n: u32| {
+                l1.lock().unwrap().push(format!("auth {}", n));
+                match n {
+                    0 => Disposition::Handoff("admin".to_string(), n),
+                    1 => Disposition::Handoff("missing".to_string(), n),
+                    _ => Disposition::Next(n),
+                }
+            })
+            .stage("app", 1, 4, move |n: u32| {
+                l2.lock().unwrap().push(format!("app {}", n));
+                if n == 2 {
+                    // Go round again, as a different request
+                    Disposition::Requeue("auth".to_string(), 3)
+                } else {
+                    Disposition::Done
+                }
+            })
+            .handler("admin", 1, 4, move |n: u32| {
+                l3.lock().unwrap().push(format!("admin {}", n));
+                None
+            })
+            .build();
+        pipeline.submit(0).unwrap();
+        pipeline.submit(1).unwrap();
+        pipeline.submit(2).unwrap();
+        // Let the requeued item get back to the first stage
+        while pipeline.stats()[0].processed < 4 {
+            thread::yield_now();
+        }
+        let stats = pipeline.stats();
+        drop(pipeline);
+
+        assert_eq!(stats[0].misrouted, 1);
+        let mut log = log.lock().unwrap();
+        log.sort();
+        assert_eq!(
+            *log,
+            vec!["admin 0", "app 2", "app 3", "auth 0", "auth 1", "auth 2", "auth 3"]
+        );
+    }
+}
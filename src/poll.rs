@@ -0,0 +1,471 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Wait for any of several listeners to become ready.
+//!
+//! [wait_readable()](fn.wait_readable.html) waits on many listening
+//! sockets in a single call, using poll() on Unix and WSAPoll() on
+//! Windows, so that a loop servicing several listeners wakes as soon as
+//! any of them has a connection rather than sleeping for a fixed time.
+//! A closed listener is reported as ready, so that the next accept()
+//! sees it has been closed.
+//!
+//! [Listener::handle_incoming_poll()](../trait.Listener.html#tymethod.handle_incoming_poll)
+//! uses the same wait in place of a fixed sleep, so connections are
+//! accepted as soon as they arrive. On Unix it also waits on an
+//! internal wake pipe, which close() writes to, because poll() on a
+//! listener doesn't reliably wake when another thread closes it. On
+//! Windows it waits on an event, selected for FD_ACCEPT with
+//! WSAEventSelect() and signalled by close(). Windows accept loops use
+//! the same wait in place of every sleep, since sleeping is
+//! particularly costly there.
+//!
+//! This is the crate's event driven accept path. It doesn't depend on
+//! mio: an application already running a mio event loop can register
+//! the TcpListener, via mio::net::TcpListener::from_std(), with its own
+//! Poll and use a mio Waker in place of close().
+
+use crate::plat_specifics::*;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+// timeout in whole milliseconds, rounded up.
+pub(crate) fn ceil_millis(timeout: Duration) -> u128 {
+    (timeout.as_micros() + 999) / 1000
+}
+
+/// Wait up to timeout for any of listeners to become ready. Returns
+/// one flag per listener, all false if the timeout expired.
+pub fn wait_readable(listeners: &[&TcpListener], timeout: Duration) -> Result<Vec<bool>, Error> {
+    // Round up, so that a short timeout doesn't become a busy loop
+    let millis = ceil_millis(timeout).min(i32::MAX as u128) as i32;
+    #[cfg(not(windows))]
+    {
+        let mut fds: Vec<libc::pollfd> = listeners
+            .iter()
+            .map(|l| libc::pollfd {
+                fd: l.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, millis) };
+        if rc < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+    }
+    #[cfg(windows)]
+    {
+        let mut fds: Vec<winsock2::WSAPOLLFD> = listeners
+            .iter()
+            .map(|l| winsock2::WSAPOLLFD {
+                fd: l.as_raw_socket() as winsock2::SOCKET,
+                events: winsock2::POLLRDNORM,
+                revents: 0,
+            })
+            .collect();
+        let rc = unsafe { winsock2::WSAPoll(fds.as_mut_ptr(), fds.len() as u32, millis) };
+        if rc < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+    }
+}
+
+// Wait up to timeout for one connection. Waits in the same way as
+// handle_incoming_poll(), so a close() ends the wait.
+pub(crate) fn accept_timeout(
+    listener: &TcpListener,
+    timeout: Duration,
+) -> Result<Option<(TcpStream, SocketAddr)>, Error> {
+    let deadline = Instant::now() + timeout;
+    let waker = wake::Waker::register(listener)?;
+    loop {
+        match listener.accept() {
+            Ok(accepted) => return Ok(Some(accepted)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    return Ok(None);
+                }
+                waker.wait(listener, remaining)?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Wake pipes for listeners in polling loops, looked up by close_raw().
+// Slots are claimed with atomics, so that waking is async signal safe
+// and can be done from a signal handler. A waker counts itself in
+// WAKING while it uses a slot's pipe, and the pipe is only closed once
+// none is.
+#[cfg(not(windows))]
+pub(crate) mod wake {
+    use std::io::Error;
+    use std::net::TcpListener;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    const SLOTS: usize = 64;
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicI32 = AtomicI32::new(-1);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const IDLE: AtomicUsize = AtomicUsize::new(0);
+    static LISTENERS: [AtomicI32; SLOTS] = [EMPTY; SLOTS];
+    static PIPES: [AtomicI32; SLOTS] = [EMPTY; SLOTS];
+    static WAKING: [AtomicUsize; SLOTS] = [IDLE; SLOTS];
+
+    pub(crate) struct Waker {
+        slot: Option<usize>,
+        read: RawFd,
+        write: RawFd,
+    }
+
+    impl Waker {
+        // Create a wake pipe for listener. If every slot is in use,
+        // close() can't wake the wait, which then relies on its timeout.
+        pub(crate) fn register(listener: &TcpListener) -> Result<Waker, Error> {
+            Waker::register_raw(listener.as_raw_fd() as usize)
+        }
+
+        // Create a wake pipe for the raw listening fd.
+        pub(crate) fn register_raw(listener: usize) -> Result<Waker, Error> {
+            let listener = listener as RawFd;
+            let fds = pipe()?;
+            let slot = LISTENERS.iter().position(|l| {
+                l.compare_exchange(-1, listener, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            });
+            if let Some(slot) = slot {
+                PIPES[slot].store(fds[1], Ordering::SeqCst);
+            }
+            Ok(Waker {
+                slot,
+                read: fds[0],
+                write: fds[1],
+            })
+        }
+
+        // Wait up to timeout for listener to be ready or woken.
+        pub(crate) fn wait(&self, listener: &TcpListener, timeout: Duration) -> Result<(), Error> {
+            self.wait_raw(listener.as_raw_fd() as usize, timeout)
+        }
+
+        // Wait up to timeout for the raw listening fd to be ready or
+        // woken.
+        pub(crate) fn wait_raw(&self, listener: usize, timeout: Duration) -> Result<(), Error> {
+            let millis = super::ceil_millis(timeout).min(i32::MAX as u128) as i32;
+            let mut fds = [
+                libc::pollfd {
+                    fd: listener as RawFd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.read,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            let rc = unsafe { libc::poll(fds.as_mut_ptr(), 2, millis) };
+            if rc < 0 {
+                let err = Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            if fds[1].revents != 0 {
+                // Discard wakes, so they don't make later waits spin
+                let mut buf = [0u8; 64];
+                unsafe { libc::read(self.read, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            }
+            Ok(())
+        }
+
+        // End a wait on this waker alone.
+        pub(crate) fn wake(&self) {
+            unsafe { libc::write(self.write, b"x".as_ptr() as *const libc::c_void, 1) };
+        }
+    }
+
+    impl Drop for Waker {
+        fn drop(&mut self) {
+            if let Some(slot) = self.slot {
+                PIPES[slot].store(-1, Ordering::SeqCst);
+                // A wake() which loaded the pipe before it was cleared
+                // may still be writing to it
+                while WAKING[slot].load(Ordering::SeqCst) != 0 {
+                    thread::yield_now();
+                }
+                LISTENERS[slot].store(-1, Ordering::SeqCst);
+            }
+            unsafe {
+                libc::close(self.read);
+                libc::close(self.write);
+            }
+        }
+    }
+
+    // Create a non-blocking, close-on-exec pipe, so that a full pipe
+    // can't block a wake and the fds don't leak into a child.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn pipe() -> Result<[RawFd; 2], Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(fds)
+    }
+
+    // Without pipe2(), a fork on another thread can see the fds before
+    // close-on-exec is set.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn pipe() -> Result<[RawFd; 2], Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        for fd in &fds {
+            unsafe {
+                libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+            }
+        }
+        Ok(fds)
+    }
+
+    // Wake any polling loop waiting on listener.
+    pub(crate) fn wake(listener: RawFd) {
+        for (slot, l) in LISTENERS.iter().enumerate() {
+            if l.load(Ordering::SeqCst) == listener {
+                WAKING[slot].fetch_add(1, Ordering::SeqCst);
+                let pipe = PIPES[slot].load(Ordering::SeqCst);
+                if pipe >= 0 {
+                    unsafe { libc::write(pipe, b"x".as_ptr() as *const libc::c_void, 1) };
+                }
+                WAKING[slot].fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::Listener;
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+
+        #[test]
+        fn test_pipe_flags() {
+            let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+            let waker = Waker::register(&listener).unwrap();
+            for fd in &[waker.read, waker.write] {
+                let fd_flags = unsafe { libc::fcntl(*fd, libc::F_GETFD) };
+                let fl_flags = unsafe { libc::fcntl(*fd, libc::F_GETFL) };
+                assert_ne!(fd_flags & libc::FD_CLOEXEC, 0);
+                assert_ne!(fl_flags & libc::O_NONBLOCK, 0);
+            }
+        }
+
+        #[test]
+        fn test_drop_while_waking() {
+            let listener = Arc::new(TcpListener::bind("127.0.0.1:0").unwrap());
+            let done = Arc::new(AtomicBool::new(false));
+            let (l_clone, d_clone) = (listener.clone(), done.clone());
+            let waking = thread::spawn(move || {
+                while !d_clone.load(Ordering::SeqCst) {
+                    wake(l_clone.as_raw_fd());
+                }
+            });
+            for _ in 0..1000 {
+                drop(Waker::register(&listener).unwrap());
+            }
+            done.store(true, Ordering::SeqCst);
+            waking.join().unwrap();
+        }
+    }
+}
+
+// Wake events for listeners, looked up by close_raw(). As on Unix,
+// slots are claimed with atomics, so waking is safe from a console
+// control handler, and an event is only closed once no wake() is
+// using it.
+#[cfg(windows)]
+pub(crate) mod wake {
+    use crate::plat_specifics::*;
+    use std::io::Error;
+    use std::net::TcpListener;
+    use std::os::windows::io::RawSocket;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    const SLOTS: usize = 64;
+    const NONE: usize = usize::MAX;
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicUsize = AtomicUsize::new(NONE);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const IDLE: AtomicUsize = AtomicUsize::new(0);
+    static LISTENERS: [AtomicUsize; SLOTS] = [EMPTY; SLOTS];
+    static EVENTS: [AtomicUsize; SLOTS] = [EMPTY; SLOTS];
+    static WAKING: [AtomicUsize; SLOTS] = [IDLE; SLOTS];
+
+    pub(crate) struct Waker {
+        slot: Option<usize>,
+        event: winsock2::WSAEVENT,
+    }
+
+    impl Waker {
+        // Create a wake event for listener. If every slot is in use,
+        // close() can't wake the wait, which then relies on its timeout.
+        pub(crate) fn register(listener: &TcpListener) -> Result<Waker, Error> {
+            Waker::register_raw(listener.as_raw_socket() as usize)
+        }
+
+        // Create a wake event for the raw listening socket.
+        pub(crate) fn register_raw(listener: usize) -> Result<Waker, Error> {
+            let event = unsafe { winsock2::WSACreateEvent() };
+            if event.is_null() {
+                return Err(Error::last_os_error());
+            }
+            let slot = LISTENERS.iter().position(|l| {
+                l.compare_exchange(NONE, listener, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            });
+            if let Some(slot) = slot {
+                EVENTS[slot].store(event as usize, Ordering::SeqCst);
+            }
+            Ok(Waker { slot, event })
+        }
+
+        // Wait up to timeout for listener to be ready or woken. FD_ACCEPT
+        // is only selected for the wait: accepted sockets inherit the
+        // selection, which would stop them being made blocking.
+        pub(crate) fn wait(&self, listener: &TcpListener, timeout: Duration) -> Result<(), Error> {
+            self.wait_raw(listener.as_raw_socket() as usize, timeout)
+        }
+
+        // Wait up to timeout for the raw listening socket to be ready or
+        // woken.
+        pub(crate) fn wait_raw(&self, listener: usize, timeout: Duration) -> Result<(), Error> {
+            let socket = listener as winsock2::SOCKET;
+            if unsafe { winsock2::WSAEventSelect(socket, self.event, winsock2::FD_ACCEPT) } != 0 {
+                // Closed: the next accept() reports it
+                return Ok(());
+            }
+            let millis = super::ceil_millis(timeout).min(u32::MAX as u128 - 1) as u32;
+            let rc = unsafe {
+                winsock2::WSAWaitForMultipleEvents(
+                    1,
+                    &self.event,
+                    minwindef::FALSE,
+                    millis,
+                    minwindef::FALSE,
+                )
+            };
+            unsafe {
+                winsock2::WSAEventSelect(socket, std::ptr::null_mut(), 0);
+                winsock2::WSAResetEvent(self.event);
+            }
+            if rc == winsock2::WSA_WAIT_FAILED {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        // End a wait on this waker alone.
+        pub(crate) fn wake(&self) {
+            unsafe { winsock2::WSASetEvent(self.event) };
+        }
+    }
+
+    // Event handles can be used from any thread.
+    unsafe impl Send for Waker {}
+    unsafe impl Sync for Waker {}
+
+    impl Drop for Waker {
+        fn drop(&mut self) {
+            if let Some(slot) = self.slot {
+                EVENTS[slot].store(NONE, Ordering::SeqCst);
+                // A wake() which loaded the event before it was cleared
+                // may still be setting it
+                while WAKING[slot].load(Ordering::SeqCst) != 0 {
+                    thread::yield_now();
+                }
+                LISTENERS[slot].store(NONE, Ordering::SeqCst);
+            }
+            unsafe { winsock2::WSACloseEvent(self.event) };
+        }
+    }
+
+    // Wake any loop waiting on listener.
+    pub(crate) fn wake(listener: RawSocket) {
+        for (slot, l) in LISTENERS.iter().enumerate() {
+            if l.load(Ordering::SeqCst) == listener as usize {
+                WAKING[slot].fetch_add(1, Ordering::SeqCst);
+                let event = EVENTS[slot].load(Ordering::SeqCst);
+                if event != NONE {
+                    unsafe { winsock2::WSASetEvent(event as winsock2::WSAEVENT) };
+                }
+                WAKING[slot].fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wait() {
+        let a: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let b: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let started = Instant::now();
+        let ready = wait_readable(&[&a, &b], Duration::from_millis(20)).unwrap();
+        assert_eq!(ready, vec![false, false]);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        let _stream = TcpStream::connect(b.local_addr().unwrap()).unwrap();
+        let ready = wait_readable(&[&a, &b], Duration::from_secs(5)).unwrap();
+        assert_eq!(ready, vec![false, true]);
+    }
+
+    #[test]
+    fn test_handle_incoming_poll() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let l_clone = listener.clone();
+        let server = thread::spawn(move || {
+            let mut handled = 0;
+            l_clone
+                .handle_incoming_poll(|_| handled += 1, Duration::from_secs(60))
+                .unwrap();
+            handled
+        });
+        let started = Instant::now();
+        let _stream = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        // close() wakes the wait long before the timeout
+        listener.close();
+        assert_eq!(server.join().unwrap(), 1);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Prefork worker processes sharing one listener (Unix only).
+//!
+//! [Prefork::run()](struct.Prefork.html#method.run) forks a number of
+//! worker processes which inherit the bound listener, and each runs the
+//! worker function on it, typically an accept loop. The parent restarts
+//! workers which crash. Closing the listener in the parent, from
+//! another thread or because SIGINT or SIGTERM was received, shuts the
+//! group down: each worker is sent SIGTERM, which closes its copy of the
+//! listener so its accept loop terminates normally and it can drain its
+//! connections, and any worker still running after the grace period is
+//! killed.
+//!
+//! A worker which keeps crashing soon after it starts is restarted
+//! with an increasing delay, doubling from the
+//! [restart_delay()](struct.Prefork.html#method.restart_delay) up to
+//! ten seconds. If a worker cannot be forked, the workers already
+//! running are shut down before the error is returned.
+//!
+//! Fork copies only the calling thread, so run() should be called
+//! before the process starts any other threads.
+
+use crate::is_listener_closed;
+use crate::signal::close_on_signal;
+use std::io::Error;
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A change in the state of a worker process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerEvent {
+    /// A worker was started in slot.
+    Started {
+        /// Worker slot, from zero.
+        slot: usize,
+        /// Process id.
+        pid: i32,
+    },
+    /// A worker exited.
+    Exited {
+        /// Worker slot, from zero.
+        slot: usize,
+        /// Process id.
+        pid: i32,
+        /// It was killed by a signal or exited with a non-zero status.
+        crashed: bool,
+    },
+}
+
+/// Runs a fixed number of worker processes.
+#[derive(Clone, Debug)]
+pub struct Prefork {
+    workers: usize,
+    restart: bool,
+    restart_delay: Duration,
+    grace: Duration,
+}
+
+// Longest delay before restarting a crashed worker. A worker which ran
+// for at least this long before crashing is restarted with the initial
+// delay again.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(10);
+
+// A worker slot.
+struct Worker {
+    pid: Option<libc::pid_t>,
+    started: Instant,
+    delay: Duration,
+    restart_at: Option<Instant>,
+}
+
+impl Prefork {
+    /// Run this many worker processes. Crashed workers are restarted
+    /// after 100ms and workers get 10 seconds to exit on shutdown.
+    pub fn new(workers: usize) -> Prefork {
+        Prefork {
+            workers: std::cmp::max(workers, 1),
+            restart: true,
+            restart_delay: Duration::from_millis(100),
+            grace: Duration::from_secs(10),
+        }
+    }
+
+    /// Whether to restart crashed workers.
+    pub fn restart(mut self, restart: bool) -> Self {
+        self.restart = restart;
+        self
+    }
+
+    /// How long to wait before restarting a crashed worker. The delay
+    /// doubles each time the worker crashes again soon after starting.
+    pub fn restart_delay(mut self, delay: Duration) -> Self {
+        self.restart_delay = delay;
+        self
+    }
+
+    /// How long workers have to exit after SIGTERM before being killed.
+    pub fn grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Fork the workers, each calling worker with the listener and
+    /// exiting when it returns, and supervise them until the listener
+    /// is closed or every worker has exited without being restarted.
+    /// on_event is called in the parent as workers start and exit.
+    pub fn run<F>(
+        &self,
+        listener: &TcpListener,
+        worker: fn(&TcpListener),
+        mut on_event: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&WorkerEvent),
+    {
+        let _signals = close_on_signal(listener)?;
+        let mut workers: Vec<Worker> = Vec::with_capacity(self.workers);
+        for slot in 0..self.workers {
+            let pid = match spawn(listener, worker) {
+                Ok(pid) => pid,
+                Err(e) => {
+                    self.shutdown(&mut workers, &mut on_event);
+                    return Err(e);
+                }
+            };
+            workers.push(Worker {
+                pid: Some(pid),
+                started: Instant::now(),
+                delay: self.restart_delay,
+                restart_at: None,
+            });
+            on_event(&WorkerEvent::Started { slot, pid });
+        }
+        while !is_listener_closed(listener) {
+            if workers
+                .iter()
+                .all(|w| w.pid.is_none() && w.restart_at.is_none())
+            {
+                return Ok(());
+            }
+            let now = Instant::now();
+            for (slot, entry) in workers.iter_mut().enumerate() {
+                match (entry.pid, entry.restart_at) {
+                    (Some(pid), _) => {
+                        if let Some(crashed) = reap(pid, libc::WNOHANG) {
+                            entry.pid = None;
+                            on_event(&WorkerEvent::Exited { slot, pid, crashed });
+                            if crashed && self.restart {
+                                if now.duration_since(entry.started) >= MAX_RESTART_DELAY {
+                                    entry.delay = self.restart_delay;
+                                }
+                                entry.restart_at = Some(now + entry.delay);
+                                entry.delay = std::cmp::min(entry.delay * 2, MAX_RESTART_DELAY);
+                            }
+                        }
+                    }
+                    (None, Some(at)) if at <= now => {
+                        entry.restart_at = None;
+                        let pid = match spawn(listener, worker) {
+                            Ok(pid) => pid,
+                            Err(e) => {
+                                self.shutdown(&mut workers, &mut on_event);
+                                return Err(e);
+                            }
+                        };
+                        entry.pid = Some(pid);
+                        entry.started = now;
+                        on_event(&WorkerEvent::Started { slot, pid });
+                    }
+                    _ => (),
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.shutdown(&mut workers, &mut on_event);
+        Ok(())
+    }
+
+    fn shutdown<F>(&self, workers: &mut [Worker], on_event: &mut F)
+    where
+        F: FnMut(&WorkerEvent),
+    {
+        for pid in workers.iter().filter_map(|w| w.pid) {
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+        }
+        let deadline = Instant::now() + self.grace;
+        while workers.iter().any(|w| w.pid.is_some()) {
+            let expired = Instant::now() >= deadline;
+            for (slot, entry) in workers.iter_mut().enumerate() {
+                let pid = match entry.pid {
+                    Some(pid) => pid,
+                    None => continue,
+                };
+                if expired {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                }
+                let flags = if expired { 0 } else { libc::WNOHANG };
+                if let Some(crashed) = reap(pid, flags) {
+                    entry.pid = None;
+                    on_event(&WorkerEvent::Exited { slot, pid, crashed });
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn spawn(listener: &TcpListener, worker: fn(&TcpListener)) -> Result<libc::pid_t, Error> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => {
+            let code = match panic::catch_unwind(AssertUnwindSafe(|| worker(listener))) {
+                Ok(()) => 0,
+                Err(_) => 101,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        pid => Ok(pid),
+    }
+}
+
+// Has pid exited? Returns whether it crashed, or None if it is still
+// running.
+fn reap(pid: libc::pid_t, flags: libc::c_int) -> Option<bool> {
+    let mut status = 0;
+    let rc = unsafe { libc::waitpid(pid, &mut status, flags) };
+    if rc == pid {
+        Some(!libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0)
+    } else if rc < 0 {
+        // Already reaped elsewhere, treat as a crash
+        Some(true)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! HAProxy PROXY protocol headers.
+//!
+//! Behind a load balancer, every connection's peer address is the load
+//! balancer's. If it is configured to send a PROXY protocol header,
+//! [read_header()](fn.read_header.html) consumes the header from the
+//! start of the stream and returns the original addresses as a
+//! [ProxyInfo](struct.ProxyInfo.html). Both the text (v1) and binary
+//! (v2) forms are understood. [ProxyProtocol](struct.ProxyProtocol.html)
+//! does this for each connection in an accept loop.
+
+use crate::peek::peek_n;
+use crate::phase::DeadlineStream;
+use crate::{accept_loop, Callbacks, StopReason};
+use std::io::{Error, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Longest v1 header, including the CRLF
+const V1_MAX: usize = 107;
+
+/// The original addresses of a proxied connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyInfo {
+    /// Protocol version of the header, None if there was no header.
+    pub version: Option<u8>,
+    /// The client. The peer address if the header didn't give one.
+    pub source: SocketAddr,
+    /// The address the client connected to. The local address if the
+    /// header didn't give one.
+    pub destination: SocketAddr,
+}
+
+/// Read a PROXY protocol header from the start of stream, allowing
+/// timeout for the whole header. If the stream doesn't start with a
+/// header, nothing is read and the version is None. This includes a
+/// client which sends nothing, or too little to tell, within timeout,
+/// e.g. one waiting for the server to speak first. The stream is left
+/// blocking, with its read timeout as it was.
+pub fn read_header(stream: &mut TcpStream, timeout: Duration) -> Result<ProxyInfo, Error> {
+    let deadline = Instant::now() + timeout;
+    let mut info = ProxyInfo {
+        version: None,
+        source: stream.peer_addr()?,
+        destination: stream.local_addr()?,
+    };
+    stream.set_nonblocking(false)?;
+    let version = match sniff(stream, deadline)? {
+        Some(version) => version,
+        None => return Ok(info),
+    };
+    let read_timeout = stream.read_timeout()?;
+    let mut deadline = DeadlineStream::new(stream, deadline);
+    let result = match version {
+        1 => read_v1(&mut deadline, &mut info),
+        _ => read_v2(&mut deadline, &mut info),
+    };
+    stream.set_read_timeout(read_timeout)?;
+    result.map(|_| info)
+}
+
+// Which header version, if any, the stream starts with. Decides as
+// soon as the bytes so far can't start a header.
+fn sniff(stream: &TcpStream, deadline: Instant) -> Result<Option<u8>, Error> {
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+    let first = match peek_n(stream, 1, remaining()) {
+        Ok(first) => first,
+        Err(err) if err.kind() == ErrorKind::TimedOut => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    match first.first() {
+        Some(&b'P') | Some(&b'\r') => (),
+        _ => return Ok(None),
+    }
+    let start = match peek_n(stream, 6, remaining()) {
+        Ok(start) => start,
+        Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::UnexpectedEof) => {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+    if start == b"PROXY " {
+        Ok(Some(1))
+    } else if start[..] == V2_SIGNATURE[..6] {
+        Ok(Some(2))
+    } else {
+        Ok(None)
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("PROXY header: {}", msg))
+}
+
+fn read_v1(stream: &mut DeadlineStream, info: &mut ProxyInfo) -> Result<(), Error> {
+    // Read a byte at a time, so nothing past the header is consumed
+    let mut line = Vec::with_capacity(V1_MAX);
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX {
+            return Err(invalid("line too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    info.version = Some(1);
+    match fields.get(1) {
+        Some(&"UNKNOWN") => Ok(()),
+        Some(&family @ "TCP4") | Some(&family @ "TCP6") if fields.len() == 6 => {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr, Error> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("bad address"))?;
+                if ip.is_ipv4() != (family == "TCP4") {
+                    return Err(invalid("address doesn't match protocol"));
+                }
+                let port: u16 = port.parse().map_err(|_| invalid("bad port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            info.source = parse(fields[2], fields[4])?;
+            info.destination = parse(fields[3], fields[5])?;
+            Ok(())
+        }
+        _ => Err(invalid("bad protocol")),
+    }
+}
+
+fn read_v2(stream: &mut DeadlineStream, info: &mut ProxyInfo) -> Result<(), Error> {
+    let mut head = [0; 16];
+    stream.read_exact(&mut head)?;
+    if head[..12] != V2_SIGNATURE[..] || head[12] >> 4 != 2 {
+        return Err(invalid("bad signature"));
+    }
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    info.version = Some(2);
+    // LOCAL connections, e.g. health checks, carry no addresses
+    if head[12] & 0x0f == 0 {
+        return Ok(());
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match head[13] >> 4 {
+        1 if len >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    body[at],
+                    body[at + 1],
+                    body[at + 2],
+                    body[at + 3],
+                ))
+            };
+            info.source = SocketAddr::new(ip(0), port(8));
+            info.destination = SocketAddr::new(ip(4), port(10));
+        }
+        2 if len >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            info.source = SocketAddr::new(ip(0), port(32));
+            info.destination = SocketAddr::new(ip(16), port(34));
+        }
+        // Unspecified or Unix addresses: keep the socket's own
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Reads a PROXY protocol header from each connection in an accept loop.
+#[derive(Clone, Debug)]
+pub struct ProxyProtocol {
+    required: bool,
+    header_timeout: Duration,
+}
+
+impl Default for ProxyProtocol {
+    /// Headers are required and must arrive within 5 seconds.
+    fn default() -> ProxyProtocol {
+        ProxyProtocol {
+            required: true,
+            header_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    /// Create with the default settings.
+    pub fn new() -> ProxyProtocol {
+        ProxyProtocol::default()
+    }
+
+    /// Whether connections without a header are dropped. If not, they
+    /// are handled with their own addresses.
+    pub fn required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    /// How long to wait for the header.
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = timeout;
+        self
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// passing each one to handler with its addresses. Connections with
+    /// a bad or (if required) missing header are dropped, where a
+    /// header which hasn't started to arrive by the header timeout
+    /// counts as missing. Headers are read on the accepting thread.
+    pub fn handle_incoming<H>(
+        &self,
+        listener: &TcpListener,
+        mut handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(TcpStream, ProxyInfo),
+    {
+        let mut handle = |mut stream, _, _| {
+            if let Ok(info) = read_header(&mut stream, self.header_timeout) {
+                if info.version.is_some() || !self.required {
+                    handler(stream, info);
+                }
+            }
+        };
+        accept_loop(listener, &mut handle, timeout, Callbacks::default())
+            .map_err(crate::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn pair(listener: &TcpListener, data: &[u8]) -> (TcpStream, TcpStream) {
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(data).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let (_c, mut s) = pair(&listener, b"PROXY TCP4 10.0.0.1 10.0.0.2 5000 80\r\nGET");
+        let info = read_header(&mut s, timeout).unwrap();
+        assert_eq!(info.version, Some(1));
+        assert_eq!(info.source, "10.0.0.1:5000".parse().unwrap());
+        assert_eq!(info.destination, "10.0.0.2:80".parse().unwrap());
+        let mut rest = [0; 3];
+        s.read_exact(&mut rest).unwrap();
+        assert_eq!(&rest, b"GET");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        v2.extend_from_slice(&src.octets());
+        v2.extend_from_slice(&dst.octets());
+        v2.extend_from_slice(&[0x13, 0x88, 0, 80]);
+        let (_c, mut s) = pair(&listener, &v2);
+        let info = read_header(&mut s, timeout).unwrap();
+        assert_eq!(info.version, Some(2));
+        assert_eq!(info.source, "[2001:db8::1]:5000".parse().unwrap());
+
+        // No header: nothing is consumed
+        let (c, mut s) = pair(&listener, b"HELLO!");
+        let info = read_header(&mut s, timeout).unwrap();
+        assert_eq!(info.version, None);
+        assert_eq!(info.source, c.local_addr().unwrap());
+        let mut rest = [0; 6];
+        s.read_exact(&mut rest).unwrap();
+        assert_eq!(&rest, b"HELLO!");
+
+        // A family which doesn't match the addresses is rejected
+        let (_c, mut s) = pair(&listener, b"PROXY TCP6 10.0.0.1 10.0.0.2 5000 80\r\n");
+        let err = read_header(&mut s, timeout).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_short_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let timeout = Duration::from_millis(100);
+
+        // A client waiting for the server to speak first
+        let (c, mut s) = pair(&listener, b"");
+        let info = read_header(&mut s, timeout).unwrap();
+        assert_eq!(info.version, None);
+        assert_eq!(info.source, c.local_addr().unwrap());
+
+        // A short first message is decided on straight away
+        let (_c, mut s) = pair(&listener, b"HI");
+        let started = Instant::now();
+        assert_eq!(
+            read_header(&mut s, Duration::from_secs(5)).unwrap().version,
+            None
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // A header which trickles in past the timeout fails
+        let (mut c, mut s) = pair(&listener, b"PROXY TCP4 ");
+        let trickle = std::thread::spawn(move || {
+            for _ in 0..20 {
+                std::thread::sleep(Duration::from_millis(10));
+                if c.write_all(b"1").is_err() {
+                    break;
+                }
+            }
+        });
+        assert!(read_header(&mut s, timeout).is_err());
+        assert_eq!(s.read_timeout().unwrap(), None);
+        trickle.join().unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Prioritised dispatch of connections to worker threads.
+//!
+//! An accept filter assigns each connection a [QosClass](enum.QosClass.html)
+//! (or rejects it). Each class has its own bounded queue and idle
+//! workers always take from the highest priority queue which has
+//! connections waiting, so important peers aren't stuck behind bulk
+//! traffic. Rejected connections are closed according to a
+//! [RejectPolicy](../reject/struct.RejectPolicy.html).
+
+use crate::reject::RejectPolicy;
+use crate::{accept_loop, Callbacks, Error, StopReason};
+use std::collections::VecDeque;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Quality of service classes, in priority order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QosClass {
+    /// Always serviced first.
+    Premium,
+    /// Serviced when no premium connections are waiting.
+    Normal,
+    /// Serviced only when nothing else is waiting.
+    Background,
+}
+
+impl QosClass {
+    fn index(self) -> usize {
+        match self {
+            QosClass::Premium => 0,
+            QosClass::Normal => 1,
+            QosClass::Background => 2,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    classes: [VecDeque<TcpStream>; 3],
+    shutdown: bool,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    ready: Condvar,
+    capacity: usize,
+}
+
+/// Dispatches connections to a fixed set of worker threads, using a
+/// separate bounded queue for each [QosClass](enum.QosClass.html).
+pub struct QosDispatcher {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    policy: RejectPolicy,
+}
+
+impl QosDispatcher {
+    /// Start worker threads which call handler for each dispatched
+    /// connection. Each class queue holds at most capacity connections.
+    pub fn new<F>(workers: usize, capacity: usize, handler: F) -> QosDispatcher
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues::default()),
+            ready: Condvar::new(),
+            capacity,
+        });
+        let handler = Arc::new(handler);
+        let workers = (0..workers)
+            .map(|_| {
+                let shared = shared.clone();
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    while let Some(stream) = shared.next() {
+                        handler(stream);
+                    }
+                })
+            })
+            .collect();
+        QosDispatcher {
+            shared,
+            workers,
+            policy: RejectPolicy::default(),
+        }
+    }
+
+    /// Close connections rejected by handle_incoming() according to
+    /// policy. By default they are closed gracefully.
+    pub fn with_reject_policy(mut self, policy: RejectPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Queue a connection in the given class. If the queue is full, the
+    /// connection is handed back.
+    pub fn dispatch(&self, stream: TcpStream, class: QosClass) -> Result<(), TcpStream> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        let queue = &mut queues.classes[class.index()];
+        if queue.len() >= self.shared.capacity {
+            return Err(stream);
+        }
+        queue.push_back(stream);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Number of connections waiting in the given class.
+    pub fn queued(&self, class: QosClass) -> usize {
+        self.shared.queues.lock().unwrap().classes[class.index()].len()
+    }
+
+    /// Accept connections from listener until it is closed, classifying
+    /// each one with filter. Connections which the filter rejects, by
+    /// returning None, or which don't fit in their queue are closed
+    /// according to the reject policy.
+    pub fn handle_incoming<F>(
+        &self,
+        listener: &TcpListener,
+        mut filter: F,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        F: FnMut(&SocketAddr) -> Option<QosClass>,
+    {
+        let mut handle = |stream, addr, _| match filter(&addr) {
+            Some(class) => {
+                if let Err(stream) = self.dispatch(stream, class) {
+                    self.policy.reject(stream, Some(class));
+                }
+            }
+            None => self.policy.reject(stream, None),
+        };
+        accept_loop(listener, &mut handle, timeout, Callbacks::default()).map_err(Error::from)
+    }
+}
+
+impl Shared {
+    // Wait for the highest priority connection. Returns None once the
+    // dispatcher is shut down and every queue is empty.
+    fn next(&self) -> Option<TcpStream> {
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            for queue in queues.classes.iter_mut() {
+                if let Some(stream) = queue.pop_front() {
+                    return Some(stream);
+                }
+            }
+            if queues.shutdown {
+                return None;
+            }
+            queues = self.ready.wait(queues).unwrap();
+        }
+    }
+}
+
+impl Drop for QosDispatcher {
+    /// Queued connections are handled before the workers exit.
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().shutdown = true;
+        self.shared.ready.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_priority() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let order = Arc::new(Mutex::new(vec![]));
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let o_clone = order.clone();
+        let g_clone = gate.clone();
+        let dispatcher = QosDispatcher::new(1, 1, move |mut stream| {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'0' {
+                // Hold the only worker until everything else is queued
+                let (lock, cvar) = &*g_clone;
+                let _open = cvar
+                    .wait_while(lock.lock().unwrap(), |open| !*open)
+                    .unwrap();
+            }
+            o_clone.lock().unwrap().push(byte[0]);
+        });
+
+        let mut clients = vec![];
+        let mut connect = |tag: u8, class: QosClass| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(&[tag]).unwrap();
+            clients.push(client);
+            let (stream, _) = listener.accept().unwrap();
+            dispatcher.dispatch(stream, class)
+        };
+        connect(b'0', QosClass::Background).unwrap();
+        while dispatcher.queued(QosClass::Background) > 0 {
+            thread::yield_now();
+        }
+        connect(b'B', QosClass::Background).unwrap();
+        connect(b'N', QosClass::Normal).unwrap();
+        connect(b'P', QosClass::Premium).unwrap();
+        assert!(connect(b'X', QosClass::Premium).is_err());
+
+        *gate.0.lock().unwrap() = true;
+        gate.1.notify_all();
+        drop(dispatcher);
+        assert_eq!(*order.lock().unwrap(), b"0PNB");
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A bounded queue of connections between an accept loop and workers.
+//!
+//! A [ConnQueue](struct.ConnQueue.html) lets the accept thread and the
+//! application's own worker threads run at different rates: the accept
+//! loop pushes connections and workers pop them. When the queue is
+//! full, a [QueueOverflow](enum.QueueOverflow.html) policy applies
+//! backpressure by pausing accepts, or sheds load by dropping the
+//! oldest waiting connection or rejecting the new one.
+//! [ThreadPoolListener](../threaded/struct.ThreadPoolListener.html)
+//! is the ready made alternative when the workers can be a plain pool.
+
+use crate::reject::{close_with, CloseMode, RejectAction};
+use crate::threaded::StreamQueue;
+use crate::until::StopCondition;
+use crate::{accept_loop, is_listener_closed, Callbacks, Error, StopReason};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// What to do with a new connection when the queue is full.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Stop accepting until a worker takes a connection, so further
+    /// connections wait in the kernel's accept queue.
+    #[default]
+    Block,
+    /// Close the connection which has waited longest, to make room.
+    DropOldest,
+    /// Answer the new connection with this action, e.g.
+    /// `RejectAction::Close(CloseMode::Abort)` to reset it.
+    RejectNew(RejectAction),
+}
+
+/// A bounded queue of accepted connections.
+pub struct ConnQueue {
+    overflow: QueueOverflow,
+    queue: StreamQueue,
+    shed: AtomicU64,
+}
+
+impl ConnQueue {
+    /// Create a queue holding up to capacity connections, which blocks
+    /// accepts when full. A capacity of zero is treated as one.
+    pub fn new(capacity: usize) -> ConnQueue {
+        ConnQueue {
+            overflow: QueueOverflow::Block,
+            queue: StreamQueue::new(capacity),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Deal with connections which don't fit according to overflow.
+    pub fn overflow(mut self, overflow: QueueOverflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Number of connections waiting.
+    pub fn len(&self) -> usize {
+        self.queue.lock().streams.len()
+    }
+
+    /// Is no connection waiting?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of connections dropped or rejected by the overflow policy,
+    /// or by handle_incoming() because the queue was closed.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::SeqCst)
+    }
+
+    /// Add a connection without waiting. If the queue is full, the
+    /// overflow policy applies; with Block, or once the queue is
+    /// closed, the connection is handed back.
+    pub fn push(&self, stream: TcpStream) -> Result<(), TcpStream> {
+        let mut queue = self.queue.lock();
+        if queue.shutdown {
+            return Err(stream);
+        }
+        if self.queue.is_full(&queue) {
+            match &self.overflow {
+                QueueOverflow::Block => return Err(stream),
+                QueueOverflow::DropOldest => {
+                    if let Some(oldest) = queue.streams.pop_front() {
+                        close_with(oldest, CloseMode::Graceful);
+                    }
+                }
+                QueueOverflow::RejectNew(action) => {
+                    drop(queue);
+                    self.shed.fetch_add(1, Ordering::SeqCst);
+                    action.apply(stream);
+                    return Ok(());
+                }
+            }
+            self.shed.fetch_add(1, Ordering::SeqCst);
+        }
+        self.queue.push_locked(&mut queue, stream);
+        Ok(())
+    }
+
+    /// Take the connection which has waited longest, waiting for one if
+    /// necessary. Returns None once the queue is closed and empty.
+    pub fn pop(&self) -> Option<TcpStream> {
+        self.queue.pop(None)
+    }
+
+    /// As pop(), but wait at most timeout.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<TcpStream> {
+        self.queue.pop(Some(timeout))
+    }
+
+    /// Stop accepting pushes. Workers take the connections still
+    /// waiting, then pop() returns None.
+    pub fn close(&self) {
+        self.queue.shut_down();
+    }
+
+    /// Accept connections from listener until it is closed, pushing
+    /// each one onto the queue, then close the queue so that workers
+    /// finish. With Block, accepting pauses while the queue is full.
+    /// Connections which can't be pushed, because the queue was closed
+    /// meanwhile or another thread filled it, are closed gracefully and
+    /// counted as shed.
+    pub fn handle_incoming(
+        &self,
+        listener: &TcpListener,
+        timeout: Duration,
+    ) -> Result<StopReason, Error> {
+        let mut handle = |stream, _, _| {
+            if let Err(stream) = self.push(stream) {
+                self.shed.fetch_add(1, Ordering::SeqCst);
+                RejectAction::default().apply(stream);
+            }
+        };
+        let mut full = Full {
+            queue: &self.queue,
+            listener,
+            timeout,
+        };
+        let callbacks = Callbacks {
+            until: match self.overflow {
+                QueueOverflow::Block => Some(&mut full),
+                _ => None,
+            },
+            ..Callbacks::default()
+        };
+        let result = accept_loop(listener, &mut handle, timeout, callbacks).map_err(Error::from);
+        self.close();
+        result
+    }
+}
+
+// Holds the loop while the queue is full, stopping it if the listener
+// is closed meanwhile.
+struct Full<'a> {
+    queue: &'a StreamQueue,
+    listener: &'a TcpListener,
+    timeout: Duration,
+}
+
+impl StopCondition for Full<'_> {
+    fn should_stop(&mut self) -> bool {
+        while !self.queue.wait_for_space(self.timeout) {
+            if is_listener_closed(self.listener) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn reason(&self) -> StopReason {
+        StopReason::Closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_queue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = || {
+            let client = TcpStream::connect(addr).unwrap();
+            (client, listener.accept().unwrap().0)
+        };
+
+        let queue = ConnQueue::new(1).overflow(QueueOverflow::DropOldest);
+        let (mut first, stream) = connect();
+        queue.push(stream).unwrap();
+        let (second, stream) = connect();
+        queue.push(stream).unwrap();
+        // The first was dropped to make room
+        assert_eq!(first.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(queue.shed(), 1);
+        let popped = queue.pop().unwrap();
+        assert_eq!(popped.peer_addr().unwrap(), second.local_addr().unwrap());
+
+        let queue = ConnQueue::new(1);
+        queue.push(connect().1).unwrap();
+        assert!(queue.push(connect().1).is_err());
+        queue.close();
+        assert!(queue.pop().is_some());
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_closed() {
+        let listener: TcpListener = crate::Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let queue = ConnQueue::new(1);
+        queue.close();
+        let mut client = TcpStream::connect(addr).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                // The late connection is closed, not dropped silently
+                assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+                while queue.shed() == 0 {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                crate::Listener::close(&listener);
+            });
+            assert_eq!(
+                queue
+                    .handle_incoming(&listener, Duration::from_millis(5))
+                    .unwrap(),
+                StopReason::Closed
+            );
+        });
+        assert_eq!(queue.shed(), 1);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_block() {
+        let listener: TcpListener = crate::Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let queue = ConnQueue::new(1);
+        let _clients: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        std::thread::scope(|scope| {
+            let server = scope.spawn(|| queue.handle_incoming(&listener, Duration::from_millis(5)));
+            // The second waits in the kernel until the first is taken
+            assert!(queue.pop_timeout(Duration::from_secs(5)).is_some());
+            assert!(queue.pop_timeout(Duration::from_secs(5)).is_some());
+            crate::Listener::close(&listener);
+            assert_eq!(server.join().unwrap().unwrap(), StopReason::Closed);
+        });
+        assert_eq!(queue.shed(), 0);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Connection quotas per source address over a sliding window.
+//!
+//! A token bucket limits the instantaneous rate, but allows a steady
+//! trickle forever. A [Quota](struct.Quota.html) instead caps the total
+//! number of connections from an address over a window, e.g. 100
+//! connections per IP per 10 minutes. Each address has a ring of
+//! counters, one per tenth of the window, so the window slides in
+//! steps of a tenth and an address costs a fixed amount of memory
+//! however many connections it makes.
+//!
+//! Quota implements [KeyedRateLimiter](../ratelimit/trait.KeyedRateLimiter.html),
+//! so it can be used as the per address limit of
+//! [AcceptLimits](../ratelimit/struct.AcceptLimits.html).
+
+use crate::ratelimit::KeyedRateLimiter;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const SLOTS: u64 = 10;
+
+// Counters for one address. slot is the index, counted from the start
+// of the quota, of the most recently updated counter.
+struct Window {
+    counts: [u32; SLOTS as usize],
+    slot: u64,
+}
+
+impl Window {
+    fn advance(&mut self, slot: u64) {
+        // Another thread may have advanced further already
+        if slot <= self.slot {
+            return;
+        }
+        if slot - self.slot >= SLOTS {
+            self.counts = [0; SLOTS as usize];
+        } else {
+            for s in self.slot + 1..=slot {
+                self.counts[(s % SLOTS) as usize] = 0;
+            }
+        }
+        self.slot = slot;
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.iter().map(|c| *c as u64).sum()
+    }
+}
+
+/// A limit on connections per address over a sliding window.
+pub struct Quota {
+    max: u64,
+    slot_len: Duration,
+    started: Instant,
+    windows: Mutex<HashMap<IpAddr, Window>>,
+}
+
+impl Quota {
+    /// Allow each address at most max connections in any window.
+    pub fn new(max: u64, window: Duration) -> Quota {
+        let slot_len = std::cmp::max(window / SLOTS as u32, Duration::from_micros(10));
+        Quota {
+            max,
+            slot_len,
+            started: Instant::now(),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Number of connections counted for ip in the current window.
+    pub fn count(&self, ip: &IpAddr) -> u64 {
+        let slot = self.current_slot();
+        match self.windows.lock().unwrap().get_mut(ip) {
+            Some(window) => {
+                window.advance(slot);
+                window.total()
+            }
+            None => 0,
+        }
+    }
+
+    /// Forget addresses with no connections in the current window, to
+    /// bound memory.
+    pub fn purge(&self) {
+        let slot = self.current_slot();
+        self.windows.lock().unwrap().retain(|_, window| {
+            window.advance(slot);
+            window.total() > 0
+        });
+    }
+
+    fn current_slot(&self) -> u64 {
+        (self.started.elapsed().as_micros() / self.slot_len.as_micros()) as u64
+    }
+}
+
+impl KeyedRateLimiter for Quota {
+    fn check_key(&self, key: &IpAddr) -> Result<(), Duration> {
+        let slot = self.current_slot();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(*key).or_insert(Window {
+            counts: [0; SLOTS as usize],
+            slot,
+        });
+        window.advance(slot);
+        if window.total() < self.max {
+            window.counts[(slot % SLOTS) as usize] += 1;
+            return Ok(());
+        }
+        // Wait for the oldest counter still in the window to expire
+        let oldest = (slot.saturating_sub(SLOTS - 1)..=slot)
+            .find(|s| window.counts[(s % SLOTS) as usize] > 0)
+            .unwrap_or(slot);
+        let expires = Duration::from_micros(self.slot_len.as_micros() as u64 * (oldest + SLOTS));
+        Err(expires.saturating_sub(self.started.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_window() {
+        let quota = Quota::new(2, Duration::from_millis(100));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(quota.check_key(&a).is_ok());
+        assert!(quota.check_key(&a).is_ok());
+        let wait = quota.check_key(&a).unwrap_err();
+        assert!(wait <= Duration::from_millis(100));
+        assert!(quota.check_key(&b).is_ok());
+        assert_eq!(quota.count(&a), 2);
+
+        thread::sleep(Duration::from_millis(120));
+        quota.purge();
+        assert_eq!(quota.count(&a), 0);
+        assert!(quota.check_key(&a).is_ok());
+    }
+}
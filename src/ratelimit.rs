@@ -0,0 +1,305 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pluggable limits on the rate of accepted connections.
+//!
+//! Limiters implement [RateLimiter](trait.RateLimiter.html), for a
+//! single shared limit, or [KeyedRateLimiter](trait.KeyedRateLimiter.html),
+//! for a limit per peer address. A `Mutex<TokenBucket>` and
+//! [PerIp](struct.PerIp.html) are provided, and an application which
+//! already has limiters, e.g. from the governor crate, can implement
+//! the traits for them to share state with the rest of the application.
+//!
+//! [AcceptLimits](struct.AcceptLimits.html) applies limiters to an
+//! accept loop. While the global limiter has no permits, the loop
+//! holds the connection it has just accepted and leaves the rest in the
+//! kernel's accept queue. Connections from a peer over its per address
+//! limit are accepted and closed, gracefully unless
+//! [reject_with()](struct.AcceptLimits.html#method.reject_with) says
+//! otherwise. The loop purges the per address limiter periodically, so
+//! that it only remembers recent peers.
+
+use crate::reject::{close_with, CloseMode};
+use crate::throttle::TokenBucket;
+use crate::{accept_loop, is_listener_closed, Callbacks, Error, StopReason, TickInfo};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A limit shared by all connections.
+pub trait RateLimiter: Send + Sync {
+    /// Take a permit. If none is available, return how long until one
+    /// will be.
+    fn check(&self) -> Result<(), Duration>;
+}
+
+/// A limit for each peer address.
+pub trait KeyedRateLimiter: Send + Sync {
+    /// Take a permit for key. If none is available, return how long
+    /// until one will be.
+    fn check_key(&self, key: &IpAddr) -> Result<(), Duration>;
+
+    /// Forget keys which are back at their full limit, to bound memory.
+    /// [AcceptLimits](struct.AcceptLimits.html) calls this periodically.
+    fn purge(&self) {}
+}
+
+impl RateLimiter for Mutex<TokenBucket> {
+    fn check(&self) -> Result<(), Duration> {
+        let mut bucket = self.lock().unwrap();
+        match bucket.time_until(1) {
+            wait if wait > Duration::from_secs(0) => Err(wait),
+            _ => {
+                bucket.consume(1);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A token bucket for each peer address.
+pub struct PerIp {
+    rate: u64,
+    burst: u64,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl PerIp {
+    /// Allow each address rate connections per second, with bursts of
+    /// up to burst.
+    pub fn new(rate: u64, burst: u64) -> PerIp {
+        PerIp {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Number of addresses currently tracked.
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    /// Is no address tracked?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl KeyedRateLimiter for PerIp {
+    fn check_key(&self, key: &IpAddr) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(*key)
+            .or_insert_with(|| TokenBucket::new(self.rate, self.burst));
+        match bucket.time_until(1) {
+            wait if wait > Duration::from_secs(0) => Err(wait),
+            _ => {
+                bucket.consume(1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Forget addresses whose buckets have refilled.
+    fn purge(&self) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| bucket.available() < bucket.burst());
+    }
+}
+
+// How often the per address limiter is purged by default
+const PURGE_EVERY: Duration = Duration::from_secs(60);
+
+/// Rate limits applied to an accept loop.
+pub struct AcceptLimits {
+    global: Option<Arc<dyn RateLimiter>>,
+    per_ip: Option<Arc<dyn KeyedRateLimiter>>,
+    close_mode: CloseMode,
+    purge_every: Duration,
+    rejected: AtomicU64,
+}
+
+impl Default for AcceptLimits {
+    fn default() -> AcceptLimits {
+        AcceptLimits {
+            global: None,
+            per_ip: None,
+            close_mode: CloseMode::default(),
+            purge_every: PURGE_EVERY,
+            rejected: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AcceptLimits {
+    /// Create an empty set of limits.
+    pub fn new() -> AcceptLimits {
+        AcceptLimits::default()
+    }
+
+    /// Limit the rate at which connections are accepted.
+    pub fn global(mut self, limiter: Arc<dyn RateLimiter>) -> Self {
+        self.global = Some(limiter);
+        self
+    }
+
+    /// Accept at most rate connections per second, allowing a burst of
+    /// up to rate. Shorthand for global() with a token bucket. A rate of
+    /// zero accepts one connection and then none.
+    pub fn max_accepts_per_sec(self, rate: u64) -> Self {
+        self.global(Arc::new(Mutex::new(TokenBucket::new(rate, rate))))
+    }
+
+    /// Limit the rate of connections from each peer address.
+    pub fn per_ip(mut self, limiter: Arc<dyn KeyedRateLimiter>) -> Self {
+        self.per_ip = Some(limiter);
+        self
+    }
+
+    /// Purge the per address limiter every interval while the loop
+    /// runs, rather than every minute.
+    pub fn purge_every(mut self, interval: Duration) -> Self {
+        self.purge_every = interval;
+        self
+    }
+
+    /// Close connections over the per address limit with mode.
+    pub fn reject_with(mut self, mode: CloseMode) -> Self {
+        self.close_mode = mode;
+        self
+    }
+
+    /// Number of connections rejected by the per address limit.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// subject to the limits.
+    pub fn handle_incoming<H>(
+        &self,
+        listener: &TcpListener,
+        mut handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut limited = |stream: TcpStream, addr: SocketAddr, _| {
+            if let Some(global) = &self.global {
+                while let Err(wait) = global.check() {
+                    // The listener may be closed meanwhile
+                    if is_listener_closed(listener) {
+                        return;
+                    }
+                    thread::sleep(std::cmp::min(wait, timeout));
+                }
+            }
+            let allowed = match &self.per_ip {
+                Some(per_ip) => per_ip.check_key(&addr.ip()).is_ok(),
+                None => true,
+            };
+            if allowed {
+                handler(stream);
+            } else {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
+                close_with(stream, self.close_mode);
+            }
+        };
+        let mut purge = |_: &TickInfo| {
+            if let Some(per_ip) = &self.per_ip {
+                per_ip.purge();
+            }
+        };
+        let callbacks = Callbacks {
+            on_tick: Some((self.purge_every, &mut purge)),
+            ..Default::default()
+        };
+        accept_loop(listener, &mut limited, timeout, callbacks).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+
+    static HANDLED: AtomicU64 = AtomicU64::new(0);
+
+    fn handle_client(_stream: TcpStream) {
+        HANDLED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_limits() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let limits = AcceptLimits::new()
+            .global(Arc::new(Mutex::new(TokenBucket::new(0, 2))))
+            .per_ip(Arc::new(PerIp::new(0, 1)));
+        let _streams: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        let l_clone = listener.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            l_clone.close();
+        });
+        limits
+            .handle_incoming(&listener, handle_client, Duration::from_millis(5))
+            .unwrap();
+        // The second was over the per address limit and the third was
+        // left waiting by the global limit
+        assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+        assert_eq!(limits.rejected(), 1);
+
+        let limits = AcceptLimits::new().max_accepts_per_sec(2);
+        let global = limits.global.as_ref().unwrap();
+        assert!(global.check().is_ok());
+        assert!(global.check().is_ok());
+        assert!(global.check().is_err());
+    }
+
+    #[test]
+    fn test_purge() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let per_ip = Arc::new(PerIp::new(1000, 1));
+        let limits = AcceptLimits::new()
+            .per_ip(per_ip.clone())
+            .purge_every(Duration::from_millis(10));
+        let _stream = TcpStream::connect(addr).unwrap();
+
+        let l_clone = listener.clone();
+        let p_clone = per_ip.clone();
+        thread::spawn(move || {
+            // Wait for the address to be tracked, then purged
+            while p_clone.is_empty() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            while !p_clone.is_empty() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            l_clone.close();
+        });
+        let mut handled = 0;
+        let reason = limits
+            .handle_incoming(&listener, |_| handled += 1, Duration::from_millis(5))
+            .unwrap();
+        assert_eq!(reason, StopReason::Closed);
+        assert_eq!(handled, 1);
+        assert!(per_ip.is_empty());
+    }
+}
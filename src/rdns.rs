@@ -0,0 +1,437 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reverse DNS enrichment of peer addresses.
+//!
+//! Reverse lookups are slow and may block for seconds, so they must
+//! never run on the accepting thread. A [ReverseDns](struct.ReverseDns.html)
+//! queues lookups for a background thread, which resolves them in
+//! batches and caches the results. Failed lookups are cached too (for a
+//! shorter time by default) so that addresses without PTR records
+//! aren't looked up over and over. The cache holds at most
+//! [DEFAULT_CAPACITY](constant.DEFAULT_CAPACITY.html) names unless
+//! changed with [capacity](struct.ReverseDns.html#method.capacity);
+//! when it is full, expired names are dropped first and then the least
+//! recently used.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Resolves addresses to PTR names.
+pub trait PtrResolver {
+    /// Look up the name for addr. May block.
+    fn reverse(&self, addr: &IpAddr) -> Option<String>;
+}
+
+impl<F> PtrResolver for F
+where
+    F: Fn(&IpAddr) -> Option<String>,
+{
+    fn reverse(&self, addr: &IpAddr) -> Option<String> {
+        self(addr)
+    }
+}
+
+/// Resolver which uses the system's getnameinfo().
+#[cfg(not(windows))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+#[cfg(not(windows))]
+impl PtrResolver for SystemResolver {
+    fn reverse(&self, addr: &IpAddr) -> Option<String> {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let len = match addr {
+            IpAddr::V4(ip) => {
+                let sin = &mut storage as *mut _ as *mut libc::sockaddr_in;
+                unsafe {
+                    (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                    (*sin).sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+                }
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            IpAddr::V6(ip) => {
+                let sin6 = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+                unsafe {
+                    (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    (*sin6).sin6_addr.s6_addr = ip.octets();
+                }
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        let mut host = [0 as libc::c_char; 1025];
+        let rc = unsafe {
+            libc::getnameinfo(
+                &storage as *const _ as *const libc::sockaddr,
+                len as libc::socklen_t,
+                host.as_mut_ptr(),
+                host.len() as _,
+                std::ptr::null_mut(),
+                0,
+                libc::NI_NAMEREQD,
+            )
+        };
+        if rc != 0 {
+            return None;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+        Some(name.to_string_lossy().into_owned())
+    }
+}
+
+/// State of a name in the [ReverseDns](struct.ReverseDns.html) cache.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PtrName {
+    /// The address resolved to this name.
+    Found(String),
+    /// The address has no name.
+    NotFound,
+    /// A lookup has been queued but hasn't finished.
+    Pending,
+}
+
+/// Default number of names cached by a [ReverseDns](struct.ReverseDns.html).
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+type NameCallback = Box<dyn FnOnce(&IpAddr, Option<&str>) + Send>;
+
+struct Entry {
+    name: Option<String>,
+    expires: Instant,
+    added: u64,
+    used: u64,
+}
+
+// Entries are also indexed by expiry and by last use, so that eviction
+// doesn't scan them all. Ticks order the uses and break ties between
+// equal expiry times. The lookup queue is kept here, under the lock, so
+// that ReverseDns is Sync without relying on Sender being Sync.
+struct Cache {
+    entries: HashMap<IpAddr, Entry>,
+    by_expiry: BTreeMap<(Instant, u64), IpAddr>,
+    by_use: BTreeMap<u64, IpAddr>,
+    tick: u64,
+    pending: HashMap<IpAddr, Vec<NameCallback>>,
+    capacity: usize,
+    queue: Option<Sender<IpAddr>>,
+}
+
+struct Inner {
+    cache: Mutex<Cache>,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+/// Background reverse DNS lookups with positive and negative caching.
+pub struct ReverseDns {
+    inner: Arc<Inner>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReverseDns {
+    /// Start a lookup thread using resolver. Names are cached for ttl
+    /// and failed lookups for negative_ttl.
+    pub fn new<R>(resolver: R, ttl: Duration, negative_ttl: Duration) -> ReverseDns
+    where
+        R: PtrResolver + Send + 'static,
+    {
+        let (queue, rx) = mpsc::channel::<IpAddr>();
+        let inner = Arc::new(Inner {
+            cache: Mutex::new(Cache {
+                entries: HashMap::new(),
+                by_expiry: BTreeMap::new(),
+                by_use: BTreeMap::new(),
+                tick: 0,
+                pending: HashMap::new(),
+                capacity: DEFAULT_CAPACITY,
+                queue: Some(queue),
+            }),
+            ttl,
+            negative_ttl,
+        });
+        let t_inner = inner.clone();
+        let thread = thread::spawn(move || {
+            while let Ok(first) = rx.recv() {
+                // Resolve everything queued so far as one batch
+                let batch: Vec<IpAddr> = std::iter::once(first).chain(rx.try_iter()).collect();
+                for addr in batch {
+                    let name = resolver.reverse(&addr);
+                    t_inner.complete(addr, name);
+                }
+            }
+        });
+        ReverseDns {
+            inner,
+            thread: Some(thread),
+        }
+    }
+
+    /// Cache at most capacity names, and queue at most capacity
+    /// lookups. Lookups beyond that are not queued and are treated as
+    /// failed by [resolve_with](#method.resolve_with).
+    pub fn capacity(self, capacity: usize) -> Self {
+        self.inner.cache.lock().unwrap().capacity = capacity.max(1);
+        self
+    }
+
+    /// Return the cached name for addr, queueing a lookup if it isn't
+    /// cached. Never blocks.
+    pub fn lookup(&self, addr: &IpAddr) -> PtrName {
+        let mut cache = self.inner.cache.lock().unwrap();
+        if let Some(name) = cache.cached(addr) {
+            return name;
+        }
+        self.queue(&mut cache, *addr);
+        PtrName::Pending
+    }
+
+    /// Call on_name with the name for addr once it is known. If it is
+    /// already cached, on_name is called immediately on this thread,
+    /// otherwise it is called later on the lookup thread. This is useful
+    /// for writing audit logs which include the peer's name.
+    pub fn resolve_with<F>(&self, addr: &IpAddr, on_name: F)
+    where
+        F: FnOnce(&IpAddr, Option<&str>) + Send + 'static,
+    {
+        let mut cache = self.inner.cache.lock().unwrap();
+        match cache.cached(addr) {
+            Some(PtrName::Found(name)) => {
+                drop(cache);
+                on_name(addr, Some(&name));
+            }
+            Some(PtrName::NotFound) => {
+                drop(cache);
+                on_name(addr, None);
+            }
+            Some(PtrName::Pending) | None => {
+                self.queue(&mut cache, *addr);
+                match cache.pending.get_mut(addr) {
+                    Some(waiting) => waiting.push(Box::new(on_name)),
+                    None => {
+                        drop(cache);
+                        on_name(addr, None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of lookups waiting to be resolved.
+    pub fn pending(&self) -> usize {
+        self.inner.cache.lock().unwrap().pending.len()
+    }
+
+    fn queue(&self, cache: &mut Cache, addr: IpAddr) {
+        if cache.pending.contains_key(&addr) || cache.pending.len() >= cache.capacity {
+            return;
+        }
+        cache.pending.insert(addr, vec![]);
+        if let Some(queue) = cache.queue.as_ref() {
+            let _ = queue.send(addr);
+        }
+    }
+}
+
+impl Drop for ReverseDns {
+    /// Queued lookups are finished before the lookup thread exits.
+    fn drop(&mut self) {
+        self.inner.cache.lock().unwrap().queue.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Cache {
+    fn cached(&mut self, addr: &IpAddr) -> Option<PtrName> {
+        if self.pending.contains_key(addr) {
+            return Some(PtrName::Pending);
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(addr) {
+            Some(entry) if entry.expires > Instant::now() => {
+                self.by_use.remove(&entry.used);
+                self.by_use.insert(tick, *addr);
+                entry.used = tick;
+                Some(match entry.name.as_ref() {
+                    Some(name) => PtrName::Found(name.clone()),
+                    None => PtrName::NotFound,
+                })
+            }
+            Some(_) => {
+                self.remove(addr);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, addr: IpAddr, name: Option<String>, expires: Instant) {
+        self.remove(&addr);
+        self.evict();
+        self.tick += 1;
+        let tick = self.tick;
+        self.by_expiry.insert((expires, tick), addr);
+        self.by_use.insert(tick, addr);
+        let entry = Entry {
+            name,
+            expires,
+            added: tick,
+            used: tick,
+        };
+        self.entries.insert(addr, entry);
+    }
+
+    fn remove(&mut self, addr: &IpAddr) {
+        if let Some(entry) = self.entries.remove(addr) {
+            self.by_expiry.remove(&(entry.expires, entry.added));
+            self.by_use.remove(&entry.used);
+        }
+    }
+
+    /// Make room for one more entry: expired names go first, then the
+    /// least recently used.
+    fn evict(&mut self) {
+        let now = Instant::now();
+        while self.entries.len() >= self.capacity {
+            let next = match self.by_expiry.iter().next() {
+                Some(((expires, _), addr)) if *expires <= now => Some(*addr),
+                _ => self.by_use.values().next().copied(),
+            };
+            match next {
+                Some(addr) => self.remove(&addr),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn complete(&self, addr: IpAddr, name: Option<String>) {
+        let ttl = if name.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        let waiting = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(addr, name.clone(), Instant::now() + ttl);
+            cache.pending.remove(&addr).unwrap_or_default()
+        };
+        for on_name in waiting {
+            on_name(&addr, name.as_deref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c_clone = calls.clone();
+        let rdns = ReverseDns::new(
+            move |addr: &IpAddr| {
+                c_clone.fetch_add(1, Ordering::SeqCst);
+                if addr.is_loopback() {
+                    Some("localhost".to_string())
+                } else {
+                    None
+                }
+            },
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let tx_clone = tx.clone();
+        rdns.resolve_with(&local, move |_, name| {
+            tx_clone.send(name.map(String::from)).unwrap()
+        });
+        rdns.resolve_with(&other, move |_, name| {
+            tx.send(name.map(String::from)).unwrap()
+        });
+        assert_eq!(rx.recv().unwrap(), Some("localhost".to_string()));
+        assert_eq!(rx.recv().unwrap(), None);
+
+        assert_eq!(rdns.lookup(&local), PtrName::Found("localhost".to_string()));
+        assert_eq!(rdns.lookup(&other), PtrName::NotFound);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let rdns = ReverseDns::new(
+            |_: &IpAddr| Some("name".to_string()),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .capacity(2);
+        for i in 1..=3u8 {
+            let addr = IpAddr::from([192, 0, 2, i]);
+            let (tx, rx) = mpsc::channel();
+            rdns.resolve_with(&addr, move |_, name| tx.send(name.is_some()).unwrap());
+            assert!(rx.recv().unwrap());
+            if i == 2 {
+                // Touch the first so that the second is least recently used
+                rdns.lookup(&IpAddr::from([192, 0, 2, 1]));
+            }
+        }
+        let cache = rdns.inner.cache.lock().unwrap();
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.entries.contains_key(&IpAddr::from([192, 0, 2, 1])));
+        assert!(!cache.entries.contains_key(&IpAddr::from([192, 0, 2, 2])));
+        assert_eq!((cache.by_use.len(), cache.by_expiry.len()), (2, 2));
+    }
+
+    #[test]
+    fn test_evict_expired() {
+        // Failed lookups expire at once
+        let rdns = ReverseDns::new(
+            |addr: &IpAddr| match addr {
+                IpAddr::V4(ip) if ip.octets()[3] == 2 => None,
+                _ => Some("name".to_string()),
+            },
+            Duration::from_secs(60),
+            Duration::from_secs(0),
+        )
+        .capacity(2);
+        for i in 1..=3u8 {
+            let (tx, rx) = mpsc::channel();
+            rdns.resolve_with(&IpAddr::from([192, 0, 2, i]), move |_, _| {
+                tx.send(()).unwrap()
+            });
+            rx.recv().unwrap();
+        }
+        // The expired second name goes before the least recently used
+        let cache = rdns.inner.cache.lock().unwrap();
+        assert!(cache.entries.contains_key(&IpAddr::from([192, 0, 2, 1])));
+        assert!(!cache.entries.contains_key(&IpAddr::from([192, 0, 2, 2])));
+    }
+
+    #[test]
+    fn test_sync() {
+        fn share<T: Send + Sync>(_: &T) {}
+        share(&ReverseDns::new(
+            |_: &IpAddr| None,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ));
+    }
+}
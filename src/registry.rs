@@ -0,0 +1,425 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Named handlers routed by local port or TLS server name.
+//!
+//! A [HandlerRegistry](struct.HandlerRegistry.html) holds handlers by
+//! name and a routing table from local ports and TLS server names to
+//! handler names, so that one accept loop over a
+//! [ListenerSet](../set/struct.ListenerSet.html) can serve several
+//! services. Routes can be given in code or loaded from a simple
+//! configuration text:
+//!
+//! ```text
+//! # port = handler
+//! 8080 = web
+//! 9090 = admin
+//! sni:api.example.com = api
+//! sni:*.example.com = web
+//! * = web
+//! ```
+//!
+//! where `*` names the handler for connections without their own route.
+//! A server name route wins over a port route. Server names are peeked
+//! from the ClientHello, as by [VirtualHosts](../vhost/struct.VirtualHosts.html),
+//! and only when there are server name routes, so plain TCP services
+//! don't wait for a ClientHello that never comes.
+//!
+//! The accept loop only routes connections: each handler runs on a
+//! thread of its own, so a slow service doesn't hold up the others. At
+//! most [DEFAULT_MAX_PENDING](constant.DEFAULT_MAX_PENDING.html)
+//! connections (see
+//! [set_max_pending()](struct.HandlerRegistry.html#method.set_max_pending))
+//! may be waiting for their server name at once, and connections beyond
+//! that are dropped.
+//!
+//! Unix socket paths aren't routed. A UnixListener is served by its own
+//! accept loop, see [UnixListenerExt](../unix/trait.UnixListenerExt.html).
+
+use crate::set::ListenerSet;
+use crate::tls::{peek_client_hello, ClientHello};
+use crate::vhost::Slot;
+use crate::StopReason;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::TcpStream;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Default limit on connections waiting for their server name.
+pub const DEFAULT_MAX_PENDING: usize = 256;
+
+type Handler = Arc<dyn Fn(TcpStream) + Send + Sync>;
+
+/// Handlers by name, and the ports and server names routed to them.
+pub struct HandlerRegistry {
+    handlers: HashMap<String, Handler>,
+    routes: HashMap<u16, String>,
+    server_names: HashMap<String, String>,
+    default: Option<String>,
+    peek_timeout: Duration,
+    max_pending: usize,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> HandlerRegistry {
+        HandlerRegistry {
+            handlers: HashMap::new(),
+            routes: HashMap::new(),
+            server_names: HashMap::new(),
+            default: None,
+            peek_timeout: Duration::from_secs(5),
+            max_pending: DEFAULT_MAX_PENDING,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl HandlerRegistry {
+    /// Create an empty registry, which waits up to 5 seconds for a
+    /// server name.
+    pub fn new() -> HandlerRegistry {
+        HandlerRegistry::default()
+    }
+
+    /// Register handler under name, replacing any handler already
+    /// registered with that name.
+    pub fn register<F>(&mut self, name: &str, handler: F)
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        self.handlers.insert(name.to_string(), Arc::new(handler));
+    }
+
+    /// Route connections to port to the handler called name.
+    pub fn route_port(&mut self, port: u16, name: &str) {
+        self.routes.insert(port, name.to_string());
+    }
+
+    /// Route TLS connections for server_name, which may be a wildcard
+    /// such as `*.example.com`, to the handler called name.
+    pub fn route_server_name(&mut self, server_name: &str, name: &str) {
+        self.server_names
+            .insert(server_name.to_ascii_lowercase(), name.to_string());
+    }
+
+    /// Route connections without a route to the handler called name.
+    pub fn route_default(&mut self, name: &str) {
+        self.default = Some(name.to_string());
+    }
+
+    /// Wait up to timeout for the ClientHello of each connection.
+    pub fn set_peek_timeout(&mut self, timeout: Duration) {
+        self.peek_timeout = timeout;
+    }
+
+    /// Let at most max_pending connections wait for their server name
+    /// at once.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    /// Add the routes in config, which has one "port = name" or
+    /// "sni:server_name = name" route per line. A port of "*" sets the
+    /// default route. Blank lines and lines starting with '#' are
+    /// ignored. Fails, without adding any routes, if a line is
+    /// malformed or names an unregistered handler.
+    pub fn load_routes(&mut self, config: &str) -> Result<(), Error> {
+        enum Route {
+            Port(u16),
+            ServerName(String),
+            Default,
+        }
+        let mut routes = vec![];
+        for (num, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: &str| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("line {}: {}: {}", num + 1, msg, line),
+                )
+            };
+            let mut parts = line.splitn(2, '=').map(str::trim);
+            let (key, name) = match (parts.next(), parts.next()) {
+                (Some(key), Some(name)) if !name.is_empty() => (key, name),
+                _ => return Err(invalid("expected port = name")),
+            };
+            if !self.handlers.contains_key(name) {
+                return Err(invalid("unknown handler"));
+            }
+            let route = if key == "*" {
+                Route::Default
+            } else if let Some(server_name) = key.strip_prefix("sni:") {
+                if server_name.is_empty() {
+                    return Err(invalid("bad server name"));
+                }
+                Route::ServerName(server_name.to_string())
+            } else {
+                Route::Port(key.parse::<u16>().map_err(|_| invalid("bad port"))?)
+            };
+            routes.push((route, name));
+        }
+        for (route, name) in routes {
+            match route {
+                Route::Port(port) => self.route_port(port, name),
+                Route::ServerName(server_name) => self.route_server_name(&server_name, name),
+                Route::Default => self.route_default(name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the handler for connections to port, if any.
+    pub fn route(&self, port: u16) -> Option<&str> {
+        self.routes
+            .get(&port)
+            .or(self.default.as_ref())
+            .map(|name| name.as_str())
+    }
+
+    /// Name of the handler for TLS connections for server_name, if it
+    /// has a route. Exact names win over wildcards, and longer
+    /// wildcards over shorter.
+    pub fn route_tls(&self, server_name: &str) -> Option<&str> {
+        let server_name = server_name.to_ascii_lowercase();
+        if let Some(name) = self.server_names.get(&server_name) {
+            return Some(name);
+        }
+        let mut suffix = server_name.as_str();
+        while let Some(dot) = suffix.find('.') {
+            suffix = &suffix[dot + 1..];
+            if let Some(name) = self.server_names.get(&format!("*.{}", suffix)) {
+                return Some(name);
+            }
+        }
+        None
+    }
+
+    /// Pass stream to the handler for its server name or the port it
+    /// was accepted on, on the calling thread. If there are server name
+    /// routes this first waits for the ClientHello. If there's no such
+    /// handler, stream is handed back.
+    pub fn dispatch(&self, stream: TcpStream) -> Result<(), TcpStream> {
+        match self.handler(&stream) {
+            Some(handler) => {
+                handler(stream);
+                Ok(())
+            }
+            None => Err(stream),
+        }
+    }
+
+    /// Accept connections from every listener in set until they are all
+    /// closed, dispatching each one on a thread of its own. Connections
+    /// without a handler are dropped, as are connections arriving while
+    /// max_pending connections are waiting for their server name.
+    pub fn handle_incoming(
+        self: &Arc<Self>,
+        set: &ListenerSet,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error> {
+        set.handle_incoming_with(
+            |stream, _| {
+                let waiting = if self.server_names.is_empty() {
+                    None
+                } else {
+                    match Slot::acquire(&self.pending, self.max_pending) {
+                        Some(slot) => Some(slot),
+                        None => return,
+                    }
+                };
+                let registry = self.clone();
+                thread::spawn(move || {
+                    let handler = registry.handler(&stream);
+                    drop(waiting);
+                    if let Some(handler) = handler {
+                        handler(stream);
+                    }
+                });
+            },
+            timeout,
+        )
+    }
+
+    fn handler(&self, stream: &TcpStream) -> Option<Handler> {
+        let name = self
+            .server_name(stream)
+            .and_then(|server_name| self.route_tls(&server_name))
+            .or_else(|| {
+                stream
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| self.route(addr.port()))
+            })?;
+        self.handlers.get(name).cloned()
+    }
+
+    fn server_name(&self, stream: &TcpStream) -> Option<String> {
+        if self.server_names.is_empty() {
+            return None;
+        }
+        let record = peek_client_hello(stream, self.peek_timeout).ok()??;
+        ClientHello::parse(&record)?.server_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+
+    fn request(port: u16) -> String {
+        send(port, b"")
+    }
+
+    fn send(port: u16, data: &[u8]) -> String {
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(data).unwrap();
+        let mut buf = String::new();
+        let _ = client.read_to_string(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut set = ListenerSet::new();
+        let mut ports = vec![];
+        for _ in 0..3 {
+            let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+            ports.push(listener.local_addr().unwrap().port());
+            set.add(listener);
+        }
+        let mut registry = HandlerRegistry::new();
+        registry.register("web", |mut s: TcpStream| s.write_all(b"web").unwrap());
+        registry.register("admin", |mut s: TcpStream| s.write_all(b"admin").unwrap());
+        let config = format!("# routes\n{} = admin\n\n* = web\n", ports[1]);
+        assert!(registry.load_routes("1 = nothing").is_err());
+        assert!(registry.load_routes("x = web").is_err());
+        registry.load_routes(&config).unwrap();
+
+        let set = Arc::new(set);
+        let registry = Arc::new(registry);
+        let (s_clone, r_clone) = (set.clone(), registry.clone());
+        let server = thread::spawn(move || {
+            r_clone
+                .handle_incoming(&s_clone, Duration::from_millis(1))
+                .unwrap()
+        });
+        assert_eq!(request(ports[0]), "web");
+        assert_eq!(request(ports[1]), "admin");
+        assert_eq!(request(ports[2]), "web");
+        set.close();
+        server.join().unwrap();
+    }
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = vec![];
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+        let mut ext = vec![0, 0];
+        ext.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        ext.extend_from_slice(&sni);
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+        let mut hs = vec![1, 0];
+        hs.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hs.extend_from_slice(&body);
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&(hs.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hs);
+        record
+    }
+
+    #[test]
+    fn test_server_name() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut set = ListenerSet::new();
+        set.add(listener);
+        let mut registry = HandlerRegistry::new();
+        registry.register("web", |mut s: TcpStream| s.write_all(b"web").unwrap());
+        registry.register("api", |mut s: TcpStream| s.write_all(b"api").unwrap());
+        registry.register("www", |mut s: TcpStream| s.write_all(b"www").unwrap());
+        assert!(registry.load_routes("sni: = web").is_err());
+        registry
+            .load_routes("sni:api.example.com = api\nsni:*.example.com = www\n* = web")
+            .unwrap();
+        assert_eq!(registry.route_tls("API.example.com"), Some("api"));
+        assert_eq!(registry.route_tls("example.org"), None);
+
+        let set = Arc::new(set);
+        let registry = Arc::new(registry);
+        let (s_clone, r_clone) = (set.clone(), registry.clone());
+        let server = thread::spawn(move || {
+            r_clone
+                .handle_incoming(&s_clone, Duration::from_millis(1))
+                .unwrap()
+        });
+        assert_eq!(send(port, &client_hello("api.example.com")), "api");
+        assert_eq!(send(port, &client_hello("a.b.example.com")), "www");
+        assert_eq!(send(port, &client_hello("example.org")), "web");
+        assert_eq!(send(port, b"GET / HTTP/1.0\r\n\r\n"), "web");
+        set.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_slow_handler() {
+        let mut set = ListenerSet::new();
+        let mut ports = vec![];
+        for _ in 0..2 {
+            let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+            ports.push(listener.local_addr().unwrap().port());
+            set.add(listener);
+        }
+        let (tx, rx) = channel::<()>();
+        let rx = Mutex::new(rx);
+        let mut registry = HandlerRegistry::new();
+        registry.register("slow", move |mut s: TcpStream| {
+            let _ = rx.lock().unwrap().recv();
+            s.write_all(b"slow").unwrap();
+        });
+        registry.register("web", |mut s: TcpStream| s.write_all(b"web").unwrap());
+        registry.route_port(ports[0], "slow");
+        registry.route_default("web");
+
+        let set = Arc::new(set);
+        let registry = Arc::new(registry);
+        let (s_clone, r_clone) = (set.clone(), registry.clone());
+        let server = thread::spawn(move || {
+            r_clone
+                .handle_incoming(&s_clone, Duration::from_millis(1))
+                .unwrap()
+        });
+        // The slow handler doesn't hold up the other port
+        let slow_port = ports[0];
+        let slow = thread::spawn(move || request(slow_port));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(request(ports[1]), "web");
+        tx.send(()).unwrap();
+        assert_eq!(slow.join().unwrap(), "slow");
+        set.close();
+        server.join().unwrap();
+    }
+}
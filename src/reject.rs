@@ -0,0 +1,200 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! How rejected connections are closed.
+//!
+//! Dropping a rejected connection closes it gracefully with a FIN,
+//! which leaves the socket in TIME_WAIT. During overload, thousands of
+//! those are a problem in their own right, so a connection can instead
+//! be aborted with a RST, by setting SO_LINGER to zero before closing.
+//! A [RejectPolicy](struct.RejectPolicy.html) picks the mode for each
+//! [QosClass](../qos/enum.QosClass.html). A [RejectAction](enum.RejectAction.html)
+//! can also send a response, such as an HTTP 503 page, before closing.
+
+use crate::plat_specifics::*;
+use crate::qos::QosClass;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+
+/// How to close a rejected connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CloseMode {
+    /// Close normally, with a FIN.
+    #[default]
+    Graceful,
+    /// Abort with a RST, so no TIME_WAIT state is left behind.
+    Abort,
+}
+
+/// Close stream as mode says.
+pub fn close_with(stream: TcpStream, mode: CloseMode) {
+    if mode == CloseMode::Abort {
+        set_linger_zero(&stream);
+    }
+    drop(stream);
+}
+
+/// What to do with a rejected connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RejectAction {
+    /// Close it.
+    Close(CloseMode),
+    /// Send a response, then close it gracefully.
+    Respond(Vec<u8>),
+}
+
+impl RejectAction {
+    /// Respond with an HTTP 503 Service Unavailable and body.
+    pub fn http_503(body: &str) -> RejectAction {
+        RejectAction::Respond(
+            format!(
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\n\
+                 Content-Length: {}\r\nConnection: close\r\nRetry-After: 60\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .into_bytes(),
+        )
+    }
+
+    /// Apply the action to stream. A response is written without
+    /// blocking, so a peer which doesn't read can't stall the caller.
+    /// It goes into the socket's send buffer, which is empty on a new
+    /// connection, so a response of up to a few KB is sent in full;
+    /// whatever doesn't fit is dropped.
+    pub fn apply(&self, mut stream: TcpStream) {
+        match self {
+            RejectAction::Close(mode) => close_with(stream, *mode),
+            RejectAction::Respond(response) => {
+                if stream.set_nonblocking(true).is_ok() {
+                    let _ = stream.write(response);
+                }
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+        }
+    }
+}
+
+impl Default for RejectAction {
+    fn default() -> RejectAction {
+        RejectAction::Close(CloseMode::Graceful)
+    }
+}
+
+fn set_linger_zero(stream: &TcpStream) {
+    #[cfg(not(windows))]
+    unsafe {
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        );
+    }
+    #[cfg(windows)]
+    unsafe {
+        let linger = winsock2::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        winsock2::setsockopt(
+            stream.as_raw_socket() as winsock2::SOCKET,
+            winsock2::SOL_SOCKET,
+            winsock2::SO_LINGER,
+            &linger as *const winsock2::linger as *const i8,
+            std::mem::size_of::<winsock2::linger>() as i32,
+        );
+    }
+}
+
+/// Close mode for rejected connections, per class.
+#[derive(Clone, Debug, Default)]
+pub struct RejectPolicy {
+    default: CloseMode,
+    classes: HashMap<QosClass, CloseMode>,
+}
+
+impl RejectPolicy {
+    /// Create a policy which closes every rejected connection with mode.
+    pub fn new(default: CloseMode) -> RejectPolicy {
+        RejectPolicy {
+            default,
+            classes: HashMap::new(),
+        }
+    }
+
+    /// Close rejected connections in class with mode.
+    pub fn class(mut self, class: QosClass, mode: CloseMode) -> Self {
+        self.classes.insert(class, mode);
+        self
+    }
+
+    /// The mode for a connection in class, or without a class.
+    pub fn mode(&self, class: Option<QosClass>) -> CloseMode {
+        class
+            .and_then(|c| self.classes.get(&c).copied())
+            .unwrap_or(self.default)
+    }
+
+    /// Close a rejected connection.
+    pub fn reject(&self, stream: TcpStream, class: Option<QosClass>) {
+        close_with(stream, self.mode(class))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{ErrorKind, Read};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_close_modes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let policy =
+            RejectPolicy::new(CloseMode::Graceful).class(QosClass::Background, CloseMode::Abort);
+        assert_eq!(policy.mode(None), CloseMode::Graceful);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        policy.reject(listener.accept().unwrap().0, Some(QosClass::Normal));
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        policy.reject(listener.accept().unwrap().0, Some(QosClass::Background));
+        let err = client.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_respond_without_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        RejectAction::http_503("down").apply(listener.accept().unwrap().0);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(reply.ends_with("\r\n\r\ndown"));
+
+        // Far more than the send buffer holds, to a peer which never
+        // reads
+        let _client = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        RejectAction::Respond(vec![0; 64 << 20]).apply(listener.accept().unwrap().0);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Hand a listening socket to a new process (Unix only).
+//!
+//! For a zero downtime restart, the old process starts the new one with
+//! [spawn_with()](fn.spawn_with.html), which passes it the listening
+//! socket. The new process picks it up with [inherited()](fn.inherited.html)
+//! and starts accepting, while the old one stops its accept loop without
+//! closing the socket, e.g. with a
+//! [ListenerHandle](../stop/struct.ListenerHandle.html), and exits. The
+//! socket stays bound throughout, so connections queued in the kernel
+//! are accepted by whichever process gets to them first and none are
+//! refused. To re-exec in place, pass the descriptor from
+//! [Listener::into_raw_parts()](../trait.Listener.html#tymethod.into_raw_parts)
+//! in [LISTEN_FD_VAR](constant.LISTEN_FD_VAR.html) instead.
+
+use crate::Listener;
+use std::env;
+use std::io::Error;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{Child, Command};
+
+/// Environment variable holding the inherited descriptor.
+pub const LISTEN_FD_VAR: &str = "NBLISTENER_FD";
+
+// Set or clear FD_CLOEXEC, which decides whether exec() closes fd.
+pub(crate) fn set_cloexec(fd: RawFd, cloexec: bool) -> Result<(), Error> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(Error::last_os_error());
+    }
+    let flags = if cloexec {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Spawn command with a copy of listener, named in its environment.
+/// The caller's listener is unaffected.
+pub fn spawn_with(listener: &TcpListener, command: &mut Command) -> Result<Child, Error> {
+    // Closed in this process when the copy is dropped
+    let copy = listener.try_clone()?;
+    set_cloexec(copy.as_raw_fd(), false)?;
+    command
+        .env(LISTEN_FD_VAR, copy.as_raw_fd().to_string())
+        .spawn()
+}
+
+/// Take ownership of the listener passed by the parent process, if
+/// there is one, making it non-blocking. The variable is removed, so
+/// the listener isn't passed on to this process's own children.
+pub fn inherited() -> Result<Option<TcpListener>, Error> {
+    let fd: RawFd = match env::var(LISTEN_FD_VAR).ok().and_then(|v| v.parse().ok()) {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    env::remove_var(LISTEN_FD_VAR);
+    set_cloexec(fd, true)?;
+    // The parent promised fd is a listener meant for this process
+    let listener: TcpListener = unsafe { Listener::from_raw_fd(fd) }?;
+    Ok(Some(listener))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_handover() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Queued before the handover, accepted after it
+        let client = TcpStream::connect(addr).unwrap();
+
+        let fd = listener.into_raw_parts().unwrap();
+        env::set_var(LISTEN_FD_VAR, fd.to_string());
+        let listener = inherited().unwrap().unwrap();
+        assert!(env::var(LISTEN_FD_VAR).is_err());
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Run a TLS handshake before the handler.
+//!
+//! A [SecureListener](struct.SecureListener.html) wraps the accept
+//! loop: each connection is passed to a [TlsAcceptor](trait.TlsAcceptor.html),
+//! and the handler receives the stream it returns once the handshake
+//! has completed. Failed handshakes are reported to an error callback
+//! and the connection is dropped. close() terminates the loop as usual.
+//!
+//! The crate doesn't depend on a TLS library, so the acceptor is
+//! implemented by the application. With rustls, for example, accept()
+//! creates a ServerConnection, drives complete_io() until the handshake
+//! is done and returns the StreamOwned.
+
+use crate::{accept_loop, Callbacks, StopReason};
+use std::io::Error;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Performs the server side of a TLS handshake.
+pub trait TlsAcceptor {
+    /// The encrypted stream passed to the handler.
+    type Stream;
+
+    /// Complete the handshake on stream, which is blocking and has read
+    /// and write timeouts set.
+    fn accept(&self, stream: TcpStream) -> Result<Self::Stream, Error>;
+}
+
+type ErrorCallback = Box<dyn Fn(&SocketAddr, &Error) + Send + Sync>;
+
+/// Accept loop which hands the handler streams that have completed a
+/// TLS handshake.
+pub struct SecureListener<A> {
+    acceptor: A,
+    handshake_timeout: Duration,
+    on_error: Option<ErrorCallback>,
+}
+
+impl<A: TlsAcceptor> SecureListener<A> {
+    /// Handshake with acceptor, allowing 10 seconds per handshake.
+    pub fn new(acceptor: A) -> SecureListener<A> {
+        SecureListener {
+            acceptor,
+            handshake_timeout: Duration::from_secs(10),
+            on_error: None,
+        }
+    }
+
+    /// Time allowed for each read and write during a handshake. The
+    /// timeouts are cleared before the handler is called.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Call on_error with the peer address and error when a handshake
+    /// fails.
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: Fn(&SocketAddr, &Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming).
+    /// Handshakes run on the accepting thread, so a slow client delays
+    /// other accepts by up to the handshake timeout; a
+    /// [HandshakePool](../handshake/struct.HandshakePool.html) avoids
+    /// this.
+    pub fn handle_incoming<H>(
+        &self,
+        listener: &TcpListener,
+        mut handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(A::Stream),
+    {
+        let mut handle = |stream, addr, _| match self.handshake(stream) {
+            Ok(stream) => handler(stream),
+            Err(err) => {
+                if let Some(on_error) = self.on_error.as_ref() {
+                    on_error(&addr, &err);
+                }
+            }
+        };
+        accept_loop(listener, &mut handle, timeout, Callbacks::default())
+            .map_err(crate::Error::from)
+    }
+
+    fn handshake(&self, stream: TcpStream) -> Result<A::Stream, Error> {
+        let timeout = Some(self.handshake_timeout);
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        // Keep a handle, to clear the timeouts once the stream is wrapped
+        let raw = stream.try_clone()?;
+        let secure = self.acceptor.accept(stream)?;
+        raw.set_read_timeout(None)?;
+        raw.set_write_timeout(None)?;
+        Ok(secure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    // A toy handshake: the client must send "HELLO"
+    struct Hello;
+
+    impl TlsAcceptor for Hello {
+        type Stream = (TcpStream, &'static str);
+
+        fn accept(&self, mut stream: TcpStream) -> Result<Self::Stream, Error> {
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf)?;
+            if &buf != b"HELLO" {
+                return Err(Error::new(ErrorKind::InvalidData, "bad hello"));
+            }
+            Ok((stream, "secure"))
+        }
+    }
+
+    #[test]
+    fn test_secure() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let e_clone = errors.clone();
+        let secure = SecureListener::new(Hello)
+            .handshake_timeout(Duration::from_secs(5))
+            .on_error(move |_, err| e_clone.lock().unwrap().push(err.to_string()));
+
+        let mut bad = TcpStream::connect(addr).unwrap();
+        bad.write_all(b"HOWDY").unwrap();
+        let mut good = TcpStream::connect(addr).unwrap();
+        good.write_all(b"HELLO").unwrap();
+        let mut handled = vec![];
+        secure
+            .handle_incoming(
+                &listener,
+                |(stream, tag)| {
+                    handled.push(tag);
+                    assert_eq!(stream.read_timeout().unwrap(), None);
+                    listener.close();
+                },
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        assert_eq!(handled, vec!["secure"]);
+        assert_eq!(*errors.lock().unwrap(), vec!["bad hello".to_string()]);
+    }
+}
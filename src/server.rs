@@ -0,0 +1,149 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An accept loop running as a background service.
+//!
+//! Most applications run handle_incoming() on its own thread, sharing
+//! the listener in an Arc so that another thread can close it. A
+//! [Server](struct.Server.html) does this for them: start() spawns the
+//! loop and returns a [ServerHandle](struct.ServerHandle.html) to stop
+//! it, wait for it, and find out where it is listening.
+
+use crate::{Error, Listener, StopReason};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// An accept loop waiting to be started.
+pub struct Server<H> {
+    listener: TcpListener,
+    handler: H,
+    timeout: Duration,
+}
+
+impl<H> Server<H>
+where
+    H: FnMut(TcpStream) + Send + 'static,
+{
+    /// Serve connections to listener with handler, sleeping for 10ms
+    /// while none is waiting.
+    pub fn new(listener: TcpListener, handler: H) -> Server<H> {
+        Server {
+            listener,
+            handler,
+            timeout: Duration::from_millis(10),
+        }
+    }
+
+    /// Sleep for timeout while no connection is waiting.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Make the listener non-blocking and start handling incoming
+    /// connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// on a new thread.
+    pub fn start(self) -> Result<ServerHandle, io::Error> {
+        self.listener.set_nonblocking(true)?;
+        let local_addr = self.listener.local_addr()?;
+        let listener = Arc::new(self.listener);
+        let running = Arc::new(AtomicBool::new(true));
+        let l_clone = listener.clone();
+        let r_clone = running.clone();
+        let handler = self.handler;
+        let timeout = self.timeout;
+        let thread = thread::spawn(move || {
+            // Clears the flag even if the handler panics
+            let _running = Running(r_clone);
+            l_clone.handle_incoming(handler, timeout)
+        });
+        Ok(ServerHandle {
+            listener,
+            local_addr,
+            running,
+            thread,
+        })
+    }
+}
+
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Controls a started [Server](struct.Server.html).
+pub struct ServerHandle {
+    listener: Arc<TcpListener>,
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<Result<StopReason, Error>>,
+}
+
+impl ServerHandle {
+    /// Close the listener, so the loop terminates normally once the
+    /// current connection, if any, has been handled.
+    pub fn stop(&self) {
+        self.listener.close();
+    }
+
+    /// Wait for the loop to terminate. A handler panic is reported as
+    /// [Error::HandlerPanicked](../enum.Error.html#variant.HandlerPanicked).
+    pub fn join(self) -> Result<StopReason, Error> {
+        self.thread.join().unwrap_or(Err(Error::HandlerPanicked))
+    }
+
+    /// Is the loop still running?
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(listener, |mut stream: TcpStream| {
+            let _ = stream.write_all(b"hello");
+        })
+        .timeout(Duration::from_millis(1))
+        .start()
+        .unwrap();
+        assert!(server.is_running());
+
+        let mut reply = String::new();
+        TcpStream::connect(server.local_addr())
+            .unwrap()
+            .read_to_string(&mut reply)
+            .unwrap();
+        assert_eq!(reply, "hello");
+
+        server.stop();
+        while server.is_running() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        server.join().unwrap();
+    }
+}
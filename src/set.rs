@@ -0,0 +1,420 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Accept connections from several listeners in one loop.
+//!
+//! Listeners are serviced in rounds, in the order they were added. In
+//! each round a listener may accept up to its weight in connections
+//! before the next listener is serviced. Every listener has a weight of
+//! at least one, so none of them can be starved, but a listener added
+//! first (e.g. an admin socket) is always serviced before the others.
+//! Listeners can be given [Labels](../labels/struct.Labels.html), which
+//! are passed to the handler along with each connection.
+//!
+//! [ListenerSet::bind()](struct.ListenerSet.html#method.bind) binds a
+//! listener to every address a name resolves to, e.g. both the IPv4 and
+//! IPv6 loopback addresses for `localhost`, so that one loop and one
+//! close() serve them all. [bind_dual_stack()](struct.ListenerSet.html#method.bind_dual_stack)
+//! listens on a port on every IPv4 and IPv6 address.
+//! [bind_with_policy()](struct.ListenerSet.html#method.bind_with_policy)
+//! tolerates addresses which can't be bound, following a
+//! [BindPolicy](enum.BindPolicy.html).
+//!
+//! When no listener has a connection ready, the loop waits on all of
+//! them at once with [wait_readable()](../poll/fn.wait_readable.html),
+//! so it wakes as soon as any of them has a connection. The loop carries
+//! on after recoverable accept errors, but doesn't assign connection
+//! ids, record stats or call hooks.
+
+#[cfg(unix)]
+use crate::builder::ListenerBuilder;
+use crate::labels::Labels;
+use crate::plat_specifics::*;
+use crate::poll::wait_readable;
+use crate::{drained, hooks, is_closed, Listener, StopReason};
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+struct Member {
+    listener: TcpListener,
+    weight: usize,
+    labels: Labels,
+}
+
+/// Which of the addresses a name resolves to are bound.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BindPolicy {
+    /// The first address, in resolved order, which can be bound.
+    #[default]
+    First,
+    /// Every address which can be bound.
+    All,
+    /// As First, but trying the IPv6 addresses before the IPv4 ones.
+    PreferV6,
+}
+
+/// A set of listeners handled by a single accept loop.
+#[derive(Default)]
+pub struct ListenerSet {
+    members: Vec<Member>,
+}
+
+impl ListenerSet {
+    /// Create an empty set.
+    pub fn new() -> ListenerSet {
+        ListenerSet::default()
+    }
+
+    /// Create a set with a listener bound to each of addr's addresses.
+    /// Fails if any of them can't be bound.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<ListenerSet, Error> {
+        let mut set = ListenerSet::new();
+        for addr in addr.to_socket_addrs()? {
+            set.add(Listener::bind(addr)?);
+        }
+        if set.members.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            ));
+        }
+        Ok(set)
+    }
+
+    /// Create a set with listeners bound to addr's addresses according
+    /// to policy. Addresses which can't be bound are skipped; it only
+    /// fails, with the last error, if none can be.
+    pub fn bind_with_policy<A: ToSocketAddrs>(
+        addr: A,
+        policy: BindPolicy,
+    ) -> Result<ListenerSet, Error> {
+        let mut addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if policy == BindPolicy::PreferV6 {
+            // Stable, so each family keeps its resolved order
+            addrs.sort_by_key(|a| a.is_ipv4());
+        }
+        let mut set = ListenerSet::new();
+        let mut last_err = None;
+        for addr in addrs {
+            match Listener::bind(addr) {
+                Ok(listener) => {
+                    set.add(listener);
+                    if policy != BindPolicy::All {
+                        break;
+                    }
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        if set.members.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "could not resolve to any address")
+            }));
+        }
+        Ok(set)
+    }
+
+    /// Create a set listening on port on every IPv4 and IPv6 address.
+    /// Where the platform allows (Unix), this is a single IPv6 socket
+    /// with IPV6_V6ONLY cleared, which also accepts IPv4 connections.
+    /// Otherwise, e.g. on Windows or where IPV6_V6ONLY is forced on,
+    /// there is an IPv4 socket and an IPv6 only socket on the same
+    /// port. A host without IPv6 gets just the IPv4 socket. A port of
+    /// zero picks the same free port for both.
+    pub fn bind_dual_stack(port: u16) -> Result<ListenerSet, Error> {
+        let mut set = ListenerSet::new();
+        #[cfg(unix)]
+        {
+            let builder = ListenerBuilder::new().only_v6(false);
+            if let Ok(listener) = builder.bind((Ipv6Addr::UNSPECIFIED, port)) {
+                set.add(listener.into_inner());
+                return Ok(set);
+            }
+        }
+        // Bind IPv4 first, so that a zero port is only chosen once
+        let v4: TcpListener = Listener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        let port = v4.local_addr()?.port();
+        set.add(v4);
+        #[cfg(unix)]
+        let v6 = ListenerBuilder::new()
+            .only_v6(true)
+            .bind((Ipv6Addr::UNSPECIFIED, port))
+            .map(|l| l.into_inner());
+        // IPv6 sockets are IPv6 only by default on Windows
+        #[cfg(windows)]
+        let v6 = Listener::bind((Ipv6Addr::UNSPECIFIED, port));
+        if let Ok(v6) = v6 {
+            set.add(v6);
+        }
+        Ok(set)
+    }
+
+    /// The addresses the listeners are bound to.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.members
+            .iter()
+            .filter_map(|m| m.listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Add a listener with a weight of one.
+    pub fn add(&mut self, listener: TcpListener) {
+        self.add_weighted(listener, 1)
+    }
+
+    /// Add a listener which may accept up to weight connections in each
+    /// round. A weight of zero is treated as one.
+    pub fn add_weighted(&mut self, listener: TcpListener, weight: usize) {
+        self.add_labeled(listener, weight, Labels::new())
+    }
+
+    /// Add a weighted listener with labels.
+    pub fn add_labeled(&mut self, listener: TcpListener, weight: usize, labels: Labels) {
+        self.members.push(Member {
+            listener,
+            weight: std::cmp::max(weight, 1),
+            labels,
+        });
+    }
+
+    /// The labels of the listener bound to port, if it is in the set.
+    pub fn labels(&self, port: u16) -> Option<&Labels> {
+        self.members
+            .iter()
+            .find(|m| m.listener.local_addr().map(|a| a.port()).ok() == Some(port))
+            .map(|m| &m.labels)
+    }
+
+    /// The listeners in this set.
+    pub fn listeners(&self) -> impl Iterator<Item = &TcpListener> {
+        self.members.iter().map(|m| &m.listener)
+    }
+
+    /// Borrow the descriptor of each listener in the set, e.g. to
+    /// register them with a polling library.
+    #[cfg(not(windows))]
+    pub fn as_fds(&self) -> Vec<BorrowedFd<'_>> {
+        self.members.iter().map(|m| m.listener.as_fd()).collect()
+    }
+
+    /// Borrow the socket of each listener in the set, e.g. to register
+    /// them with a polling library.
+    #[cfg(windows)]
+    pub fn as_sockets(&self) -> Vec<BorrowedSocket<'_>> {
+        self.members
+            .iter()
+            .map(|m| m.listener.as_socket())
+            .collect()
+    }
+
+    /// Close every listener in the set. An active handle_incoming()
+    /// will terminate normally.
+    pub fn close(&self) {
+        for member in self.members.iter() {
+            member.listener.close();
+        }
+    }
+
+    /// Start handling incoming connections on all listeners. If no
+    /// listener has a connection ready, wait up to timeout for one. Terminates
+    /// normally once every listener has been closed and with an error
+    /// on any other accept failure. handler may be a plain function or a
+    /// closure which captures application state.
+    pub fn handle_incoming<F>(
+        &self,
+        mut handler: F,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error>
+    where
+        F: FnMut(TcpStream),
+    {
+        self.handle_incoming_with(|stream, _| handler(stream), timeout)
+    }
+
+    /// Works like handle_incoming(), but handler is a closure which is
+    /// also given the peer address.
+    pub fn handle_incoming_with<F>(
+        &self,
+        mut handler: F,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error>
+    where
+        F: FnMut(TcpStream, SocketAddr),
+    {
+        self.handle_incoming_labeled(|stream, addr, _| handler(stream, addr), timeout)
+    }
+
+    /// Works like handle_incoming_with(), but handler is also given the
+    /// labels of the listener which accepted the connection.
+    pub fn handle_incoming_labeled<F>(
+        &self,
+        mut handler: F,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error>
+    where
+        F: FnMut(TcpStream, SocketAddr, &Labels),
+    {
+        let mut closed = vec![false; self.members.len()];
+        let mut reason = StopReason::Closed;
+        loop {
+            let mut accepted = false;
+            for (idx, member) in self.members.iter().enumerate() {
+                if closed[idx] {
+                    continue;
+                }
+                for _ in 0..member.weight {
+                    match member.listener.accept() {
+                        Ok((stream, addr)) => {
+                            accepted = true;
+                            handler(stream, addr, &member.labels);
+                        }
+                        Err(err) => {
+                            if err.kind() == ErrorKind::WouldBlock {
+                                if drained(&member.listener) {
+                                    closed[idx] = true;
+                                    reason = StopReason::Drained;
+                                }
+                                break;
+                            } else if is_closed(&err) {
+                                closed[idx] = true;
+                                break;
+                            } else if let Some(sleep) = hooks::recoverable(&err) {
+                                if sleep {
+                                    thread::sleep(timeout);
+                                }
+                                break;
+                            }
+                            return Err(err.into());
+                        }
+                    }
+                }
+            }
+            if closed.iter().all(|c| *c) {
+                return Ok(reason);
+            }
+            if !accepted {
+                let open: Vec<&TcpListener> = self
+                    .members
+                    .iter()
+                    .zip(closed.iter())
+                    .filter(|(_, closed)| !**closed)
+                    .map(|(m, _)| &m.listener)
+                    .collect();
+                if wait_readable(&open, timeout).is_err() {
+                    thread::sleep(timeout);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_weighted() {
+        let admin: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let public: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let a = admin.local_addr().unwrap();
+        let p = public.local_addr().unwrap();
+        let mut clients = vec![];
+        for _ in 0..3 {
+            clients.push(TcpStream::connect(p).unwrap());
+            clients.push(TcpStream::connect(a).unwrap());
+        }
+
+        let mut set = ListenerSet::new();
+        set.add_weighted(admin, 2);
+        set.add(public);
+        let set = Arc::new(set);
+        let s_clone = set.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            s_clone.close();
+        });
+        let mut order = vec![];
+        set.handle_incoming(
+            |stream| order.push(stream.local_addr().unwrap().port()),
+            Duration::from_millis(10),
+        )
+        .unwrap();
+
+        let (a, p) = (a.port(), p.port());
+        assert_eq!(order, vec![a, a, p, a, p, p]);
+    }
+
+    #[test]
+    fn test_bind() {
+        let addrs: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap(); 2];
+        let set = ListenerSet::bind(&addrs[..]).unwrap();
+        let bound = set.local_addrs();
+        assert_eq!(bound.len(), 2);
+        let _clients: Vec<TcpStream> = bound
+            .iter()
+            .map(|a| TcpStream::connect(a).unwrap())
+            .collect();
+        let mut ports = vec![];
+        set.handle_incoming_with(
+            |stream, _| {
+                ports.push(stream.local_addr().unwrap());
+                if ports.len() == 2 {
+                    set.close();
+                }
+            },
+            Duration::from_millis(5),
+        )
+        .unwrap();
+        assert_eq!(ports, bound);
+    }
+
+    #[test]
+    fn test_bind_policy() {
+        // Hosts without IPv6 can't bind the last candidate
+        if TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        // The port is taken, so the first candidate fails
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs: Vec<SocketAddr> = vec![
+            taken.local_addr().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+            "[::1]:0".parse().unwrap(),
+        ];
+        let first = ListenerSet::bind_with_policy(&addrs[..], BindPolicy::First).unwrap();
+        assert!(first.local_addrs()[0].is_ipv4());
+        assert_eq!(first.local_addrs().len(), 1);
+        let v6 = ListenerSet::bind_with_policy(&addrs[..], BindPolicy::PreferV6).unwrap();
+        assert!(v6.local_addrs()[0].is_ipv6());
+        let all = ListenerSet::bind_with_policy(&addrs[..], BindPolicy::All).unwrap();
+        assert_eq!(all.local_addrs().len(), 2);
+        assert!(ListenerSet::bind_with_policy(&addrs[..1], BindPolicy::All).is_err());
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let set = ListenerSet::bind_dual_stack(0).unwrap();
+        let port = set.local_addrs()[0].port();
+        assert!(set.local_addrs().iter().all(|a| a.port() == port));
+        let _v4 = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        let mut handled = 0;
+        set.handle_incoming_with(
+            |_, _| {
+                handled += 1;
+                set.close();
+            },
+            Duration::from_millis(5),
+        )
+        .unwrap();
+        assert_eq!(handled, 1);
+    }
+}
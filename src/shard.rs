@@ -0,0 +1,126 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Accept on several threads with SO_REUSEPORT (Unix only).
+//!
+//! [bind_reuseport()](fn.bind_reuseport.html) binds several listeners
+//! to the same address with SO_REUSEPORT, and on Linux the kernel
+//! spreads incoming connections across them. A
+//! [ShardedListener](struct.ShardedListener.html) runs an accept loop
+//! for each on its own thread, so accepting scales beyond one thread,
+//! and closes them all together.
+
+use crate::builder::ListenerBuilder;
+use crate::{Error, Listener};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Bind n listeners to addr with SO_REUSEPORT. If addr has port zero,
+/// they all share the port chosen for the first.
+pub fn bind_reuseport<A: ToSocketAddrs>(addr: A, n: usize) -> Result<Vec<TcpListener>, io::Error> {
+    let builder = ListenerBuilder::new().reuse_port(true);
+    let first = builder.bind(addr)?.into_inner();
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..n {
+        listeners.push(builder.bind(addr)?.into_inner());
+    }
+    Ok(listeners)
+}
+
+/// Accept loops sharing one address, one thread each.
+pub struct ShardedListener {
+    listeners: Arc<Vec<TcpListener>>,
+    threads: Vec<JoinHandle<Result<(), Error>>>,
+}
+
+impl ShardedListener {
+    /// Bind n listeners to addr with SO_REUSEPORT and start a thread
+    /// handling incoming connections on each, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming).
+    pub fn start<A, H>(
+        addr: A,
+        n: usize,
+        handler: H,
+        timeout: Duration,
+    ) -> Result<ShardedListener, io::Error>
+    where
+        A: ToSocketAddrs,
+        H: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let listeners = Arc::new(bind_reuseport(addr, std::cmp::max(n, 1))?);
+        let handler = Arc::new(handler);
+        let threads = (0..listeners.len())
+            .map(|i| {
+                let listeners = listeners.clone();
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    listeners[i].handle_incoming(|s| handler(s), timeout)?;
+                    Ok(())
+                })
+            })
+            .collect();
+        Ok(ShardedListener { listeners, threads })
+    }
+
+    /// The address the listeners share.
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Close every listener, so every loop terminates normally.
+    pub fn close(&self) {
+        for listener in self.listeners.iter() {
+            listener.close();
+        }
+    }
+
+    /// Wait for every loop to terminate. Returns the first error.
+    pub fn join(self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for thread in self.threads {
+            let outcome = thread.join().unwrap_or(Err(Error::HandlerPanicked));
+            if result.is_ok() {
+                result = outcome;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_shards() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let h_clone = handled.clone();
+        let shards = ShardedListener::start(
+            "127.0.0.1:0",
+            2,
+            move |_| {
+                h_clone.fetch_add(1, Ordering::SeqCst);
+            },
+            Duration::from_millis(1),
+        )
+        .unwrap();
+        let addr = shards.local_addr().unwrap();
+        let _clients: Vec<TcpStream> = (0..8).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        while handled.load(Ordering::SeqCst) < 8 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        shards.close();
+        shards.join().unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Close listeners when the process is asked to terminate.
+//!
+//! On Unix, SIGINT and SIGTERM are caught. On Windows, a console
+//! control handler catches Ctrl-C, Ctrl-Break and console close
+//! events. In both cases every registered listener is closed, which
+//! makes an active handle_incoming() terminate normally.
+//!
+//! Nothing is installed until [close_on_signal()](fn.close_on_signal.html)
+//! is called. A listener stays registered until the returned
+//! [SignalGuard](struct.SignalGuard.html) is dropped, and the guard
+//! borrows the listener, so a signal can never close a descriptor which
+//! has since been reused. When the last guard is dropped the handlers
+//! are removed again. On Unix they are installed with sigaction(), and
+//! a signal is passed on to any handler function installed before
+//! them, which is put back when they are removed.
+
+use crate::close_raw;
+use crate::plat_specifics::*;
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Maximum number of listeners which may be registered at once.
+pub const MAX_REGISTERED: usize = 16;
+
+// Slots hold the raw handle plus one, so that zero means empty. Plain
+// atomics are used since the handlers may run in signal context.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicUsize = AtomicUsize::new(0);
+static REGISTERED: [AtomicUsize; MAX_REGISTERED] = [EMPTY; MAX_REGISTERED];
+// Number of live guards. The handlers are installed while it is
+// non-zero.
+static GUARDS: Mutex<usize> = Mutex::new(0);
+
+/// Keeps a listener registered with [close_on_signal()](fn.close_on_signal.html).
+/// Dropping it deregisters the listener.
+#[must_use = "the listener is only registered while the guard is held"]
+#[derive(Debug)]
+pub struct SignalGuard<'a> {
+    slot: usize,
+    raw: usize,
+    listener: PhantomData<&'a TcpListener>,
+}
+
+/// Register a listener to be closed when a termination signal or
+/// console control event is received, until the returned guard is
+/// dropped. The handler is installed if no other listener is
+/// registered.
+pub fn close_on_signal(listener: &TcpListener) -> Result<SignalGuard<'_>, Error> {
+    #[cfg(windows)]
+    let raw = listener.as_raw_socket() as usize;
+    #[cfg(not(windows))]
+    let raw = listener.as_raw_fd() as usize;
+    let mut guards = GUARDS.lock().unwrap();
+    for (slot, registered) in REGISTERED.iter().enumerate() {
+        if registered
+            .compare_exchange(0, raw + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            if *guards == 0 {
+                install();
+            }
+            *guards += 1;
+            return Ok(SignalGuard {
+                slot,
+                raw,
+                listener: PhantomData,
+            });
+        }
+    }
+    Err(Error::new(
+        ErrorKind::Other,
+        "too many listeners registered for signal handling",
+    ))
+}
+
+impl Drop for SignalGuard<'_> {
+    fn drop(&mut self) {
+        let mut guards = GUARDS.lock().unwrap();
+        // A signal may have emptied the slot, and another listener may
+        // have taken it since
+        let _ = REGISTERED[self.slot].compare_exchange(
+            self.raw + 1,
+            0,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        *guards -= 1;
+        if *guards == 0 {
+            uninstall();
+        }
+    }
+}
+
+fn close_registered() {
+    for slot in REGISTERED.iter() {
+        let raw = slot.swap(0, Ordering::SeqCst);
+        if raw != 0 {
+            close_raw(raw - 1);
+        }
+    }
+}
+
+#[cfg(not(windows))]
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+// The handlers replaced by install(), for on_signal() to chain to.
+#[cfg(not(windows))]
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+#[cfg(not(windows))]
+static PREVIOUS: [AtomicUsize; 2] = [NO_HANDLER; 2];
+#[cfg(not(windows))]
+static PREVIOUS_SIGINFO: [AtomicUsize; 2] = [EMPTY; 2];
+// And for uninstall() to restore.
+#[cfg(not(windows))]
+static SAVED: Mutex<Vec<(libc::c_int, libc::sigaction)>> = Mutex::new(Vec::new());
+
+#[cfg(not(windows))]
+extern "C" fn on_signal(
+    signum: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    close_registered();
+    let index = match SIGNALS.iter().position(|s| *s == signum) {
+        Some(index) => index,
+        None => return,
+    };
+    let previous = PREVIOUS[index].load(Ordering::SeqCst);
+    if previous == libc::SIG_DFL || previous == libc::SIG_IGN {
+        return;
+    }
+    unsafe {
+        if PREVIOUS_SIGINFO[index].load(Ordering::SeqCst) != 0 {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                std::mem::transmute(previous);
+            handler(signum, info, context);
+        } else {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(previous);
+            handler(signum);
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn install() {
+    let mut saved = SAVED.lock().unwrap();
+    for (index, signum) in SIGNALS.iter().enumerate() {
+        unsafe {
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(*signum, std::ptr::null(), &mut previous) != 0 {
+                continue;
+            }
+            // Publish the previous handler before ours can run
+            PREVIOUS[index].store(previous.sa_sigaction, Ordering::SeqCst);
+            let siginfo = (previous.sa_flags & libc::SA_SIGINFO != 0) as usize;
+            PREVIOUS_SIGINFO[index].store(siginfo, Ordering::SeqCst);
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(*signum, &action, std::ptr::null_mut()) == 0 {
+                saved.push((*signum, previous));
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn uninstall() {
+    let mut saved = SAVED.lock().unwrap();
+    for (signum, previous) in saved.drain(..) {
+        unsafe { libc::sigaction(signum, &previous, std::ptr::null_mut()) };
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn on_ctrl(ctrl_type: minwindef::DWORD) -> minwindef::BOOL {
+    match ctrl_type {
+        wincon::CTRL_C_EVENT | wincon::CTRL_BREAK_EVENT | wincon::CTRL_CLOSE_EVENT => {
+            close_registered();
+            minwindef::TRUE
+        }
+        _ => minwindef::FALSE,
+    }
+}
+
+#[cfg(windows)]
+fn install() {
+    unsafe {
+        consoleapi::SetConsoleCtrlHandler(Some(on_ctrl), minwindef::TRUE);
+    }
+}
+
+#[cfg(windows)]
+fn uninstall() {
+    unsafe {
+        consoleapi::SetConsoleCtrlHandler(Some(on_ctrl), minwindef::FALSE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+
+    fn registered(listener: &TcpListener) -> bool {
+        #[cfg(windows)]
+        let raw = listener.as_raw_socket() as usize;
+        #[cfg(not(windows))]
+        let raw = listener.as_raw_fd() as usize;
+        REGISTERED
+            .iter()
+            .any(|slot| slot.load(Ordering::SeqCst) == raw + 1)
+    }
+
+    // Signals are process wide, so the handlers themselves are tested
+    // from tests/signal.rs, in a process of their own.
+    #[test]
+    fn test_guard() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let guard = close_on_signal(&listener).unwrap();
+        assert!(registered(&listener));
+        drop(guard);
+        assert!(!registered(&listener));
+    }
+}
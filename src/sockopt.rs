@@ -0,0 +1,147 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Read back the socket options in effect on a listener.
+//!
+//! The kernel may silently adjust or ignore requested options (Linux
+//! doubles buffer sizes and caps them at a sysctl limit, for example),
+//! so [effective_options()](trait.EffectiveOptions.html#tymethod.effective_options)
+//! reports the values actually in force. Options which can't be read
+//! on the current platform are reported as None.
+
+#[cfg(not(windows))]
+use crate::plat_specifics::*;
+use std::fmt;
+use std::io::Error;
+use std::net::TcpListener;
+
+/// Socket options in effect on a listener.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptionsReport {
+    /// SO_RCVBUF, in bytes.
+    pub recv_buffer: Option<usize>,
+    /// SO_SNDBUF, in bytes.
+    pub send_buffer: Option<usize>,
+    /// SO_REUSEADDR.
+    pub reuse_addr: Option<bool>,
+    /// SO_REUSEPORT.
+    pub reuse_port: Option<bool>,
+    /// TCP_FASTOPEN queue length, zero if disabled.
+    pub fast_open: Option<usize>,
+    /// IPV6_V6ONLY, for IPv6 listeners.
+    pub only_v6: Option<bool>,
+    /// IP_TTL.
+    pub ttl: Option<u32>,
+    /// O_NONBLOCK.
+    pub nonblocking: Option<bool>,
+}
+
+impl fmt::Display for SocketOptionsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn show<T: fmt::Display>(val: &Option<T>) -> String {
+            val.as_ref()
+                .map_or_else(|| "-".to_string(), |v| v.to_string())
+        }
+        write!(
+            f,
+            "rcvbuf={} sndbuf={} reuseaddr={} reuseport={} fastopen={} v6only={} ttl={} nonblocking={}",
+            show(&self.recv_buffer),
+            show(&self.send_buffer),
+            show(&self.reuse_addr),
+            show(&self.reuse_port),
+            show(&self.fast_open),
+            show(&self.only_v6),
+            show(&self.ttl),
+            show(&self.nonblocking)
+        )
+    }
+}
+
+/// Report the socket options in effect.
+pub trait EffectiveOptions {
+    /// Read back the options currently in force.
+    fn effective_options(&self) -> Result<SocketOptionsReport, Error>;
+}
+
+impl EffectiveOptions for TcpListener {
+    fn effective_options(&self) -> Result<SocketOptionsReport, Error> {
+        #[allow(unused_mut)]
+        let mut report = SocketOptionsReport {
+            ttl: Some(self.ttl()?),
+            ..Default::default()
+        };
+        #[cfg(not(windows))]
+        {
+            let fd = self.as_raw_fd();
+            if self.local_addr()?.is_ipv6() {
+                report.only_v6 =
+                    getsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY).map(|v| v != 0);
+            }
+            report.recv_buffer =
+                getsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF).map(|v| v as usize);
+            report.send_buffer =
+                getsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF).map(|v| v as usize);
+            report.reuse_addr =
+                getsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR).map(|v| v != 0);
+            report.reuse_port =
+                getsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT).map(|v| v != 0);
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                report.fast_open =
+                    getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN).map(|v| v as usize);
+            }
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags >= 0 {
+                report.nonblocking = Some(flags & libc::O_NONBLOCK != 0);
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(not(windows))]
+fn getsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> Option<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut val as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc == 0 {
+        Some(val)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+
+    #[test]
+    fn test_report() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        listener.set_ttl(42).unwrap();
+        let report = listener.effective_options().unwrap();
+        assert_eq!(report.ttl, Some(42));
+        assert_eq!(report.only_v6, None);
+        #[cfg(not(windows))]
+        {
+            assert_eq!(report.nonblocking, Some(true));
+            assert!(report.recv_buffer.unwrap() > 0);
+        }
+        assert!(report.to_string().contains("ttl=42"));
+    }
+}
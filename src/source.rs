@@ -0,0 +1,180 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A listener as an event source for an existing event loop.
+//!
+//! Applications built around an event loop, such as calloop in
+//! Wayland compositors, can't block in handle_incoming(). Instead a
+//! [ListenerSource](struct.ListenerSource.html) exposes the listening
+//! socket for readiness registration, and the loop calls
+//! [dispatch()](struct.ListenerSource.html#method.dispatch) whenever it
+//! becomes readable. Each accepted stream is passed to the callback as
+//! an event. Once the listener is closed, dispatch() returns
+//! [PostAction::Remove](enum.PostAction.html) so the loop can
+//! unregister the source. Accept errors are returned to the caller,
+//! which decides whether to keep the source.
+//!
+//! With calloop, wrap the descriptor in a `Generic` source with
+//! `Interest::READ` and level-triggered mode, call dispatch() from its
+//! callback and map the result onto calloop's PostAction.
+//!
+//! With GLib, register the descriptor with `unix_fd_add_local()` for
+//! `IOCondition::IN` and call the closure returned by
+//! [source_func()](struct.ListenerSource.html#method.source_func),
+//! which returns false, i.e. G_SOURCE_REMOVE, once the listener is
+//! closed.
+
+use crate::plat_specifics::*;
+use crate::{drained, is_closed};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+
+/// What the event loop should do with the source after a dispatch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PostAction {
+    /// Keep the source registered.
+    Continue,
+    /// The listener was closed, unregister the source.
+    Remove,
+}
+
+/// A non-blocking listener driven by an external event loop.
+pub struct ListenerSource {
+    listener: Arc<TcpListener>,
+}
+
+impl ListenerSource {
+    /// Create a source from a non-blocking listener. The listener can
+    /// be closed from elsewhere with
+    /// [Listener::close()](../trait.Listener.html#tymethod.close).
+    pub fn new(listener: Arc<TcpListener>) -> ListenerSource {
+        ListenerSource { listener }
+    }
+
+    /// The listener this source accepts from.
+    pub fn listener(&self) -> &Arc<TcpListener> {
+        &self.listener
+    }
+
+    /// Accept every pending connection, passing each to on_stream, and
+    /// return once the listener would block.
+    pub fn dispatch<F>(&self, mut on_stream: F) -> Result<PostAction, Error>
+    where
+        F: FnMut(TcpStream, SocketAddr),
+    {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => on_stream(stream, addr),
+                Err(err) => {
+                    if err.kind() == ErrorKind::WouldBlock {
+                        if drained(&*self.listener) {
+                            return Ok(PostAction::Remove);
+                        }
+                        return Ok(PostAction::Continue);
+                    } else if is_closed(&err) {
+                        return Ok(PostAction::Remove);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Convert into a closure with GSourceFunc semantics: each call
+    /// dispatches pending streams to on_stream and returns true while
+    /// the source should stay registered. An accept error is passed to
+    /// on_error and removes the source.
+    pub fn source_func<F, E>(self, mut on_stream: F, mut on_error: E) -> impl FnMut() -> bool
+    where
+        F: FnMut(TcpStream, SocketAddr),
+        E: FnMut(Error),
+    {
+        move || match self.dispatch(&mut on_stream) {
+            Ok(action) => action == PostAction::Continue,
+            Err(err) => {
+                on_error(err);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+impl AsFd for ListenerSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsSocket for ListenerSource {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.listener.as_socket()
+    }
+}
+
+#[cfg(not(windows))]
+impl AsRawFd for ListenerSource {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for ListenerSource {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.listener.as_raw_socket()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_dispatch() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let source = ListenerSource::new(listener.clone());
+        #[cfg(not(windows))]
+        assert_eq!(source.as_fd().as_raw_fd(), listener.as_raw_fd());
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let mut accepted = 0;
+        let action = source.dispatch(|_, _| accepted += 1).unwrap();
+        assert_eq!(action, PostAction::Continue);
+        assert_eq!(accepted, 2);
+
+        listener.close();
+        assert_eq!(source.dispatch(|_, _| ()).unwrap(), PostAction::Remove);
+    }
+
+    #[test]
+    fn test_source_func() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let _a = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let mut accepted = 0;
+        let mut func =
+            ListenerSource::new(listener.clone()).source_func(|_, _| accepted += 1, |_| ());
+        assert!(func());
+        listener.close();
+        assert!(!func());
+        drop(func);
+        assert_eq!(accepted, 1);
+    }
+}
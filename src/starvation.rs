@@ -0,0 +1,242 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Warnings when connections wait too long to be accepted.
+//!
+//! Connections wait in the kernel's accept queue while the accept loop
+//! is sleeping or running a handler. An [AcceptMonitor](struct.AcceptMonitor.html)
+//! watches the listener from a second thread and records when it
+//! becomes readable, and warns when a connection is accepted longer
+//! than a threshold after that. Frequent warnings suggest that the
+//! sleep timeout is too long or that a handler is blocking the loop.
+//!
+//! Readiness is noticed to within a quarter of the threshold. Where it
+//! can't be checked, no warnings are raised.
+
+use crate::poll::wait_readable;
+use crate::{accept_loop, Callbacks, Error, StopReason};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A warning raised by an [AcceptMonitor](struct.AcceptMonitor.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AcceptWarning {
+    /// A connection which became pending while the loop slept waited
+    /// this long to be accepted.
+    SlowAccept(Duration),
+    /// A connection which became pending while a handler ran waited
+    /// this long to be accepted.
+    BlockingHandler(Duration),
+}
+
+type WarningCallback = Box<dyn Fn(&AcceptWarning) + Send + Sync>;
+
+/// Watches an accept loop for connections left waiting.
+pub struct AcceptMonitor {
+    threshold: Duration,
+    slow_accepts: AtomicU64,
+    blocking_handlers: AtomicU64,
+    on_warning: WarningCallback,
+}
+
+impl AcceptMonitor {
+    /// Create a monitor which calls on_warning whenever a connection
+    /// waits more than threshold, from the listener becoming readable,
+    /// to be accepted.
+    pub fn new<F>(threshold: Duration, on_warning: F) -> AcceptMonitor
+    where
+        F: Fn(&AcceptWarning) + Send + Sync + 'static,
+    {
+        AcceptMonitor {
+            threshold,
+            slow_accepts: AtomicU64::new(0),
+            blocking_handlers: AtomicU64::new(0),
+            on_warning: Box::new(on_warning),
+        }
+    }
+
+    /// Number of SlowAccept warnings raised.
+    pub fn slow_accepts(&self) -> u64 {
+        self.slow_accepts.load(Ordering::SeqCst)
+    }
+
+    /// Number of BlockingHandler warnings raised.
+    pub fn blocking_handlers(&self) -> u64 {
+        self.blocking_handlers.load(Ordering::SeqCst)
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// raising warnings as connections are left waiting.
+    pub fn handle_incoming<H>(
+        &self,
+        listener: &TcpListener,
+        mut handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let ready_since = Mutex::new(None);
+        let stopped = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| self.watch(listener, &ready_since, &stopped));
+            // Stops the watcher even if the handler panics
+            let _stopping = Stopping(&stopped);
+            let mut handler_ran = None;
+            let mut monitored = |stream: TcpStream, _, _| {
+                let accepted = Instant::now();
+                let ready = ready_since.lock().unwrap().take();
+                // The next connection is ready as soon as this one is
+                // accepted
+                if is_pending(listener) {
+                    *ready_since.lock().unwrap() = Some(accepted);
+                }
+                if let Some(ready) = ready {
+                    self.check(ready, accepted, handler_ran);
+                }
+                handler(stream);
+                handler_ran = Some((accepted, Instant::now()));
+            };
+            accept_loop(listener, &mut monitored, timeout, Callbacks::default())
+                .map_err(Error::from)
+        })
+    }
+
+    // Record when the listener becomes readable, until stopped.
+    fn watch(
+        &self,
+        listener: &TcpListener,
+        ready_since: &Mutex<Option<Instant>>,
+        stopped: &AtomicBool,
+    ) {
+        let resolution = std::cmp::max(self.threshold / 4, Duration::from_millis(1));
+        while !stopped.load(Ordering::SeqCst) {
+            if ready_since.lock().unwrap().is_some() {
+                thread::sleep(resolution);
+                continue;
+            }
+            match wait_readable(&[listener], resolution) {
+                Ok(ready) if ready[0] => {
+                    let mut since = ready_since.lock().unwrap();
+                    if since.is_none() {
+                        *since = Some(Instant::now());
+                    }
+                }
+                Ok(_) => (),
+                // Readiness can't be told, so raise no warnings
+                Err(_) => return,
+            }
+        }
+    }
+
+    // Warn if the connection which became ready at ready waited more
+    // than the threshold. If that was while the last handler ran, it is
+    // to blame.
+    fn check(&self, ready: Instant, accepted: Instant, handler_ran: Option<(Instant, Instant)>) {
+        let waited = accepted.duration_since(ready);
+        if waited <= self.threshold {
+            return;
+        }
+        let warning = match handler_ran {
+            Some((_, finished)) if ready < finished => AcceptWarning::BlockingHandler(waited),
+            _ => AcceptWarning::SlowAccept(waited),
+        };
+        let counter = match warning {
+            AcceptWarning::SlowAccept(_) => &self.slow_accepts,
+            AcceptWarning::BlockingHandler(_) => &self.blocking_handlers,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        (self.on_warning)(&warning);
+    }
+}
+
+// Is a connection waiting to be accepted?
+fn is_pending(listener: &TcpListener) -> bool {
+    match wait_readable(&[listener], Duration::from_secs(0)) {
+        Ok(ready) => ready[0],
+        Err(_) => false,
+    }
+}
+
+struct Stopping<'a>(&'a AtomicBool);
+
+impl Drop for Stopping<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::sync::Arc;
+
+    fn slow_handler(_stream: TcpStream) {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_blocking_handler() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        let monitor = AcceptMonitor::new(Duration::from_millis(20), |_| ());
+
+        let l_clone = listener.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            l_clone.close();
+        });
+        monitor
+            .handle_incoming(&listener, slow_handler, Duration::from_millis(5))
+            .unwrap();
+        // Only the first handler kept a connection waiting
+        assert_eq!(monitor.blocking_handlers(), 1);
+        assert_eq!(monitor.slow_accepts(), 0);
+    }
+
+    #[test]
+    fn test_slow_accept() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let warnings = Arc::new(Mutex::new(vec![]));
+        let w_clone = warnings.clone();
+        let monitor = AcceptMonitor::new(Duration::from_millis(100), move |warning| {
+            w_clone.lock().unwrap().push(warning.clone())
+        });
+
+        let l_clone = listener.clone();
+        thread::spawn(move || {
+            // Connect well into the loop's sleep, so the connection
+            // waits less than the time asleep
+            thread::sleep(Duration::from_millis(250));
+            let _client = TcpStream::connect(addr).unwrap();
+            thread::sleep(Duration::from_millis(500));
+            l_clone.close();
+        });
+        monitor
+            .handle_incoming(&listener, |_| (), Duration::from_millis(400))
+            .unwrap();
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(warnings.len(), 1);
+        match warnings[0] {
+            AcceptWarning::SlowAccept(waited) => {
+                assert!(waited >= Duration::from_millis(100));
+                assert!(waited < Duration::from_millis(350));
+            }
+            _ => panic!("expected SlowAccept"),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Accept loop statistics.
+//!
+//! A [ListenerStats](struct.ListenerStats.html) is updated by
+//! [handle_incoming_with_stats()](../trait.Listener.html#tymethod.handle_incoming_with_stats)
+//! as it runs, and can be read from any other thread, e.g. by a test
+//! asserting on the loop's behaviour or to feed a dashboard. A loop run
+//! inside [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope)
+//! stamps its labels on the stats, and
+//! [metrics()](struct.StatsSnapshot.html#method.metrics) names each
+//! counter with them, ready for a
+//! [StatsWriter](../export/struct.StatsWriter.html).
+
+use crate::labels::Labels;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counters kept by an accept loop.
+#[derive(Debug, Default)]
+pub struct ListenerStats {
+    accepted: AtomicU64,
+    handler_errors: AtomicU64,
+    sleeps: AtomicU64,
+    slept_micros: AtomicU64,
+    active: AtomicU64,
+    last_connection_id: AtomicU64,
+    labels: Mutex<Labels>,
+}
+
+/// Values of a [ListenerStats](struct.ListenerStats.html) at one time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Connections accepted.
+    pub accepted: u64,
+    /// Handlers which returned an error.
+    pub handler_errors: u64,
+    /// Times the loop slept because no connection was waiting.
+    pub sleeps: u64,
+    /// Total time spent sleeping.
+    pub slept: Duration,
+    /// Handlers currently running.
+    pub active: u64,
+    /// The id of the connection most recently accepted by the loop, as
+    /// in [Connection](../connection/struct.Connection.html), or zero
+    /// if none has been.
+    pub last_connection_id: u64,
+    /// Labels of the loop.
+    pub labels: Labels,
+}
+
+impl StatsSnapshot {
+    /// The counters as (name, value) pairs, each name followed by the
+    /// labels in Prometheus form, e.g. `accepted{service="api"}`.
+    pub fn metrics(&self) -> Vec<(String, u64)> {
+        let labels = self.labels.prometheus();
+        vec![
+            ("accepted", self.accepted),
+            ("handler_errors", self.handler_errors),
+            ("sleeps", self.sleeps),
+            ("slept_micros", self.slept.as_micros() as u64),
+            ("active", self.active),
+            ("last_connection_id", self.last_connection_id),
+        ]
+        .into_iter()
+        .map(|(name, value)| (format!("{}{}", name, labels), value))
+        .collect()
+    }
+}
+
+impl ListenerStats {
+    /// Create zeroed counters.
+    pub fn new() -> ListenerStats {
+        ListenerStats::default()
+    }
+
+    /// Create zeroed counters with labels, for a loop run outside
+    /// Labels::in_scope().
+    pub fn with_labels(labels: Labels) -> ListenerStats {
+        ListenerStats {
+            labels: Mutex::new(labels),
+            ..ListenerStats::default()
+        }
+    }
+
+    /// Read the current values.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            accepted: self.accepted.load(Ordering::SeqCst),
+            handler_errors: self.handler_errors.load(Ordering::SeqCst),
+            sleeps: self.sleeps.load(Ordering::SeqCst),
+            slept: Duration::from_micros(self.slept_micros.load(Ordering::SeqCst)),
+            active: self.active.load(Ordering::SeqCst),
+            last_connection_id: self.last_connection_id.load(Ordering::SeqCst),
+            labels: self.labels.lock().unwrap().clone(),
+        }
+    }
+
+    pub(crate) fn labeled(&self, labels: Labels) {
+        *self.labels.lock().unwrap() = labels;
+    }
+
+    pub(crate) fn handler_started(&self) -> Running<'_> {
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Running(self)
+    }
+
+    pub(crate) fn handler_failed(&self) {
+        self.handler_errors.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn assigned(&self, id: u64) {
+        self.last_connection_id.store(id, Ordering::SeqCst);
+    }
+
+    pub(crate) fn slept(&self, duration: Duration) {
+        self.sleeps.fetch_add(1, Ordering::SeqCst);
+        self.slept_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::SeqCst);
+    }
+}
+
+// Counts a handler out of active when it returns or panics.
+pub(crate) struct Running<'a>(&'a ListenerStats);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::io::Error;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_stats() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(ListenerStats::new());
+        let l_clone = listener.clone();
+        let s_clone = stats.clone();
+        let server = thread::spawn(move || {
+            let mut calls = 0;
+            l_clone
+                .handle_incoming_with_stats(
+                    |_| {
+                        calls += 1;
+                        if calls == 2 {
+                            return Err(Error::new(std::io::ErrorKind::Other, "failed"));
+                        }
+                        Ok(())
+                    },
+                    Duration::from_millis(5),
+                    &s_clone,
+                )
+                .unwrap()
+        });
+        let _a = TcpStream::connect(addr).unwrap();
+        let _b = TcpStream::connect(addr).unwrap();
+        while stats.snapshot().accepted < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        thread::sleep(Duration::from_millis(20));
+        listener.close();
+        server.join().unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.accepted, 2);
+        assert_eq!(snapshot.handler_errors, 1);
+        assert_eq!(snapshot.active, 0);
+        assert!(snapshot.sleeps > 0);
+        assert!(snapshot.slept >= Duration::from_millis(5));
+        // The second connection's id, which other loops can't have
+        // changed
+        assert!(snapshot.last_connection_id > 1);
+        assert_eq!(ListenerStats::new().snapshot().last_connection_id, 0);
+    }
+
+    #[test]
+    fn test_panic() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = ListenerStats::new();
+        let _client = TcpStream::connect(addr).unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            listener.handle_incoming_with_stats(
+                |_| panic!("handler failed"),
+                Duration::from_millis(5),
+                &stats,
+            )
+        }));
+        assert!(result.is_err());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.accepted, 1);
+        assert_eq!(snapshot.active, 0);
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Stop an accept loop without closing its socket.
+//!
+//! [Listener::close()](../trait.Listener.html#tymethod.close) replaces
+//! the listening socket underneath the TcpListener, which leaves the
+//! listener unusable afterwards. A listener created by
+//! [Listener::with_shutdown()](../trait.Listener.html#tymethod.with_shutdown)
+//! instead comes with a [ListenerHandle](struct.ListenerHandle.html):
+//! the accept loop checks a [StopToken](struct.StopToken.html) each time
+//! round, and [stop()](struct.ListenerHandle.html#method.stop) also
+//! wakes the loop from its wait, which then returns
+//! [StopReason::Condition](../enum.StopReason.html#variant.Condition).
+//! Once stopped, the listener is still open and bound, and can be used
+//! again or dropped normally. A handle outliving its listener does
+//! nothing, so it can't wake a loop on a socket which has since reused
+//! the listener's descriptor.
+//!
+//! The handle can also [pause()](struct.ListenerHandle.html#method.pause)
+//! the loop, which then stops accepting until it is resumed. New
+//! connections wait in the kernel's accept queue meanwhile, as they
+//! would if the server were too busy to accept them.
+
+use crate::plat_specifics::*;
+use crate::poll::wake;
+use crate::until::StopCondition;
+use crate::{accept_loop, Callbacks, StopReason};
+use std::io::Error;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Records whether an accept loop has been asked to stop.
+#[derive(Clone, Debug, Default)]
+pub struct StopToken {
+    stopped: Arc<AtomicBool>,
+    paused: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl StopToken {
+    /// Has stop been requested?
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Is the loop paused?
+    pub fn is_paused(&self) -> bool {
+        *self.paused.0.lock().unwrap()
+    }
+
+    fn set_paused(&self, paused: bool) {
+        let (lock, changed) = &*self.paused;
+        *lock.lock().unwrap() = paused;
+        changed.notify_all();
+    }
+}
+
+impl StopCondition for StopToken {
+    // Hold the loop here while paused, until resumed or stopped
+    fn should_stop(&mut self) -> bool {
+        let (lock, changed) = &*self.paused;
+        let _paused = changed
+            .wait_while(lock.lock().unwrap(), |paused| {
+                *paused && !self.stopped.load(Ordering::SeqCst)
+            })
+            .unwrap();
+        self.is_stopped()
+    }
+}
+
+// Marks the listener's socket as gone when dropped, before the socket
+// is closed.
+#[derive(Debug)]
+struct Live(Arc<Mutex<bool>>);
+
+impl Drop for Live {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() = false;
+    }
+}
+
+/// Stops the accept loop of a [StoppableListener](struct.StoppableListener.html)
+/// from any thread.
+#[derive(Clone, Debug)]
+pub struct ListenerHandle {
+    token: StopToken,
+    local_addr: SocketAddr,
+    live: Arc<Mutex<bool>>,
+    #[cfg(not(windows))]
+    fd: RawFd,
+    #[cfg(windows)]
+    socket: RawSocket,
+}
+
+impl ListenerHandle {
+    /// Ask the accept loop to terminate normally, returning
+    /// [StopReason::Condition](../enum.StopReason.html#variant.Condition).
+    /// It finishes handling the current connection, if any, first.
+    pub fn stop(&self) {
+        self.token.stopped.store(true, Ordering::SeqCst);
+        // Wake a paused loop too. Holding the lock means the loop can't
+        // miss this between checking for a stop and waiting.
+        let (lock, changed) = &*self.token.paused;
+        let _paused = lock.lock().unwrap();
+        changed.notify_all();
+        self.wake();
+    }
+
+    /// Stop accepting connections, leaving them in the kernel's accept
+    /// queue, until resume() is called. The loop finishes handling the
+    /// current connection, if any, first.
+    pub fn pause(&self) {
+        self.token.set_paused(true);
+        self.wake();
+    }
+
+    /// Start accepting connections again after a pause().
+    pub fn resume(&self) {
+        self.token.set_paused(false);
+    }
+
+    /// The address the listener is bound to, e.g. to find the port
+    /// chosen when binding to port zero.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The token checked by the accept loop.
+    pub fn token(&self) -> StopToken {
+        self.token.clone()
+    }
+
+    /// Is the listener still open? Once it has been dropped or
+    /// unwrapped, the handle does nothing.
+    pub fn is_live(&self) -> bool {
+        *self.live.lock().unwrap()
+    }
+
+    fn wake(&self) {
+        // Holding the lock keeps the socket open while it is woken
+        let live = self.live.lock().unwrap();
+        if !*live {
+            return;
+        }
+        #[cfg(not(windows))]
+        wake::wake(self.fd);
+        #[cfg(windows)]
+        wake::wake(self.socket);
+    }
+}
+
+/// A non-blocking listener whose accept loop is stopped by a
+/// [ListenerHandle](struct.ListenerHandle.html).
+#[derive(Debug)]
+pub struct StoppableListener {
+    // Dropped first, so handles see the socket gone before it closes
+    _live: Live,
+    listener: TcpListener,
+    token: StopToken,
+}
+
+impl StoppableListener {
+    pub(crate) fn new(listener: TcpListener) -> Result<(StoppableListener, ListenerHandle), Error> {
+        let token = StopToken::default();
+        let live = Arc::new(Mutex::new(true));
+        let handle = ListenerHandle {
+            token: token.clone(),
+            local_addr: listener.local_addr()?,
+            live: live.clone(),
+            #[cfg(not(windows))]
+            fd: listener.as_raw_fd(),
+            #[cfg(windows)]
+            socket: listener.as_raw_socket(),
+        };
+        Ok((
+            StoppableListener {
+                _live: Live(live),
+                listener,
+                token,
+            },
+            handle,
+        ))
+    }
+
+    /// The underlying listener.
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    /// Unwrap the listener. Its handles no longer affect it.
+    pub fn into_inner(self) -> TcpListener {
+        self.listener
+    }
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming),
+    /// until stop is requested. Waits for connections for up to timeout
+    /// at a time rather than sleeping, and doesn't accept while paused.
+    /// Returns [StopReason::Condition](../enum.StopReason.html#variant.Condition)
+    /// once stopped.
+    pub fn handle_incoming<H>(
+        &self,
+        handler: H,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(TcpStream),
+    {
+        let mut handler = handler;
+        let mut token = self.token.clone();
+        let callbacks = Callbacks {
+            until: Some(&mut token),
+            poll: true,
+            ..Callbacks::default()
+        };
+        accept_loop(
+            &self.listener,
+            &mut |s, _, _| handler(s),
+            timeout,
+            callbacks,
+        )
+        .map_err(crate::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Listener;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_stop() {
+        let (listener, handle) = TcpListener::with_shutdown("127.0.0.1:0").unwrap();
+        let listener = Arc::new(listener);
+        let addr = handle.local_addr();
+        let l_clone = listener.clone();
+        let server = thread::spawn(move || {
+            let mut handled = 0;
+            let reason = l_clone
+                .handle_incoming(|_| handled += 1, Duration::from_secs(60))
+                .unwrap();
+            (reason, handled)
+        });
+        let _stream = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        handle.stop();
+        assert_eq!(server.join().unwrap(), (StopReason::Condition, 1));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(handle.token().is_stopped());
+        // The listener is still bound
+        assert_eq!(listener.listener().local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_pause() {
+        let (listener, handle) = TcpListener::with_shutdown("127.0.0.1:0").unwrap();
+        let addr = handle.local_addr();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let server = thread::spawn(move || {
+            listener
+                .handle_incoming(|_| tx.send(()).unwrap(), Duration::from_secs(60))
+                .unwrap()
+        });
+        handle.pause();
+        assert!(handle.token().is_paused());
+        // Queued, but not accepted
+        let _stream = TcpStream::connect(addr).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        handle.resume();
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        handle.pause();
+        handle.stop();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_outlived() {
+        let (listener, handle) = TcpListener::with_shutdown("127.0.0.1:0").unwrap();
+        assert!(handle.is_live());
+        let listener = listener.into_inner();
+        assert!(!handle.is_live());
+        drop(listener);
+
+        // A later listener may reuse the descriptor, but isn't affected
+        let (listener, other) = TcpListener::with_shutdown("127.0.0.1:0").unwrap();
+        handle.pause();
+        handle.stop();
+        let _stream = TcpStream::connect(other.local_addr()).unwrap();
+        let reason = listener
+            .handle_incoming(|_| other.stop(), Duration::from_millis(5))
+            .unwrap();
+        assert_eq!(reason, StopReason::Condition);
+    }
+}
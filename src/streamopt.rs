@@ -0,0 +1,251 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Socket options applied to each accepted stream.
+//!
+//! A [StreamConfig](struct.StreamConfig.html) lists the options once,
+//! and [Listener::handle_incoming_configured()](../trait.Listener.html#tymethod.handle_incoming_configured)
+//! applies them to every stream before the handler sees it. Options
+//! which aren't set are left at the system default. The config is
+//! checked once, with [validate()](struct.StreamConfig.html#method.validate),
+//! before the loop starts, so a value no stream could accept fails the
+//! call instead of silently dropping every connection.
+
+use crate::plat_specifics::*;
+#[cfg(not(windows))]
+use libc::{SO_RCVBUF, SO_SNDBUF};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::net::TcpStream;
+use std::time::Duration;
+#[cfg(windows)]
+use winsock2::{SO_RCVBUF, SO_SNDBUF};
+
+/// Options for accepted streams.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamConfig {
+    nodelay: Option<bool>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    linger: Option<Duration>,
+    recv_buffer: Option<usize>,
+    send_buffer: Option<usize>,
+}
+
+impl StreamConfig {
+    /// Create a config which changes nothing.
+    pub fn new() -> StreamConfig {
+        StreamConfig::default()
+    }
+
+    /// Set TCP_NODELAY.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Time out blocking reads after timeout.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Time out blocking writes after timeout.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Enable SO_KEEPALIVE. Where the platform allows (Linux, Android),
+    /// probes start after the connection has been idle for idle;
+    /// elsewhere the system default applies.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Set SO_LINGER, so that closing blocks for up to linger while
+    /// unsent data is delivered. Zero aborts the connection on close.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// Set SO_RCVBUF, in bytes.
+    pub fn recv_buffer(mut self, size: usize) -> Self {
+        self.recv_buffer = Some(size);
+        self
+    }
+
+    /// Set SO_SNDBUF, in bytes.
+    pub fn send_buffer(mut self, size: usize) -> Self {
+        self.send_buffer = Some(size);
+        self
+    }
+
+    /// Check that every option set has a value the system could accept.
+    /// Fails with InvalidInput for zero timeouts and for sizes or
+    /// durations too large for the underlying socket option.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.read_timeout == Some(Duration::from_secs(0))
+            || self.write_timeout == Some(Duration::from_secs(0))
+        {
+            return Err(invalid("timeouts must be greater than zero"));
+        }
+        if let Some(linger) = self.linger {
+            linger_secs(linger)?;
+        }
+        for size in self.recv_buffer.iter().chain(self.send_buffer.iter()) {
+            buffer_size(*size)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the options to stream, stopping at the first which fails.
+    pub fn apply(&self, stream: &TcpStream) -> Result<(), Error> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if self.read_timeout.is_some() {
+            stream.set_read_timeout(self.read_timeout)?;
+        }
+        if self.write_timeout.is_some() {
+            stream.set_write_timeout(self.write_timeout)?;
+        }
+        if let Some(idle) = self.keepalive {
+            set_keepalive(stream, idle)?;
+        }
+        if let Some(linger) = self.linger {
+            set_linger(stream, linger)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            set_int(stream, SO_RCVBUF, buffer_size(size)?)?;
+        }
+        if let Some(size) = self.send_buffer {
+            set_int(stream, SO_SNDBUF, buffer_size(size)?)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn buffer_size(size: usize) -> Result<i32, Error> {
+    i32::try_from(size).map_err(|_| invalid("buffer size is too large"))
+}
+
+#[cfg(not(windows))]
+fn linger_secs(linger: Duration) -> Result<libc::c_int, Error> {
+    libc::c_int::try_from(linger.as_secs()).map_err(|_| invalid("linger is too long"))
+}
+
+#[cfg(windows)]
+fn linger_secs(linger: Duration) -> Result<u16, Error> {
+    u16::try_from(linger.as_secs()).map_err(|_| invalid("linger is too long"))
+}
+
+#[cfg(not(windows))]
+fn setsockopt<T>(stream: &TcpStream, level: i32, name: i32, val: &T) -> Result<(), Error> {
+    let rc = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            val as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn setsockopt<T>(stream: &TcpStream, level: i32, name: i32, val: &T) -> Result<(), Error> {
+    let rc = unsafe {
+        winsock2::setsockopt(
+            stream.as_raw_socket() as winsock2::SOCKET,
+            level,
+            name,
+            val as *const T as *const i8,
+            std::mem::size_of::<T>() as i32,
+        )
+    };
+    if rc != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn set_int(stream: &TcpStream, name: i32, val: i32) -> Result<(), Error> {
+    setsockopt(stream, libc::SOL_SOCKET, name, &(val as libc::c_int))
+}
+
+#[cfg(windows)]
+fn set_int(stream: &TcpStream, name: i32, val: i32) -> Result<(), Error> {
+    setsockopt(stream, winsock2::SOL_SOCKET, name, &val)
+}
+
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> Result<(), Error> {
+    #[cfg(not(windows))]
+    set_int(stream, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(windows)]
+    set_int(stream, winsock2::SO_KEEPALIVE, 1)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let secs = std::cmp::max(idle.as_secs(), 1) as libc::c_int;
+        setsockopt(stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, &secs)?;
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = idle;
+    Ok(())
+}
+
+fn set_linger(stream: &TcpStream, linger: Duration) -> Result<(), Error> {
+    #[cfg(not(windows))]
+    let val = libc::linger {
+        l_onoff: 1,
+        l_linger: linger_secs(linger)?,
+    };
+    #[cfg(windows)]
+    let val = winsock2::linger {
+        l_onoff: 1,
+        l_linger: linger_secs(linger)?,
+    };
+    #[cfg(not(windows))]
+    return setsockopt(stream, libc::SOL_SOCKET, libc::SO_LINGER, &val);
+    #[cfg(windows)]
+    return setsockopt(stream, winsock2::SOL_SOCKET, winsock2::SO_LINGER, &val);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(StreamConfig::new().nodelay(true).validate().is_ok());
+        let configs = [
+            StreamConfig::new().read_timeout(Duration::from_secs(0)),
+            StreamConfig::new().recv_buffer(i32::MAX as usize + 1),
+            StreamConfig::new().linger(Duration::from_secs(u64::MAX)),
+        ];
+        for config in configs.iter() {
+            assert_eq!(
+                config.validate().unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Restart an accept loop which fails.
+//!
+//! A [Supervisor](struct.Supervisor.html) runs an accept loop on its
+//! own thread. If the loop terminates with an error, rather than
+//! because the listener was closed, the supervisor binds a new listener
+//! to the same address and restarts the loop, waiting longer after each
+//! failure, until a [RestartPolicy](struct.RestartPolicy.html)
+//! budget is used up. Each transition is reported as a
+//! [SupervisorEvent](enum.SupervisorEvent.html).
+
+use crate::{Listener, StopReason};
+use std::io::Error;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A change in the state of a supervised accept loop.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    /// The loop was started, after this many restarts.
+    Started(usize),
+    /// The loop, or binding its listener, failed with this error.
+    Failed(String),
+    /// The loop will be restarted after a delay.
+    Restarting(Duration),
+    /// The restart budget was used up, so the loop won't be restarted.
+    GaveUp,
+    /// The listener was closed and the loop terminated normally.
+    Stopped,
+}
+
+/// When and how often to restart a failed accept loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Maximum number of restarts.
+    pub max_restarts: usize,
+    /// Delay before the first restart.
+    pub initial_backoff: Duration,
+    /// The delay doubles after each failure, up to this.
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    /// 10 restarts, backing off from 100ms to 30s.
+    fn default() -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+type Current = Arc<Mutex<Option<Arc<TcpListener>>>>;
+
+/// Runs and restarts an accept loop on a managed thread.
+pub struct Supervisor {
+    current: Current,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Bind a listener to addr and start a thread which calls run with
+    /// it, typically to run handle_incoming(). Restarts rebind to the
+    /// address first bound, so a port of zero keeps the same port.
+    pub fn start<A, R, E>(
+        addr: A,
+        policy: RestartPolicy,
+        run: R,
+        on_event: E,
+    ) -> Result<Supervisor, Error>
+    where
+        A: ToSocketAddrs,
+        R: Fn(&TcpListener) -> Result<StopReason, crate::Error> + Send + 'static,
+        E: Fn(&SupervisorEvent) + Send + 'static,
+    {
+        let listener: TcpListener = Listener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let current: Current = Arc::new(Mutex::new(Some(Arc::new(listener))));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let c_clone = current.clone();
+        let s_clone = stopped.clone();
+        let thread =
+            thread::spawn(move || supervise(addr, policy, c_clone, s_clone, run, on_event));
+        Ok(Supervisor {
+            current,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    /// The address the listener is bound to.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let current = self.current.lock().unwrap();
+        current.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// Close the listener. The loop terminates normally and isn't
+    /// restarted.
+    pub fn close(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(listener) = self.current.lock().unwrap().as_ref() {
+            listener.close();
+        }
+    }
+
+    /// Wait for the supervisor thread to finish.
+    pub fn join(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.close();
+            let _ = thread.join();
+        }
+    }
+}
+
+fn supervise<R, E>(
+    addr: SocketAddr,
+    policy: RestartPolicy,
+    current: Current,
+    stopped: Arc<AtomicBool>,
+    run: R,
+    on_event: E,
+) where
+    R: Fn(&TcpListener) -> Result<StopReason, crate::Error>,
+    E: Fn(&SupervisorEvent),
+{
+    let mut restarts = 0;
+    let mut backoff = policy.initial_backoff;
+    loop {
+        // Bind a new listener if the last one failed
+        let listener = match current.lock().unwrap().clone() {
+            Some(listener) => Ok(listener),
+            None => Listener::bind(addr).map(Arc::new),
+        };
+        let result = listener
+            .map_err(|err| err.to_string())
+            .and_then(|listener| {
+                *current.lock().unwrap() = Some(listener.clone());
+                // close() may have found no listener to close
+                if stopped.load(Ordering::SeqCst) {
+                    return Ok(StopReason::Closed);
+                }
+                on_event(&SupervisorEvent::Started(restarts));
+                run(&listener).map_err(|err| err.to_string())
+            });
+        let err = match result {
+            Ok(_) => {
+                on_event(&SupervisorEvent::Stopped);
+                return;
+            }
+            Err(_) if stopped.load(Ordering::SeqCst) => {
+                on_event(&SupervisorEvent::Stopped);
+                return;
+            }
+            Err(err) => err,
+        };
+        *current.lock().unwrap() = None;
+        on_event(&SupervisorEvent::Failed(err));
+        if restarts >= policy.max_restarts {
+            on_event(&SupervisorEvent::GaveUp);
+            return;
+        }
+        restarts += 1;
+        on_event(&SupervisorEvent::Restarting(backoff));
+        if !sleep_unless_stopped(&stopped, backoff) {
+            on_event(&SupervisorEvent::Stopped);
+            return;
+        }
+        backoff = std::cmp::min(backoff * 2, policy.max_backoff);
+    }
+}
+
+// Sleep for duration, waking early if stopped. Returns false if stopped.
+fn sleep_unless_stopped(stopped: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !stopped.load(Ordering::SeqCst) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            return true;
+        }
+        thread::sleep(std::cmp::min(left, Duration::from_millis(10)));
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_restart() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let events = Arc::new(Mutex::new(vec![]));
+        let policy = RestartPolicy {
+            max_restarts: 5,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+        let r_clone = runs.clone();
+        let e_clone = events.clone();
+        let supervisor = Supervisor::start(
+            "127.0.0.1:0",
+            policy,
+            move |listener| {
+                // Fail twice, then run normally
+                if r_clone.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return Err(crate::Error::HandlerFailed("boom".into()));
+                }
+                listener.handle_incoming(|_| (), Duration::from_millis(5))
+            },
+            move |event| e_clone.lock().unwrap().push(event.clone()),
+        )
+        .unwrap();
+        // There is no listener while the first runs are failing
+        let addr = loop {
+            if let Some(addr) = supervisor.local_addr() {
+                break addr;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        while runs.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(supervisor.local_addr(), Some(addr));
+        supervisor.close();
+        supervisor.join();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], SupervisorEvent::Started(0));
+        assert_eq!(
+            events[1],
+            SupervisorEvent::Failed("handler failed: boom".to_string())
+        );
+        assert_eq!(
+            events[2],
+            SupervisorEvent::Restarting(Duration::from_millis(1))
+        );
+        assert_eq!(
+            events[5],
+            SupervisorEvent::Restarting(Duration::from_millis(2))
+        );
+        assert_eq!(events[6], SupervisorEvent::Started(2));
+        assert_eq!(events[7], SupervisorEvent::Stopped);
+    }
+}
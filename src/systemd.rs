@@ -0,0 +1,132 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Find sockets passed by systemd socket activation.
+//!
+//! systemd passes LISTEN_FDS descriptors starting at 3, names them in
+//! LISTEN_FDNAMES (from FileDescriptorName=, defaulting to the socket
+//! unit's name) and sets LISTEN_PID to the process they are meant for.
+
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::RawFd;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+// Find the descriptor called name in the activation environment and
+// check that it is a listening TCP socket.
+pub(crate) fn listen_fd(name: &str) -> Result<RawFd, Error> {
+    let var = |key| env::var(key).ok();
+    let fd = find_fd(
+        name,
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+    )?;
+    validate(fd)?;
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(fd)
+}
+
+fn not_found(msg: String) -> Error {
+    Error::new(ErrorKind::NotFound, msg)
+}
+
+fn find_fd(
+    name: &str,
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+) -> Result<RawFd, Error> {
+    // The variables may have been inherited from a parent
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return Err(not_found("LISTEN_PID is for another process".into()));
+        }
+    }
+    let count: RawFd = fds
+        .and_then(|fds| fds.parse().ok())
+        .ok_or_else(|| not_found("LISTEN_FDS is not set".into()))?;
+    let index = names
+        .unwrap_or("")
+        .split(':')
+        .take(count as usize)
+        .position(|n| n == name)
+        .ok_or_else(|| not_found(format!("no socket named {}", name)))?;
+    Ok(LISTEN_FDS_START + index as RawFd)
+}
+
+fn validate(fd: RawFd) -> Result<(), Error> {
+    let option = |name| -> Result<libc::c_int, Error> {
+        let mut val: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &mut val as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(val)
+    };
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockname(
+            fd,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(Error::last_os_error());
+    }
+    let family = storage.ss_family as libc::c_int;
+    if option(libc::SO_TYPE)? != libc::SOCK_STREAM
+        || (family != libc::AF_INET && family != libc::AF_INET6)
+    {
+        return Err(Error::new(ErrorKind::InvalidInput, "not a TCP socket"));
+    }
+    if option(libc::SO_ACCEPTCONN)? == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "socket is not listening",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_find_and_validate() {
+        let pid = std::process::id().to_string();
+        let names = Some("http:https");
+        assert_eq!(find_fd("https", Some(&pid), Some("2"), names).unwrap(), 4);
+        assert!(find_fd("https", Some(&pid), Some("1"), names).is_err());
+        assert!(find_fd("http", Some("1"), Some("2"), names).is_err());
+        assert!(find_fd("http", None, None, names).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        validate(listener.as_raw_fd()).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(validate(stream.as_raw_fd()).is_err());
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(validate(udp.as_raw_fd()).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Servers for tests.
+//!
+//! Tests of network code usually need a server to talk to: bound to an
+//! ephemeral port on the loopback address, running on a background
+//! thread, and shut down at the end of the test.
+//! [spawn_server_with()](fn.spawn_server_with.html) starts one with a
+//! given handler, and [spawn_echo_server()](fn.spawn_echo_server.html)
+//! one which echoes everything back. The returned
+//! [TestServer](struct.TestServer.html) shuts the server down when it
+//! is dropped, so a failing test doesn't leave it running.
+
+use crate::server::{Server, ServerHandle};
+use crate::{Error, Listener, StopReason};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A server running on a background thread.
+pub struct TestServer {
+    /// The address the server is listening on.
+    pub addr: SocketAddr,
+    handle: Option<ServerHandle>,
+}
+
+impl TestServer {
+    /// Stop the server and wait for its accept loop to terminate. A
+    /// handler panic is reported as
+    /// [Error::HandlerPanicked](../enum.Error.html#variant.HandlerPanicked).
+    pub fn shutdown(mut self) -> Result<StopReason, Error> {
+        let handle = self.handle.take().unwrap();
+        handle.stop();
+        handle.join()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.stop();
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Start a server on an ephemeral loopback port, calling handler for
+/// each connection on the accepting thread.
+pub fn spawn_server_with<H>(handler: H) -> io::Result<TestServer>
+where
+    H: FnMut(TcpStream) + Send + 'static,
+{
+    let (listener, addr) = TcpListener::bind_ephemeral()?;
+    let handle = Server::new(listener, handler)
+        .timeout(Duration::from_millis(1))
+        .start()?;
+    Ok(TestServer {
+        addr,
+        handle: Some(handle),
+    })
+}
+
+/// Start a server which echoes back whatever each connection sends,
+/// serving each on its own thread.
+pub fn spawn_echo_server() -> io::Result<TestServer> {
+    spawn_server_with(|stream: TcpStream| {
+        thread::spawn(move || {
+            let _ = stream.set_nonblocking(false);
+            if let Ok(mut reader) = stream.try_clone() {
+                let mut writer = stream;
+                let _ = io::copy(&mut reader, &mut writer);
+            }
+        });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+
+    #[test]
+    fn test_echo() {
+        let server = spawn_echo_server().unwrap();
+        let mut client = TcpStream::connect(server.addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ping");
+        server.shutdown().unwrap();
+    }
+}
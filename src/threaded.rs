@@ -0,0 +1,677 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Handle connections on a pool of worker threads.
+//!
+//! Normally the handler runs on the accepting thread, so one slow
+//! connection holds up every other accept. A
+//! [ThreadPoolListener](struct.ThreadPoolListener.html) instead queues
+//! each accepted connection for a fixed set of worker threads. The
+//! queue is bounded and, when it is full, an
+//! [Overflow](enum.Overflow.html) policy decides whether the accept
+//! loop waits for space or answers the connection with a
+//! [RejectAction](../reject/enum.RejectAction.html).
+//!
+//! [shutdown()](struct.ThreadPoolListener.html#method.shutdown) closes
+//! the listener and waits a grace period for queued and running
+//! handlers to finish, reporting how many did as a
+//! [DrainSummary](../drain/struct.DrainSummary.html).
+//!
+//! The workers are started up front and wait for connections, so none
+//! is held up by a thread being spawned. For latency sensitive
+//! benchmarks, the `core_affinity` feature adds
+//! [new_pinned()](struct.ThreadPoolListener.html#method.new_pinned),
+//! which also pins each worker to a CPU core (Linux only).
+//!
+//! With [with_watchdog()](struct.ThreadPoolListener.html#method.with_watchdog),
+//! each handler is registered with a [Watchdog](../watchdog/struct.Watchdog.html)
+//! while it runs.
+
+use crate::drain::DrainSummary;
+use crate::reject::RejectAction;
+use crate::watchdog::Watchdog;
+use crate::{accept_loop, Callbacks, Listener, StopReason};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// What to do with a connection when the queue is full.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Answer the connection with this action.
+    Reject(RejectAction),
+    /// Stop accepting until a worker frees a place in the queue, so
+    /// further connections wait in the kernel's accept queue.
+    Block,
+}
+
+impl Default for Overflow {
+    /// Close the connection gracefully.
+    fn default() -> Overflow {
+        Overflow::Reject(RejectAction::default())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Queue {
+    pub(crate) streams: VecDeque<TcpStream>,
+    // Handlers currently running
+    running: usize,
+    // Worker threads which haven't exited
+    alive: usize,
+    pub(crate) shutdown: bool,
+}
+
+// A bounded queue of accepted connections, between the accept loop
+// which pushes them and the threads which take them. It also underlies
+// a ConnQueue, whose threads belong to the application.
+pub(crate) struct StreamQueue {
+    queue: Mutex<Queue>,
+    // Signalled when a connection is queued or the queue shut down
+    ready: Condvar,
+    // Signalled when a connection is taken from the queue
+    space: Condvar,
+    // Signalled when a handler finishes
+    finished: Condvar,
+    depth: usize,
+}
+
+impl StreamQueue {
+    // A queue holding up to depth connections. A depth of zero is
+    // treated as one.
+    pub(crate) fn new(depth: usize) -> StreamQueue {
+        StreamQueue {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            finished: Condvar::new(),
+            depth: std::cmp::max(depth, 1),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+
+    pub(crate) fn is_full(&self, queue: &Queue) -> bool {
+        queue.streams.len() >= self.depth
+    }
+
+    // Queue a connection, handing it back if the queue is full or shut
+    // down.
+    pub(crate) fn push(&self, stream: TcpStream) -> Result<(), TcpStream> {
+        let mut queue = self.lock();
+        if queue.shutdown || self.is_full(&queue) {
+            return Err(stream);
+        }
+        self.push_locked(&mut queue, stream);
+        Ok(())
+    }
+
+    pub(crate) fn push_locked(&self, queue: &mut Queue, stream: TcpStream) {
+        queue.streams.push_back(stream);
+        self.ready.notify_one();
+    }
+
+    // Take the connection which has waited longest, waiting up to
+    // timeout, or for as long as it takes, for one. Returns None once
+    // the queue is shut down and empty. A handler taking it is counted
+    // as running until finish().
+    fn take(&self, timeout: Option<Duration>, handler: bool) -> Option<TcpStream> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut queue = self.lock();
+        loop {
+            if let Some(stream) = queue.streams.pop_front() {
+                if handler {
+                    queue.running += 1;
+                }
+                self.space.notify_one();
+                return Some(stream);
+            }
+            if queue.shutdown {
+                return None;
+            }
+            queue = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining == Duration::from_secs(0) {
+                        return None;
+                    }
+                    self.ready.wait_timeout(queue, remaining).unwrap().0
+                }
+                None => self.ready.wait(queue).unwrap(),
+            };
+        }
+    }
+
+    pub(crate) fn pop(&self, timeout: Option<Duration>) -> Option<TcpStream> {
+        self.take(timeout, false)
+    }
+
+    // Wait for the next connection for a worker.
+    fn next(&self) -> Option<TcpStream> {
+        self.take(None, true)
+    }
+
+    fn finish(&self) {
+        self.lock().running -= 1;
+        self.finished.notify_all();
+    }
+
+    // Wait up to timeout for the queue to have space.
+    pub(crate) fn wait_for_space(&self, timeout: Duration) -> bool {
+        let queue = self.lock();
+        if !self.is_full(&queue) {
+            return true;
+        }
+        let (queue, _) = self.space.wait_timeout(queue, timeout).unwrap();
+        !self.is_full(&queue)
+    }
+
+    // Refuse further connections, and let takers finish once the
+    // queue is empty.
+    pub(crate) fn shut_down(&self) {
+        self.lock().shutdown = true;
+        self.ready.notify_all();
+    }
+}
+
+struct Shared {
+    queue: StreamQueue,
+    watchdog: Mutex<Option<Arc<Watchdog>>>,
+}
+
+/// Dispatches accepted connections to a fixed set of worker threads.
+pub struct ThreadPoolListener {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    overflow: Overflow,
+    rejected: AtomicU64,
+    abandoned: AtomicBool,
+}
+
+impl ThreadPoolListener {
+    /// Start pool_size worker threads which call handler for each
+    /// dispatched connection. At most depth connections wait in the
+    /// queue. Connections which don't fit are closed gracefully.
+    pub fn new<F>(pool_size: usize, depth: usize, handler: F) -> ThreadPoolListener
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        // Workers which aren't pinned can't fail to start
+        ThreadPoolListener::start(pool_size, depth, &[], handler).unwrap()
+    }
+
+    /// Works like new(), but pins worker i to CPU core cores[i %
+    /// cores.len()] and only returns once every worker is running, so
+    /// that the first connections find them ready. Fails with
+    /// ErrorKind::InvalidInput if a core number is out of range, if a
+    /// worker can't be pinned, and with ErrorKind::Unsupported on
+    /// platforms other than Linux.
+    #[cfg(feature = "core_affinity")]
+    pub fn new_pinned<F>(
+        pool_size: usize,
+        depth: usize,
+        cores: &[usize],
+        handler: F,
+    ) -> Result<ThreadPoolListener, Error>
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        if cores.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "no cores given"));
+        }
+        if let Some(core) = cores.iter().find(|core| !valid_core(**core)) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("core {} out of range", core),
+            ));
+        }
+        ThreadPoolListener::start(pool_size, depth, cores, handler)
+    }
+
+    // Start the workers, pinning them if cores isn't empty, and wait
+    // until they are all running.
+    fn start<F>(
+        pool_size: usize,
+        depth: usize,
+        cores: &[usize],
+        handler: F,
+    ) -> Result<ThreadPoolListener, Error>
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: StreamQueue::new(depth),
+            watchdog: Mutex::new(None),
+        });
+        let handler = Arc::new(handler);
+        let (started_tx, started) = mpsc::channel();
+        let pool_size = std::cmp::max(pool_size, 1);
+        shared.queue.lock().alive = pool_size;
+        let workers: Vec<JoinHandle<()>> = (0..pool_size)
+            .map(|i| {
+                let shared = shared.clone();
+                let handler = handler.clone();
+                let core = cores.get(i % std::cmp::max(cores.len(), 1)).copied();
+                let started_tx = started_tx.clone();
+                thread::spawn(move || {
+                    let _alive = Alive(&shared);
+                    let pinned = core.map_or(Ok(()), pin_to_core);
+                    let failed = pinned.is_err();
+                    let _ = started_tx.send(pinned);
+                    if failed {
+                        return;
+                    }
+                    while let Some(stream) = shared.queue.next() {
+                        let watchdog = shared.watchdog.lock().unwrap().clone();
+                        let guard = watchdog.map(|w| w.watch(&stream));
+                        // A panicking handler mustn't take the worker,
+                        // or its place in the running count, with it
+                        if let None | Some(Ok(_)) = guard {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(stream)));
+                        }
+                        drop(guard);
+                        shared.queue.finish();
+                    }
+                })
+            })
+            .collect();
+        // Only the workers hold senders now, so a worker which dies
+        // before reporting can't leave the wait below hanging
+        drop(started_tx);
+        let pool = ThreadPoolListener {
+            shared,
+            workers,
+            overflow: Overflow::default(),
+            rejected: AtomicU64::new(0),
+            abandoned: AtomicBool::new(false),
+        };
+        for _ in 0..pool.workers.len() {
+            // Dropping the pool stops the workers which did start
+            match started.recv() {
+                Ok(pinned) => pinned?,
+                Err(_) => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "worker exited before starting",
+                    ))
+                }
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Deal with connections which don't fit in the queue according to
+    /// overflow.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Register each handler with watchdog while it runs. Connections
+    /// which can't be registered are dropped without being handled.
+    pub fn with_watchdog(self, watchdog: Arc<Watchdog>) -> Self {
+        *self.shared.watchdog.lock().unwrap() = Some(watchdog);
+        self
+    }
+
+    /// Queue a connection. If the queue is full, or the pool is shut
+    /// down, the connection is handed back.
+    pub fn dispatch(&self, stream: TcpStream) -> Result<(), TcpStream> {
+        self.shared.queue.push(stream)
+    }
+
+    /// Number of connections waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().streams.len()
+    }
+
+    /// Number of connections rejected because the queue was full.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Accept connections from listener until it is closed, dispatching
+    /// each one to the pool. Connections which don't fit in the queue
+    /// are dealt with according to the overflow policy.
+    pub fn handle_incoming(
+        &self,
+        listener: &TcpListener,
+        timeout: Duration,
+    ) -> Result<StopReason, crate::Error> {
+        let mut submit = |stream, _, _| self.submit(stream);
+        accept_loop(listener, &mut submit, timeout, Callbacks::default())
+            .map_err(crate::Error::from)
+    }
+
+    /// Close listener, so handle_incoming() terminates, and wait up to
+    /// grace for queued and running handlers to finish. Connections
+    /// still queued when the grace period expires are closed without
+    /// being handled, and handlers still running are left to finish on
+    /// their own: both are counted as aborted.
+    pub fn shutdown(&self, listener: &TcpListener, grace: Duration) -> DrainSummary {
+        listener.close();
+        let deadline = Instant::now() + grace;
+        let shared = &self.shared.queue;
+        let mut queue = shared.lock();
+        let in_flight = queue.streams.len() + queue.running;
+        queue.shutdown = true;
+        shared.ready.notify_all();
+        while queue.streams.len() + queue.running > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            queue = shared.finished.wait_timeout(queue, remaining).unwrap().0;
+        }
+        let aborted = queue.streams.len() + queue.running;
+        queue.streams.clear();
+        if queue.running > 0 {
+            // Don't let drop wait for handlers which overran
+            self.abandoned.store(true, Ordering::SeqCst);
+        }
+        DrainSummary {
+            drained: in_flight - aborted,
+            aborted,
+        }
+    }
+
+    fn submit(&self, stream: TcpStream) {
+        let stream = match self.dispatch(stream) {
+            Ok(()) => return,
+            Err(stream) => stream,
+        };
+        match self.overflow {
+            Overflow::Reject(ref action) => {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
+                action.apply(stream);
+            }
+            Overflow::Block => {
+                let shared = &self.shared.queue;
+                let mut queue = shared.lock();
+                while shared.is_full(&queue) || queue.shutdown {
+                    // With no workers left, the queue will never drain
+                    if queue.alive == 0 || queue.shutdown {
+                        drop(queue);
+                        self.rejected.fetch_add(1, Ordering::SeqCst);
+                        RejectAction::default().apply(stream);
+                        return;
+                    }
+                    queue = shared
+                        .space
+                        .wait_timeout(queue, Duration::from_millis(100))
+                        .unwrap()
+                        .0;
+                }
+                shared.push_locked(&mut queue, stream);
+            }
+        }
+    }
+}
+
+#[cfg(all(
+    feature = "core_affinity",
+    any(target_os = "linux", target_os = "android")
+))]
+fn pin_to_core(core: usize) -> Result<(), Error> {
+    // CPU_SET() panics beyond the set's size
+    if !valid_core(core) {
+        return Err(Error::new(ErrorKind::InvalidInput, "core out of range"));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(
+    feature = "core_affinity",
+    any(target_os = "linux", target_os = "android")
+))]
+fn valid_core(core: usize) -> bool {
+    core < libc::CPU_SETSIZE as usize
+}
+
+#[cfg(all(
+    feature = "core_affinity",
+    not(any(target_os = "linux", target_os = "android"))
+))]
+fn valid_core(_core: usize) -> bool {
+    true
+}
+
+#[cfg(not(all(
+    feature = "core_affinity",
+    any(target_os = "linux", target_os = "android")
+)))]
+fn pin_to_core(_core: usize) -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "pinning threads to cores is not supported",
+    ))
+}
+
+// Counts a worker out when its thread exits.
+struct Alive<'a>(&'a Shared);
+
+impl Drop for Alive<'_> {
+    fn drop(&mut self) {
+        self.0.queue.lock().alive -= 1;
+        self.0.queue.space.notify_all();
+    }
+}
+
+impl Drop for ThreadPoolListener {
+    /// Queued connections are handled before the workers exit. Workers
+    /// whose handlers overran a shutdown() are not waited for.
+    fn drop(&mut self) {
+        self.shared.queue.shut_down();
+        if self.abandoned.load(Ordering::SeqCst) {
+            return;
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_pool() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        // The single worker is held until the test releases it
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let started_tx = Mutex::new(started_tx);
+        let release_rx = Mutex::new(release_rx);
+        let pool = Arc::new(
+            ThreadPoolListener::new(1, 1, move |mut stream: TcpStream| {
+                started_tx.lock().unwrap().send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+                let _ = stream.set_nonblocking(false);
+                let _ = stream.write_all(b"ok");
+            })
+            .with_overflow(Overflow::Reject(RejectAction::Respond(b"busy".to_vec()))),
+        );
+        let l_clone = listener.clone();
+        let p_clone = pool.clone();
+        let server = thread::spawn(move || {
+            p_clone
+                .handle_incoming(&l_clone, Duration::from_millis(5))
+                .unwrap()
+        });
+
+        // One connection is being handled, one queued and one rejected
+        let mut handled = TcpStream::connect(addr).unwrap();
+        started.recv().unwrap();
+        let mut queued = TcpStream::connect(addr).unwrap();
+        while pool.queued() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        let mut reply = String::new();
+        rejected.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "busy");
+        assert_eq!(pool.rejected(), 1);
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        for client in [&mut handled, &mut queued] {
+            reply.clear();
+            client.read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, "ok");
+        }
+        listener.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_panic() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let pool = ThreadPoolListener::new(1, 1, move |_| {
+            tx.lock().unwrap().send(()).unwrap();
+            panic!("handler");
+        })
+        .with_overflow(Overflow::Block);
+        let _clients: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for _ in 0..2 {
+            let stream = loop {
+                if let Ok((stream, _)) = listener.accept() {
+                    break stream;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+            pool.submit(stream);
+        }
+        // The worker survives the first panic to handle the second
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        let started = Instant::now();
+        let summary = pool.shutdown(&listener, Duration::from_secs(30));
+        assert_eq!(summary.aborted, 0);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let watchdog = Arc::new(Watchdog::new(Duration::from_millis(20), true, |_| ()));
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let pool = ThreadPoolListener::new(1, 1, move |mut stream: TcpStream| {
+            // Blocks until the watchdog shuts the stream down
+            let _ = stream.read(&mut [0; 1]);
+            tx.lock().unwrap().send(()).unwrap();
+        })
+        .with_watchdog(watchdog.clone());
+        let _client = TcpStream::connect(addr).unwrap();
+        let stream = loop {
+            if let Ok((stream, _)) = listener.accept() {
+                break stream;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        stream.set_nonblocking(false).unwrap();
+        pool.submit(stream);
+        rx.recv().unwrap();
+        pool.shutdown(&listener, Duration::from_secs(5));
+        assert_eq!(watchdog.active(), 0);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let listener: Arc<TcpListener> = Arc::new(Listener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        // Handlers finish when their client sends a byte
+        let (started_tx, started) = mpsc::channel();
+        let started_tx = Mutex::new(started_tx);
+        let pool = Arc::new(ThreadPoolListener::new(2, 4, move |stream: TcpStream| {
+            started_tx.lock().unwrap().send(()).unwrap();
+            let _ = stream.set_nonblocking(false);
+            let _ = (&stream).read(&mut [0]);
+        }));
+        let l_clone = listener.clone();
+        let p_clone = pool.clone();
+        let server = thread::spawn(move || {
+            p_clone
+                .handle_incoming(&l_clone, Duration::from_millis(5))
+                .unwrap()
+        });
+
+        let mut quick = TcpStream::connect(addr).unwrap();
+        let _slow = TcpStream::connect(addr).unwrap();
+        started.recv().unwrap();
+        started.recv().unwrap();
+        let finisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            quick.write_all(b"x").unwrap();
+        });
+        let summary = pool.shutdown(&listener, Duration::from_millis(500));
+        assert_eq!(
+            summary,
+            DrainSummary {
+                drained: 1,
+                aborted: 1
+            }
+        );
+        server.join().unwrap();
+        finisher.join().unwrap();
+    }
+
+    #[cfg(all(feature = "core_affinity", target_os = "linux"))]
+    #[test]
+    fn test_pinned() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let pool = ThreadPoolListener::new_pinned(2, 4, &[0], move |_| {
+            // Report the cores this worker may run on
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            let size = std::mem::size_of::<libc::cpu_set_t>();
+            assert_eq!(unsafe { libc::sched_getaffinity(0, size, &mut set) }, 0);
+            let count = unsafe { libc::CPU_COUNT(&set) };
+            let on_zero = unsafe { libc::CPU_ISSET(0, &set) };
+            tx.lock().unwrap().send((count, on_zero)).unwrap();
+        })
+        .unwrap();
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = loop {
+            if let Ok((stream, _)) = listener.accept() {
+                break stream;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        pool.dispatch(stream).unwrap();
+        assert_eq!(rx.recv().unwrap(), (1, true));
+
+        let err = ThreadPoolListener::new_pinned(1, 1, &[5000], |_| ())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
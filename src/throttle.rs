@@ -0,0 +1,248 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bandwidth throttling of individual connections.
+//!
+//! A [Throttle](struct.Throttle.html) describes read and/or write rate
+//! limits and wraps streams in a [Throttled](struct.Throttled.html),
+//! which enforces them with a token bucket per direction. Pick the
+//! throttle in the accept path, e.g. based on the peer address or its
+//! [QosClass](../qos/enum.QosClass.html), and wrap the stream before
+//! the handler sees it. A rate of zero blocks a direction outright once
+//! its burst is used up: reads or writes then fail with
+//! [WouldBlock](https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.WouldBlock)
+//! rather than waiting forever.
+
+use crate::clock::{RealTime, Time};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A token bucket which refills at rate tokens per second, up to burst.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last: Instant,
+    time: Arc<dyn Time>,
+}
+
+impl TokenBucket {
+    /// Create a full bucket. A burst of zero is treated as one.
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        TokenBucket::with_time(rate, burst, Arc::new(RealTime))
+    }
+
+    /// Create a full bucket which reads the time from, and waits on,
+    /// time rather than the real clock.
+    pub fn with_time(rate: u64, burst: u64, time: Arc<dyn Time>) -> TokenBucket {
+        let burst = std::cmp::max(burst, 1);
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last: time.now(),
+            time,
+        }
+    }
+
+    /// Maximum number of tokens the bucket holds.
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// Number of whole tokens currently available.
+    pub fn available(&mut self) -> u64 {
+        self.refill();
+        self.tokens as u64
+    }
+
+    /// Remove n tokens. The bucket may go into debt, in which case it
+    /// takes longer to refill.
+    pub fn consume(&mut self, n: u64) {
+        self.refill();
+        self.tokens -= n as f64;
+    }
+
+    /// How long until n tokens (capped at burst) are available. This is
+    /// Duration::MAX if they never will be, i.e. the rate is zero.
+    pub fn time_until(&mut self, n: u64) -> Duration {
+        self.refill();
+        let wanted = std::cmp::min(n, self.burst) as f64;
+        if self.tokens >= wanted {
+            Duration::from_secs(0)
+        } else if self.rate == 0 {
+            Duration::MAX
+        } else {
+            Duration::from_secs_f64((wanted - self.tokens) / self.rate as f64)
+        }
+    }
+
+    /// Wait until n tokens (capped at burst) are available and return
+    /// how many are, which may be more than n. If the rate is zero this
+    /// doesn't wait, and returns however many are available now.
+    pub fn wait_for(&mut self, n: u64) -> u64 {
+        loop {
+            let wait = self.time_until(n);
+            if wait == Duration::from_secs(0) || wait == Duration::MAX {
+                return self.available();
+            }
+            self.time.sleep(wait);
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = self.time.now();
+        let earned = now.duration_since(self.last).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + earned).min(self.burst as f64);
+        self.last = now;
+    }
+}
+
+/// Read and write rate limits, in bytes per second.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Throttle {
+    read: Option<(u64, u64)>,
+    write: Option<(u64, u64)>,
+}
+
+impl Throttle {
+    /// Create a throttle which doesn't limit anything.
+    pub fn new() -> Throttle {
+        Throttle::default()
+    }
+
+    /// Limit reads to rate bytes per second, allowing bursts of up to
+    /// burst bytes.
+    pub fn read_rate(mut self, rate: u64, burst: u64) -> Self {
+        self.read = Some((rate, burst));
+        self
+    }
+
+    /// Limit writes to rate bytes per second, allowing bursts of up to
+    /// burst bytes.
+    pub fn write_rate(mut self, rate: u64, burst: u64) -> Self {
+        self.write = Some((rate, burst));
+        self
+    }
+
+    /// Wrap stream so that it is subject to these limits.
+    pub fn wrap<S>(&self, stream: S) -> Throttled<S> {
+        Throttled {
+            stream,
+            read: self.read.map(|(rate, burst)| TokenBucket::new(rate, burst)),
+            write: self
+                .write
+                .map(|(rate, burst)| TokenBucket::new(rate, burst)),
+        }
+    }
+}
+
+/// A stream whose reads and writes are rate limited.
+#[derive(Debug)]
+pub struct Throttled<S> {
+    stream: S,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+}
+
+impl<S> Throttled<S> {
+    /// The wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The wrapped stream. Reading or writing it directly bypasses the
+    /// limits.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Unwrap the stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let bucket = match self.read.as_mut() {
+            Some(bucket) => bucket,
+            None => return self.stream.read(buf),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let allowed = std::cmp::min(bucket.wait_for(buf.len() as u64) as usize, buf.len());
+        if allowed == 0 {
+            return Err(exhausted());
+        }
+        let n = self.stream.read(&mut buf[..allowed])?;
+        bucket.consume(n as u64);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let bucket = match self.write.as_mut() {
+            Some(bucket) => bucket,
+            None => return self.stream.write(buf),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let allowed = std::cmp::min(bucket.wait_for(buf.len() as u64) as usize, buf.len());
+        if allowed == 0 {
+            return Err(exhausted());
+        }
+        let n = self.stream.write(&buf[..allowed])?;
+        bucket.consume(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn exhausted() -> Error {
+    Error::new(ErrorKind::WouldBlock, "rate limit of zero exhausted")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_rate() {
+        let throttle = Throttle::new().write_rate(1000, 100);
+        let mut stream = throttle.wrap(Vec::new());
+        let started = Instant::now();
+        stream.write_all(&[0; 300]).unwrap();
+        // The first 100 bytes are a burst, the rest take ~200ms
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(stream.into_inner().len(), 300);
+
+        let mut stream = Throttle::new().wrap(&b"unlimited"[..]);
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "unlimited");
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let mut stream = Throttle::new().read_rate(0, 4).wrap(&b"abcdefgh"[..]);
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 4);
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+}
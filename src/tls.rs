@@ -0,0 +1,158 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Just enough TLS to read a ClientHello which has been peeked at.
+
+use crate::peek::peek_n;
+use std::io::Error;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const EXT_SERVER_NAME: u16 = 0;
+
+pub(crate) struct ClientHello<'a> {
+    pub(crate) version: u16,
+    pub(crate) ciphers: &'a [u8],
+    pub(crate) extensions: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> ClientHello<'a> {
+    // Parse the ClientHello at the start of data, which should be the
+    // first TLS record sent by the client.
+    pub(crate) fn parse(data: &'a [u8]) -> Option<ClientHello<'a>> {
+        let mut record = Reader::new(data);
+        if record.u8()? != HANDSHAKE {
+            return None;
+        }
+        record.skip(2)?;
+        let mut hello = Reader::new(record.vec16()?);
+        if hello.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        let len = hello.u24()?;
+        let mut hello = Reader::new(hello.bytes(len)?);
+        let version = hello.u16()?;
+        hello.skip(32)?;
+        hello.vec8()?;
+        let ciphers = hello.vec16()?;
+        hello.vec8()?;
+        let mut extensions = vec![];
+        if !hello.is_empty() {
+            let mut exts = Reader::new(hello.vec16()?);
+            while !exts.is_empty() {
+                let kind = exts.u16()?;
+                extensions.push((kind, exts.vec16()?));
+            }
+        }
+        Some(ClientHello {
+            version,
+            ciphers,
+            extensions,
+        })
+    }
+
+    // The host name from the server_name extension, in lower case.
+    pub(crate) fn server_name(&self) -> Option<String> {
+        let (_, body) = self
+            .extensions
+            .iter()
+            .find(|(kind, _)| *kind == EXT_SERVER_NAME)?;
+        let mut names = Reader::new(Reader::new(body).vec16()?);
+        while !names.is_empty() {
+            let kind = names.u8()?;
+            let name = names.vec16()?;
+            if kind == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+            }
+        }
+        None
+    }
+}
+
+// Peek at the first TLS record on stream, waiting up to timeout for it
+// to arrive. Returns None if the client doesn't appear to be speaking
+// TLS.
+pub(crate) fn peek_client_hello(
+    stream: &TcpStream,
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>, Error> {
+    let deadline = Instant::now() + timeout;
+    // Check the first byte alone, so that other protocols are spotted
+    // without waiting for a full record header
+    if peek_n(stream, 1, timeout)?.first() != Some(&HANDSHAKE) {
+        return Ok(None);
+    }
+    let header = peek_n(
+        stream,
+        5,
+        deadline.saturating_duration_since(Instant::now()),
+    )?;
+    if header.len() < 5 {
+        return Ok(None);
+    }
+    let wanted = 5 + u16::from_be_bytes([header[3], header[4]]) as usize;
+    let record = peek_n(
+        stream,
+        wanted,
+        deadline.saturating_duration_since(Instant::now()),
+    )?;
+    Ok(Some(record))
+}
+
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    pub(crate) fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub(crate) fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    pub(crate) fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    pub(crate) fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
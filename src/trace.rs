@@ -0,0 +1,124 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Events describing what an accept loop is doing.
+//!
+//! [Listener::handle_incoming_traced()](../trait.Listener.html#tymethod.handle_incoming_traced)
+//! reports a [LoopEvent](enum.LoopEvent.html) as the loop starts, for
+//! each connection and handler, for idle sleeps (sampled) and when it
+//! stops. The crate doesn't depend on a logging library; forward the
+//! events to log or tracing from the callback, e.g. with
+//! `log::debug!("{}", event)`, whose Display form is one line with the
+//! connection id and peer address, preceded by the loop's labels, if
+//! any.
+
+use crate::labels::Labels;
+use std::fmt;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Something an accept loop did.
+#[derive(Debug)]
+pub enum LoopEvent<'a> {
+    /// The loop started on a listener bound to addr.
+    Started {
+        /// The listener's address.
+        addr: SocketAddr,
+        /// Labels of the loop, see
+        /// [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope).
+        labels: &'a Labels,
+    },
+    /// A connection was accepted.
+    Accepted {
+        /// Id of the connection, as in
+        /// [Connection](../connection/struct.Connection.html).
+        id: u64,
+        /// Address of the peer.
+        peer: SocketAddr,
+        /// Labels of the loop, see
+        /// [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope).
+        labels: &'a Labels,
+    },
+    /// The handler returned.
+    HandlerFinished {
+        /// Id of the connection, as in
+        /// [Connection](../connection/struct.Connection.html).
+        id: u64,
+        /// Address of the peer.
+        peer: SocketAddr,
+        /// How long the handler ran.
+        elapsed: Duration,
+        /// Labels of the loop, see
+        /// [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope).
+        labels: &'a Labels,
+    },
+    /// No connection was waiting, so the loop slept. Reported for the
+    /// first sleep of each idle period, then at every power of two.
+    Idle {
+        /// Number of sleeps so far in this idle period.
+        sleeps: u64,
+        /// Labels of the loop, see
+        /// [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope).
+        labels: &'a Labels,
+    },
+    /// The loop failed with err.
+    Failed {
+        /// The error.
+        err: &'a Error,
+        /// Labels of the loop, see
+        /// [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope).
+        labels: &'a Labels,
+    },
+    /// The listener was closed, so the loop terminated normally.
+    Stopped {
+        /// Total number of connections accepted.
+        accepted: u64,
+        /// Labels of the loop, see
+        /// [Labels::in_scope()](../labels/struct.Labels.html#method.in_scope).
+        labels: &'a Labels,
+    },
+}
+
+impl fmt::Display for LoopEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = match self {
+            LoopEvent::Started { labels, .. }
+            | LoopEvent::Accepted { labels, .. }
+            | LoopEvent::HandlerFinished { labels, .. }
+            | LoopEvent::Idle { labels, .. }
+            | LoopEvent::Failed { labels, .. }
+            | LoopEvent::Stopped { labels, .. } => labels,
+        };
+        if !labels.is_empty() {
+            write!(f, "{} ", labels)?;
+        }
+        match self {
+            LoopEvent::Started { addr, .. } => write!(f, "listening on {}", addr),
+            LoopEvent::Accepted { id, peer, .. } => {
+                write!(f, "conn={} peer={} accepted", id, peer)
+            }
+            LoopEvent::HandlerFinished {
+                id, peer, elapsed, ..
+            } => {
+                write!(f, "conn={} peer={} handled in {:?}", id, peer, elapsed)
+            }
+            LoopEvent::Idle { sleeps, .. } => write!(f, "idle, {} sleeps", sleeps),
+            LoopEvent::Failed { err, .. } => write!(f, "accept loop failed: {}", err),
+            LoopEvent::Stopped { accepted, .. } => {
+                write!(f, "stopped after {} connections", accepted)
+            }
+        }
+    }
+}
+
+// Report idle sleeps 1, 2, 4, 8, ... of each idle period.
+pub(crate) fn sample_idle(sleeps: u64) -> bool {
+    sleeps.is_power_of_two()
+}
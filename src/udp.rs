@@ -0,0 +1,136 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A stoppable receive loop for UDP sockets.
+//!
+//! [UdpSocketExt](trait.UdpSocketExt.html) gives a UdpSocket the same
+//! treatment as the [Listener](../trait.Listener.html) trait gives a
+//! TcpListener: bind() makes the socket non-blocking,
+//! handle_incoming() passes each datagram to a handler, sleeping while
+//! none is waiting, and close(), from any thread, makes it terminate
+//! normally.
+
+use crate::{close_raw, is_closed, StopReason};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+#[cfg(not(windows))]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+use std::thread;
+use std::time::Duration;
+
+// Large enough for any UDP datagram
+const MAX_DATAGRAM: usize = 65536;
+
+/// Stoppable receive loop for UDP sockets.
+pub trait UdpSocketExt {
+    /// Creates a new UdpSocket bound to addr. Works exactly the same as
+    /// UdpSocket::bind(), but always forces the socket to be
+    /// non-blocking.
+    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Close the socket. No more datagrams will be received and if
+    /// handle_incoming() is active, it will terminate normally.
+    fn close(&self);
+
+    /// Start handling incoming datagrams, passing each one to handler
+    /// with its sender and the socket, to reply on. While none is
+    /// waiting, sleep for timeout.
+    fn handle_incoming<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(&[u8], SocketAddr, &UdpSocket);
+}
+
+impl UdpSocketExt for UdpSocket {
+    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(socket)
+    }
+
+    fn close(&self) {
+        #[cfg(windows)]
+        close_raw(self.as_raw_socket() as usize);
+        #[cfg(not(windows))]
+        close_raw(self.as_raw_fd() as usize);
+    }
+
+    fn handle_incoming<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(&[u8], SocketAddr, &UdpSocket),
+    {
+        let mut handler = handler;
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            match self.recv_from(&mut buf) {
+                Ok((len, addr)) => handler(&buf[..len], addr, self),
+                Err(err) => {
+                    if err.kind() == ErrorKind::WouldBlock {
+                        thread::sleep(timeout);
+                    } else if err.kind() == ErrorKind::ConnectionReset {
+                        // Windows reports an earlier send's ICMP
+                        // unreachable this way; it isn't fatal
+                        continue;
+                    } else if is_closed(&err) || is_socket_closed(self) {
+                        return Ok(StopReason::Closed);
+                    } else {
+                        return Err(err.into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// On Unix, close() replaces the socket with an unbound TCP socket,
+// whose recv_from() fails with ENOTCONN rather than EBADF. Like a
+// closed listener, it has port zero.
+fn is_socket_closed(socket: &UdpSocket) -> bool {
+    match socket.local_addr() {
+        Ok(addr) => addr.port() == 0,
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_udp() {
+        let socket: Arc<UdpSocket> = Arc::new(UdpSocketExt::bind("127.0.0.1:0").unwrap());
+        let addr = socket.local_addr().unwrap();
+        let s_clone = socket.clone();
+        let server = thread::spawn(move || {
+            s_clone
+                .handle_incoming(
+                    |data, from, socket| {
+                        socket.send_to(data, from).unwrap();
+                    },
+                    Duration::from_millis(5),
+                )
+                .unwrap()
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", addr).unwrap();
+        let mut buf = [0; 16];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, addr);
+
+        socket.close();
+        server.join().unwrap();
+    }
+}
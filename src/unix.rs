@@ -0,0 +1,100 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Unix domain socket listeners (Unix only).
+//!
+//! [UnixListenerExt](trait.UnixListenerExt.html) gives a
+//! `std::os::unix::net::UnixListener` the same stoppable accept loop as
+//! the [Listener](../trait.Listener.html) trait gives a TcpListener:
+//! bind() makes the socket non-blocking, and close(), from any thread,
+//! makes handle_incoming() terminate normally. The loop is the one
+//! provided by [Acceptor](../acceptor/trait.Acceptor.html).
+
+use crate::{acceptor, StopReason};
+use std::io::Error;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// Stoppable accept loop for Unix domain sockets.
+pub trait UnixListenerExt {
+    /// Creates a new UnixListener bound to path. Works exactly the same
+    /// as UnixListener::bind(), but always forces the bound socket to
+    /// be non-blocking.
+    fn bind<P: AsRef<Path>>(path: P) -> Result<Self, Error>
+    where
+        Self: std::marker::Sized;
+
+    /// Close the listener. No more connections will be accepted and
+    /// if handle_incoming() is active, it will terminate normally. The
+    /// socket file is not removed.
+    fn close(&self);
+
+    /// Start handling incoming connections, as
+    /// [Listener::handle_incoming()](../trait.Listener.html#tymethod.handle_incoming).
+    fn handle_incoming<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(UnixStream);
+}
+
+impl UnixListenerExt for UnixListener {
+    fn bind<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(listener)
+    }
+
+    fn close(&self) {
+        acceptor::Acceptor::close_listener(self)
+    }
+
+    fn handle_incoming<H>(&self, handler: H, timeout: Duration) -> Result<StopReason, crate::Error>
+    where
+        H: FnMut(UnixStream),
+    {
+        acceptor::Acceptor::serve(self, handler, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_unix() {
+        let path = std::env::temp_dir().join(format!("nblistener-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener: Arc<UnixListener> = Arc::new(UnixListenerExt::bind(&path).unwrap());
+        let l_clone = listener.clone();
+        let server = thread::spawn(move || {
+            l_clone
+                .handle_incoming(
+                    |mut stream| {
+                        let _ = stream.set_nonblocking(false);
+                        let _ = stream.write_all(b"ok");
+                    },
+                    Duration::from_millis(5),
+                )
+                .unwrap()
+        });
+
+        let mut reply = String::new();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ok");
+
+        listener.close();
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Conditions which end an accept loop.
+//!
+//! [handle_incoming_until()](../trait.Listener.html#tymethod.handle_incoming_until)
+//! checks a [StopCondition](trait.StopCondition.html) before each
+//! accept and returns normally once it is met, without the listener
+//! having to be closed. A [Deadline](struct.Deadline.html) stops the
+//! loop at a fixed time, e.g. to run a test server for two seconds, and
+//! any `FnMut() -> bool` closure can be used as a predicate. A
+//! Deadline reads the time from a [Time](../clock/trait.Time.html), so
+//! a [SimClock](../clock/struct.SimClock.html) can run it out.
+
+use crate::clock::{RealTime, Time};
+use crate::StopReason;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Decides when an accept loop should stop.
+pub trait StopCondition {
+    /// Should the loop stop now?
+    fn should_stop(&mut self) -> bool;
+
+    /// Why the loop stopped, once should_stop() returned true.
+    fn reason(&self) -> StopReason {
+        StopReason::Condition
+    }
+}
+
+impl<F: FnMut() -> bool> StopCondition for F {
+    fn should_stop(&mut self) -> bool {
+        self()
+    }
+}
+
+/// Stops the loop at a point in time.
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    at: Instant,
+    time: Arc<dyn Time>,
+}
+
+impl Deadline {
+    /// A deadline at a point in time on the real clock.
+    pub fn at(at: Instant) -> Deadline {
+        Deadline {
+            at,
+            time: Arc::new(RealTime),
+        }
+    }
+
+    /// A deadline duration from now.
+    pub fn after(duration: Duration) -> Deadline {
+        Deadline::with_time(duration, Arc::new(RealTime))
+    }
+
+    /// A deadline duration from now, reading the time from time rather
+    /// than the real clock.
+    pub fn with_time(duration: Duration, time: Arc<dyn Time>) -> Deadline {
+        Deadline {
+            at: time.now() + duration,
+            time,
+        }
+    }
+
+    /// The point in time at which the loop stops.
+    pub fn instant(&self) -> Instant {
+        self.at
+    }
+
+    /// Time left until the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(self.time.now())
+    }
+}
+
+impl StopCondition for Deadline {
+    fn should_stop(&mut self) -> bool {
+        self.time.now() >= self.at
+    }
+
+    fn reason(&self) -> StopReason {
+        StopReason::Deadline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{SimClock, Sleeper};
+    use crate::Listener;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_until() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let started = Instant::now();
+        let reason = listener
+            .handle_incoming_until(
+                |_| (),
+                Duration::from_millis(5),
+                Deadline::after(Duration::from_millis(50)),
+            )
+            .unwrap();
+        assert_eq!(reason, StopReason::Deadline);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let _a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut handled = 0;
+        let mut checks = 0;
+        let reason = listener
+            .handle_incoming_until(
+                |_| handled += 1,
+                Duration::from_millis(5),
+                || {
+                    checks += 1;
+                    checks > 3
+                },
+            )
+            .unwrap();
+        assert_eq!(reason, StopReason::Condition);
+        assert_eq!(handled, 1);
+    }
+
+    #[test]
+    fn test_sim_deadline() {
+        let clock = SimClock::new();
+        let mut deadline = Deadline::with_time(Duration::from_secs(3600), Arc::new(clock.clone()));
+        assert!(!deadline.should_stop());
+        clock.advance(Duration::from_secs(1800));
+        assert_eq!(deadline.remaining(), Duration::from_secs(1800));
+        clock.sleep(Duration::from_secs(1800));
+        assert!(deadline.should_stop());
+        assert_eq!(deadline.remaining(), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Virtual hosting by TLS server name.
+//!
+//! [VirtualHosts](struct.VirtualHosts.html) peeks at the ClientHello of
+//! each connection and passes the connection to the handler for the
+//! host named in its SNI extension. Hosts may be exact names or
+//! wildcards such as `*.example.com`, and each host has its own limit
+//! on concurrent connections. Nothing is consumed from the stream, so
+//! the handler performs the TLS handshake itself, e.g. with a
+//! [TlsAcceptor](../secure/trait.TlsAcceptor.html) holding the host's
+//! certificate.
+//!
+//! Each connection's ClientHello is peeked on its own thread, but at
+//! most [DEFAULT_MAX_PENDING](constant.DEFAULT_MAX_PENDING.html) peeks
+//! (see [max_pending()](struct.VirtualHosts.html#method.max_pending))
+//! run at once; connections beyond that are dropped without starting a
+//! thread, so a flood of silent clients can't exhaust threads.
+//!
+//! For a test listener impersonating several hosts, the builder style
+//! [route()](struct.VirtualHosts.html#method.route) and
+//! [route_default()](struct.VirtualHosts.html#method.route_default)
+//! add hosts without connection limits.
+
+use crate::tls::{peek_client_hello, ClientHello};
+use crate::{accept_loop, Callbacks, Error, StopReason};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Default limit on ClientHellos being peeked at once.
+pub const DEFAULT_MAX_PENDING: usize = 256;
+
+type Handler = Arc<dyn Fn(TcpStream, Option<&str>) + Send + Sync>;
+
+struct Host {
+    handler: Handler,
+    max_active: usize,
+    active: Arc<AtomicUsize>,
+}
+
+/// Handlers for TLS connections, chosen by server name.
+pub struct VirtualHosts {
+    hosts: HashMap<String, Arc<Host>>,
+    default: Option<Arc<Host>>,
+    peek_timeout: Duration,
+    max_pending: usize,
+    pending: Arc<AtomicUsize>,
+}
+
+// One of a bounded number of slots, given back when dropped.
+pub(crate) struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    pub(crate) fn acquire(count: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        if count.fetch_add(1, Ordering::SeqCst) >= max {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot(count.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl VirtualHosts {
+    /// Create an empty set of hosts, which waits up to peek_timeout for
+    /// each ClientHello.
+    pub fn new(peek_timeout: Duration) -> VirtualHosts {
+        VirtualHosts {
+            hosts: HashMap::new(),
+            default: None,
+            peek_timeout,
+            max_pending: DEFAULT_MAX_PENDING,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Peek at most max_pending ClientHellos at once.
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Add a host, which may be a wildcard such as `*.example.com`.
+    /// At most max_active of its connections are handled at once. The
+    /// handler is given the stream and the requested server name.
+    pub fn add_host<F>(&mut self, name: &str, max_active: usize, handler: F)
+    where
+        F: Fn(TcpStream, Option<&str>) + Send + Sync + 'static,
+    {
+        self.hosts
+            .insert(name.to_ascii_lowercase(), host(max_active, handler));
+    }
+
+    /// Set the host for connections which don't match any other host,
+    /// including those without SNI.
+    pub fn set_default<F>(&mut self, max_active: usize, handler: F)
+    where
+        F: Fn(TcpStream, Option<&str>) + Send + Sync + 'static,
+    {
+        self.default = Some(host(max_active, handler));
+    }
+
+    /// Add a host, without a limit on its connections, and return self
+    /// for chaining.
+    pub fn route<F>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(TcpStream, Option<&str>) + Send + Sync + 'static,
+    {
+        self.add_host(name, usize::MAX, handler);
+        self
+    }
+
+    /// Set the default host, without a limit on its connections, and
+    /// return self for chaining.
+    pub fn route_default<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(TcpStream, Option<&str>) + Send + Sync + 'static,
+    {
+        self.set_default(usize::MAX, handler);
+        self
+    }
+
+    /// Number of connections being handled for the host added as name.
+    pub fn active(&self, name: &str) -> usize {
+        self.hosts
+            .get(&name.to_ascii_lowercase())
+            .map_or(0, |host| host.active.load(Ordering::SeqCst))
+    }
+
+    /// Handle stream on a new thread: peek at its server name and pass
+    /// it to the matching host's handler. Connections which don't match
+    /// a host, or whose host is at its limit, are dropped, as are
+    /// connections arriving while max_pending peeks are in progress.
+    pub fn dispatch(self: &Arc<Self>, stream: TcpStream) {
+        let peeking = match Slot::acquire(&self.pending, self.max_pending) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let hosts = self.clone();
+        thread::spawn(move || {
+            let name = match peek_client_hello(&stream, hosts.peek_timeout) {
+                Ok(record) => record
+                    .as_ref()
+                    .and_then(|record| ClientHello::parse(record))
+                    .and_then(|hello| hello.server_name()),
+                Err(_) => return,
+            };
+            drop(peeking);
+            let host = match hosts.lookup(name.as_deref()) {
+                Some(host) => host,
+                None => return,
+            };
+            // Released even if the handler panics
+            let _active = match Slot::acquire(&host.active, host.max_active) {
+                Some(slot) => slot,
+                None => return,
+            };
+            (host.handler)(stream, name.as_deref());
+        });
+    }
+
+    /// Accept connections from listener until it is closed, dispatching
+    /// each one to its host.
+    pub fn handle_incoming(
+        self: &Arc<Self>,
+        listener: &TcpListener,
+        timeout: Duration,
+    ) -> Result<StopReason, Error> {
+        let mut handle = |stream, _, _| self.dispatch(stream);
+        accept_loop(listener, &mut handle, timeout, Callbacks::default()).map_err(Error::from)
+    }
+
+    // Exact names win over wildcards, and longer wildcards over shorter
+    fn lookup(&self, name: Option<&str>) -> Option<Arc<Host>> {
+        if let Some(name) = name {
+            if let Some(host) = self.hosts.get(name) {
+                return Some(host.clone());
+            }
+            let mut suffix = name;
+            while let Some(dot) = suffix.find('.') {
+                suffix = &suffix[dot + 1..];
+                if let Some(host) = self.hosts.get(&format!("*.{}", suffix)) {
+                    return Some(host.clone());
+                }
+            }
+        }
+        self.default.clone()
+    }
+}
+
+fn host<F>(max_active: usize, handler: F) -> Arc<Host>
+where
+    F: Fn(TcpStream, Option<&str>) + Send + Sync + 'static,
+{
+    Arc::new(Host {
+        handler: Arc::new(handler),
+        max_active,
+        active: Arc::new(AtomicUsize::new(0)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = vec![];
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+        let mut ext = vec![0, 0];
+        ext.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        ext.extend_from_slice(&sni);
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+        let mut hs = vec![1, 0];
+        hs.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hs.extend_from_slice(&body);
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&(hs.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hs);
+        record
+    }
+
+    fn reply(tag: &'static str) -> impl Fn(TcpStream, Option<&str>) + Send + Sync {
+        move |mut stream, name| {
+            // Consume the ClientHello, so that closing doesn't reset
+            stream.read_to_end(&mut vec![]).unwrap();
+            let msg = format!("{} {}", tag, name.unwrap_or("-"));
+            stream.write_all(msg.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sni() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut hosts = VirtualHosts::new(Duration::from_secs(5));
+        hosts.add_host("api.example.com", 4, reply("api"));
+        hosts.add_host("*.example.com", 4, reply("wild"));
+        hosts.set_default(4, reply("default"));
+        let hosts = Arc::new(hosts);
+
+        let request = |name: &str| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(&client_hello(name)).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            hosts.dispatch(listener.accept().unwrap().0);
+            let mut buf = String::new();
+            client.read_to_string(&mut buf).unwrap();
+            buf
+        };
+        assert_eq!(request("API.example.com"), "api api.example.com");
+        assert_eq!(request("www.example.com"), "wild www.example.com");
+        assert_eq!(request("example.org"), "default example.org");
+
+        let router = VirtualHosts::new(Duration::from_secs(5))
+            .route("api.test.local", reply("api"))
+            .route_default(reply("default"));
+        assert!(router.lookup(Some("api.test.local")).is_some());
+        assert_eq!(router.active("api.test.local"), 0);
+        let fallback = router.lookup(Some("www.test.local")).unwrap();
+        assert!(Arc::ptr_eq(&fallback, router.default.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut hosts = VirtualHosts::new(Duration::from_secs(5)).max_pending(1);
+        hosts.add_host("api.example.com", 1, |_, _| panic!("handler failed"));
+        let hosts = Arc::new(hosts);
+
+        // A silent client holds the only peek slot, so the next is dropped
+        let silent = TcpStream::connect(addr).unwrap();
+        hosts.dispatch(listener.accept().unwrap().0);
+        let mut dropped = TcpStream::connect(addr).unwrap();
+        hosts.dispatch(listener.accept().unwrap().0);
+        assert_eq!(dropped.read(&mut [0; 1]).unwrap_or(0), 0);
+        drop(silent);
+
+        // A panicking handler gives its slot back
+        while hosts.pending.load(Ordering::SeqCst) != 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&client_hello("api.example.com")).unwrap();
+        hosts.dispatch(listener.accept().unwrap().0);
+        // The stream is dropped as the handler unwinds, before the slot
+        let _ = client.read(&mut [0; 1]);
+        while hosts.active("api.example.com") != 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Watchdog for handlers which run for too long.
+//!
+//! Handlers register their stream with [Watchdog::watch](struct.Watchdog.html#method.watch)
+//! and hold on to the returned guard while they run. A background
+//! thread fires a callback for any handler which exceeds the configured
+//! threshold and, optionally, shuts the stream down so that a handler
+//! blocked on I/O is woken up. Each entry is removed when its guard is
+//! dropped, including when the handler panics.
+//!
+//! [Listener::handle_incoming_spawn_with_watchdog()](../trait.Listener.html#tymethod.handle_incoming_spawn_with_watchdog)
+//! and [ThreadPoolListener::with_watchdog()](../threaded/struct.ThreadPoolListener.html#method.with_watchdog)
+//! watch every handler they run. A watchdog can be shared between
+//! threads in an Arc, and [watch_for()](struct.Watchdog.html#method.watch_for)
+//! gives a handler its own threshold, so one watchdog thread can serve
+//! loops with different limits.
+//! [handle_incoming_with_deadline()](../trait.Listener.html#tymethod.handle_incoming_with_deadline)
+//! loops share one in this way.
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Details of a handler which has exceeded the watchdog threshold.
+#[derive(Clone, Debug)]
+pub struct HungHandler {
+    /// Watchdog assigned identifier for this registration.
+    pub id: u64,
+    /// Address of the connected peer, if known.
+    pub peer_addr: Option<SocketAddr>,
+    /// When the handler was registered.
+    pub started: Instant,
+    /// How long the handler had been running when detected.
+    pub elapsed: Duration,
+}
+
+type HungCallback = Box<dyn Fn(&HungHandler) + Send + Sync>;
+
+struct Entry {
+    stream: TcpStream,
+    peer_addr: Option<SocketAddr>,
+    started: Instant,
+    threshold: Duration,
+    fired: bool,
+}
+
+struct Inner {
+    entries: Mutex<HashMap<u64, Entry>>,
+    next_id: AtomicU64,
+    threshold: Duration,
+    force_close: bool,
+    on_hung: HungCallback,
+}
+
+/// Monitors registered handlers and reports those which hang.
+pub struct Watchdog {
+    inner: Arc<Inner>,
+    stop: Option<Mutex<Sender<()>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Keeps a handler registered with a [Watchdog](struct.Watchdog.html).
+/// Dropping the guard marks the handler as finished.
+#[must_use = "the handler is only watched while the guard is held"]
+pub struct WatchGuard {
+    inner: Arc<Inner>,
+    id: u64,
+}
+
+impl Watchdog {
+    /// Start a watchdog which calls on_hung once for each handler which
+    /// is still registered after threshold. If force_close is true, the
+    /// stream of a hung handler is also shut down.
+    pub fn new<F>(threshold: Duration, force_close: bool, on_hung: F) -> Watchdog
+    where
+        F: Fn(&HungHandler) + Send + Sync + 'static,
+    {
+        let inner = Arc::new(Inner {
+            entries: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            threshold,
+            force_close,
+            on_hung: Box::new(on_hung),
+        });
+        let (stop, rx) = mpsc::channel::<()>();
+        let interval = std::cmp::max(threshold / 4, Duration::from_millis(1));
+        let t_inner = inner.clone();
+        let thread = thread::spawn(move || {
+            // Scan every interval, and when a handler with a shorter
+            // threshold is due. A message means one was registered.
+            let mut wait = interval;
+            while let Ok(()) | Err(RecvTimeoutError::Timeout) = rx.recv_timeout(wait) {
+                wait = match t_inner.scan() {
+                    Some(due) => due
+                        .saturating_duration_since(Instant::now())
+                        .clamp(Duration::from_millis(1), interval),
+                    None => interval,
+                };
+            }
+        });
+        Watchdog {
+            inner,
+            stop: Some(Mutex::new(stop)),
+            thread: Some(thread),
+        }
+    }
+
+    /// Register a handler's stream. The handler is considered finished
+    /// when the returned guard is dropped.
+    pub fn watch(&self, stream: &TcpStream) -> Result<WatchGuard, Error> {
+        self.watch_for(stream, self.inner.threshold)
+    }
+
+    /// Works like watch(), but the handler is reported once it has run
+    /// for threshold rather than the watchdog's own threshold.
+    pub fn watch_for(&self, stream: &TcpStream, threshold: Duration) -> Result<WatchGuard, Error> {
+        let entry = Entry {
+            stream: stream.try_clone()?,
+            peer_addr: stream.peer_addr().ok(),
+            started: Instant::now(),
+            threshold,
+            fired: false,
+        };
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        self.inner.entries.lock().unwrap().insert(id, entry);
+        if threshold < self.inner.threshold {
+            // Have the thread work out when to scan again
+            if let Some(stop) = &self.stop {
+                let _ = stop.lock().unwrap().send(());
+            }
+        }
+        Ok(WatchGuard {
+            inner: self.inner.clone(),
+            id,
+        })
+    }
+
+    /// Number of handlers currently registered.
+    pub fn active(&self) -> usize {
+        self.inner.entries.lock().unwrap().len()
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The watchdog shared by handle_incoming_with_deadline() loops, which
+// watch each handler with their own lifetime. It is never dropped.
+pub(crate) fn lifetimes() -> Arc<Watchdog> {
+    static LIFETIMES: Mutex<Option<Arc<Watchdog>>> = Mutex::new(None);
+    LIFETIMES
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| Arc::new(Watchdog::new(Duration::MAX, true, |_| ())))
+        .clone()
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        self.inner.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Inner {
+    // Report hung handlers, and return when the next one is due.
+    fn scan(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut hung = vec![];
+        let mut next = None;
+        {
+            let mut entries = self.entries.lock().unwrap();
+            for (id, entry) in entries.iter_mut() {
+                let elapsed = now.duration_since(entry.started);
+                if !entry.fired && elapsed < entry.threshold {
+                    if let Some(due) = entry.started.checked_add(entry.threshold) {
+                        next = Some(next.map_or(due, |next: Instant| next.min(due)));
+                    }
+                }
+                if !entry.fired && elapsed >= entry.threshold {
+                    entry.fired = true;
+                    if self.force_close {
+                        let _ = entry.stream.shutdown(Shutdown::Both);
+                    }
+                    hung.push(HungHandler {
+                        id: *id,
+                        peer_addr: entry.peer_addr,
+                        started: entry.started,
+                        elapsed,
+                    });
+                }
+            }
+        }
+        for info in hung.iter() {
+            (self.on_hung)(info);
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_hung_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let f_clone = fired.clone();
+        let watchdog = Watchdog::new(Duration::from_millis(20), true, move |_| {
+            f_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        let guard = watchdog.watch(&stream).unwrap();
+        assert_eq!(watchdog.active(), 1);
+        let mut buf = [0; 1];
+        // The watchdog shuts the stream down, so the client sees EOF
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+        drop(guard);
+        assert_eq!(watchdog.active(), 0);
+    }
+
+    #[test]
+    fn test_watch_for() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        // The handler's own threshold applies, long before the
+        // watchdog's first scan
+        let watchdog = Watchdog::new(Duration::from_secs(3600), true, |_| ());
+        let started = Instant::now();
+        let _guard = watchdog
+            .watch_for(&stream, Duration::from_millis(20))
+            .unwrap();
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Prefork::run() must be called before any other threads are started,
+// so this runs without the test harness. The client is a forked
+// process too, which ends the run by sending SIGTERM to the parent.
+
+#[cfg(unix)]
+mod prefork {
+    use nblistener::prefork::{Prefork, WorkerEvent};
+    use nblistener::Listener;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn handle_client(mut stream: TcpStream) {
+        let mut byte = [0];
+        let _ = stream.read_exact(&mut byte);
+        if byte[0] == b'x' {
+            unsafe { libc::_exit(3) };
+        }
+        let _ = stream.write_all(b"ok");
+    }
+
+    fn worker(listener: &TcpListener) {
+        let _ = listener.handle_incoming(handle_client, Duration::from_millis(5));
+    }
+
+    fn client(addr: SocketAddr) -> bool {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"a").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+
+        // Crash a worker, which is restarted
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"x").unwrap();
+        let _ = stream.read_to_end(&mut vec![]);
+        thread::sleep(Duration::from_millis(500));
+        reply == "ok"
+    }
+
+    pub fn test_prefork() {
+        let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => {
+                let ok = std::panic::catch_unwind(|| client(addr)).unwrap_or(false);
+                unsafe {
+                    libc::kill(libc::getppid(), libc::SIGTERM);
+                    libc::_exit(if ok { 0 } else { 1 })
+                }
+            }
+            pid => pid,
+        };
+
+        let mut events = vec![];
+        Prefork::new(2)
+            .restart_delay(Duration::from_millis(50))
+            .grace(Duration::from_secs(5))
+            .run(&listener, worker, |event| events.push(*event))
+            .unwrap();
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(client, &mut status, 0) }, client);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        let started = events
+            .iter()
+            .filter(|e| matches!(e, WorkerEvent::Started { .. }))
+            .count();
+        let crashed = events
+            .iter()
+            .filter(|e| matches!(e, WorkerEvent::Exited { crashed: true, .. }))
+            .count();
+        assert_eq!(started, 3);
+        assert_eq!(crashed, 1);
+    }
+}
+
+fn main() {
+    #[cfg(unix)]
+    prefork::test_prefork();
+}
@@ -0,0 +1,60 @@
+// Copyright 2019 nblistener developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Signals are process wide, so this file holds a single test and runs
+// as a process of its own.
+
+#![cfg(unix)]
+
+use nblistener::signal::close_on_signal;
+use nblistener::Listener;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static CHAINED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn previous(_signum: libc::c_int) {
+    CHAINED.store(true, Ordering::SeqCst);
+}
+
+fn handler(signum: libc::c_int) -> libc::sighandler_t {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(signum, std::ptr::null(), &mut action);
+        action.sa_sigaction
+    }
+}
+
+#[test]
+fn test_close_on_sigterm() {
+    let previous = previous as *const () as libc::sighandler_t;
+    unsafe { libc::signal(libc::SIGTERM, previous) };
+
+    let listener: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+    let guard = close_on_signal(&listener).unwrap();
+    assert_ne!(handler(libc::SIGTERM), previous);
+    unsafe { libc::raise(libc::SIGTERM) };
+    // The listener is closed and the earlier handler still runs
+    assert!(listener
+        .handle_incoming(|_| (), Duration::from_millis(10))
+        .is_ok());
+    assert!(CHAINED.load(Ordering::SeqCst));
+
+    // Another listener takes the slot the signal emptied, and dropping
+    // the first guard leaves it registered
+    let other: TcpListener = Listener::bind("127.0.0.1:0").unwrap();
+    let other_guard = close_on_signal(&other).unwrap();
+    drop(guard);
+    unsafe { libc::raise(libc::SIGTERM) };
+    assert!(matches!(other.accept_nonblocking(), Err(err) if err.is_closed()));
+
+    drop(other_guard);
+    assert_eq!(handler(libc::SIGTERM), previous);
+}